@@ -0,0 +1,153 @@
+//! Update a local zchunk file from a remote one over HTTP range requests
+//!
+//! The example serves the newer testdata file from a tiny local HTTP server and walks through
+//! the whole flow with plain std networking: fetch the header, find the chunks missing from
+//! the old file, coalesce them into few range requests, fetch them and assemble the new file.
+//!
+//! Run with `cargo run --example http_sync`.
+
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Cursor, Read, Write},
+    net::{TcpListener, TcpStream},
+    ops::Range,
+    thread,
+};
+
+use sha2::{Digest, Sha256};
+use zchunk::{ChunkAvailability, CoalescePolicy, Decoder, RangePlanner, ZchunkError};
+
+const REMOTE: &str = "testdata/c25ffa05cf1fdeb67801847df96c33933b1ee1ea081af52edff4ff371a1c814c-comps-Server.x86_64.xml.zck";
+const LOCAL: &str = "testdata/3c6181c789ef9e8ed23f4072eb2f8f529002abd5166273a9734d7d39f7a810ae-comps-Server.x86_64.xml.zck";
+const EXPECTED: &str = "14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68";
+
+/// Serve `data` answering single `Range: bytes=a-b` requests, one request per connection
+fn serve(listener: TcpListener, data: Vec<u8>) {
+    for stream in listener.incoming() {
+        let mut stream = stream.unwrap();
+        let mut range = 0..data.len();
+        for line in BufReader::new(&mut stream).lines() {
+            let line = line.unwrap();
+            if line.is_empty() {
+                break;
+            }
+            if let Some(spec) = line.strip_prefix("Range: bytes=") {
+                let (start, end) = spec.split_once('-').unwrap();
+                let end = end.parse::<usize>().unwrap() + 1;
+                range = start.parse().unwrap()..end.min(data.len());
+            }
+        }
+
+        let body = &data[range.clone()];
+        write!(
+            stream,
+            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            range.start,
+            range.end - 1,
+            data.len(),
+            body.len()
+        )
+        .unwrap();
+        stream.write_all(body).unwrap();
+    }
+}
+
+/// Fetch a byte range with a plain HTTP/1.1 request
+fn fetch(addr: &str, range: Range<u64>) -> io::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect(addr)?;
+    write!(
+        stream,
+        "GET /file.zck HTTP/1.1\r\nHost: {addr}\r\nRange: bytes={}-{}\r\nConnection: close\r\n\r\n",
+        range.start,
+        range.end - 1
+    )?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    let body_start = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| io::Error::other("malformed response"))?;
+    if !response.starts_with(b"HTTP/1.1 206") {
+        return Err(io::Error::other("range request refused"));
+    }
+    Ok(response.split_off(body_start + 4))
+}
+
+/// Fetch a growing prefix of the file until the whole header is in it
+fn fetch_header(addr: &str) -> Result<Vec<u8>, ZchunkError> {
+    let mut size = 1024;
+    loop {
+        let prefix = fetch(addr, 0..size)?;
+        match Decoder::new(Cursor::new(&prefix)) {
+            Ok(_) => return Ok(prefix),
+            Err(ZchunkError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => size *= 2,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn main() -> Result<(), ZchunkError> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?.to_string();
+    let remote = fs::read(REMOTE)?;
+    thread::spawn(move || serve(listener, remote));
+
+    // 1. header
+    let mut file = fetch_header(&addr)?;
+    let remote_header = Decoder::new(Cursor::new(file.clone()))?;
+    let header = remote_header.header();
+    let mut cache = Decoder::new(Cursor::new(fs::read(LOCAL)?))?;
+
+    // 2. plan: every chunk the local file has is available already
+    let keys = header.export_chunk_keys()?;
+    let mut availability = ChunkAvailability::none(keys.len());
+    for (id, key) in keys.iter().enumerate() {
+        availability.set(id, cache.header().lookup_one(key.digest()).is_some());
+    }
+    let missing = header.missing_ranges(&availability)?;
+
+    // 3. coalesce
+    let policy = CoalescePolicy {
+        max_requests: 4,
+        max_waste_bytes: 64 * 1024,
+        gap_tolerance: 512,
+    };
+    let requests = RangePlanner::coalesce(&missing, &policy);
+    println!(
+        "{} chunks, {} missing ranges, {} requests",
+        keys.len(),
+        missing.len(),
+        requests.len()
+    );
+
+    // 4. fetch into a sparse copy of the remote file
+    let mut fetched = Vec::new();
+    for request in &requests {
+        let data = fetch(&addr, request.range.clone())?;
+        let range = request.range.start as usize..request.range.end as usize;
+        if file.len() < range.end {
+            file.resize(range.end, 0);
+        }
+        file[range].copy_from_slice(&data);
+        fetched.push(request.range.clone());
+        println!(
+            "fetched {:?}, {} bytes wasted",
+            request.range, request.wasted_bytes
+        );
+    }
+
+    // 5. assemble the new file from fetched and local chunks
+    let fetched_availability = ChunkAvailability::from_byte_ranges(header, &fetched)?;
+    let mut partial = Decoder::with_availability(Cursor::new(file), fetched_availability)?;
+    let mut assembled = Vec::new();
+    partial.sync_to(&mut cache, &mut assembled)?;
+
+    let mut hasher = Sha256::new();
+    Decoder::new(Cursor::new(assembled))?.decompress_to(&mut hasher)?;
+    let checksum = hex::encode(hasher.finalize());
+    assert_eq!(checksum, EXPECTED);
+    println!("assembled file decodes to {checksum}");
+
+    Ok(())
+}
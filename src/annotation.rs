@@ -0,0 +1,144 @@
+#[cfg(feature = "zstd")]
+use crate::{format::OptionalElement, types::VariantInt};
+use crate::{
+    format::{ChunkId, Header},
+    types::ReadVariantInt,
+};
+
+/// Optional element id of the chunk annotation table
+///
+/// The id is specific to this crate and far away from the ids assigned by upstream, other
+/// readers skip the element like any unknown optional element.
+pub(crate) const CHUNK_ANNOTATIONS_ELEMENT_ID: u64 = 0x7a63_6b01;
+
+/// Payload layout version, written as the first varint of the element
+const CHUNK_ANNOTATIONS_VERSION: u64 = 1;
+
+/// Build the annotation table element: version, count, then one varint per chunk
+#[cfg(feature = "zstd")]
+pub(crate) fn chunk_annotations_element(annotations: &[u64]) -> OptionalElement {
+    let mut data = Vec::new();
+    for n in [CHUNK_ANNOTATIONS_VERSION, annotations.len() as u64]
+        .iter()
+        .chain(annotations)
+    {
+        // writing into a Vec never fails
+        let _ = VariantInt::from(*n).write_to(&mut data);
+    }
+
+    OptionalElement {
+        id: CHUNK_ANNOTATIONS_ELEMENT_ID,
+        data,
+    }
+}
+
+/// Parse the annotation table, `None` for unknown versions or malformed payloads
+pub(crate) fn parse_chunk_annotations(mut data: &[u8]) -> Option<Vec<u64>> {
+    let mut next = || data.read_variant_int().ok()?.to_u64().ok();
+    if next()? != CHUNK_ANNOTATIONS_VERSION {
+        return None;
+    }
+
+    let count = next()?;
+    let mut annotations = Vec::new();
+    for _ in 0..count {
+        annotations.push(next()?);
+    }
+    Some(annotations)
+}
+
+impl Header {
+    /// The application annotation of a data chunk
+    ///
+    /// `None` when the file has no annotation table, the table is of an unknown version, or
+    /// the table is shorter than the chunk list.
+    pub fn chunk_annotation(&self, id: ChunkId) -> Option<u64> {
+        self.chunk_annotations
+            .get_or_init(|| {
+                self.preface
+                    .optional_element(CHUNK_ANNOTATIONS_ELEMENT_ID)
+                    .and_then(parse_chunk_annotations)
+            })
+            .as_ref()?
+            .get(id)
+            .copied()
+    }
+}
+
+#[cfg(all(test, feature = "zstd"))]
+mod tests {
+    use std::{
+        fs::File,
+        io::{BufReader, Cursor},
+    };
+
+    use sha2::{Digest, Sha256};
+
+    use super::{chunk_annotations_element, parse_chunk_annotations, CHUNK_ANNOTATIONS_ELEMENT_ID};
+    use crate::{types::VariantInt, Decoder, Encoder, EncoderOptions, ZchunkError};
+
+    const INPUT: &str = "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml";
+
+    fn encode(options: EncoderOptions) -> Result<Vec<u8>, ZchunkError> {
+        let input = BufReader::new(File::open(INPUT).unwrap());
+        let mut encoder = Encoder::with_options(input, Cursor::new(Vec::new()), options)?;
+        encoder.prepare_chunks()?;
+        let mut output = Vec::new();
+        encoder.compress_to(&mut output)?;
+        Ok(output)
+    }
+
+    #[test]
+    fn test_annotation_round_trip() {
+        let output = encode(EncoderOptions::new().chunk_annotations(vec![1, 0, 300])).unwrap();
+
+        let mut decoder = Decoder::new(Cursor::new(output)).unwrap();
+        let header = &decoder.header;
+        assert!(header.preface.flags.has_optional());
+        assert_eq!(header.chunk_annotation(0), Some(1));
+        assert_eq!(header.chunk_annotation(1), Some(0));
+        assert_eq!(header.chunk_annotation(2), Some(300));
+        assert_eq!(header.chunk_annotation(3), None);
+
+        // annotations do not affect decoding
+        let mut hasher = Sha256::new();
+        decoder.decompress_to(&mut hasher).unwrap();
+        assert_eq!(
+            hex::encode(hasher.finalize()),
+            "14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68"
+        );
+    }
+
+    #[test]
+    fn test_annotation_absent() {
+        let output = encode(EncoderOptions::new()).unwrap();
+        let decoder = Decoder::new(Cursor::new(output)).unwrap();
+        assert!(!decoder.header.preface.flags.has_optional());
+        assert_eq!(decoder.header.chunk_annotation(0), None);
+    }
+
+    #[test]
+    fn test_annotation_too_many() {
+        let err = encode(EncoderOptions::new().chunk_annotations(vec![0; 100])).unwrap_err();
+        assert!(matches!(
+            err,
+            ZchunkError::TooManyChunkAnnotations {
+                chunks: 3,
+                annotations: 100
+            }
+        ));
+    }
+
+    #[test]
+    fn test_annotation_unknown_version() {
+        let mut element = chunk_annotations_element(&[1, 2]);
+        assert_eq!(element.id, CHUNK_ANNOTATIONS_ELEMENT_ID);
+        assert_eq!(parse_chunk_annotations(&element.data), Some(vec![1, 2]));
+
+        let mut data = Vec::new();
+        VariantInt::from(2).write_to(&mut data).unwrap();
+        element.data.splice(..1, data);
+        assert_eq!(parse_chunk_annotations(&element.data), None);
+        assert_eq!(parse_chunk_annotations(&[]), None);
+    }
+}
@@ -0,0 +1,236 @@
+use crate::format::{ChunkId, Header};
+
+/// Default factor a chunk ratio may deviate from the median before it is flagged
+const DEFAULT_RATIO_FACTOR: f64 = 50.0;
+
+/// Thresholds used by `Header::anomalies`
+#[derive(Debug, Clone)]
+pub struct AnomalyOptions {
+    ratio_factor: f64,
+    max_uncompressed_length: Option<u64>,
+    file_size: Option<u64>,
+}
+
+impl Default for AnomalyOptions {
+    fn default() -> Self {
+        Self {
+            ratio_factor: DEFAULT_RATIO_FACTOR,
+            max_uncompressed_length: None,
+            file_size: None,
+        }
+    }
+}
+
+impl AnomalyOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Flag chunks whose compression ratio is more than `factor` times above or below
+    /// the median ratio of the file
+    pub fn ratio_factor(mut self, factor: f64) -> Self {
+        self.ratio_factor = factor;
+        self
+    }
+
+    /// Flag chunks whose uncompressed length exceeds `max`
+    pub fn max_uncompressed_length(mut self, max: u64) -> Self {
+        self.max_uncompressed_length = Some(max);
+        self
+    }
+
+    /// The size of the whole file, flag chunks extending past its end
+    pub fn file_size(mut self, file_size: u64) -> Self {
+        self.file_size = Some(file_size);
+        self
+    }
+}
+
+/// Why a chunk looks suspicious
+#[derive(Debug, Clone, PartialEq)]
+pub enum AnomalyReason {
+    /// The chunk has no compressed data but claims uncompressed data
+    EmptyCompressed { uncompressed_length: u64 },
+    /// The compression ratio is far away from the median ratio of the file
+    RatioOutlier { ratio: f64, median: f64 },
+    /// The uncompressed length exceeds the configured maximum
+    UncompressedTooLarge { length: u64, max: u64 },
+    /// The chunk ends after the end of the file
+    BeyondFileEnd { end: u64, file_size: u64 },
+}
+
+/// A suspicious chunk found by `Header::anomalies`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Anomaly {
+    pub id: ChunkId,
+    pub reason: AnomalyReason,
+}
+
+impl Header {
+    /// Flag chunks whose index entries look corrupted, using the header alone
+    ///
+    /// A chunk may be reported once per reason, anomalies are ordered by chunk id.
+    pub fn anomalies(&self, options: AnomalyOptions) -> Vec<Anomaly> {
+        let data_offset = self.data_offset().unwrap_or(0);
+        let lengths: Vec<(u64, u64, u64)> = self
+            .index
+            .data_chunks
+            .iter()
+            .map(|(chunk, offset)| {
+                (
+                    chunk.length.to_u64().unwrap_or(0),
+                    chunk.uncompressed_length.to_u64().unwrap_or(0),
+                    *offset,
+                )
+            })
+            .collect();
+
+        let mut ratios: Vec<f64> = lengths
+            .iter()
+            .filter(|(length, _, _)| *length > 0)
+            .map(|(length, uncompressed, _)| *uncompressed as f64 / *length as f64)
+            .collect();
+        ratios.sort_by(f64::total_cmp);
+        let median = ratios.get(ratios.len() / 2).copied();
+
+        let mut anomalies = Vec::new();
+        for (id, &(length, uncompressed_length, offset)) in lengths.iter().enumerate() {
+            let mut flag = |reason| anomalies.push(Anomaly { id, reason });
+
+            if length == 0 && uncompressed_length > 0 {
+                flag(AnomalyReason::EmptyCompressed {
+                    uncompressed_length,
+                });
+            }
+            if let Some(median) = median.filter(|m| *m > 0.0 && length > 0) {
+                let ratio = uncompressed_length as f64 / length as f64;
+                if ratio > median * options.ratio_factor || ratio * options.ratio_factor < median {
+                    flag(AnomalyReason::RatioOutlier { ratio, median });
+                }
+            }
+            if let Some(max) = options.max_uncompressed_length {
+                if uncompressed_length > max {
+                    flag(AnomalyReason::UncompressedTooLarge {
+                        length: uncompressed_length,
+                        max,
+                    });
+                }
+            }
+            if let Some(file_size) = options.file_size {
+                let end = data_offset.saturating_add(offset).saturating_add(length);
+                if end > file_size {
+                    flag(AnomalyReason::BeyondFileEnd { end, file_size });
+                }
+            }
+        }
+
+        anomalies
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Anomaly, AnomalyOptions, AnomalyReason};
+    use crate::test_utils::HeaderBuilder;
+
+    const CHECKSUM: &str = "01010101010101010101010101010101";
+
+    #[test]
+    fn test_no_anomalies() {
+        let header = HeaderBuilder::new()
+            .chunk(CHECKSUM, 100, 400)
+            .chunk(CHECKSUM, 120, 400)
+            .chunk(CHECKSUM, 0, 0)
+            .build()
+            .unwrap();
+        assert_eq!(header.anomalies(AnomalyOptions::new()), vec![]);
+    }
+
+    #[test]
+    fn test_empty_compressed() {
+        let header = HeaderBuilder::new()
+            .chunk(CHECKSUM, 100, 400)
+            .chunk(CHECKSUM, 0, 400)
+            .build()
+            .unwrap();
+        assert_eq!(
+            header.anomalies(AnomalyOptions::new()),
+            vec![Anomaly {
+                id: 1,
+                reason: AnomalyReason::EmptyCompressed {
+                    uncompressed_length: 400
+                }
+            }]
+        );
+    }
+
+    #[test]
+    fn test_ratio_outlier() {
+        let header = HeaderBuilder::new()
+            .chunk(CHECKSUM, 100, 400)
+            .chunk(CHECKSUM, 100, 400_000)
+            .chunk(CHECKSUM, 100, 500)
+            .chunk(CHECKSUM, 100, 300)
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            header.anomalies(AnomalyOptions::new().ratio_factor(10.0)),
+            vec![Anomaly {
+                id: 1,
+                reason: AnomalyReason::RatioOutlier {
+                    ratio: 4000.0,
+                    median: 5.0
+                }
+            }]
+        );
+        assert_eq!(
+            header.anomalies(AnomalyOptions::new().ratio_factor(1000.0)),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn test_uncompressed_too_large() {
+        let header = HeaderBuilder::new()
+            .chunk(CHECKSUM, 100, 400)
+            .chunk(CHECKSUM, 100, 500)
+            .build()
+            .unwrap();
+        assert_eq!(
+            header.anomalies(AnomalyOptions::new().max_uncompressed_length(450)),
+            vec![Anomaly {
+                id: 1,
+                reason: AnomalyReason::UncompressedTooLarge {
+                    length: 500,
+                    max: 450
+                }
+            }]
+        );
+    }
+
+    #[test]
+    fn test_beyond_file_end() {
+        let header = HeaderBuilder::new()
+            .chunk(CHECKSUM, 100, 400)
+            .chunk(CHECKSUM, 100, 400)
+            .build()
+            .unwrap();
+        let data_offset = header.data_offset().unwrap();
+
+        assert_eq!(
+            header.anomalies(AnomalyOptions::new().file_size(data_offset + 150)),
+            vec![Anomaly {
+                id: 1,
+                reason: AnomalyReason::BeyondFileEnd {
+                    end: data_offset + 200,
+                    file_size: data_offset + 150
+                }
+            }]
+        );
+        assert_eq!(
+            header.anomalies(AnomalyOptions::new().file_size(data_offset + 200)),
+            vec![]
+        );
+    }
+}
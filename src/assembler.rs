@@ -0,0 +1,326 @@
+use std::{
+    collections::BTreeMap,
+    io::{Cursor, Write},
+    ops::Range,
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::{
+    errors::{WriteStage, ZchunkError},
+    format::{checked_add, to_usize, ChunkId, CountingWriter, Decoder, Header},
+    options::AssemblerOptions,
+};
+
+/// Writes a zchunk file from ranges fetched in any order
+///
+/// The data region of the file, after the header, is split into ranges of whole chunks, see
+/// `ranges`. A fetcher submits each range as it arrives, the chunks in it are verified right
+/// away and the range is written once all ranges before it are written. Ranges that arrive
+/// early wait in a reorder buffer of at most `AssemblerOptions::max_buffered_bytes`, and the
+/// range that holds up writing is `next_needed`, which a fetcher should request first.
+pub struct PipelinedAssembler<W: Write> {
+    header: Header,
+    ranges: Vec<Range<u64>>,
+    /// The chunks of every range, `None` for the dict chunk
+    range_chunks: Vec<Vec<Option<ChunkId>>>,
+    writer: CountingWriter<W>,
+    hasher: Sha256,
+    /// The first range not written yet
+    frontier: usize,
+    buffered: BTreeMap<usize, Vec<u8>>,
+    buffered_bytes: u64,
+    max_buffered_bytes: u64,
+}
+
+impl<W: Write> PipelinedAssembler<W> {
+    /// Check the header against its checksum and write it to `out`
+    pub fn new(header: &Header, out: W, options: AssemblerOptions) -> Result<Self, ZchunkError> {
+        let mut header_bytes = Vec::new();
+        header.write_to(&mut header_bytes, false)?;
+        // the assembler keeps its own copy of the header
+        let header = Decoder::new(Cursor::new(header_bytes.as_slice()))?.header;
+        header.check_checksum()?;
+
+        let data_offset = header.data_offset()?;
+        let dict_length = header.index.dict_chunk.length.to_u64()?;
+        let mut chunks = Vec::with_capacity(header.index.data_chunks.len() + 1);
+        if dict_length > 0 {
+            chunks.push((None, 0..dict_length));
+        }
+        for (id, (chunk, offset)) in header.index.data_chunks.iter().enumerate() {
+            chunks.push((
+                Some(id),
+                *offset..checked_add(*offset, chunk.length.to_u64()?)?,
+            ));
+        }
+
+        let mut ranges: Vec<Range<u64>> = Vec::new();
+        let mut range_chunks: Vec<Vec<Option<ChunkId>>> = Vec::new();
+        for (id, range) in chunks {
+            let start = checked_add(data_offset, range.start)?;
+            let end = checked_add(data_offset, range.end)?;
+            match ranges.last_mut() {
+                Some(last) if end - last.start <= options.max_range_bytes => {
+                    last.end = end;
+                    range_chunks.last_mut().unwrap().push(id);
+                }
+                _ => {
+                    ranges.push(start..end);
+                    range_chunks.push(vec![id]);
+                }
+            }
+        }
+
+        let mut assembler = Self {
+            header,
+            ranges,
+            range_chunks,
+            writer: CountingWriter::new(out),
+            hasher: Sha256::new(),
+            frontier: 0,
+            buffered: BTreeMap::new(),
+            buffered_bytes: 0,
+            max_buffered_bytes: options.max_buffered_bytes,
+        };
+        assembler.emit(WriteStage::Header, &header_bytes)?;
+        Ok(assembler)
+    }
+
+    /// The absolute byte ranges of the file to fetch, a range id is an index into them
+    pub fn ranges(&self) -> &[Range<u64>] {
+        &self.ranges
+    }
+
+    /// The range the write frontier waits for, `None` once every range is written
+    pub fn next_needed(&self) -> Option<usize> {
+        (self.frontier < self.ranges.len()).then_some(self.frontier)
+    }
+
+    /// Bytes of ranges waiting in the reorder buffer
+    pub fn buffered_bytes(&self) -> u64 {
+        self.buffered_bytes
+    }
+
+    /// Verify the chunks of a fetched range, and write it with the ranges after it that
+    /// wait in the buffer, or buffer it when ranges before it are missing
+    ///
+    /// A range that fails verification, or does not fit the buffer with `ReorderBufferFull`,
+    /// is dropped and can be submitted again. Submitting a range that was accepted before
+    /// does nothing.
+    pub fn submit(&mut self, range_id: usize, bytes: Vec<u8>) -> Result<(), ZchunkError> {
+        let range = self
+            .ranges
+            .get(range_id)
+            .ok_or(ZchunkError::RangeNotFound(range_id))?;
+        if range_id < self.frontier || self.buffered.contains_key(&range_id) {
+            return Ok(());
+        }
+        let expected = range.end - range.start;
+        if bytes.len() as u64 != expected {
+            return Err(ZchunkError::RangeSizeMismatch {
+                range_id,
+                expected,
+                found: bytes.len() as u64,
+            });
+        }
+        self.verify(range_id, &bytes)?;
+
+        if range_id != self.frontier {
+            if self.buffered_bytes + expected > self.max_buffered_bytes {
+                return Err(ZchunkError::ReorderBufferFull {
+                    range_id,
+                    buffered: self.buffered_bytes,
+                    max: self.max_buffered_bytes,
+                });
+            }
+            self.buffered_bytes += expected;
+            self.buffered.insert(range_id, bytes);
+            return Ok(());
+        }
+
+        self.write_range(range_id, &bytes)?;
+        while let Some(bytes) = self.buffered.remove(&self.frontier) {
+            self.buffered_bytes -= bytes.len() as u64;
+            self.write_range(self.frontier, &bytes)?;
+        }
+        Ok(())
+    }
+
+    /// Flush the output once every range is written, and return the SHA-256 of the file
+    pub fn finish(mut self) -> Result<[u8; 32], ZchunkError> {
+        if let Some(next_needed) = self.next_needed() {
+            return Err(ZchunkError::AssemblyIncomplete { next_needed });
+        }
+        self.writer.flush()?;
+        Ok(self.hasher.finalize().into())
+    }
+
+    /// Check every chunk of a range against its checksum
+    fn verify(&self, range_id: usize, bytes: &[u8]) -> Result<(), ZchunkError> {
+        let mut rest = bytes;
+        for &id in &self.range_chunks[range_id] {
+            let chunk = match id {
+                Some(id) => &self.header.index.data_chunks[id].0,
+                None => &self.header.index.dict_chunk,
+            };
+            let (data, after) = rest.split_at(to_usize(chunk.length.to_u64()?, "chunk length")?);
+            self.header.check_chunk_data(id, chunk, data)?;
+            rest = after;
+        }
+        Ok(())
+    }
+
+    fn write_range(&mut self, range_id: usize, bytes: &[u8]) -> Result<(), ZchunkError> {
+        let mut rest = bytes;
+        for i in 0..self.range_chunks[range_id].len() {
+            let (stage, chunk) = match self.range_chunks[range_id][i] {
+                Some(id) => (WriteStage::Chunk(id), &self.header.index.data_chunks[id].0),
+                None => (WriteStage::Dict, &self.header.index.dict_chunk),
+            };
+            let (data, after) = rest.split_at(to_usize(chunk.length.to_u64()?, "chunk length")?);
+            self.emit(stage, data)?;
+            rest = after;
+        }
+        self.frontier += 1;
+        Ok(())
+    }
+
+    fn emit(&mut self, stage: WriteStage, bytes: &[u8]) -> Result<(), ZchunkError> {
+        self.writer
+            .write_all(bytes)
+            .map_err(|e| self.writer.fail(stage, e))?;
+        self.hasher.update(bytes);
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "zstd"))]
+mod tests {
+    use std::{fs, io::Cursor};
+
+    use sha2::{Digest, Sha256};
+
+    use super::PipelinedAssembler;
+    use crate::{
+        AssemblerOptions, ChunkerParams, Decoder, Encoder, EncoderOptions, Header, ZchunkError,
+    };
+
+    const INPUT: &str = "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml";
+
+    fn encoded() -> (Header, Vec<u8>) {
+        let input = fs::read(INPUT).unwrap();
+        let options = EncoderOptions::new()
+            .chunker_params(ChunkerParams::new(1024, 8192, 2047))
+            .dict(b"<group>".repeat(64));
+        let mut encoder =
+            Encoder::with_options(input.as_slice(), Cursor::new(Vec::new()), options).unwrap();
+        encoder.prepare_chunks().unwrap();
+        let mut file = Vec::new();
+        encoder.compress_to(&mut file).unwrap();
+        (
+            Decoder::new(Cursor::new(file.clone())).unwrap().header,
+            file,
+        )
+    }
+
+    /// A permutation of `0..n` that differs for every seed
+    fn shuffled(n: usize, seed: u64) -> Vec<usize> {
+        let mut ids: Vec<usize> = (0..n).collect();
+        ids.sort_by_key(|&i| {
+            Sha256::digest([seed.to_le_bytes(), (i as u64).to_le_bytes()].concat())
+        });
+        ids
+    }
+
+    fn range_bytes(file: &[u8], assembler: &PipelinedAssembler<Vec<u8>>, id: usize) -> Vec<u8> {
+        let range = &assembler.ranges()[id];
+        file[range.start as usize..range.end as usize].to_vec()
+    }
+
+    #[test]
+    fn test_assemble_shuffled() {
+        let (header, file) = encoded();
+        let expected: [u8; 32] = Sha256::digest(&file).into();
+
+        for (seed, max_range_bytes) in [(1, 0), (2, 0), (3, 4_000)] {
+            let max_buffered_bytes = 10_000;
+            let options = AssemblerOptions::new()
+                .max_buffered_bytes(max_buffered_bytes)
+                .max_range_bytes(max_range_bytes);
+            let mut assembler = PipelinedAssembler::new(&header, Vec::new(), options).unwrap();
+            let ranges = assembler.ranges().len();
+            assert!(ranges > 4, "{ranges}");
+
+            // the fetcher prefers the order it was given, but fetches the frontier when the
+            // buffer is full
+            let mut pending = shuffled(ranges, seed);
+            while !pending.is_empty() {
+                let id = pending[0];
+                match assembler.submit(id, range_bytes(&file, &assembler, id)) {
+                    Ok(()) => {
+                        pending.remove(0);
+                    }
+                    Err(ZchunkError::ReorderBufferFull { .. }) => {
+                        let needed = assembler.next_needed().unwrap();
+                        assembler
+                            .submit(needed, range_bytes(&file, &assembler, needed))
+                            .unwrap();
+                        pending.retain(|&i| i != needed);
+                    }
+                    Err(e) => panic!("{e}"),
+                }
+                assert!(assembler.buffered_bytes() <= max_buffered_bytes);
+            }
+
+            assert_eq!(assembler.next_needed(), None);
+            assert_eq!(assembler.finish().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        let (header, file) = encoded();
+        let mut assembler =
+            PipelinedAssembler::new(&header, Vec::new(), AssemblerOptions::new()).unwrap();
+        let ranges = assembler.ranges().len();
+        assert!(matches!(
+            assembler.submit(ranges, Vec::new()),
+            Err(ZchunkError::RangeNotFound(id)) if id == ranges
+        ));
+        assert!(matches!(
+            assembler.submit(1, vec![0; 3]),
+            Err(ZchunkError::RangeSizeMismatch {
+                range_id: 1,
+                found: 3,
+                ..
+            })
+        ));
+
+        // a corrupt range is dropped and can be fetched again
+        let mut corrupt = range_bytes(&file, &assembler, 1);
+        corrupt[0] ^= 0xff;
+        assert!(matches!(
+            assembler.submit(1, corrupt),
+            Err(ZchunkError::ChunkChecksumNotMatch { id: Some(0), .. })
+        ));
+        assert_eq!(assembler.buffered_bytes(), 0);
+        assembler
+            .submit(1, range_bytes(&file, &assembler, 1))
+            .unwrap();
+        assembler.submit(1, Vec::new()).unwrap();
+        assert_eq!(assembler.next_needed(), Some(0));
+
+        assert!(matches!(
+            assembler.finish(),
+            Err(ZchunkError::AssemblyIncomplete { next_needed: 0 })
+        ));
+
+        let mut tampered = header;
+        tampered.index.data_chunks[0].0.checksum[0] ^= 0xff;
+        assert!(matches!(
+            PipelinedAssembler::new(&tampered, Vec::new(), AssemblerOptions::new()),
+            Err(ZchunkError::HeaderChecksumNotMatch { .. })
+        ));
+    }
+}
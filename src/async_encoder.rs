@@ -0,0 +1,206 @@
+use std::io::{self, Write};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+    task,
+};
+
+use crate::{
+    errors::ZchunkError, options::EncoderOptions, report::EncodeStats, sink::EncoderSink,
+    temp::TempStore,
+};
+
+/// Input read before it is handed to a blocking thread to be chunked and compressed
+const INPUT_BATCH: usize = 1 << 20;
+
+/// Size of the output blocks the blocking thread sends to the writer
+const OUTPUT_BLOCK: usize = 64 << 10;
+
+/// Output blocks buffered between the blocking thread and the writer
+const OUTPUT_BLOCKS_IN_FLIGHT: usize = 4;
+
+/// An `Encoder` reading a tokio `AsyncRead` and writing to an `AsyncWrite`
+///
+/// Chunking, compression and all temp I/O run on tokio's blocking threads with
+/// `spawn_blocking`, the runtime only waits for the input and the output. The file written
+/// is the same as `Encoder` writes for the same input and options. Needs the `async`
+/// feature.
+///
+/// A future of the encoder that is dropped before it completes leaves the encoder
+/// unusable, later calls fail with `SinkFailed`.
+pub struct AsyncEncoder<RW, R> {
+    reader: R,
+    /// `None` while a blocking thread works on it
+    sink: Option<EncoderSink<RW>>,
+    prepared: bool,
+}
+
+impl<RW: TempStore + Send + 'static, R: AsyncRead + Unpin> AsyncEncoder<RW, R> {
+    /// Construct an encoder from an async input reader and a temp reader&writer
+    pub fn new(reader: R, temp: RW) -> Result<Self, ZchunkError> {
+        Self::with_options(reader, temp, EncoderOptions::default())
+    }
+
+    /// Construct an encoder with options, which fails like `Encoder::with_options`
+    pub fn with_options(reader: R, temp: RW, options: EncoderOptions) -> Result<Self, ZchunkError> {
+        Ok(Self {
+            reader,
+            sink: Some(EncoderSink::new(temp, options)?),
+            prepared: false,
+        })
+    }
+
+    /// Read the whole input, and store its compressed chunks in the temp, see
+    /// `Encoder::prepare_chunks`
+    ///
+    /// Runs once per encoder, any later call returns `AlreadyPrepared`. A failing input
+    /// reader is returned as `ZchunkError::Io` and cannot be resumed.
+    pub async fn prepare_chunks(&mut self) -> Result<(), ZchunkError> {
+        if self.prepared {
+            return Err(ZchunkError::AlreadyPrepared);
+        }
+        self.prepared = true;
+        let mut batch = Vec::with_capacity(INPUT_BATCH);
+        loop {
+            batch.clear();
+            let read = (&mut self.reader)
+                .take(INPUT_BATCH as u64)
+                .read_to_end(&mut batch)
+                .await?;
+            if read == 0 {
+                break;
+            }
+            batch = self
+                .blocking(move |sink| sink.push(&batch).map(|_| batch))
+                .await?;
+        }
+        self.blocking(|sink| sink.finish_prepare()).await
+    }
+
+    /// Write the zchunk file to `writer`, which requires `prepare_chunks`, and return the
+    /// `Encoder::stats`
+    ///
+    /// The file is streamed to `writer` in blocks as the blocking thread produces it. Like
+    /// `Encoder::compress_to` every call writes the same file, a failing writer is returned
+    /// as `ZchunkError::Io`.
+    pub async fn compress_to(
+        &mut self,
+        mut writer: impl AsyncWrite + Unpin,
+    ) -> Result<EncodeStats, ZchunkError> {
+        if !self.prepared {
+            return Err(ZchunkError::HeaderNotFound);
+        }
+        let (sender, mut receiver) = mpsc::channel(OUTPUT_BLOCKS_IN_FLIGHT);
+        let produce = self.blocking(move |sink| {
+            let mut output = io::BufWriter::with_capacity(OUTPUT_BLOCK, ChannelWriter(sender));
+            let stats = sink.compress_to(&mut output)?;
+            output.flush()?;
+            Ok(stats)
+        });
+        // dropping the receiver on a failed write stops the producer
+        let consume = async move {
+            while let Some(block) = receiver.recv().await {
+                writer.write_all(&block).await?;
+            }
+            writer.flush().await
+        };
+        let (stats, written) = tokio::join!(produce, consume);
+        written?;
+        stats
+    }
+
+    /// Run `f` on the sink in a blocking thread
+    async fn blocking<T: Send + 'static>(
+        &mut self,
+        f: impl FnOnce(&mut EncoderSink<RW>) -> Result<T, ZchunkError> + Send + 'static,
+    ) -> Result<T, ZchunkError> {
+        let mut sink = self.sink.take().ok_or(ZchunkError::SinkFailed)?;
+        let (sink, result) = match task::spawn_blocking(move || {
+            let result = f(&mut sink);
+            (sink, result)
+        })
+        .await
+        {
+            Ok(done) => done,
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        };
+        self.sink = Some(sink);
+        result
+    }
+}
+
+/// Sends every write to the receiving task, blocking while the channel is full
+struct ChannelWriter(mpsc::Sender<Vec<u8>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .blocking_send(buf.to_vec())
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::AsyncEncoder;
+    use crate::{ChunkerParams, Decoder, Encoder, EncoderOptions, MemoryTemp, ZchunkError};
+
+    const INPUT: &str = "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml";
+
+    #[tokio::test]
+    async fn test_async_round_trip() {
+        let input = std::fs::read(INPUT).unwrap();
+        let options = || {
+            [
+                EncoderOptions::new(),
+                EncoderOptions::new().chunker_params(ChunkerParams::new(1024, 8192, 2047)),
+                EncoderOptions::new()
+                    .chunker_params(ChunkerParams::new(1024, 8192, 2047))
+                    .auto_dict(4096),
+            ]
+        };
+        for (async_options, sync_options) in options().into_iter().zip(options()) {
+            let mut expected = Vec::new();
+            let expected_stats =
+                Encoder::with_options(input.as_slice(), Cursor::new(Vec::new()), sync_options)
+                    .unwrap()
+                    .compress(&mut expected)
+                    .unwrap();
+
+            let mut encoder =
+                AsyncEncoder::with_options(input.as_slice(), MemoryTemp::new(), async_options)
+                    .unwrap();
+            assert!(matches!(
+                encoder.compress_to(Vec::new()).await,
+                Err(ZchunkError::HeaderNotFound)
+            ));
+            encoder.prepare_chunks().await.unwrap();
+            assert!(matches!(
+                encoder.prepare_chunks().await,
+                Err(ZchunkError::AlreadyPrepared)
+            ));
+            let mut output = Vec::new();
+            let stats = encoder.compress_to(&mut output).await.unwrap();
+            assert!(output == expected);
+            assert_eq!(stats, expected_stats);
+            let mut again = Vec::new();
+            encoder.compress_to(&mut again).await.unwrap();
+            assert!(again == output);
+
+            let mut decoded = Vec::new();
+            Decoder::new(Cursor::new(output))
+                .unwrap()
+                .decompress_to(&mut decoded)
+                .unwrap();
+            assert!(decoded == input);
+        }
+    }
+}
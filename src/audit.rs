@@ -0,0 +1,174 @@
+#[cfg(feature = "zstd")]
+use std::io::{BufRead, Seek};
+use std::{collections::HashSet, io::Read};
+
+use sha2::{Digest, Sha256};
+
+#[cfg(feature = "zstd")]
+use crate::format::Decoder;
+use crate::{
+    chunker::{Chunker, ChunkerParams},
+    errors::ZchunkError,
+    format::Header,
+};
+
+/// How well re-chunking new input reproduces the chunks of an old file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BoundaryAudit {
+    /// Number of data chunks in the old index
+    pub old_chunks: usize,
+    /// Number of chunks produced from the new input
+    pub new_chunks: usize,
+    /// Number of old chunks whose content appears among the new chunks
+    pub reproduced: usize,
+    /// Uncompressed offset of the first chunk that differs from the old chunk at the same
+    /// position, `None` when both chunk lists are identical
+    pub first_divergence: Option<u64>,
+}
+
+impl BoundaryAudit {
+    /// Fraction of old chunks reproduced, 1.0 when the old file has no chunks
+    pub fn reproduced_fraction(&self) -> f64 {
+        if self.old_chunks == 0 {
+            return 1.0;
+        }
+        self.reproduced as f64 / self.old_chunks as f64
+    }
+}
+
+/// Chunk `new_input` with `params` and compare the chunks against the index of an old file
+///
+/// Chunks are matched by the SHA-256 of their uncompressed data, which the zchunk index does
+/// not carry, so the caller provides one checksum per old data chunk in index order, e.g.
+/// from `Decoder::uncompressed_checksums` on the old file.
+pub fn boundary_audit(
+    old: &Header,
+    old_uncompressed_checksums: &[[u8; 32]],
+    new_input: impl Read,
+    params: &ChunkerParams,
+) -> Result<BoundaryAudit, ZchunkError> {
+    let old_chunks = &old.index.data_chunks;
+    if old_uncompressed_checksums.len() != old_chunks.len() {
+        return Err(ZchunkError::ChecksumCountMismatch {
+            expected: old_chunks.len(),
+            found: old_uncompressed_checksums.len(),
+        });
+    }
+
+    let mut audit = BoundaryAudit {
+        old_chunks: old_chunks.len(),
+        ..Default::default()
+    };
+    let mut found = HashSet::new();
+    let mut offset = 0;
+    for (id, data) in Chunker::with_params(params.clone(), new_input)?.enumerate() {
+        let data = data?;
+        let checksum: [u8; 32] = Sha256::digest(&data).into();
+
+        if audit.first_divergence.is_none() && old_uncompressed_checksums.get(id) != Some(&checksum)
+        {
+            audit.first_divergence = Some(offset);
+        }
+        found.insert(checksum);
+        offset += data.len() as u64;
+        audit.new_chunks += 1;
+    }
+
+    // the new input ended early, it diverges where the old chunks continue
+    if audit.first_divergence.is_none() && audit.new_chunks < audit.old_chunks {
+        audit.first_divergence = Some(offset);
+    }
+    audit.reproduced = old_uncompressed_checksums
+        .iter()
+        .filter(|c| found.contains(*c))
+        .count();
+
+    Ok(audit)
+}
+
+#[cfg(feature = "zstd")]
+impl<R: BufRead + Seek> Decoder<R> {
+    /// SHA-256 of the uncompressed data of every data chunk, in index order
+    pub fn uncompressed_checksums(&mut self) -> Result<Vec<[u8; 32]>, ZchunkError> {
+        (0..self.header.index.data_chunks.len())
+            .map(|id| Ok(Sha256::digest(self.decompress_chunk(id)?).into()))
+            .collect()
+    }
+}
+
+#[cfg(all(test, feature = "zstd"))]
+mod tests {
+    use std::{fs::File, io::Cursor};
+
+    use super::boundary_audit;
+    use crate::{ChunkerParams, Decoder, Encoder, ZchunkError};
+
+    const INPUT: &str = "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml";
+
+    fn old_file() -> Decoder<Cursor<Vec<u8>>> {
+        let mut encoder =
+            Encoder::new(File::open(INPUT).unwrap(), Cursor::new(Vec::new())).unwrap();
+        encoder.prepare_chunks().unwrap();
+        let mut output = Vec::new();
+        encoder.compress_to(&mut output).unwrap();
+        Decoder::new(Cursor::new(output)).unwrap()
+    }
+
+    #[test]
+    fn test_boundary_audit_identical() {
+        let mut old = old_file();
+        let checksums = old.uncompressed_checksums().unwrap();
+
+        let audit = boundary_audit(
+            &old.header,
+            &checksums,
+            File::open(INPUT).unwrap(),
+            &ChunkerParams::default(),
+        )
+        .unwrap();
+        assert_eq!(audit.old_chunks, checksums.len());
+        assert_eq!(audit.new_chunks, checksums.len());
+        assert_eq!(audit.reproduced_fraction(), 1.0);
+        assert_eq!(audit.first_divergence, None);
+    }
+
+    #[test]
+    fn test_boundary_audit_divergence() {
+        let mut old = old_file();
+        let checksums = old.uncompressed_checksums().unwrap();
+
+        let audit = boundary_audit(
+            &old.header,
+            &checksums,
+            File::open(INPUT).unwrap(),
+            &ChunkerParams::new(1024, 8192, 2047),
+        )
+        .unwrap();
+        assert!(audit.reproduced_fraction() < 1.0);
+        assert_eq!(audit.first_divergence, Some(0));
+
+        // a truncated input reproduces the leading chunks only
+        let first_len = old.decompress_chunk(0).unwrap().len();
+        let mut input = std::fs::read(INPUT).unwrap();
+        input.truncate(first_len);
+        let audit = boundary_audit(
+            &old.header,
+            &checksums,
+            input.as_slice(),
+            &ChunkerParams::default(),
+        )
+        .unwrap();
+        assert_eq!(audit.reproduced, 1);
+        assert_eq!(audit.first_divergence, Some(first_len as u64));
+
+        assert!(matches!(
+            boundary_audit(
+                &old.header,
+                &[],
+                input.as_slice(),
+                &ChunkerParams::default()
+            ),
+            Err(ZchunkError::ChecksumCountMismatch { .. })
+        ));
+    }
+}
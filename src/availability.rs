@@ -0,0 +1,229 @@
+use std::ops::Range;
+
+use crate::{
+    errors::ZchunkError,
+    format::{checked_add, ChunkId, Header},
+};
+
+/// A bitmap over chunk ids marking which chunk extents of a partial file contain valid data
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkAvailability {
+    dict: bool,
+    bits: Vec<u64>,
+    count: usize,
+}
+
+impl ChunkAvailability {
+    /// No chunk is available
+    pub fn none(chunk_count: usize) -> Self {
+        Self {
+            dict: false,
+            bits: vec![0; chunk_count.div_ceil(64)],
+            count: chunk_count,
+        }
+    }
+
+    /// Every chunk is available
+    pub fn all(chunk_count: usize) -> Self {
+        let mut availability = Self::none(chunk_count);
+        availability.dict = true;
+        (0..chunk_count).for_each(|id| availability.set(id, true));
+        availability
+    }
+
+    /// Build availability from downloaded byte ranges of the file
+    ///
+    /// Ranges are absolute file offsets and may overlap or come in any order. A chunk is
+    /// available when its whole extent is covered, so empty chunks are always available.
+    pub fn from_byte_ranges(header: &Header, ranges: &[Range<u64>]) -> Result<Self, ZchunkError> {
+        let mut ranges = ranges.to_vec();
+        ranges.sort_by_key(|r| r.start);
+
+        // merge overlapping and adjacent ranges
+        let mut merged: Vec<Range<u64>> = Vec::new();
+        for range in ranges.into_iter().filter(|r| r.start < r.end) {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+        let covered = |extent: Range<u64>| {
+            extent.is_empty()
+                || merged
+                    .iter()
+                    .any(|r| r.start <= extent.start && extent.end <= r.end)
+        };
+
+        let data_offset = header.data_offset()?;
+        let mut availability = Self::none(header.index.data_chunks.len());
+        let dict_length = header.index.dict_chunk.length.to_u64()?;
+        availability.dict = covered(data_offset..checked_add(data_offset, dict_length)?);
+        for (id, (chunk, offset)) in header.index.data_chunks.iter().enumerate() {
+            let start = checked_add(data_offset, *offset)?;
+            availability.set(
+                id,
+                covered(start..checked_add(start, chunk.length.to_u64()?)?),
+            );
+        }
+
+        Ok(availability)
+    }
+
+    /// Number of data chunks covered by the bitmap
+    pub fn chunk_count(&self) -> usize {
+        self.count
+    }
+
+    /// Mark a data chunk as available or not, ids out of range are ignored
+    pub fn set(&mut self, id: ChunkId, available: bool) {
+        if id >= self.count {
+            return;
+        }
+
+        if available {
+            self.bits[id / 64] |= 1 << (id % 64);
+        } else {
+            self.bits[id / 64] &= !(1 << (id % 64));
+        }
+    }
+
+    /// Whether the data chunk is available, ids out of range are never available
+    pub fn is_available(&self, id: ChunkId) -> bool {
+        id < self.count && self.bits[id / 64] & (1 << (id % 64)) != 0
+    }
+
+    /// Mark the dict chunk as available or not
+    pub fn set_dict(&mut self, available: bool) {
+        self.dict = available;
+    }
+
+    /// Whether the dict chunk is available
+    pub fn dict_available(&self) -> bool {
+        self.dict
+    }
+}
+
+impl Header {
+    /// Absolute byte ranges of the chunks that are not available in file order, adjacent chunks
+    /// are merged
+    pub fn missing_ranges(
+        &self,
+        availability: &ChunkAvailability,
+    ) -> Result<Vec<Range<u64>>, ZchunkError> {
+        let data_offset = self.data_offset()?;
+        let dict_length = self.index.dict_chunk.length.to_u64()?;
+
+        let mut extents = Vec::new();
+        if !availability.dict_available() {
+            extents.push(data_offset..checked_add(data_offset, dict_length)?);
+        }
+        for (id, (chunk, offset)) in self.index.data_chunks.iter().enumerate() {
+            if !availability.is_available(id) {
+                let start = checked_add(data_offset, *offset)?;
+                extents.push(start..checked_add(start, chunk.length.to_u64()?)?);
+            }
+        }
+
+        let mut ranges: Vec<Range<u64>> = Vec::new();
+        for extent in extents.into_iter().filter(|e| !e.is_empty()) {
+            match ranges.last_mut() {
+                Some(last) if last.end == extent.start => last.end = extent.end,
+                _ => ranges.push(extent),
+            }
+        }
+
+        Ok(ranges)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "zstd")]
+    use std::{fs::File, io::Cursor};
+
+    use super::ChunkAvailability;
+    #[cfg(feature = "zstd")]
+    use crate::{Decoder, Encoder, VerifyOptions, ZchunkError};
+
+    #[cfg(feature = "zstd")]
+    fn encode_fixture() -> Vec<u8> {
+        let input = File::open(
+            "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml",
+        )
+        .unwrap();
+
+        let mut encoder = Encoder::new(input, Cursor::new(Vec::new())).unwrap();
+        encoder.prepare_chunks().unwrap();
+        let mut output = Vec::new();
+        encoder.compress_to(&mut output).unwrap();
+        output
+    }
+
+    #[test]
+    fn test_availability_bitmap() {
+        let mut availability = ChunkAvailability::none(130);
+        assert!(!availability.is_available(0));
+        availability.set(129, true);
+        availability.set(64, true);
+        availability.set(64, false);
+        assert!(availability.is_available(129));
+        assert!(!availability.is_available(64));
+        assert!(!availability.is_available(130));
+
+        let availability = ChunkAvailability::all(3);
+        assert!((0..3).all(|id| availability.is_available(id)));
+        assert!(availability.dict_available());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_partial_file() {
+        let full = encode_fixture();
+        let decoder = Decoder::new(Cursor::new(full.clone())).unwrap();
+        let header = &decoder.header;
+        let chunk_count = header.index.data_chunks.len();
+        assert!(chunk_count > 2);
+
+        // download everything up to the end of the second chunk
+        let data_offset = header.data_offset().unwrap();
+        let (chunk, offset) = &header.index.data_chunks[1];
+        let downloaded = data_offset + *offset + chunk.length.to_u64().unwrap();
+        let mut partial = full.clone();
+        partial[downloaded as usize..].fill(0);
+
+        let ranges = [0..downloaded / 2, downloaded / 3..downloaded];
+        let availability = ChunkAvailability::from_byte_ranges(header, &ranges).unwrap();
+        assert!(availability.dict_available());
+        assert!(availability.is_available(0));
+        assert!(availability.is_available(1));
+        assert!((2..chunk_count).all(|id| !availability.is_available(id)));
+        assert_eq!(
+            header.missing_ranges(&availability).unwrap(),
+            vec![downloaded..full.len() as u64]
+        );
+
+        // corrupt a byte of the first chunk
+        partial[data_offset as usize + 10] ^= 0xff;
+
+        let mut decoder = Decoder::with_availability(Cursor::new(partial), availability).unwrap();
+        assert!(decoder.decompress_chunk(1).is_ok());
+        assert!(matches!(
+            decoder.decompress_chunk(0),
+            Err(ZchunkError::ChunkChecksumNotMatch { .. })
+        ));
+        assert!(matches!(
+            decoder.decompress_chunk(2),
+            Err(ZchunkError::ChunkUnavailable { id: 2 })
+        ));
+        assert!(matches!(
+            decoder.decompress_range(1..chunk_count, &mut Vec::new()),
+            Err(ZchunkError::ChunkUnavailable { id: 2 })
+        ));
+
+        let report = decoder.verify(&VerifyOptions::new()).unwrap();
+        assert_eq!(report.chunks_checked, 2);
+        assert_eq!(report.failures.len(), 1);
+        assert_eq!(report.failures[0].id, 0);
+        assert_eq!(report.unavailable, (2..chunk_count).collect::<Vec<_>>());
+    }
+}
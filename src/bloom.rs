@@ -0,0 +1,318 @@
+use crate::{
+    errors::ZchunkError,
+    format::Header,
+    sidecar::{envelope, open_envelope, SidecarKind},
+};
+
+const BLOOM_VERSION: u16 = 1;
+
+/// Bytes in front of the bit words in the encoding of `ChunkBloom::to_bytes`
+const BLOOM_PREFIX_LEN: usize = 9;
+
+/// A bloom filter over the data chunk checksums of a header
+///
+/// `maybe_contains` never answers `false` for a checksum in the index, and answers `true`
+/// for other checksums at about the rate of `false_positive_rate`. The positions are derived
+/// from the checksum bytes by double hashing, which relies on the checksums being
+/// cryptographic digests.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ChunkBloom {
+    hashes: u8,
+    bits: Vec<u64>,
+}
+
+impl ChunkBloom {
+    /// An empty filter sized for `keys` entries
+    fn with_capacity(keys: usize, bits_per_key: u8) -> Self {
+        let bits_per_key = bits_per_key.max(1);
+        let bits = keys.saturating_mul(bits_per_key as usize).max(64);
+        // k = ln 2 * m / n minimizes the false positive rate
+        let hashes = (bits_per_key as f64 * std::f64::consts::LN_2).round() as u8;
+        Self {
+            hashes: hashes.clamp(1, 30),
+            bits: vec![0; bits.div_ceil(64)],
+        }
+    }
+
+    fn positions(&self, checksum: &[u8]) -> impl Iterator<Item = u64> {
+        let mut bytes = [0; 16];
+        let len = checksum.len().min(16);
+        bytes[..len].copy_from_slice(&checksum[..len]);
+        let h1 = u64::from_le_bytes(bytes[..8].try_into().unwrap());
+        // an odd step visits different bits for every hash
+        let h2 = u64::from_le_bytes(bytes[8..].try_into().unwrap()) | 1;
+        let num_bits = self.bits.len() as u64 * 64;
+        (0..self.hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % num_bits)
+    }
+
+    fn insert(&mut self, checksum: &[u8]) {
+        let positions: Vec<u64> = self.positions(checksum).collect();
+        for bit in positions {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+    }
+
+    /// Whether a data chunk may have `checksum`, `false` means it surely has not
+    pub fn maybe_contains(&self, checksum: &[u8]) -> bool {
+        // only a deserialized filter can be empty, it rules nothing out
+        if self.bits.is_empty() {
+            return true;
+        }
+        self.positions(checksum)
+            .all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    /// The expected false positive rate for `keys` entries, the ones the filter was built for
+    pub fn false_positive_rate(&self, keys: usize) -> f64 {
+        let bits = (self.bits.len() * 64) as f64;
+        let k = self.hashes as f64;
+        (1.0 - (-k * keys as f64 / bits).exp()).powf(k)
+    }
+
+    /// Stable binary encoding in a `SidecarKind::ChunkBloom` envelope, version 1: number of
+    /// hashes, number of bit words as u64 little endian, then the words little endian
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(BLOOM_PREFIX_LEN + self.bits.len() * 8);
+        payload.push(self.hashes);
+        payload.extend_from_slice(&(self.bits.len() as u64).to_le_bytes());
+        for word in &self.bits {
+            payload.extend_from_slice(&word.to_le_bytes());
+        }
+        envelope(SidecarKind::ChunkBloom, BLOOM_VERSION, &payload)
+    }
+
+    /// Load a filter from the encoding of `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ZchunkError> {
+        let (_, payload) = open_envelope(bytes, SidecarKind::ChunkBloom, BLOOM_VERSION)?;
+        let bytes = payload.as_slice();
+        if bytes.len() < BLOOM_PREFIX_LEN {
+            return Err(ZchunkError::InvalidChunkBloom);
+        }
+        let (prefix, words) = bytes.split_at(BLOOM_PREFIX_LEN);
+        let hashes = prefix[0];
+        let count = u64::from_le_bytes(prefix[1..].try_into()?);
+        if hashes == 0 || count == 0 || words.len() as u64 != count.saturating_mul(8) {
+            return Err(ZchunkError::InvalidChunkBloom);
+        }
+
+        let bits = words
+            .chunks_exact(8)
+            .map(|w| u64::from_le_bytes(w.try_into().unwrap()))
+            .collect();
+        Ok(Self { hashes, bits })
+    }
+}
+
+impl Header {
+    /// Build a bloom filter over the data chunk checksums, using `bits_per_key` bits per
+    /// chunk
+    ///
+    /// 10 bits per key give about 1% false positives.
+    pub fn chunk_bloom(&self, bits_per_key: u8) -> ChunkBloom {
+        let mut bloom = ChunkBloom::with_capacity(self.index.data_chunks.len(), bits_per_key);
+        for (chunk, _) in &self.index.data_chunks {
+            bloom.insert(&chunk.checksum);
+        }
+        bloom
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "sha512")]
+    use std::{
+        fs::File,
+        io::{BufReader, Cursor},
+    };
+
+    use sha2::{Digest, Sha256};
+
+    use super::ChunkBloom;
+    #[cfg(feature = "sha512")]
+    use crate::Decoder;
+    use crate::{sidecar::envelope, test_utils::HeaderBuilder, Checksum, SidecarKind, ZchunkError};
+
+    /// A pseudo random checksum, different for every seed
+    fn checksum(seed: u64) -> [u8; 16] {
+        Sha256::digest(seed.to_le_bytes())[..16].try_into().unwrap()
+    }
+
+    #[cfg(feature = "sha512")]
+    #[test]
+    fn test_bloom_testdata() {
+        for path in [
+            "testdata/c25ffa05cf1fdeb67801847df96c33933b1ee1ea081af52edff4ff371a1c814c-comps-Server.x86_64.xml.zck",
+            "testdata/3c6181c789ef9e8ed23f4072eb2f8f529002abd5166273a9734d7d39f7a810ae-comps-Server.x86_64.xml.zck",
+        ] {
+            let decoder = Decoder::new(BufReader::new(File::open(path).unwrap())).unwrap();
+            let header = decoder.header();
+            let bloom = header.chunk_bloom(10);
+            for (chunk, _) in &header.index.data_chunks {
+                assert!(bloom.maybe_contains(&chunk.checksum));
+            }
+        }
+    }
+
+    #[test]
+    fn test_bloom_false_positives() {
+        const KEYS: u64 = 10_000;
+        const PROBES: u64 = 100_000;
+
+        let header = (0..KEYS)
+            .fold(HeaderBuilder::new(), |builder, seed| {
+                builder.chunk(&hex::encode(checksum(seed)), 1, 1)
+            })
+            .build()
+            .unwrap();
+        for bits_per_key in [4, 10] {
+            let bloom = header.chunk_bloom(bits_per_key);
+            assert!((0..KEYS).all(|seed| bloom.maybe_contains(&checksum(seed))));
+
+            let false_positives = (KEYS..KEYS + PROBES)
+                .filter(|&seed| bloom.maybe_contains(&checksum(seed)))
+                .count();
+            let rate = false_positives as f64 / PROBES as f64;
+            let bound = bloom.false_positive_rate(KEYS as usize);
+            assert!(rate <= bound * 1.5, "{bits_per_key}: {rate} > {bound}");
+        }
+    }
+
+    #[test]
+    fn test_bloom_bytes() {
+        let header = HeaderBuilder::new()
+            .chunk(&hex::encode(checksum(1)), 1, 1)
+            .build()
+            .unwrap();
+        let bloom = header.chunk_bloom(10);
+        let bytes = bloom.to_bytes();
+        assert_eq!(ChunkBloom::from_bytes(&bytes).unwrap(), bloom);
+
+        for bad in [&bytes[..bytes.len() - 1], &[0; 17][..]] {
+            assert!(matches!(
+                ChunkBloom::from_bytes(bad),
+                Err(ZchunkError::InvalidSidecar)
+            ));
+        }
+        let no_words = envelope(SidecarKind::ChunkBloom, 1, &[1; 9]);
+        assert!(matches!(
+            ChunkBloom::from_bytes(&no_words),
+            Err(ZchunkError::InvalidChunkBloom)
+        ));
+
+        // an empty index still answers
+        let empty = HeaderBuilder::new().build().unwrap().chunk_bloom(10);
+        assert!(!empty.maybe_contains(&checksum(1)));
+    }
+
+    #[test]
+    fn test_lookup_filtered() {
+        let cache = (0..100)
+            .fold(HeaderBuilder::new(), |builder, seed| {
+                builder.chunk(&hex::encode(checksum(seed)), 1, 1)
+            })
+            .build()
+            .unwrap();
+        let bloom = cache.chunk_bloom(10);
+        let probes: Vec<_> = (50..150)
+            .map(|seed| Checksum::from_bytes(&checksum(seed)).unwrap())
+            .collect();
+        let expected = cache.lookup(probes.clone());
+        assert_eq!(
+            cache.lookup_filtered(probes.clone(), Some(&bloom)),
+            expected
+        );
+        assert_eq!(cache.lookup_filtered(probes, None), expected);
+    }
+
+    #[test]
+    fn test_lookup_filtered_skips_misses() {
+        let cache = (0..100)
+            .fold(HeaderBuilder::new(), |builder, seed| {
+                builder.chunk(&hex::encode(checksum(seed)), 1, 1)
+            })
+            .build()
+            .unwrap();
+        let probes: Vec<_> = (0..100)
+            .map(|seed| Checksum::from_bytes(&checksum(seed)).unwrap())
+            .collect();
+
+        // a filter that rules out everything answers without a lookup, even for chunks the
+        // index has
+        let nothing = ChunkBloom {
+            hashes: 1,
+            bits: vec![0],
+        };
+        assert_eq!(
+            cache.lookup_filtered(probes.clone(), Some(&nothing)),
+            vec![None; 100]
+        );
+        assert!(cache.chunk_lookup.get().is_none());
+
+        // and so do the definite misses of the filter of the header
+        let bloom = cache.chunk_bloom(10);
+        let misses: Vec<_> = (100..1000)
+            .map(|seed| Checksum::from_bytes(&checksum(seed)).unwrap())
+            .filter(|checksum| !bloom.maybe_contains(checksum))
+            .collect();
+        assert!(!misses.is_empty());
+        assert!(cache
+            .lookup_filtered(misses, Some(&bloom))
+            .iter()
+            .all(Option::is_none));
+        assert!(cache.chunk_lookup.get().is_none());
+
+        cache.lookup_filtered(probes, Some(&bloom));
+        assert!(cache.chunk_lookup.get().is_some());
+    }
+
+    #[cfg(feature = "sha512")]
+    #[test]
+    fn test_sync_to_filtered() {
+        let source = std::fs::read("testdata/c25ffa05cf1fdeb67801847df96c33933b1ee1ea081af52edff4ff371a1c814c-comps-Server.x86_64.xml.zck").unwrap();
+        let cache = std::fs::read("testdata/3c6181c789ef9e8ed23f4072eb2f8f529002abd5166273a9734d7d39f7a810ae-comps-Server.x86_64.xml.zck").unwrap();
+        let decoder = |bytes: &Vec<u8>| Decoder::new(Cursor::new(bytes.clone())).unwrap();
+
+        let mut expected = Vec::new();
+        let expected_stats = decoder(&source)
+            .sync_to(decoder(&cache), &mut expected)
+            .unwrap();
+        assert!(expected_stats.chunks_from_cache > 0);
+
+        // the filter of the cache finds the same chunks
+        let mut cache_decoder = decoder(&cache);
+        let bloom = cache_decoder.header().chunk_bloom(10);
+        let mut output = Vec::new();
+        let stats = decoder(&source)
+            .sync_to_filtered(&mut cache_decoder, Some(&bloom), &mut output)
+            .unwrap();
+        assert!(output == expected);
+        assert_eq!(stats, expected_stats);
+
+        // chunks it rules out come from the source, without looking at the cache index
+        let nothing = ChunkBloom {
+            hashes: 1,
+            bits: vec![0],
+        };
+        let mut cache_decoder = decoder(&cache);
+        let mut output = Vec::new();
+        let stats = decoder(&source)
+            .sync_to_filtered(&mut cache_decoder, Some(&nothing), &mut output)
+            .unwrap();
+        assert!(output == expected);
+        assert_eq!(stats.chunks_from_cache, 0);
+        assert!(cache_decoder.header().chunk_lookup.get().is_none());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_bloom_serde() {
+        let header = HeaderBuilder::new()
+            .chunk(&hex::encode(checksum(1)), 1, 1)
+            .build()
+            .unwrap();
+        let bloom = header.chunk_bloom(10);
+        let json = serde_json::to_string(&bloom).unwrap();
+        assert_eq!(serde_json::from_str::<ChunkBloom>(&json).unwrap(), bloom);
+    }
+}
@@ -0,0 +1,202 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+
+use crate::checksum::Checksum;
+
+/// A store of decompressed data chunks, keyed by the chunk checksum from the index
+///
+/// Decoders consult the cache before decompressing a data chunk and fill it afterwards, so
+/// one cache can be shared by decoders of related files. Chunks with equal checksums only
+/// decompress to equal data when the files use the same dict and transform, so decoders
+/// sharing a cache should agree on both.
+pub trait DecompressedCache: Send + Sync {
+    fn get(&self, key: &Checksum) -> Option<Arc<Vec<u8>>>;
+
+    fn put(&self, key: Checksum, data: Arc<Vec<u8>>);
+}
+
+/// A least recently used cache holding at most `max_bytes` of decompressed data
+pub struct LruChunkCache {
+    max_bytes: usize,
+    state: Mutex<LruState>,
+}
+
+#[derive(Default)]
+struct LruState {
+    entries: HashMap<Checksum, (Arc<Vec<u8>>, u64)>,
+    /// keys by the tick of their last use, the first entry is evicted next
+    recency: BTreeMap<u64, Checksum>,
+    bytes: usize,
+    tick: u64,
+}
+
+impl LruState {
+    /// Mark `key` as used now
+    fn touch(&mut self, key: &Checksum) {
+        self.tick += 1;
+        if let Some((_, last_use)) = self.entries.get_mut(key) {
+            self.recency.remove(last_use);
+            *last_use = self.tick;
+            self.recency.insert(self.tick, *key);
+        }
+    }
+}
+
+impl LruChunkCache {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            state: Mutex::new(LruState::default()),
+        }
+    }
+
+    /// Number of decompressed bytes held
+    pub fn bytes(&self) -> usize {
+        self.lock().bytes
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, LruState> {
+        // the state stays consistent between statements, so a poisoned lock is still usable
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl DecompressedCache for LruChunkCache {
+    fn get(&self, key: &Checksum) -> Option<Arc<Vec<u8>>> {
+        let mut state = self.lock();
+        let data = state.entries.get(key)?.0.clone();
+        state.touch(key);
+        Some(data)
+    }
+
+    /// Chunks larger than `max_bytes` are not cached
+    fn put(&self, key: Checksum, data: Arc<Vec<u8>>) {
+        if data.len() > self.max_bytes {
+            return;
+        }
+
+        let mut state = self.lock();
+        if state.entries.contains_key(&key) {
+            state.touch(&key);
+            return;
+        }
+        while state.bytes + data.len() > self.max_bytes {
+            let Some((_, oldest)) = state.recency.pop_first() else {
+                break;
+            };
+            if let Some((evicted, _)) = state.entries.remove(&oldest) {
+                state.bytes -= evicted.len();
+            }
+        }
+
+        state.tick += 1;
+        let tick = state.tick;
+        state.bytes += data.len();
+        state.entries.insert(key, (data, tick));
+        state.recency.insert(tick, key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    #[cfg(feature = "sha512")]
+    use std::{
+        fs::File,
+        io::{BufReader, Cursor},
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use super::{DecompressedCache, LruChunkCache};
+    use crate::Checksum;
+    #[cfg(feature = "sha512")]
+    use crate::{DecodeOptions, Decoder};
+
+    #[cfg(feature = "sha512")]
+    const FIXTURE: &str = "testdata/c25ffa05cf1fdeb67801847df96c33933b1ee1ea081af52edff4ff371a1c814c-comps-Server.x86_64.xml.zck";
+
+    /// An `LruChunkCache` counting hits and misses
+    #[cfg(feature = "sha512")]
+    struct CountingCache {
+        inner: LruChunkCache,
+        hits: AtomicUsize,
+        misses: AtomicUsize,
+    }
+
+    #[cfg(feature = "sha512")]
+    impl DecompressedCache for CountingCache {
+        fn get(&self, key: &Checksum) -> Option<Arc<Vec<u8>>> {
+            let data = self.inner.get(key);
+            let counter = match data {
+                Some(_) => &self.hits,
+                None => &self.misses,
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+            data
+        }
+
+        fn put(&self, key: Checksum, data: Arc<Vec<u8>>) {
+            self.inner.put(key, data);
+        }
+    }
+
+    fn key(n: u8) -> Checksum {
+        Checksum::from_bytes(&[n; 16]).unwrap()
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let cache = LruChunkCache::new(10);
+        cache.put(key(1), Arc::new(vec![1; 4]));
+        cache.put(key(2), Arc::new(vec![2; 4]));
+        assert!(cache.get(&key(1)).is_some());
+
+        // the least recently used chunk 2 makes room for chunk 3
+        cache.put(key(3), Arc::new(vec![3; 4]));
+        assert!(cache.get(&key(2)).is_none());
+        assert!(cache.get(&key(1)).is_some());
+        assert!(cache.get(&key(3)).is_some());
+        assert_eq!(cache.bytes(), 8);
+
+        // too large to be cached at all
+        cache.put(key(4), Arc::new(vec![4; 11]));
+        assert!(cache.get(&key(4)).is_none());
+        assert_eq!(cache.bytes(), 8);
+    }
+
+    #[cfg(feature = "sha512")]
+    #[test]
+    fn test_decoder_cache() {
+        let cache = Arc::new(CountingCache {
+            inner: LruChunkCache::new(1 << 20),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        });
+        let options = DecodeOptions::new().cache(cache.clone());
+        let bytes = std::fs::read(FIXTURE).unwrap();
+        let mut cached = Decoder::with_options(Cursor::new(bytes), options).unwrap();
+        let mut uncached = Decoder::new(BufReader::new(File::open(FIXTURE).unwrap())).unwrap();
+        let count = cached.header().index.data_chunks.len();
+
+        let mut expected = Vec::new();
+        uncached.decompress_range(0..count, &mut expected).unwrap();
+
+        let mut first = Vec::new();
+        cached.decompress_range(0..count, &mut first).unwrap();
+        assert_eq!(first, expected);
+        assert_eq!(cache.hits.load(Ordering::Relaxed), 0);
+        assert_eq!(cache.misses.load(Ordering::Relaxed), count);
+
+        let mut second = Vec::new();
+        cached.decompress_range(0..count, &mut second).unwrap();
+        assert_eq!(
+            cached.decompress_chunk(0).unwrap(),
+            uncached.decompress_chunk(0).unwrap()
+        );
+        assert_eq!(second, expected);
+        assert_eq!(cache.hits.load(Ordering::Relaxed), count + 1);
+        assert_eq!(cache.misses.load(Ordering::Relaxed), count);
+    }
+}
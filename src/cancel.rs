@@ -0,0 +1,108 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+#[cfg(feature = "zstd")]
+use crate::errors::ZchunkError;
+
+/// A flag another thread sets to stop a long running operation, see
+/// `EncoderOptions::cancel_token`
+///
+/// Clones share the flag, so the caller keeps one clone and hands another to the
+/// operation. The operation checks the flag between chunks and fails with
+/// `ZchunkError::Cancelled` once it is set. A cancelled token stays cancelled.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ask every operation holding a clone of the token to stop
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// `Cancelled` once the token is cancelled
+    #[cfg(feature = "zstd")]
+    pub(crate) fn check(&self) -> Result<(), ZchunkError> {
+        match self.is_cancelled() {
+            true => Err(ZchunkError::Cancelled),
+            false => Ok(()),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "zstd"))]
+mod tests {
+    use std::{
+        io,
+        time::{Duration, Instant},
+    };
+
+    use super::CancelToken;
+    use crate::{ChunkerParams, Encoder, EncoderOptions, MemoryTemp, ZchunkError};
+
+    #[test]
+    fn test_cancel_prepare() {
+        // an endless input, only the token ends the encode
+        let token = CancelToken::new();
+        let options = EncoderOptions::new()
+            .chunker_params(ChunkerParams::new(1024, 8192, 2047))
+            .cancel_token(token.clone());
+        let mut encoder =
+            Encoder::with_options(io::repeat(b'z'), MemoryTemp::new(), options).unwrap();
+
+        let start = Instant::now();
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            token.cancel();
+        });
+        assert!(matches!(
+            encoder.prepare_chunks(),
+            Err(ZchunkError::Cancelled)
+        ));
+        assert!(start.elapsed() < Duration::from_secs(10));
+        canceller.join().unwrap();
+
+        // nothing is left to resume or write, the encoder must be reset to start over
+        assert!(matches!(
+            encoder.resume_prepare(io::repeat(b'z')),
+            Err(ZchunkError::NothingToResume)
+        ));
+        assert!(matches!(
+            encoder.compress_to(io::sink()),
+            Err(ZchunkError::HeaderNotFound)
+        ));
+        encoder.reset(io::repeat(b'z'));
+        assert!(matches!(
+            encoder.prepare_chunks(),
+            Err(ZchunkError::Cancelled)
+        ));
+    }
+
+    #[test]
+    fn test_cancel_before_start() {
+        let token = CancelToken::new();
+        token.cancel();
+        let options = EncoderOptions::new().auto_dict(4096).cancel_token(token);
+        assert!(matches!(
+            Encoder::compress_small(b"<group>", io::sink(), options),
+            Err(ZchunkError::Cancelled)
+        ));
+
+        // a token that is never cancelled changes nothing
+        let mut expected = Vec::new();
+        Encoder::compress_small(b"<group>", &mut expected, EncoderOptions::new()).unwrap();
+        let mut output = Vec::new();
+        let options = EncoderOptions::new().cancel_token(CancelToken::new());
+        Encoder::compress_small(b"<group>", &mut output, options).unwrap();
+        assert_eq!(output, expected);
+    }
+}
@@ -0,0 +1,314 @@
+use std::borrow::Cow;
+
+use thiserror::Error;
+
+use crate::{
+    checksum::{ChecksumType, SUPPORTED_CHECKSUM_TYPES},
+    compression::{CompressionRegistry, BUILTIN_COMPRESSION_TYPES},
+    errors::ZchunkError,
+    format::{CompressionType, Header, SUPPORTED_HEADER_CHECKSUM_TYPES, SUPPORTED_PREFACE_FLAGS},
+};
+
+/// The largest header the test suite decodes, lead included
+const MAX_TESTED_HEADER_SIZE: u64 = 1 << 20;
+
+/// What this build of the crate can read, see `capabilities` and `capabilities_with`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Capabilities {
+    /// The crate version
+    pub version: &'static str,
+    /// Chunk checksum types that can be verified
+    pub checksum_types: &'static [ChecksumType],
+    /// Header checksum types that can be verified
+    pub header_checksum_types: &'static [ChecksumType],
+    /// Compression types that have a backend, custom types only from `capabilities_with`
+    pub compression_types: Cow<'static, [CompressionType]>,
+    /// Preface flag bits that are understood
+    pub preface_flags: u64,
+    /// Whether detached headers, without chunk data, can be parsed
+    pub detached_headers: bool,
+    /// Larger headers are parsed as well, but no test covers them
+    pub max_tested_header_size: u64,
+    /// The enabled cargo features that change what can be read or what the encoder writes
+    pub features: &'static [&'static str],
+}
+
+/// The first requirement of a header this build does not meet, see `Capabilities::can_read`
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum UnsupportedFeature {
+    #[error("header checksum type {0:?} is not supported")]
+    HeaderChecksumType(ChecksumType),
+
+    #[error("chunk checksum type {0:?} is not supported")]
+    ChecksumType(ChecksumType),
+
+    #[error("compression type {0:?} is not supported")]
+    CompressionType(CompressionType),
+
+    #[error("preface flags {0:#x} are not supported")]
+    PrefaceFlags(u64),
+
+    #[error("header of {size} bytes is larger than the tested {max} bytes")]
+    HeaderSize { size: u64, max: u64 },
+
+    #[error("malformed header: {0}")]
+    Malformed(String),
+}
+
+impl From<ZchunkError> for UnsupportedFeature {
+    fn from(err: ZchunkError) -> Self {
+        Self::Malformed(err.to_string())
+    }
+}
+
+/// What this build can read without custom compression backends, taken from the tables
+/// next to the code the `Decoder` dispatches on
+pub const fn capabilities() -> Capabilities {
+    Capabilities {
+        version: env!("CARGO_PKG_VERSION"),
+        checksum_types: SUPPORTED_CHECKSUM_TYPES,
+        header_checksum_types: SUPPORTED_HEADER_CHECKSUM_TYPES,
+        compression_types: Cow::Borrowed(BUILTIN_COMPRESSION_TYPES),
+        preface_flags: SUPPORTED_PREFACE_FLAGS,
+        detached_headers: true,
+        max_tested_header_size: MAX_TESTED_HEADER_SIZE,
+        features: &[
+            #[cfg(feature = "zstd")]
+            "zstd",
+            #[cfg(feature = "zstdmt")]
+            "zstdmt",
+            #[cfg(feature = "sha512")]
+            "sha512",
+            #[cfg(feature = "bytes")]
+            "bytes",
+            #[cfg(feature = "serde")]
+            "serde",
+        ],
+    }
+}
+
+/// What this build can read with the custom compression backends of `registry`, as passed
+/// to `DecodeOptions::compression_registry`
+pub fn capabilities_with(registry: &CompressionRegistry) -> Capabilities {
+    let compression_types = BUILTIN_COMPRESSION_TYPES
+        .iter()
+        .copied()
+        .chain(registry.custom_types())
+        .collect();
+    Capabilities {
+        compression_types: Cow::Owned(compression_types),
+        ..capabilities()
+    }
+}
+
+impl Capabilities {
+    /// Check a parsed header against the capabilities, naming the first unsupported
+    /// requirement
+    ///
+    /// A header that passes can be verified and decompressed in full.
+    pub fn can_read(&self, header: &Header) -> Result<(), UnsupportedFeature> {
+        let header_checksum_type = header.lead.checksum_type()?;
+        if !self.header_checksum_types.contains(&header_checksum_type) {
+            return Err(UnsupportedFeature::HeaderChecksumType(header_checksum_type));
+        }
+
+        let flags = header.preface.flags.bits();
+        if flags & !self.preface_flags != 0 {
+            return Err(UnsupportedFeature::PrefaceFlags(
+                flags & !self.preface_flags,
+            ));
+        }
+
+        let compression_type = header.compression_type()?;
+        if !self.compression_types.contains(&compression_type) {
+            return Err(UnsupportedFeature::CompressionType(compression_type));
+        }
+
+        let checksum_type = header.checksum_type()?;
+        if !self.checksum_types.contains(&checksum_type) {
+            return Err(UnsupportedFeature::ChecksumType(checksum_type));
+        }
+
+        let size = header.data_offset()?;
+        if size > self.max_tested_header_size {
+            return Err(UnsupportedFeature::HeaderSize {
+                size,
+                max: self.max_tested_header_size,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(all(feature = "zstd", feature = "sha512"))]
+    use std::{
+        fs::{self, File},
+        io::BufReader,
+    };
+    use std::{io::Cursor, sync::Arc};
+
+    use super::{capabilities, capabilities_with, UnsupportedFeature, MAX_TESTED_HEADER_SIZE};
+    use crate::{
+        checksum::DEFAULT_CHECKSUM_TYPE, test_utils::HeaderBuilder, ChecksumType,
+        CompressionRegistry, CompressionType, Decoder, NoCompression,
+    };
+    #[cfg(all(feature = "zstd", feature = "sha512"))]
+    use crate::{Encoder, EncoderOptions};
+
+    #[cfg(all(feature = "zstd", feature = "sha512"))]
+    const INPUT: &str = "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml";
+
+    /// The tables agree with what the dispatching code accepts, for every type id
+    #[test]
+    fn test_tables_match_dispatch() {
+        const CAPS: super::Capabilities = capabilities();
+        let supported = |caps: &super::Capabilities, registry: &CompressionRegistry| {
+            for t in 0..=u8::MAX {
+                if let Ok(checksum_type) = ChecksumType::from_u8(t) {
+                    assert_eq!(
+                        caps.checksum_types.contains(&checksum_type),
+                        checksum_type.is_supported(),
+                        "{checksum_type:?}"
+                    );
+                }
+                if let Ok(compression_type) = CompressionType::from_u8(t) {
+                    assert_eq!(
+                        caps.compression_types.contains(&compression_type),
+                        registry.backend(compression_type).is_ok(),
+                        "{compression_type:?}"
+                    );
+                }
+            }
+        };
+        supported(&CAPS, &CompressionRegistry::new());
+        assert!(CAPS.compression_types.contains(&CompressionType::None));
+
+        // registered custom types are reported, in id order
+        let registry = CompressionRegistry::new()
+            .register(0xf0, Arc::new(NoCompression))
+            .unwrap()
+            .register(0x80, Arc::new(NoCompression))
+            .unwrap();
+        let caps = capabilities_with(&registry);
+        supported(&caps, &registry);
+        let custom: Vec<_> = caps
+            .compression_types
+            .iter()
+            .filter(|t| matches!(t, CompressionType::Custom(_)))
+            .collect();
+        assert_eq!(
+            custom,
+            [
+                &CompressionType::Custom(0x80),
+                &CompressionType::Custom(0xf0)
+            ]
+        );
+
+        let mut custom_header = HeaderBuilder::new().build().unwrap();
+        custom_header.preface.compression_type = 0xf0.into();
+        assert_eq!(
+            capabilities().can_read(&custom_header),
+            Err(UnsupportedFeature::CompressionType(
+                CompressionType::Custom(0xf0)
+            ))
+        );
+        caps.can_read(&custom_header).unwrap();
+    }
+
+    #[cfg(all(feature = "zstd", feature = "sha512"))]
+    #[test]
+    fn test_can_read_decodable() {
+        let caps = capabilities();
+        for path in fs::read_dir("testdata").unwrap() {
+            let path = path.unwrap().path();
+            if path.extension().is_some_and(|e| e == "zck") {
+                let decoder = Decoder::new(BufReader::new(File::open(&path).unwrap())).unwrap();
+                caps.can_read(decoder.header()).unwrap();
+            }
+        }
+
+        for options in [
+            EncoderOptions::new(),
+            EncoderOptions::new().checksum_type(ChecksumType::Sha256),
+            EncoderOptions::new().checksum_type(ChecksumType::Sha512),
+            EncoderOptions::new().dict(b"<group>".repeat(64)),
+            EncoderOptions::new().chunk_annotations(vec![1]),
+        ] {
+            let input = File::open(INPUT).unwrap();
+            let mut encoder =
+                Encoder::with_options(input, Cursor::new(Vec::new()), options).unwrap();
+            encoder.prepare_chunks().unwrap();
+            let mut output = Vec::new();
+            encoder.compress_to(&mut output).unwrap();
+            let decoder = Decoder::new(Cursor::new(output)).unwrap();
+            caps.can_read(decoder.header()).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_can_read_rejected() {
+        let caps = capabilities();
+        let chunk = "00112233445566778899aabbccddeeff";
+
+        let sha1 = HeaderBuilder::new()
+            .checksum_type(ChecksumType::Sha1)
+            .chunk(chunk, 1, 1)
+            .build()
+            .unwrap();
+        // the compression type is checked first
+        if cfg!(feature = "zstd") {
+            assert_eq!(
+                caps.can_read(&sha1),
+                Err(UnsupportedFeature::ChecksumType(ChecksumType::Sha1))
+            );
+        }
+
+        // uncompressed chunks, the first flag this crate does not know
+        let uncompressed = HeaderBuilder::new().flags(0x05).build().unwrap();
+        assert_eq!(
+            caps.can_read(&uncompressed),
+            Err(UnsupportedFeature::PrefaceFlags(0x04))
+        );
+
+        // a header at the tested size decodes, one entry more is past it
+        let entry_size = DEFAULT_CHECKSUM_TYPE.digest_size() as u64 + 2;
+        let empty_size = HeaderBuilder::new().build().unwrap().data_offset().unwrap();
+        // the index size and chunk count grow by two bytes each
+        let entries = (MAX_TESTED_HEADER_SIZE - empty_size - 4) / entry_size;
+        let mut builder = HeaderBuilder::new();
+        for i in 0..entries {
+            builder = builder.chunk(&format!("{i:032x}"), 1, 1);
+        }
+        let largest = builder.build().unwrap();
+        let size = largest.data_offset().unwrap();
+        assert!(size <= MAX_TESTED_HEADER_SIZE && size + entry_size > MAX_TESTED_HEADER_SIZE);
+        let mut bytes = Vec::new();
+        largest.write_to(&mut bytes, false).unwrap();
+        let decoded = Decoder::new(Cursor::new(bytes)).unwrap();
+        let huge = builder
+            .chunk(&format!("{:032x}", u64::MAX), 1, 1)
+            .build()
+            .unwrap();
+        if cfg!(feature = "zstd") {
+            caps.can_read(decoded.header()).unwrap();
+            assert!(matches!(
+                caps.can_read(&huge),
+                Err(UnsupportedFeature::HeaderSize { .. })
+            ));
+        }
+
+        let stored = HeaderBuilder::new().build().unwrap();
+        if cfg!(feature = "zstd") {
+            caps.can_read(&stored).unwrap();
+        } else {
+            assert_eq!(
+                caps.can_read(&stored),
+                Err(UnsupportedFeature::CompressionType(CompressionType::Zstd))
+            );
+        }
+    }
+}
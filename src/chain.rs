@@ -0,0 +1,65 @@
+use std::io::{self, Read};
+
+/// Reads several readers one after another, as if their inputs were concatenated
+///
+/// A reader at its end moves on to the next one, only the end of the last reader is the
+/// end of the chain, so a chunker reading the chain cuts chunks across source boundaries.
+/// See `Encoder::from_readers`.
+pub struct ChainedReader<I: Iterator> {
+    readers: I,
+    current: Option<I::Item>,
+}
+
+impl<I: Iterator> ChainedReader<I>
+where
+    I::Item: Read,
+{
+    pub fn new(readers: impl IntoIterator<IntoIter = I>) -> Self {
+        let mut readers = readers.into_iter();
+        let current = readers.next();
+        Self { readers, current }
+    }
+}
+
+impl<I: Iterator> Read for ChainedReader<I>
+where
+    I::Item: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while let Some(reader) = &mut self.current {
+            match reader.read(buf)? {
+                0 => self.current = self.readers.next(),
+                n => return Ok(n),
+            }
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::ChainedReader;
+
+    #[test]
+    fn test_chained_reader() {
+        let sources: [&[u8]; 4] = [b"ab", b"", b"c", b"def"];
+        let mut chain = ChainedReader::new(sources);
+        let mut buf = [0; 8];
+        // a read never spans two sources, and an empty source is skipped
+        assert_eq!(chain.read(&mut buf).unwrap(), 2);
+        assert_eq!(chain.read(&mut buf).unwrap(), 1);
+        assert_eq!(chain.read(&mut buf[..0]).unwrap(), 0);
+        let mut rest = Vec::new();
+        chain.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"def");
+        assert_eq!(chain.read(&mut buf).unwrap(), 0);
+
+        let mut none = ChainedReader::new(Vec::<&[u8]>::new());
+        assert_eq!(none.read(&mut buf).unwrap(), 0);
+    }
+}
@@ -0,0 +1,486 @@
+//! Checkpoints of an interrupted `Encoder::prepare_chunks`, see `Encoder::checkpoint`
+
+use crate::{
+    checksum::Checksum,
+    errors::ZchunkError,
+    format::Chunk,
+    report::DictEffectiveness,
+    sidecar::{envelope, open_envelope, SidecarKind},
+};
+
+/// Newest checkpoint payload version
+const CHECKPOINT_VERSION: u16 = 1;
+
+/// The progress of a prepare that `Encoder::resume` continues
+///
+/// Stable binary encoding in a `SidecarKind::EncodeCheckpoint` envelope, version 1: the
+/// 32 byte options digest; the data start, the bytes consumed and the stored end as u64 little
+/// endian; a flag byte and, when it is 1, the five dict effectiveness counts as u64; the
+/// pending input as a u64 length and its bytes; a flag byte and, when it is 1, the dict chunk;
+/// the number of data chunks as u64 and the chunks. A chunk is the checksum as a length byte
+/// and its bytes, the length and the uncompressed length as u64, and a flag byte followed by
+/// the stream as u64 when it is 1.
+pub(crate) struct Checkpoint {
+    /// `EncoderOptions::stored_chunks_digest` of the interrupted encoder
+    pub(crate) options_digest: [u8; 32],
+    pub(crate) data_start: u64,
+    pub(crate) bytes_consumed: u64,
+    pub(crate) stored_end: u64,
+    pub(crate) effectiveness: Option<DictEffectiveness>,
+    pub(crate) pending: Vec<u8>,
+    pub(crate) dict_chunk: Option<Chunk>,
+    pub(crate) chunks: Vec<Chunk>,
+}
+
+impl Checkpoint {
+    pub(crate) fn to_bytes(&self) -> Result<Vec<u8>, ZchunkError> {
+        let mut payload = self.options_digest.to_vec();
+        for n in [self.data_start, self.bytes_consumed, self.stored_end] {
+            payload.extend_from_slice(&n.to_le_bytes());
+        }
+        match &self.effectiveness {
+            Some(e) => {
+                payload.push(1);
+                for n in [
+                    e.sampled_chunks as u64,
+                    e.sampled_with_dict,
+                    e.sampled_without_dict,
+                    e.data_with_dict,
+                    e.dict_chunk_size,
+                ] {
+                    payload.extend_from_slice(&n.to_le_bytes());
+                }
+            }
+            None => payload.push(0),
+        }
+        payload.extend_from_slice(&(self.pending.len() as u64).to_le_bytes());
+        payload.extend_from_slice(&self.pending);
+        match &self.dict_chunk {
+            Some(chunk) => {
+                payload.push(1);
+                write_chunk(&mut payload, chunk)?;
+            }
+            None => payload.push(0),
+        }
+        payload.extend_from_slice(&(self.chunks.len() as u64).to_le_bytes());
+        for chunk in &self.chunks {
+            write_chunk(&mut payload, chunk)?;
+        }
+        Ok(envelope(
+            SidecarKind::EncodeCheckpoint,
+            CHECKPOINT_VERSION,
+            &payload,
+        ))
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Result<Self, ZchunkError> {
+        let (_, payload) = open_envelope(bytes, SidecarKind::EncodeCheckpoint, CHECKPOINT_VERSION)?;
+        let mut r = PayloadReader(&payload);
+        let options_digest = r.bytes(32)?.try_into()?;
+        let data_start = r.u64()?;
+        let bytes_consumed = r.u64()?;
+        let stored_end = r.u64()?;
+        let effectiveness = match r.flag()? {
+            true => Some(DictEffectiveness {
+                sampled_chunks: usize::try_from(r.u64()?)
+                    .map_err(|_| ZchunkError::InvalidCheckpoint)?,
+                sampled_with_dict: r.u64()?,
+                sampled_without_dict: r.u64()?,
+                data_with_dict: r.u64()?,
+                dict_chunk_size: r.u64()?,
+            }),
+            false => None,
+        };
+        let pending_len = r.len()?;
+        let pending = r.bytes(pending_len)?.to_vec();
+        let dict_chunk = match r.flag()? {
+            true => Some(r.chunk()?),
+            false => None,
+        };
+        let count = r.len()?;
+        // every chunk takes at least 18 bytes, which bounds the allocation
+        let mut chunks = Vec::with_capacity(count.min(r.0.len() / 18));
+        for _ in 0..count {
+            chunks.push(r.chunk()?);
+        }
+        if !r.0.is_empty() {
+            return Err(ZchunkError::InvalidCheckpoint);
+        }
+        Ok(Self {
+            options_digest,
+            data_start,
+            bytes_consumed,
+            stored_end,
+            effectiveness,
+            pending,
+            dict_chunk,
+            chunks,
+        })
+    }
+}
+
+fn write_chunk(payload: &mut Vec<u8>, chunk: &Chunk) -> Result<(), ZchunkError> {
+    let checksum = chunk.checksum.as_bytes();
+    payload.push(checksum.len() as u8);
+    payload.extend_from_slice(checksum);
+    payload.extend_from_slice(&chunk.length.to_u64()?.to_le_bytes());
+    payload.extend_from_slice(&chunk.uncompressed_length.to_u64()?.to_le_bytes());
+    match &chunk.stream {
+        Some(stream) => {
+            payload.push(1);
+            payload.extend_from_slice(&stream.to_u64()?.to_le_bytes());
+        }
+        None => payload.push(0),
+    }
+    Ok(())
+}
+
+/// Reads the fields of a checkpoint payload, running short is `InvalidCheckpoint`
+struct PayloadReader<'a>(&'a [u8]);
+
+impl<'a> PayloadReader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], ZchunkError> {
+        if self.0.len() < len {
+            return Err(ZchunkError::InvalidCheckpoint);
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u64(&mut self) -> Result<u64, ZchunkError> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into()?))
+    }
+
+    /// A length of bytes or of entries that take at least a byte each, which cannot be more
+    /// than the bytes left
+    fn len(&mut self) -> Result<usize, ZchunkError> {
+        match usize::try_from(self.u64()?) {
+            Ok(len) if len <= self.0.len() => Ok(len),
+            _ => Err(ZchunkError::InvalidCheckpoint),
+        }
+    }
+
+    fn flag(&mut self) -> Result<bool, ZchunkError> {
+        match self.bytes(1)?[0] {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(ZchunkError::InvalidCheckpoint),
+        }
+    }
+
+    fn chunk(&mut self) -> Result<Chunk, ZchunkError> {
+        let checksum_len = self.bytes(1)?[0];
+        let checksum = Checksum::from_bytes(self.bytes(checksum_len.into())?)?;
+        let length = self.u64()?;
+        let uncompressed_length = self.u64()?;
+        let mut chunk = Chunk::new(checksum, length, uncompressed_length);
+        if self.flag()? {
+            chunk.stream = Some(self.u64()?.into());
+        }
+        Ok(chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::OpenOptions,
+        io::{self, Cursor, Read},
+        sync::{Arc, Mutex},
+    };
+
+    use super::Checkpoint;
+    use crate::{
+        read_envelope, write_envelope, ChunkerParams, Encoder, EncoderOptions, ZchunkError,
+    };
+
+    const INPUT: &str = "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml";
+
+    /// A reader whose input went away
+    struct Gone;
+
+    impl Read for Gone {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::other("input went away"))
+        }
+    }
+
+    /// The input offsets and checkpoints an encoder saved
+    type Saved = Arc<Mutex<Vec<(u64, Vec<u8>)>>>;
+
+    /// Encode the first `limit` bytes of `input` until the reader fails, and return the
+    /// checkpoint and the bytes consumed
+    fn interrupt(
+        input: &[u8],
+        limit: usize,
+        temp: &mut Cursor<Vec<u8>>,
+        options: EncoderOptions,
+    ) -> (Vec<u8>, u64) {
+        let mut encoder = Encoder::with_options(input[..limit].chain(Gone), temp, options).unwrap();
+        let result = encoder.prepare_chunks();
+        let Err(ZchunkError::ReadFailed { bytes_consumed, .. }) = result else {
+            panic!("expected ReadFailed at {limit}, got {result:?}");
+        };
+        let mut checkpoint = Vec::new();
+        encoder.checkpoint(&mut checkpoint).unwrap();
+        (checkpoint, bytes_consumed)
+    }
+
+    #[test]
+    fn test_checkpoint_resume() {
+        let input = std::fs::read(INPUT).unwrap();
+        let options = || {
+            [
+                EncoderOptions::new(),
+                EncoderOptions::new()
+                    .chunker_params(ChunkerParams::new(1024, 8192, 2047))
+                    .dict(b"<group><id>".repeat(64)),
+            ]
+        };
+        for (options, resume_options) in options().into_iter().zip(options()) {
+            let mut expected = Vec::new();
+            let expected_stats =
+                Encoder::with_options(input.as_slice(), Cursor::new(Vec::new()), options.clone())
+                    .unwrap()
+                    .compress(&mut expected)
+                    .unwrap();
+
+            for limit in [0, input.len() / 3, input.len() - 1] {
+                let mut temp = Cursor::new(Vec::new());
+                let (checkpoint, bytes_consumed) =
+                    interrupt(&input, limit, &mut temp, options.clone());
+                assert_eq!(bytes_consumed, limit as u64);
+
+                // a new encoder, with the input seeked to where the first one stopped
+                let mut encoder = Encoder::resume(
+                    &input[bytes_consumed as usize..],
+                    &mut temp,
+                    checkpoint.as_slice(),
+                    resume_options.clone(),
+                )
+                .unwrap();
+                encoder.prepare_chunks().unwrap();
+                assert!(matches!(
+                    encoder.prepare_chunks(),
+                    Err(ZchunkError::AlreadyPrepared)
+                ));
+                let mut output = Vec::new();
+                let stats = encoder.compress(&mut output).unwrap();
+                assert!(output == expected, "resumed at {limit}");
+                assert_eq!(stats, expected_stats);
+            }
+        }
+    }
+
+    #[test]
+    fn test_checkpoint_every() {
+        let input = std::fs::read(INPUT).unwrap();
+        let options = |saved: &Saved| {
+            let saved = saved.clone();
+            EncoderOptions::new()
+                .chunker_params(ChunkerParams::new(1024, 8192, 2047))
+                .checkpoint_every(
+                    4096,
+                    Arc::new(move |bytes_consumed, checkpoint| {
+                        saved
+                            .lock()
+                            .unwrap()
+                            .push((bytes_consumed, checkpoint.to_vec()));
+                        Ok(())
+                    }),
+                )
+        };
+        let mut expected = Vec::new();
+        Encoder::with_options(
+            input.as_slice(),
+            Cursor::new(Vec::new()),
+            options(&Arc::default()),
+        )
+        .unwrap()
+        .compress(&mut expected)
+        .unwrap();
+
+        // the process dies halfway, after the input read so far was chunked
+        let dir = tempfile::tempdir().unwrap();
+        let temp_path = dir.path().join("temp");
+        let saved = Saved::default();
+        {
+            let temp = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&temp_path)
+                .unwrap();
+            let mut encoder =
+                Encoder::with_options(&input[..input.len() / 2], temp, options(&saved)).unwrap();
+            encoder.prepare_chunks().unwrap();
+        }
+        let saved = saved.lock().unwrap().clone();
+        assert!(saved.len() > 2, "{}", saved.len());
+        assert!(saved.windows(2).all(|w| w[1].0 >= w[0].0 + 4096));
+
+        for (bytes_consumed, checkpoint) in [&saved[0], &saved[saved.len() / 2]] {
+            let temp = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&temp_path)
+                .unwrap();
+            let mut encoder = Encoder::resume(
+                &input[*bytes_consumed as usize..],
+                temp,
+                checkpoint.as_slice(),
+                options(&Arc::default()),
+            )
+            .unwrap();
+            let mut output = Vec::new();
+            encoder.compress(&mut output).unwrap();
+            assert!(output == expected, "resumed at {bytes_consumed}");
+        }
+
+        // the digest of the uncompressed input cannot be saved
+        assert!(matches!(
+            options(&Arc::default())
+                .uncompressed_checksum(true)
+                .validate(),
+            Err(ZchunkError::InvalidOption {
+                name: "uncompressed_checksum",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_checkpoint_refused() {
+        let input = std::fs::read(INPUT).unwrap();
+        let options = || EncoderOptions::new().chunker_params(ChunkerParams::new(1024, 8192, 2047));
+        let mut temp = Cursor::new(Vec::new());
+        let (checkpoint, bytes_consumed) = interrupt(&input, input.len() / 2, &mut temp, options());
+        let resume = |temp: &mut Cursor<Vec<u8>>, checkpoint: &[u8], options| {
+            Encoder::resume(&input[bytes_consumed as usize..], temp, checkpoint, options)
+                .map(|_| ())
+        };
+
+        // options the stored chunks depend on must not change
+        for other in [
+            EncoderOptions::new(),
+            options().compression_level(1),
+            options().dict(b"<group>".to_vec()),
+        ] {
+            assert!(matches!(
+                resume(&mut temp, &checkpoint, other),
+                Err(ZchunkError::CheckpointMismatch)
+            ));
+        }
+        // others may
+        resume(&mut temp, &checkpoint, options().verify_output(true)).unwrap();
+
+        // a truncated checkpoint, or a payload with more than the checkpoint
+        assert!(resume(&mut temp, &checkpoint[..checkpoint.len() - 1], options()).is_err());
+        let (kind, version, mut payload) = read_envelope(checkpoint.as_slice()).unwrap();
+        payload.push(0);
+        let mut trailing = Vec::new();
+        write_envelope(kind, version, &payload, &mut trailing).unwrap();
+        assert!(matches!(
+            resume(&mut temp, &trailing, options()),
+            Err(ZchunkError::InvalidCheckpoint)
+        ));
+        let mut newer = Vec::new();
+        write_envelope(kind, version + 1, &payload, &mut newer).unwrap();
+        assert!(matches!(
+            resume(&mut temp, &newer, options()),
+            Err(ZchunkError::UnsupportedSidecarVersion { .. })
+        ));
+
+        // a temp that lost its chunks, or ends before them
+        let mut short = Cursor::new(temp.get_ref()[..temp.get_ref().len() - 1].to_vec());
+        assert!(matches!(
+            resume(&mut short, &checkpoint, options()),
+            Err(ZchunkError::InvalidCheckpoint)
+        ));
+        assert!(matches!(
+            resume(&mut Cursor::new(Vec::new()), &checkpoint, options()),
+            Err(ZchunkError::InvalidCheckpoint)
+        ));
+        temp.get_mut()[100] ^= 0xff;
+        assert!(matches!(
+            resume(&mut temp, &checkpoint, options()),
+            Err(ZchunkError::InvalidCheckpoint)
+        ));
+    }
+
+    #[test]
+    fn test_checkpoint_lengths() {
+        let input = std::fs::read(INPUT).unwrap();
+        let options = || EncoderOptions::new().chunker_params(ChunkerParams::new(1024, 8192, 2047));
+        let mut temp = Cursor::new(Vec::new());
+        let (bytes, bytes_consumed) = interrupt(&input, input.len() / 2, &mut temp, options());
+        let resume = |checkpoint: &Checkpoint| {
+            let mut temp = temp.clone();
+            Encoder::resume(
+                &input[bytes_consumed as usize..],
+                &mut temp,
+                checkpoint.to_bytes().unwrap().as_slice(),
+                options(),
+            )
+            .map(|_| ())
+        };
+        let parsed = || Checkpoint::from_bytes(&bytes).unwrap();
+        resume(&parsed()).unwrap();
+
+        // chunk lengths past the stored end are refused before anything is read back, a
+        // stored end as large as them runs out of temp instead of allocating it
+        let mut huge = parsed();
+        huge.chunks[0].length = (u64::MAX / 2).into();
+        assert!(matches!(resume(&huge), Err(ZchunkError::InvalidCheckpoint)));
+        huge.stored_end = huge.chunks.iter().map(|c| c.length.to_u64().unwrap()).sum();
+        assert!(matches!(resume(&huge), Err(ZchunkError::InvalidCheckpoint)));
+        let mut short = parsed();
+        short.stored_end -= 1;
+        assert!(matches!(
+            resume(&short),
+            Err(ZchunkError::InvalidCheckpoint)
+        ));
+
+        // a count or pending length past the end of the payload
+        let (kind, version, payload) = read_envelope(bytes.as_slice()).unwrap();
+        let pending_at = 32 + 3 * 8 + 1;
+        assert_eq!(payload[pending_at - 1], 0, "no dict effectiveness");
+        let mut long = payload.clone();
+        long[pending_at..pending_at + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        let mut envelope = Vec::new();
+        write_envelope(kind, version, &long, &mut envelope).unwrap();
+        assert!(matches!(
+            Checkpoint::from_bytes(&envelope),
+            Err(ZchunkError::InvalidCheckpoint)
+        ));
+    }
+
+    #[test]
+    fn test_checkpoint_without_interruption() {
+        let mut encoder = Encoder::new(&b"<group>"[..], Cursor::new(Vec::new())).unwrap();
+        assert!(matches!(
+            encoder.checkpoint(io::sink()),
+            Err(ZchunkError::NothingToResume)
+        ));
+        encoder.prepare_chunks().unwrap();
+        assert!(matches!(
+            encoder.checkpoint(io::sink()),
+            Err(ZchunkError::NothingToResume)
+        ));
+
+        // the digest of the uncompressed input cannot be saved
+        let options = EncoderOptions::new().uncompressed_checksum(true);
+        let mut encoder =
+            Encoder::with_options(b"<group>".chain(Gone), Cursor::new(Vec::new()), options)
+                .unwrap();
+        assert!(encoder.prepare_chunks().is_err());
+        assert!(matches!(
+            encoder.checkpoint(io::sink()),
+            Err(ZchunkError::InvalidOption {
+                name: "uncompressed_checksum",
+                ..
+            })
+        ));
+    }
+}
@@ -0,0 +1,450 @@
+#[cfg(feature = "zstd")]
+use std::io::Write;
+use std::{
+    borrow::Borrow,
+    fmt,
+    hash::{Hash, Hasher},
+    ops::{Deref, DerefMut},
+};
+
+#[cfg(feature = "sha512")]
+use sha2::Sha512;
+use sha2::{Digest, Sha256};
+
+use crate::{errors::ZchunkError, format::ChunkId, hex::Hex};
+
+pub(crate) const CHECKSUM_SHA1: u8 = 0;
+pub(crate) const CHECKSUM_SHA256: u8 = 1;
+pub(crate) const CHECKSUM_SHA512: u8 = 2;
+pub(crate) const CHECKSUM_SHA512_128: u8 = 3; //first 128 bits of SHA-512 checksum
+
+/// The chunk checksum type of new files, SHA-512/128 as upstream unless SHA-512 is compiled out
+#[cfg(feature = "sha512")]
+pub(crate) const DEFAULT_CHECKSUM_TYPE: ChecksumType = ChecksumType::Sha512_128;
+#[cfg(not(feature = "sha512"))]
+pub(crate) const DEFAULT_CHECKSUM_TYPE: ChecksumType = ChecksumType::Sha256;
+
+/// Checksum types defined by the zchunk format
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ChecksumType {
+    Sha1,
+    Sha256,
+    Sha512,
+    /// First 128 bits of SHA-512 checksum
+    Sha512_128,
+}
+
+impl ChecksumType {
+    /// The type id stored in the file
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Self::Sha1 => CHECKSUM_SHA1,
+            Self::Sha256 => CHECKSUM_SHA256,
+            Self::Sha512 => CHECKSUM_SHA512,
+            Self::Sha512_128 => CHECKSUM_SHA512_128,
+        }
+    }
+
+    /// Load the checksum type from the type id stored in the file
+    pub fn from_u8(t: u8) -> Result<Self, ZchunkError> {
+        match t {
+            CHECKSUM_SHA1 => Ok(Self::Sha1),
+            CHECKSUM_SHA256 => Ok(Self::Sha256),
+            CHECKSUM_SHA512 => Ok(Self::Sha512),
+            CHECKSUM_SHA512_128 => Ok(Self::Sha512_128),
+            t => Err(ZchunkError::InvalidChecksumType(t)),
+        }
+    }
+
+    /// The length of a checksum of the type as stored in the file
+    pub fn digest_size(self) -> usize {
+        match self {
+            Self::Sha1 => 20,
+            Self::Sha256 => 32,
+            Self::Sha512 => 64,
+            Self::Sha512_128 => 16,
+        }
+    }
+
+    /// Whether this build can compute checksums of the type, the SHA-512 family needs the
+    /// `sha512` feature and SHA-1 is never computed
+    pub fn is_supported(self) -> bool {
+        ChunkHasher::new(self).is_ok()
+    }
+
+    /// Fail with `UnsupportedChecksumType` when the type is compiled out of this build
+    pub(crate) fn check_enabled(self) -> Result<Self, ZchunkError> {
+        match self {
+            #[cfg(not(feature = "sha512"))]
+            Self::Sha512 | Self::Sha512_128 => Err(ZchunkError::UnsupportedChecksumType(self)),
+            t => Ok(t),
+        }
+    }
+}
+
+/// Compute the checksum of `checksum_type` over `data`, as long as its `digest_size`
+pub(crate) fn compute_checksum(
+    checksum_type: ChecksumType,
+    data: &[u8],
+) -> Result<Checksum, ZchunkError> {
+    let mut hasher = ChunkHasher::new(checksum_type)?;
+    hasher.update(data);
+    Ok(hasher.finalize())
+}
+
+/// The longest `digest_size` of all checksum types, the one of SHA-512
+const MAX_DIGEST_SIZE: usize = 64;
+
+/// A checksum as stored in the file, as long as the `digest_size` of its type
+///
+/// Formatted as hex. The bytes are kept inline, so it is as cheap to copy as a fixed array.
+#[derive(Clone, Copy)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(try_from = "Vec<u8>", into = "Vec<u8>")
+)]
+pub struct Checksum {
+    len: u8,
+    bytes: [u8; MAX_DIGEST_SIZE],
+}
+
+impl Checksum {
+    /// Fails with `InvalidChecksumLength` for more bytes than any checksum type has
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ZchunkError> {
+        if bytes.len() > MAX_DIGEST_SIZE {
+            return Err(ZchunkError::InvalidChecksumLength(bytes.len()));
+        }
+        let mut checksum = Self {
+            len: bytes.len() as u8,
+            bytes: [0; MAX_DIGEST_SIZE],
+        };
+        checksum.bytes[..bytes.len()].copy_from_slice(bytes);
+        Ok(checksum)
+    }
+
+    /// A checksum of `len` zero bytes, such as a placeholder to fill in later
+    pub(crate) fn zeroed(len: usize) -> Self {
+        Self {
+            len: len.min(MAX_DIGEST_SIZE) as u8,
+            bytes: [0; MAX_DIGEST_SIZE],
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+}
+
+impl Deref for Checksum {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl DerefMut for Checksum {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.bytes[..self.len as usize]
+    }
+}
+
+impl AsRef<[u8]> for Checksum {
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl PartialEq for Checksum {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_bytes() == other.as_bytes()
+    }
+}
+
+impl Eq for Checksum {}
+
+impl Hash for Checksum {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_bytes().hash(state);
+    }
+}
+
+// hashes as its bytes, so maps keyed by checksums can be queried with a slice
+impl Borrow<[u8]> for Checksum {
+    fn borrow(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl TryFrom<Vec<u8>> for Checksum {
+    type Error = ZchunkError;
+
+    fn try_from(bytes: Vec<u8>) -> Result<Self, ZchunkError> {
+        Self::from_bytes(&bytes)
+    }
+}
+
+impl From<Checksum> for Vec<u8> {
+    fn from(checksum: Checksum) -> Self {
+        checksum.as_bytes().to_vec()
+    }
+}
+
+impl PartialEq<[u8]> for Checksum {
+    fn eq(&self, other: &[u8]) -> bool {
+        self.as_bytes() == other
+    }
+}
+
+impl<const N: usize> PartialEq<[u8; N]> for Checksum {
+    fn eq(&self, other: &[u8; N]) -> bool {
+        self.as_bytes() == other
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&Hex(self.as_bytes()), f)
+    }
+}
+
+impl fmt::Debug for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&Hex(self.as_bytes()), f)
+    }
+}
+
+/// Check chunk data against the `expected` checksum from an index with `checksum_type`,
+/// without a header at hand
+///
+/// A mismatch is reported as `ChunkChecksumNotMatch` without a chunk id.
+pub fn verify_chunk_checksum(
+    checksum_type: ChecksumType,
+    expected: &[u8],
+    data: &[u8],
+) -> Result<(), ZchunkError> {
+    let found = compute_checksum(checksum_type, data)?;
+    check_checksum(None, expected, found, data.len())
+}
+
+/// Compare the `found` checksum of `len` bytes of chunk data with the `expected` one
+fn check_checksum(
+    id: Option<ChunkId>,
+    expected: &[u8],
+    found: Checksum,
+    len: usize,
+) -> Result<(), ZchunkError> {
+    if found != *expected {
+        return Err(ZchunkError::ChunkChecksumNotMatch {
+            id,
+            len,
+            expected: Box::new(Checksum::from_bytes(expected)?),
+            found: Box::new(found),
+        });
+    }
+    Ok(())
+}
+
+/// The chunk checksum types `ChunkHasher::new` computes, SHA-512 needs the `sha512` feature
+pub(crate) const SUPPORTED_CHECKSUM_TYPES: &[ChecksumType] = &[
+    ChecksumType::Sha256,
+    #[cfg(feature = "sha512")]
+    ChecksumType::Sha512,
+    #[cfg(feature = "sha512")]
+    ChecksumType::Sha512_128,
+];
+
+/// Incremental version of `compute_checksum`, for chunk data that is not in memory at once
+///
+/// All chunk checksums are computed through this type, so it is the one place where
+/// compiled out checksum types are turned away.
+pub(crate) enum ChunkHasher {
+    Sha256(Sha256),
+    /// The digest is truncated to the `digest_size` of the type
+    #[cfg(feature = "sha512")]
+    Sha512(Sha512, usize),
+}
+
+impl ChunkHasher {
+    pub(crate) fn new(checksum_type: ChecksumType) -> Result<Self, ZchunkError> {
+        match checksum_type.check_enabled()? {
+            ChecksumType::Sha256 => Ok(Self::Sha256(Sha256::new())),
+            #[cfg(feature = "sha512")]
+            t @ (ChecksumType::Sha512 | ChecksumType::Sha512_128) => {
+                Ok(Self::Sha512(Sha512::new(), t.digest_size()))
+            }
+            t => Err(ZchunkError::InvalidChecksumType(t.to_u8())),
+        }
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha256(hasher) => hasher.update(data),
+            #[cfg(feature = "sha512")]
+            Self::Sha512(hasher, _) => hasher.update(data),
+        }
+    }
+
+    pub(crate) fn finalize(self) -> Checksum {
+        let (digest, len) = match self {
+            Self::Sha256(hasher) => (hasher.finalize().to_vec(), 32),
+            #[cfg(feature = "sha512")]
+            Self::Sha512(hasher, len) => (hasher.finalize().to_vec(), len),
+        };
+        Checksum::from_bytes(&digest[..len]).expect("digests fit a checksum")
+    }
+
+    /// Compare the checksum of the `len` bytes hashed with the `expected` one
+    pub(crate) fn verify(
+        self,
+        id: Option<ChunkId>,
+        expected: &[u8],
+        len: usize,
+    ) -> Result<(), ZchunkError> {
+        check_checksum(id, expected, self.finalize(), len)
+    }
+}
+
+/// Something that can be fed with bytes to hash
+#[cfg(feature = "zstd")]
+pub(crate) trait HashUpdate {
+    fn update_hash(&mut self, data: &[u8]);
+}
+
+#[cfg(feature = "zstd")]
+impl<D: Digest> HashUpdate for D {
+    fn update_hash(&mut self, data: &[u8]) {
+        Digest::update(self, data);
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl HashUpdate for ChunkHasher {
+    fn update_hash(&mut self, data: &[u8]) {
+        self.update(data);
+    }
+}
+
+/// A writer adapter that feeds the written bytes to several hashers and the inner writer,
+/// so the buffer is traversed once per chunk
+///
+/// The chunk digest and the total data digest cover different ranges, so they can not share
+/// a hasher even when their types coincide.
+#[cfg(feature = "zstd")]
+pub(crate) struct MultiHasher<'a, W, const N: usize> {
+    inner: W,
+    hashers: [&'a mut dyn HashUpdate; N],
+}
+
+#[cfg(feature = "zstd")]
+impl<'a, W: Write, const N: usize> MultiHasher<'a, W, N> {
+    pub(crate) fn new(inner: W, hashers: [&'a mut dyn HashUpdate; N]) -> Self {
+        Self { inner, hashers }
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl<W: Write, const N: usize> Write for MultiHasher<'_, W, N> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        for hasher in self.hashers.iter_mut() {
+            hasher.update_hash(&buf[..n]);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "zstd")]
+    use std::io::Write;
+
+    use sha2::{Digest, Sha256, Sha512};
+
+    use super::{verify_chunk_checksum, ChecksumType};
+    #[cfg(feature = "zstd")]
+    use super::{HashUpdate, MultiHasher};
+    use crate::ZchunkError;
+
+    #[cfg(feature = "zstd")]
+    #[derive(Default)]
+    struct CountingHasher {
+        calls: usize,
+        bytes: usize,
+    }
+
+    #[cfg(feature = "zstd")]
+    impl HashUpdate for CountingHasher {
+        fn update_hash(&mut self, data: &[u8]) {
+            self.calls += 1;
+            self.bytes += data.len();
+        }
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_multi_hasher() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+
+        let mut counter = CountingHasher::default();
+        let mut sha256 = Sha256::new();
+        let mut sha512 = Sha512::new();
+        let mut output = Vec::new();
+        MultiHasher::new(&mut output, [&mut counter, &mut sha256, &mut sha512])
+            .write_all(&data)
+            .unwrap();
+
+        // a Vec accepts the whole buffer at once, so every hasher is fed exactly once
+        assert_eq!(counter.calls, 1);
+        assert_eq!(counter.bytes, data.len());
+        assert_eq!(output, data);
+        assert_eq!(sha256.finalize(), Sha256::digest(&data));
+        assert_eq!(sha512.finalize(), Sha512::digest(&data));
+    }
+
+    #[test]
+    fn test_verify_chunk_checksum() {
+        let data = b"<group><id>base</id></group>".to_vec();
+        let sha256 = Sha256::digest(&data);
+        let sha512 = Sha512::digest(&data);
+
+        for (checksum_type, expected) in [
+            (ChecksumType::Sha256, &sha256[..]),
+            (ChecksumType::Sha512, &sha512[..]),
+            (ChecksumType::Sha512_128, &sha512[..16]),
+        ] {
+            if !cfg!(feature = "sha512") && checksum_type != ChecksumType::Sha256 {
+                assert!(!checksum_type.is_supported());
+                assert!(matches!(
+                    verify_chunk_checksum(checksum_type, expected, &data),
+                    Err(ZchunkError::UnsupportedChecksumType(t)) if t == checksum_type
+                ));
+                continue;
+            }
+            assert!(checksum_type.is_supported());
+            verify_chunk_checksum(checksum_type, expected, &data).unwrap();
+            // a checksum of another width does not match
+            assert!(verify_chunk_checksum(checksum_type, &expected[..8], &data).is_err());
+
+            let mut flipped = data.clone();
+            flipped[3] ^= 0x08;
+            assert!(matches!(
+                verify_chunk_checksum(checksum_type, expected, &flipped),
+                Err(ZchunkError::ChunkChecksumNotMatch { id: None, len, expected: e, found })
+                    if len == data.len() && *e == *expected && found.len() == expected.len()
+            ));
+        }
+
+        assert!(!ChecksumType::Sha1.is_supported());
+        assert!(matches!(
+            verify_chunk_checksum(ChecksumType::Sha1, &sha256, &data),
+            Err(ZchunkError::InvalidChecksumType(0))
+        ));
+    }
+}
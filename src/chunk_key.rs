@@ -0,0 +1,194 @@
+use std::collections::HashSet;
+
+use crate::{checksum::ChecksumType, errors::ZchunkError, format::Header};
+
+/// A compact, orderable key identifying chunk data across files
+///
+/// Keys from files with different index checksum types never compare equal, even when the
+/// digest bytes happen to match.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ChunkKey {
+    checksum_type: ChecksumType,
+    digest: Vec<u8>,
+}
+
+impl ChunkKey {
+    pub fn new(checksum_type: ChecksumType, digest: &[u8]) -> Self {
+        Self {
+            checksum_type,
+            digest: digest.to_vec(),
+        }
+    }
+
+    pub fn checksum_type(&self) -> ChecksumType {
+        self.checksum_type
+    }
+
+    pub fn digest(&self) -> &[u8] {
+        &self.digest
+    }
+
+    /// Stable binary encoding: checksum type id, digest length and digest bytes
+    ///
+    /// Encoded keys sort in the same order as the keys themselves as long as keys of one
+    /// checksum type have digests of one length, as the keys read from a header do. The
+    /// length byte comes first, so a shorter digest sorts before a longer one.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(2 + self.digest.len());
+        bytes.push(self.checksum_type.to_u8());
+        bytes.push(self.digest.len() as u8);
+        bytes.extend_from_slice(&self.digest);
+        bytes
+    }
+
+    /// Load a key from the encoding of `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ZchunkError> {
+        if bytes.len() < 2 {
+            return Err(ZchunkError::InvalidChunkKey);
+        }
+        let (t, len, digest) = (bytes[0], bytes[1], &bytes[2..]);
+        if digest.len() != len as usize {
+            return Err(ZchunkError::InvalidChunkKey);
+        }
+
+        Ok(Self::new(ChecksumType::from_u8(t)?, digest))
+    }
+}
+
+impl Header {
+    /// Keys of all data chunks in index order
+    pub fn export_chunk_keys(&self) -> Result<Vec<ChunkKey>, ZchunkError> {
+        let checksum_type = self.checksum_type()?;
+        Ok(self
+            .index
+            .data_chunks
+            .iter()
+            .map(|(c, _)| ChunkKey::new(checksum_type, &c.checksum))
+            .collect())
+    }
+
+    /// Whether any data chunk has the key, backed by a sorted key list built on first use
+    ///
+    /// Fails like `export_chunk_keys` for an invalid checksum type, which is not cached.
+    pub fn contains_key(&self, key: &ChunkKey) -> Result<bool, ZchunkError> {
+        let sorted = match self.sorted_chunk_keys.get() {
+            Some(sorted) => sorted,
+            None => {
+                let mut keys = self.export_chunk_keys()?;
+                keys.sort();
+                self.sorted_chunk_keys.get_or_init(|| keys)
+            }
+        };
+        Ok(sorted.binary_search(key).is_ok())
+    }
+
+    /// Number of distinct data chunks that are also present in the other header
+    pub fn shared_chunk_count(&self, other: &Header) -> Result<usize, ZchunkError> {
+        let keys: HashSet<_> = self.export_chunk_keys()?.into_iter().collect();
+        let mut count = 0;
+        for key in &keys {
+            count += usize::from(other.contains_key(key)?);
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "sha512")]
+    use std::{collections::BTreeSet, fs::File, io::BufReader};
+
+    use super::ChunkKey;
+    #[cfg(feature = "sha512")]
+    use crate::Decoder;
+    use crate::{test_utils::HeaderBuilder, ChecksumType, ZchunkError};
+
+    #[cfg(feature = "sha512")]
+    fn decoder(path: &str) -> Decoder<BufReader<File>> {
+        Decoder::new(BufReader::new(File::open(path).unwrap())).unwrap()
+    }
+
+    #[cfg(feature = "sha512")]
+    #[test]
+    fn test_chunk_keys() {
+        let source = decoder("testdata/c25ffa05cf1fdeb67801847df96c33933b1ee1ea081af52edff4ff371a1c814c-comps-Server.x86_64.xml.zck");
+        let cache = decoder("testdata/3c6181c789ef9e8ed23f4072eb2f8f529002abd5166273a9734d7d39f7a810ae-comps-Server.x86_64.xml.zck");
+
+        let source_keys: BTreeSet<_> = source
+            .header
+            .export_chunk_keys()
+            .unwrap()
+            .into_iter()
+            .collect();
+        let cache_keys: BTreeSet<_> = cache
+            .header
+            .export_chunk_keys()
+            .unwrap()
+            .into_iter()
+            .collect();
+        let shared = source_keys.intersection(&cache_keys).count();
+
+        assert!(shared > 0);
+        assert_eq!(
+            shared,
+            source.header.shared_chunk_count(&cache.header).unwrap()
+        );
+        assert!(source_keys
+            .iter()
+            .all(|k| source.header.contains_key(k).unwrap()));
+        for key in source_keys.difference(&cache_keys) {
+            assert!(!cache.header.contains_key(key).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_chunk_keys_invalid_checksum_type() {
+        let valid = HeaderBuilder::new().chunk("01", 1, 1).build().unwrap();
+        let mut invalid = HeaderBuilder::new().chunk("01", 1, 1).build().unwrap();
+        invalid.index.checksum_type = 0x7f.into();
+        let key = &valid.export_chunk_keys().unwrap()[0];
+
+        // an error every time, not an empty key list
+        for _ in 0..2 {
+            assert!(matches!(
+                invalid.contains_key(key),
+                Err(ZchunkError::InvalidChecksumType(0x7f))
+            ));
+        }
+        assert!(invalid.shared_chunk_count(&valid).is_err());
+        assert!(valid.shared_chunk_count(&invalid).is_err());
+        assert_eq!(valid.shared_chunk_count(&valid).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_chunk_key_bytes() {
+        let key = ChunkKey::new(ChecksumType::Sha512_128, &[7; 16]);
+        let bytes = key.to_bytes();
+        assert_eq!(bytes.len(), 18);
+        assert_eq!(ChunkKey::from_bytes(&bytes).unwrap(), key);
+        assert!(matches!(
+            ChunkKey::from_bytes(&bytes[..17]),
+            Err(ZchunkError::InvalidChunkKey)
+        ));
+
+        // same digest with another checksum type is another key
+        let other = ChunkKey::new(ChecksumType::Sha512, &[7; 16]);
+        assert_ne!(key, other);
+        assert!(other < key);
+        assert!(other.to_bytes() < key.to_bytes());
+
+        // the length byte orders digests of different lengths before their bytes do
+        let short = ChunkKey::new(ChecksumType::Sha512_128, &[8; 15]);
+        assert!(key < short);
+        assert!(short.to_bytes() < key.to_bytes());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_chunk_key_serde() {
+        let key = ChunkKey::new(ChecksumType::Sha256, &[1; 16]);
+        let json = serde_json::to_string(&key).unwrap();
+        assert_eq!(serde_json::from_str::<ChunkKey>(&json).unwrap(), key);
+    }
+}
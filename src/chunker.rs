@@ -1,3 +1,5 @@
+//! Content defined chunking of the input, see `ChunkerParams`
+
 use std::io::Read;
 
 use crate::errors::ZchunkError;
@@ -7,6 +9,16 @@ const CHUNKER_BUZHASH_BITMASK: u32 = 2u32.pow(15) - 1;
 const CHUNKER_SIZE_MIN_DEFAULT: usize = (CHUNKER_BUZHASH_BITMASK as usize + 1) / 4;
 const CHUNKER_SIZE_MAX_DEFAULT: usize = (CHUNKER_BUZHASH_BITMASK as usize + 1) * 4;
 
+/// The smallest minimum chunk size accepted, which keeps the index small compared to the data
+pub const MIN_CHUNK_SIZE: usize = 64;
+
+/// The largest maximum chunk size accepted, the chunker buffers a chunk of it in memory
+pub const MAX_CHUNK_SIZE: usize = 1 << 30;
+
+/// How many times the minimum chunk size the maximum may be, every cut moves the buffered
+/// input past it so the ratio bounds the work per input byte
+pub const MAX_CHUNK_SIZE_RATIO: usize = 64;
+
 const HASH_TABLE: &[u32] = &[
     0x458be752, 0xc10748cc, 0xfbbcdbb8, 0x6ded5b68, 0xb10a82b5, 0x20d75648, 0xdfc5665f, 0xa8428801,
     0x7ebf5191, 0x841135c7, 0x65cc53b3, 0x280a597c, 0x16f60255, 0xc78cbc3e, 0x294415f5, 0xb938d494,
@@ -42,73 +54,129 @@ const HASH_TABLE: &[u32] = &[
     0x5eff22f4, 0x6027f4cc, 0x77178b3c, 0xae507131, 0x7bf7cabc, 0xf9c18d66, 0x593ade65, 0xd95ddf11,
 ];
 
-pub struct Chunker<R> {
-    min: usize,
-    max: usize,
-    bitmask: u32,
-
-    reader: R,
-    buf: Vec<u8>,
-    reach_eof: bool,
+/// Parameters of the content-defined chunker
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkerParams {
+    pub(crate) min: usize,
+    pub(crate) max: usize,
+    pub(crate) bitmask: u32,
+    pub(crate) normalization_level: u8,
 }
 
-impl<R: Read> Chunker<R> {
-    pub fn default(reader: R) -> Self {
-        Self::new(
-            CHUNKER_SIZE_MIN_DEFAULT,
-            CHUNKER_SIZE_MAX_DEFAULT,
-            CHUNKER_BUZHASH_BITMASK,
-            reader,
-        )
+impl Default for ChunkerParams {
+    fn default() -> Self {
+        Self {
+            min: CHUNKER_SIZE_MIN_DEFAULT,
+            max: CHUNKER_SIZE_MAX_DEFAULT,
+            bitmask: CHUNKER_BUZHASH_BITMASK,
+            normalization_level: 0,
+        }
     }
+}
 
-    pub fn new(min: usize, max: usize, bitmask: u32, reader: R) -> Self {
+impl ChunkerParams {
+    pub fn new(min: usize, max: usize, bitmask: u32) -> Self {
         Self {
             min,
             max,
-            reader,
-            buf: Vec::new(),
             bitmask,
-            reach_eof: false,
+            normalization_level: 0,
         }
     }
 
-    fn fill_buffer(&mut self) -> Result<(), std::io::Error> {
-        if self.buf.len() < self.max {
-            let mut buf = vec![0; self.max - self.buf.len()];
-            let n = self.reader.read(&mut buf)?;
+    /// Parameters scaled like the defaults to chunks of about `size` bytes, rounded up to a
+    /// power of two
+    ///
+    /// The bitmask is `size - 1` and chunks are between a quarter and four times `size`, so
+    /// the defaults are those of 32 KiB. Sizes below 256 make a minimum too small to validate,
+    /// sizes above a quarter of `MAX_CHUNK_SIZE` are lowered to it.
+    pub fn with_target_size(size: usize) -> Self {
+        let size = size.clamp(1, MAX_CHUNK_SIZE / 4).next_power_of_two();
+        Self::new(
+            size / 4,
+            size.saturating_mul(4),
+            u32::try_from(size - 1).unwrap_or(u32::MAX),
+        )
+    }
 
-            self.buf.extend_from_slice(&buf[..n]);
-        }
+    /// Normalize chunk sizes around the target size `bitmask + 1`
+    ///
+    /// Before the target size a boundary needs `level` more zero bits than the bitmask, after it
+    /// `level` fewer, which narrows the chunk size distribution. Level 0 keeps the plain buzhash
+    /// cutoff, and the boundaries are deterministic for a given level.
+    pub fn normalization_level(mut self, level: u8) -> Self {
+        self.normalization_level = level;
+        self
+    }
 
+    /// Check that the parameters chunk sanely: `min` is at least `MIN_CHUNK_SIZE`, `max` is
+    /// between `min` and `MAX_CHUNK_SIZE` and at most `MAX_CHUNK_SIZE_RATIO` times `min`, and
+    /// the bitmask is not zero
+    pub fn validate(&self) -> Result<(), ZchunkError> {
+        if self.min < MIN_CHUNK_SIZE
+            || self.max < self.min
+            || self.max > MAX_CHUNK_SIZE
+            || self.max > self.min.saturating_mul(MAX_CHUNK_SIZE_RATIO)
+            || self.bitmask == 0
+        {
+            return Err(ZchunkError::InvalidChunkerParams {
+                min: self.min,
+                max: self.max,
+                bitmask: self.bitmask,
+            });
+        }
         Ok(())
     }
-}
 
-impl<R: Read> Iterator for Chunker<R> {
-    type Item = Result<Vec<u8>, ZchunkError>;
+    /// The expected chunk size, a boundary is expected every `bitmask + 1` bytes past `min`
+    #[cfg(feature = "zstd")]
+    pub(crate) fn average_size(&self) -> usize {
+        self.min
+            .saturating_add(self.bitmask as usize)
+            .saturating_add(1)
+            .min(self.max)
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if !self.reach_eof {
-            if let Err(e) = self.fill_buffer() {
-                if e.kind() == std::io::ErrorKind::UnexpectedEof {
-                    self.reach_eof = true;
-                } else {
-                    return Some(Err(e.into()));
-                }
-            }
-        }
+    /// The masks used before and after the target size
+    fn masks(&self) -> (u32, u32) {
+        let level = self.normalization_level as u32;
+        let strict = ((self.bitmask as u64) << level | self.bitmask as u64).min(u32::MAX as u64);
+        let loose = self.bitmask.checked_shr(level).unwrap_or(0);
+        (strict as u32, loose)
+    }
+}
 
-        let buf_len = self.buf.len();
+/// Finds where the next chunk ends, the part of the chunker that does not read
+pub(crate) struct Boundaries {
+    min: usize,
+    pub(crate) max: usize,
+    target: usize,
+    strict_bitmask: u32,
+    loose_bitmask: u32,
+}
 
-        // buf is empty, so no more data
-        if buf_len == 0 {
-            return None;
-        }
+impl Boundaries {
+    pub(crate) fn new(params: &ChunkerParams) -> Result<Self, ZchunkError> {
+        params.validate()?;
+        let (strict_bitmask, loose_bitmask) = params.masks();
+        Ok(Self {
+            min: params.min,
+            max: params.max,
+            target: params.bitmask as usize + 1,
+            strict_bitmask,
+            loose_bitmask,
+        })
+    }
+
+    /// The length of the chunk at the start of `buf`, which holds `max` bytes, or fewer at
+    /// the end of the input
+    pub(crate) fn next_cut(&self, buf: &[u8]) -> usize {
+        let buf = &buf[..buf.len().min(self.max)];
+        let buf_len = buf.len();
 
         // when buf size less than minimum size, return all buffer data instead of computing hash
         if buf_len < self.min {
-            return Some(Ok(self.buf.drain(..).collect()));
+            return buf_len;
         }
 
         // determine first window position
@@ -117,9 +185,11 @@ impl<R: Read> Iterator for Chunker<R> {
         } else {
             (0, CHUNKER_WINDOW_SIZE)
         };
-        let mut window = self.buf[first_window_start..first_window_end]
-            .to_vec()
-            .clone();
+        // too short to fill the first window
+        if buf_len < first_window_end {
+            return buf_len;
+        }
+        let mut window = buf[first_window_start..first_window_end].to_vec();
 
         let mut checksum: u32 = 0;
         // compute hash for all bytes in window
@@ -130,7 +200,7 @@ impl<R: Read> Iterator for Chunker<R> {
         let mut idx: usize = 0;
 
         // shift the window to the buffer end
-        for (i, &b) in self.buf[self.min..].iter().enumerate() {
+        for (i, &b) in buf[self.min..].iter().enumerate() {
             let out = window[idx];
             window[idx] = b;
             idx = (idx + 1) % CHUNKER_WINDOW_SIZE;
@@ -138,22 +208,133 @@ impl<R: Read> Iterator for Chunker<R> {
                 ^ HASH_TABLE[out as usize].rotate_left(CHUNKER_WINDOW_SIZE as u32)
                 ^ HASH_TABLE[b as usize];
 
-            if checksum & self.bitmask == 0 {
-                return Some(Ok(self.buf.drain(..self.min + i).collect()));
+            let bitmask = if self.min + i < self.target {
+                self.strict_bitmask
+            } else {
+                self.loose_bitmask
+            };
+            if checksum & bitmask == 0 {
+                return self.min + i;
+            }
+        }
+
+        buf_len
+    }
+}
+
+/// Chunker parameters for about `target_chunks` chunks from `input_size` bytes, see
+/// `ChunkerParams::with_target_size`
+///
+/// The target size is rounded up to a power of two, at least 256 bytes and at most a
+/// quarter of `MAX_CHUNK_SIZE`, so the input usually makes fewer chunks than
+/// `target_chunks`, and at most four times as many unless the input is too large for it.
+pub fn estimate_chunker_params(input_size: u64, target_chunks: usize) -> ChunkerParams {
+    let size = input_size
+        .div_ceil(target_chunks.max(1) as u64)
+        .clamp(256, (MAX_CHUNK_SIZE / 4) as u64);
+    ChunkerParams::with_target_size(size as usize)
+}
+
+pub struct Chunker<R> {
+    boundaries: Boundaries,
+
+    reader: R,
+    buf: Vec<u8>,
+    reach_eof: bool,
+    consumed: u64,
+}
+
+impl<R: Read> Chunker<R> {
+    /// Construct a chunker, failing with `InvalidChunkerParams` when `params` do not validate
+    pub fn with_params(params: ChunkerParams, reader: R) -> Result<Self, ZchunkError> {
+        Ok(Self {
+            boundaries: Boundaries::new(&params)?,
+            reader,
+            buf: Vec::new(),
+            reach_eof: false,
+            consumed: 0,
+        })
+    }
+
+    /// Continue from input that was read but not yet chunked by a previous chunker
+    #[cfg(feature = "zstd")]
+    pub(crate) fn with_pending(mut self, pending: Vec<u8>) -> Self {
+        self.buf = pending;
+        self
+    }
+
+    /// Input read but not yet returned as a chunk
+    #[cfg(feature = "zstd")]
+    pub(crate) fn into_pending(self) -> Vec<u8> {
+        self.buf
+    }
+
+    /// `into_pending` without giving up the chunker
+    #[cfg(feature = "zstd")]
+    pub(crate) fn pending(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// Bytes read from the reader so far
+    #[cfg(feature = "zstd")]
+    pub(crate) fn consumed(&self) -> u64 {
+        self.consumed
+    }
+
+    /// Fill the buffer up to the maximum chunk size, so boundaries do not depend on how
+    /// the reader splits its reads
+    ///
+    /// The buffer grows as the input arrives. Bytes read before an error are kept in the
+    /// buffer.
+    fn fill_buffer(&mut self) -> Result<(), std::io::Error> {
+        let wanted = self.boundaries.max.saturating_sub(self.buf.len());
+        let start = self.buf.len();
+        let result = (&mut self.reader)
+            .take(wanted as u64)
+            .read_to_end(&mut self.buf);
+        let filled = self.buf.len() - start;
+        self.consumed += filled as u64;
+        if result.is_ok() && filled < wanted {
+            self.reach_eof = true;
+        }
+        result.map(|_| ())
+    }
+}
+
+impl<R: Read> Iterator for Chunker<R> {
+    type Item = Result<Vec<u8>, ZchunkError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.reach_eof {
+            if let Err(e) = self.fill_buffer() {
+                return Some(Err(e.into()));
             }
         }
 
-        Some(Ok(self.buf.drain(..).collect()))
+        // buf is empty, so no more data
+        if self.buf.is_empty() {
+            return None;
+        }
+
+        let cut = self.boundaries.next_cut(&self.buf);
+        Some(Ok(self.buf.drain(..cut).collect()))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{fs::File, io::BufReader};
+    use std::{
+        fs::File,
+        io::{self, BufReader},
+    };
 
     use sha2::{Digest, Sha512_256};
 
-    use super::Chunker;
+    use super::{
+        estimate_chunker_params, Chunker, ChunkerParams, MAX_CHUNK_SIZE, MAX_CHUNK_SIZE_RATIO,
+        MIN_CHUNK_SIZE,
+    };
+    use crate::ZchunkError;
 
     struct Chunk {
         size: usize,
@@ -286,7 +467,7 @@ mod tests {
             ),
         ];
 
-        let chunker = Chunker::default(reader);
+        let chunker = Chunker::with_params(ChunkerParams::default(), reader).unwrap();
         let mut total_size = 0;
         for (i, c) in chunker.into_iter().enumerate() {
             let chunk = c.unwrap();
@@ -304,4 +485,113 @@ mod tests {
 
         assert_eq!(file_size, total_size as u64);
     }
+
+    fn chunk_sizes(params: ChunkerParams) -> Vec<usize> {
+        let reader = BufReader::new(File::open("testdata/chunker.input").unwrap());
+        Chunker::with_params(params, reader)
+            .unwrap()
+            .map(|c| c.unwrap().len())
+            .collect()
+    }
+
+    #[test]
+    fn test_chunker_normalization() {
+        let spread = |sizes: &[usize]| {
+            // the final chunk is cut by the end of input, so leave it out
+            let sizes = &sizes[..sizes.len() - 1];
+            *sizes.iter().max().unwrap() as f64 / *sizes.iter().min().unwrap() as f64
+        };
+
+        let plain = chunk_sizes(ChunkerParams::default());
+        assert_eq!(
+            plain,
+            chunk_sizes(ChunkerParams::default().normalization_level(0))
+        );
+
+        let normalized = chunk_sizes(ChunkerParams::default().normalization_level(2));
+        assert_eq!(
+            normalized,
+            chunk_sizes(ChunkerParams::default().normalization_level(2))
+        );
+        assert_eq!(
+            normalized.iter().sum::<usize>(),
+            plain.iter().sum::<usize>()
+        );
+        assert!(spread(&normalized) < spread(&plain));
+    }
+
+    #[test]
+    fn test_chunker_target_size() {
+        assert_eq!(
+            ChunkerParams::with_target_size(32 << 10),
+            ChunkerParams::default()
+        );
+        assert_eq!(
+            ChunkerParams::with_target_size(48 << 10),
+            ChunkerParams::new(16 << 10, 256 << 10, (64 << 10) - 1)
+        );
+        ChunkerParams::with_target_size(256).validate().unwrap();
+        assert_eq!(
+            ChunkerParams::with_target_size(usize::MAX),
+            ChunkerParams::new(
+                MAX_CHUNK_SIZE / 16,
+                MAX_CHUNK_SIZE,
+                (MAX_CHUNK_SIZE / 4 - 1) as u32
+            )
+        );
+    }
+
+    #[test]
+    fn test_estimate_chunker_params() {
+        // 50 GB in 65536 chunks rounds the target up to 1 MiB
+        assert_eq!(
+            estimate_chunker_params(50_000_000_000, 1 << 16),
+            ChunkerParams::with_target_size(1 << 20)
+        );
+        assert_eq!(
+            estimate_chunker_params(100, 10),
+            ChunkerParams::with_target_size(256)
+        );
+        assert_eq!(
+            estimate_chunker_params(u64::MAX, 0),
+            ChunkerParams::with_target_size(MAX_CHUNK_SIZE / 4)
+        );
+    }
+
+    #[test]
+    fn test_chunker_invalid_params() {
+        let invalid = [
+            ChunkerParams::new(MIN_CHUNK_SIZE - 1, 4096, 1023),
+            ChunkerParams::new(16, 4096, 1023),
+            ChunkerParams::new(4096, 1024, 1023),
+            ChunkerParams::new(1024, 4096, 0),
+            ChunkerParams::new(64, usize::MAX, 1023),
+            ChunkerParams::new(MAX_CHUNK_SIZE, MAX_CHUNK_SIZE + 1, 1023),
+            ChunkerParams::new(64, 64 * MAX_CHUNK_SIZE_RATIO + 1, 1023),
+            ChunkerParams::with_target_size(MIN_CHUNK_SIZE),
+        ];
+        for params in invalid {
+            assert!(matches!(
+                Chunker::with_params(params, io::empty()),
+                Err(ZchunkError::InvalidChunkerParams { .. })
+            ));
+        }
+
+        // the smallest sizes still cut the input into many chunks of at least the floor
+        let sizes = chunk_sizes(ChunkerParams::new(MIN_CHUNK_SIZE, MIN_CHUNK_SIZE, 1));
+        assert!(sizes.len() > 1);
+        assert!(sizes[..sizes.len() - 1]
+            .iter()
+            .all(|&s| s == MIN_CHUNK_SIZE));
+    }
+
+    #[test]
+    fn test_chunker_short_buffer() {
+        // a minimum below the window size, which validation no longer lets through
+        let mut chunker =
+            Chunker::with_params(ChunkerParams::default(), [7u8; 20].as_slice()).unwrap();
+        chunker.boundaries.min = 10;
+        let chunks: Vec<Vec<u8>> = chunker.map(|c| c.unwrap()).collect();
+        assert_eq!(chunks, vec![vec![7u8; 20]]);
+    }
 }
@@ -0,0 +1,208 @@
+use std::{
+    env,
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use crate::{
+    errors::ZchunkError,
+    format::Encoder,
+    options::EncoderOptions,
+    report::{EncodeReport, EncodeStats},
+    temp::{parent_dir, SpillingTemp, TempFile},
+};
+
+/// Upper bound of the temp preallocated from the input size hint, and of the compressed
+/// bytes `compress_file` keeps in memory before its temp spills to a file
+pub(crate) const MAX_PREALLOCATED_TEMP: u64 = 64 << 20;
+
+/// Encode the file at `path` and write the zchunk file to `out`
+///
+/// Unless `options` carry an input size hint, the size is taken from the file metadata. The
+/// compressed chunks are kept until the header is written, in memory up to 64 MiB and in a
+/// file in the system temp directory past it.
+pub fn compress_file_to(
+    path: &Path,
+    out: impl Write,
+    options: EncoderOptions,
+) -> Result<EncodeReport, ZchunkError> {
+    let mut encoder = prepared_encoder(path, options, &env::temp_dir())?;
+    encoder.compress_to(out)?;
+    Ok(encoder.report().cloned().unwrap_or_default())
+}
+
+/// Encode the file at `src` into a zchunk file at `dst`, see `compress_file_to`
+///
+/// The output goes to a temp file next to `dst` that is renamed over it once complete, so
+/// `dst` is never left half written, and the temp is removed on error. Compressed chunks
+/// that do not fit in memory spill to a file next to `dst` as well. Failing to open `src`
+/// or to write next to `dst` is a `ZchunkError::PathIo` naming the path.
+pub fn compress_file(
+    src: &Path,
+    dst: &Path,
+    options: EncoderOptions,
+) -> Result<EncodeStats, ZchunkError> {
+    let mut encoder = prepared_encoder(src, options, parent_dir(dst))?;
+
+    let (temp, file) = TempFile::create_sibling(dst).map_err(|e| ZchunkError::path_io(dst, e))?;
+    let mut out = BufWriter::new(file);
+    encoder.compress_to(&mut out)?;
+    let file = out
+        .into_inner()
+        .map_err(|e| ZchunkError::path_io(dst, e.into_error()))?;
+    encoder.check_output(file)?;
+    temp.persist(dst, false)
+        .map_err(|e| ZchunkError::path_io(dst, e))?;
+
+    encoder.stats()
+}
+
+/// An encoder over the file at `path` with its chunks prepared in a temp that spills to a
+/// file in `spill_dir`
+fn prepared_encoder(
+    path: &Path,
+    mut options: EncoderOptions,
+    spill_dir: &Path,
+) -> Result<Encoder<SpillingTemp, BufReader<File>>, ZchunkError> {
+    let open = || -> Result<_, std::io::Error> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        Ok((file, len))
+    };
+    let (file, len) = open().map_err(|e| ZchunkError::path_io(path, e))?;
+    if options.input_size_hint.is_none() {
+        options = options.input_size_hint(len);
+    }
+    let temp = SpillingTemp::new(MAX_PREALLOCATED_TEMP, spill_dir);
+
+    let mut encoder = Encoder::with_options(BufReader::new(file), temp, options)?;
+    encoder.prepare_chunks()?;
+    Ok(encoder)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{self, File},
+        io::Cursor,
+        path::Path,
+        sync::{Arc, Mutex},
+    };
+
+    use super::compress_file_to;
+    use crate::{Decoder, EncodeProgress, Encoder, EncoderOptions};
+
+    const INPUT: &str = "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml";
+
+    fn encode(options: EncoderOptions) -> Vec<u8> {
+        let input = File::open(INPUT).unwrap();
+        let mut encoder = Encoder::with_options(input, Cursor::new(Vec::new()), options).unwrap();
+        encoder.prepare_chunks().unwrap();
+        let mut output = Vec::new();
+        encoder.compress_to(&mut output).unwrap();
+        output
+    }
+
+    #[test]
+    fn test_wrong_size_hint() {
+        let expected = encode(EncoderOptions::new());
+        for hint in [0, 1, 1000, u64::MAX] {
+            assert_eq!(
+                encode(EncoderOptions::new().input_size_hint(hint)),
+                expected
+            );
+            let mut output = Vec::new();
+            compress_file_to(
+                Path::new(INPUT),
+                &mut output,
+                EncoderOptions::new().input_size_hint(hint),
+            )
+            .unwrap();
+            assert_eq!(output, expected);
+        }
+
+        let mut output = Vec::new();
+        compress_file_to(Path::new(INPUT), &mut output, EncoderOptions::new()).unwrap();
+        assert_eq!(output, expected);
+        let mut decompressed = Vec::new();
+        Decoder::new(Cursor::new(output))
+            .unwrap()
+            .decompress_to(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, fs::read(INPUT).unwrap());
+    }
+
+    #[test]
+    fn test_progress_fractions() {
+        let seen = Arc::new(Mutex::new(Vec::<EncodeProgress>::new()));
+        let sink = seen.clone();
+        let options = EncoderOptions::new().progress(Arc::new(move |p| {
+            sink.lock().unwrap().push(p);
+        }));
+        // the hint comes from the file metadata
+        compress_file_to(Path::new(INPUT), Vec::new(), options).unwrap();
+
+        let seen = seen.lock().unwrap();
+        let chunks = Decoder::new(Cursor::new(encode(EncoderOptions::new())))
+            .unwrap()
+            .header()
+            .index
+            .data_chunks
+            .len();
+        assert_eq!(seen.len(), chunks + 1);
+        let fractions: Vec<f64> = seen.iter().map(|p| p.fraction.unwrap()).collect();
+        assert!(fractions.windows(2).all(|w| w[0] <= w[1]), "{fractions:?}");
+        assert!(fractions.iter().all(|&f| (0.0..=1.0).contains(&f)));
+        assert_eq!(fractions.last(), Some(&1.0));
+        let last = seen.last().unwrap();
+        assert_eq!(last.bytes_consumed, fs::metadata(INPUT).unwrap().len());
+        assert_eq!(last.chunks, chunks);
+
+        // no hint, no fractions
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        encode(
+            EncoderOptions::new().progress(Arc::new(move |p: EncodeProgress| {
+                sink.lock().unwrap().push(p.fraction);
+            })),
+        );
+        assert!(seen.lock().unwrap().iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn test_progress_bytes() {
+        let seen = Arc::new(Mutex::new(Vec::<EncodeProgress>::new()));
+        let sink = seen.clone();
+        let output = encode(
+            EncoderOptions::new()
+                .dict(b"<group>".repeat(64))
+                .progress(Arc::new(move |p| {
+                    sink.lock().unwrap().push(p);
+                })),
+        );
+
+        let seen = seen.lock().unwrap();
+        let decoder = Decoder::new(Cursor::new(output.as_slice())).unwrap();
+        let header = decoder.header();
+        let chunks = header.index.data_chunks.len();
+        // once per data chunk and once when done
+        assert_eq!(seen.len(), chunks + 1);
+        for (i, p) in seen[..chunks].iter().enumerate() {
+            assert_eq!(p.chunks, i + 1);
+            let (chunk, offset) = &header.index.data_chunks[i];
+            assert_eq!(p.bytes_written, offset + chunk.length.to_u64().unwrap());
+        }
+        let last = seen.last().unwrap();
+        assert_eq!(last.chunks, chunks);
+        assert_eq!(last.bytes_consumed, fs::metadata(INPUT).unwrap().len());
+        assert_eq!(
+            last.bytes_written,
+            output.len() as u64 - header.data_offset().unwrap()
+        );
+        assert!(seen
+            .windows(2)
+            .all(|w| w[0].bytes_consumed <= w[1].bytes_consumed
+                && w[0].bytes_written <= w[1].bytes_written));
+    }
+}
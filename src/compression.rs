@@ -0,0 +1,300 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, Read},
+    ops::RangeInclusive,
+    sync::Arc,
+};
+
+use crate::{errors::ZchunkError, format::CompressionType};
+
+/// The compression type ids a `Compression` backend can be registered for
+///
+/// They are not part of the zchunk format: other readers refuse files of these types, and
+/// this crate refuses them unless the same backend is registered for the id when decoding.
+pub const CUSTOM_COMPRESSION_TYPES: RangeInclusive<u8> = 0x80..=0xff;
+
+/// Compresses and decompresses chunks of one compression type, see `CompressionRegistry`
+///
+/// Data chunks are compressed with the dict of the file, the dict chunk itself without one.
+/// Every chunk is compressed on its own, so `compress` must not carry state from one chunk
+/// to the next.
+pub trait Compression: Send + Sync {
+    fn compress(&self, data: &[u8], dict: Option<&[u8]>) -> io::Result<Vec<u8>>;
+
+    /// A reader of the decompressed data of the chunk `reader` reads
+    fn decompressor<'a>(
+        &self,
+        reader: Box<dyn Read + 'a>,
+        dict: Option<&'a [u8]>,
+    ) -> io::Result<Box<dyn Read + 'a>>;
+}
+
+/// Stores chunks as they are, `CompressionType::None`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoCompression;
+
+impl Compression for NoCompression {
+    fn compress(&self, data: &[u8], _dict: Option<&[u8]>) -> io::Result<Vec<u8>> {
+        Ok(data.to_vec())
+    }
+
+    fn decompressor<'a>(
+        &self,
+        reader: Box<dyn Read + 'a>,
+        _dict: Option<&'a [u8]>,
+    ) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(reader)
+    }
+}
+
+/// Compresses chunks into single zstd frames at the default level, `CompressionType::Zstd`
+///
+/// `Encoder` compresses zstd chunks with the level and parameters of its options instead.
+#[cfg(feature = "zstd")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ZstdCompression;
+
+#[cfg(feature = "zstd")]
+impl Compression for ZstdCompression {
+    fn compress(&self, data: &[u8], dict: Option<&[u8]>) -> io::Result<Vec<u8>> {
+        let level = crate::format::DEFAULT_COMPRESSION_LEVEL;
+        zstd::bulk::Compressor::with_dictionary(level, dict.unwrap_or_default())?.compress(data)
+    }
+
+    fn decompressor<'a>(
+        &self,
+        reader: Box<dyn Read + 'a>,
+        dict: Option<&'a [u8]>,
+    ) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(Box::new(zstd::Decoder::with_dictionary(
+            io::BufReader::new(reader),
+            dict.unwrap_or_default(),
+        )?))
+    }
+}
+
+/// `ZstdCompression` decompressing windows of up to 2^`window_log_max` bytes, see
+/// `DecodeOptions::zstd_window_log_max`
+#[cfg(feature = "zstd")]
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct LargeWindowZstd {
+    pub(crate) window_log_max: u32,
+}
+
+#[cfg(feature = "zstd")]
+impl Compression for LargeWindowZstd {
+    fn compress(&self, data: &[u8], dict: Option<&[u8]>) -> io::Result<Vec<u8>> {
+        ZstdCompression.compress(data, dict)
+    }
+
+    fn decompressor<'a>(
+        &self,
+        reader: Box<dyn Read + 'a>,
+        dict: Option<&'a [u8]>,
+    ) -> io::Result<Box<dyn Read + 'a>> {
+        let mut decoder =
+            zstd::Decoder::with_dictionary(io::BufReader::new(reader), dict.unwrap_or_default())?;
+        decoder.window_log_max(self.window_log_max)?;
+        Ok(Box::new(decoder))
+    }
+}
+
+/// The built-in compression types `CompressionRegistry::backend` has a backend for, zstd
+/// needs the `zstd` feature
+pub(crate) const BUILTIN_COMPRESSION_TYPES: &[CompressionType] = &[
+    CompressionType::None,
+    #[cfg(feature = "zstd")]
+    CompressionType::Zstd,
+];
+
+/// The `Compression` backends by compression type
+///
+/// The built-in types are always there, backends for non-standard types are registered
+/// with ids from `CUSTOM_COMPRESSION_TYPES`, see `EncoderOptions::compression_registry`
+/// and `DecodeOptions::compression_registry`.
+#[derive(Clone, Default)]
+pub struct CompressionRegistry {
+    custom: BTreeMap<u8, Arc<dyn Compression>>,
+}
+
+impl CompressionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use `backend` for `CompressionType::Custom(id)`, replacing a backend registered for
+    /// the same id
+    ///
+    /// An id outside of `CUSTOM_COMPRESSION_TYPES` fails with `InvalidCompresionType`.
+    pub fn register(mut self, id: u8, backend: Arc<dyn Compression>) -> Result<Self, ZchunkError> {
+        if !CUSTOM_COMPRESSION_TYPES.contains(&id) {
+            return Err(ZchunkError::InvalidCompresionType(id));
+        }
+        self.custom.insert(id, backend);
+        Ok(self)
+    }
+
+    /// The custom compression types with a registered backend, in id order
+    pub(crate) fn custom_types(&self) -> impl Iterator<Item = CompressionType> + '_ {
+        self.custom.keys().map(|id| CompressionType::Custom(*id))
+    }
+
+    /// The backend of `compression_type`, `InvalidCompresionType` for a custom type that
+    /// is not registered or, without the `zstd` feature, for zstd
+    pub fn backend(
+        &self,
+        compression_type: CompressionType,
+    ) -> Result<&dyn Compression, ZchunkError> {
+        match compression_type {
+            CompressionType::None => Ok(&NoCompression),
+            #[cfg(feature = "zstd")]
+            CompressionType::Zstd => Ok(&ZstdCompression),
+            #[cfg(not(feature = "zstd"))]
+            CompressionType::Zstd => {
+                Err(ZchunkError::InvalidCompresionType(compression_type.to_u8()))
+            }
+            CompressionType::Custom(id) => match self.custom.get(&id) {
+                Some(backend) => Ok(backend.as_ref()),
+                None => Err(ZchunkError::InvalidCompresionType(id)),
+            },
+        }
+    }
+}
+
+#[cfg(all(test, feature = "zstd"))]
+mod tests {
+    use std::{
+        io::{self, Cursor, Read},
+        sync::Arc,
+    };
+
+    use super::{Compression, CompressionRegistry};
+    use crate::{
+        ChunkerParams, CompressionType, DecodeOptions, Decoder, Encoder, EncoderOptions,
+        VerifyOptions, ZchunkError,
+    };
+
+    const INPUT: &str = "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml";
+
+    /// XORs every byte with the key, and with the first byte of the dict
+    struct XorCompression(u8);
+
+    impl XorCompression {
+        fn key(&self, dict: Option<&[u8]>) -> u8 {
+            self.0 ^ dict.and_then(|d| d.first().copied()).unwrap_or(0)
+        }
+    }
+
+    struct XorReader<'a> {
+        inner: Box<dyn Read + 'a>,
+        key: u8,
+    }
+
+    impl Read for XorReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.inner.read(buf)?;
+            buf[..n].iter_mut().for_each(|b| *b ^= self.key);
+            Ok(n)
+        }
+    }
+
+    impl Compression for XorCompression {
+        fn compress(&self, data: &[u8], dict: Option<&[u8]>) -> io::Result<Vec<u8>> {
+            let key = self.key(dict);
+            Ok(data.iter().map(|b| b ^ key).collect())
+        }
+
+        fn decompressor<'a>(
+            &self,
+            reader: Box<dyn Read + 'a>,
+            dict: Option<&'a [u8]>,
+        ) -> io::Result<Box<dyn Read + 'a>> {
+            Ok(Box::new(XorReader {
+                inner: reader,
+                key: self.key(dict),
+            }))
+        }
+    }
+
+    fn registry() -> CompressionRegistry {
+        CompressionRegistry::new()
+            .register(0xf0, Arc::new(XorCompression(0x5a)))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_custom_compression_round_trip() {
+        let input = std::fs::read(INPUT).unwrap();
+        for dict in [None, Some(b"<group>".repeat(16))] {
+            let mut options = EncoderOptions::new()
+                .chunker_params(ChunkerParams::new(1024, 8192, 2047))
+                .compression_registry(registry())
+                .compression_type(CompressionType::Custom(0xf0));
+            if let Some(dict) = &dict {
+                options = options.dict(dict.clone());
+            }
+            let mut file = Vec::new();
+            Encoder::compress_small(&input, &mut file, options).unwrap();
+
+            let decoder = Decoder::new(Cursor::new(file.as_slice())).unwrap();
+            assert_eq!(
+                decoder.header().compression_type().unwrap(),
+                CompressionType::Custom(0xf0)
+            );
+            // the xor keeps the size of every chunk
+            let (chunk, _) = &decoder.header().index.data_chunks[0];
+            assert_eq!(chunk.length, chunk.uncompressed_length);
+
+            // without the backend the file is read, but not decompressed
+            let mut decoder = Decoder::new(Cursor::new(file.as_slice())).unwrap();
+            assert!(matches!(
+                decoder.decompress_to(io::sink()),
+                Err(ZchunkError::InvalidCompresionType(0xf0))
+            ));
+
+            let options = DecodeOptions::new().compression_registry(registry());
+            let mut decoder = Decoder::with_options(Cursor::new(file.as_slice()), options).unwrap();
+            assert!(decoder.verify(&VerifyOptions::new()).unwrap().is_ok());
+            assert_eq!(decoder.get_uncompressed_dict().unwrap(), dict);
+            let mut output = Vec::new();
+            decoder.decompress_to(&mut output).unwrap();
+            assert!(output == input);
+            let first = decoder.decompress_chunk(0).unwrap();
+            assert!(input.starts_with(&first));
+        }
+    }
+
+    #[test]
+    fn test_compression_registry() {
+        let registry = registry();
+        assert!(registry.backend(CompressionType::None).is_ok());
+        assert!(registry.backend(CompressionType::Zstd).is_ok());
+        assert!(registry.backend(CompressionType::Custom(0xf0)).is_ok());
+        assert!(matches!(
+            registry.backend(CompressionType::Custom(0xf1)),
+            Err(ZchunkError::InvalidCompresionType(0xf1))
+        ));
+        // the standard ids are not for custom backends
+        assert!(matches!(
+            CompressionRegistry::new().register(1, Arc::new(XorCompression(1))),
+            Err(ZchunkError::InvalidCompresionType(1))
+        ));
+
+        // a custom type needs its backend to encode
+        let err = Encoder::compress_small(
+            b"data",
+            Vec::new(),
+            EncoderOptions::new().compression_type(CompressionType::Custom(0xf0)),
+        )
+        .unwrap_err();
+        assert!(matches!(err, ZchunkError::InvalidCompresionType(0xf0)));
+        assert!(matches!(
+            CompressionType::from_u8(3),
+            Err(ZchunkError::InvalidCompresionType(3))
+        ));
+        assert_eq!(
+            CompressionType::from_u8(0x80).unwrap(),
+            CompressionType::Custom(0x80)
+        );
+    }
+}
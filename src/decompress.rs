@@ -0,0 +1,124 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use crate::{
+    errors::ZchunkError,
+    format::{CountingWriter, Decoder},
+    temp::TempFile,
+};
+
+/// Decompress the zchunk file at `src` into `dst`, and return the bytes written
+///
+/// The output goes to a temp file next to `dst` that is renamed over it once complete, so
+/// `dst` is never left half written, and the temp is removed on error. Failing to open `src`
+/// or to write next to `dst` is a `ZchunkError::PathIo` naming the path.
+pub fn decompress_file(src: &Path, dst: &Path) -> Result<u64, ZchunkError> {
+    let file = File::open(src).map_err(|e| ZchunkError::path_io(src, e))?;
+    let mut decoder = Decoder::new(BufReader::new(file))?;
+
+    let (temp, file) = TempFile::create_sibling(dst).map_err(|e| ZchunkError::path_io(dst, e))?;
+    let mut out = CountingWriter::new(BufWriter::new(file));
+    decoder.decompress_to(&mut out)?;
+    out.flush().map_err(|e| ZchunkError::path_io(dst, e))?;
+    let written = out.written();
+    drop(out);
+    temp.persist(dst, false)
+        .map_err(|e| ZchunkError::path_io(dst, e))?;
+
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use tempfile::tempdir;
+
+    use super::decompress_file;
+    use crate::{compress_file, EncoderOptions, ZchunkError};
+
+    const INPUT: &str = "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml";
+
+    fn entries(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_file_round_trip() {
+        let dir = tempdir().unwrap();
+        let zck = dir.path().join("comps.xml.zck");
+        let xml = dir.path().join("comps.xml");
+        fs::write(&zck, b"replaced").unwrap();
+
+        let stats = compress_file(Path::new(INPUT), &zck, EncoderOptions::new()).unwrap();
+        assert_eq!(stats.file_size(), fs::metadata(&zck).unwrap().len());
+        assert_eq!(stats.input_bytes, fs::metadata(INPUT).unwrap().len());
+
+        let written = decompress_file(&zck, &xml).unwrap();
+        assert_eq!(written, stats.input_bytes);
+        assert_eq!(fs::read(&xml).unwrap(), fs::read(INPUT).unwrap());
+        assert_eq!(entries(dir.path()), ["comps.xml", "comps.xml.zck"]);
+
+        // the verified output is the same file, and no temp is left behind
+        let verified = dir.path().join("verified.zck");
+        compress_file(
+            Path::new(INPUT),
+            &verified,
+            EncoderOptions::new().verify_output(true),
+        )
+        .unwrap();
+        assert_eq!(fs::read(&verified).unwrap(), fs::read(&zck).unwrap());
+        assert_eq!(
+            entries(dir.path()),
+            ["comps.xml", "comps.xml.zck", "verified.zck"]
+        );
+    }
+
+    #[test]
+    fn test_file_errors() {
+        let dir = tempdir().unwrap();
+        let missing = dir.path().join("missing");
+        let dst = dir.path().join("out");
+
+        let err = compress_file(&missing, &dst, EncoderOptions::new()).unwrap_err();
+        assert!(
+            matches!(&err, ZchunkError::PathIo { path, .. } if path.path() == missing),
+            "{err:?}"
+        );
+        assert!(err.to_string().starts_with(&missing.display().to_string()));
+        let err = decompress_file(&missing, &dst).unwrap_err();
+        assert!(
+            matches!(&err, ZchunkError::PathIo { path, .. } if path.path() == missing),
+            "{err:?}"
+        );
+
+        // the destination directory does not exist
+        let zck = dir.path().join("file.zck");
+        compress_file(Path::new(INPUT), &zck, EncoderOptions::new()).unwrap();
+        let unwritable = dir.path().join("no such dir").join("out");
+        let err = compress_file(Path::new(INPUT), &unwritable, EncoderOptions::new()).unwrap_err();
+        assert!(
+            matches!(&err, ZchunkError::PathIo { path, .. } if path.path() == unwritable),
+            "{err:?}"
+        );
+        let err = decompress_file(&zck, &unwritable).unwrap_err();
+        assert!(
+            matches!(&err, ZchunkError::PathIo { path, .. } if path.path() == unwritable),
+            "{err:?}"
+        );
+
+        // a file cut short fails while decompressing, and the temp is removed
+        let bytes = fs::read(&zck).unwrap();
+        fs::write(&zck, &bytes[..bytes.len() - 100]).unwrap();
+        assert!(decompress_file(&zck, &dst).is_err());
+        assert_eq!(entries(dir.path()), ["file.zck"]);
+    }
+}
@@ -0,0 +1,355 @@
+//! Header deltas, which carry a new header to a reader that holds the old one
+//!
+//! The format is specific to this crate and not part of the zchunk format. It is the payload
+//! of a `SidecarKind::HeaderDelta` envelope, version 1, with variable-length integers as in
+//! the zchunk format:
+//!
+//! ```text
+//! digest      [u8; 32], the header checksum computed from the new header
+//! head size   varint
+//! head        the new lead, preface and index up to the dict chunk entry, as in the file
+//! op count    varint
+//! ops         0, start, count: copy `count` data chunk entries of the old index from `start`
+//!             1, count, entries: `count` data chunk entries, as in the file
+//! signatures  the new signatures, as in the file, up to the end of the delta
+//! ```
+
+use std::{
+    collections::HashMap,
+    io::{self, Cursor, Read, Write},
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::{
+    checksum::{Checksum, ChecksumType},
+    errors::ZchunkError,
+    format::{checked_add, checked_cast, Chunk, Decoder, Header, Lead, Preface},
+    sidecar::{envelope, open_envelope, SidecarKind},
+    types::{ReadVariantInt, VariantInt},
+};
+
+const DELTA_VERSION: u16 = 1;
+
+const OP_COPY: u64 = 0;
+const OP_INSERT: u64 = 1;
+
+enum Op<'a> {
+    /// A run of entries found in the old index
+    Copy {
+        start: usize,
+        count: usize,
+    },
+    Insert(Vec<&'a [u8]>),
+}
+
+impl Header {
+    /// Encode this header as a delta against `old`, see `apply_delta`
+    ///
+    /// Runs of data chunk entries found in the old index are referenced by their position
+    /// there, all other parts of the header are carried in full.
+    pub fn encode_delta(&self, old: &Header) -> Vec<u8> {
+        let mut payload = Vec::new();
+        self.write_delta(old, &mut payload)
+            .expect("writing to a Vec does not fail");
+        envelope(SidecarKind::HeaderDelta, DELTA_VERSION, &payload)
+    }
+
+    fn write_delta(&self, old: &Header, delta: &mut Vec<u8>) -> Result<(), io::Error> {
+        let mut unchecked = Vec::new();
+        self.write_to(&mut unchecked, true)?;
+        // the digest of `content_digest`
+        delta.write_all(&Sha256::digest(&unchecked))?;
+
+        let mut head = Vec::new();
+        self.lead.write_to(&mut head, false)?;
+        self.preface.write_to(&mut head)?;
+        self.index.write_head_to(&mut head)?;
+        VariantInt::from(head.len() as u64).write_to(&mut *delta)?;
+        delta.write_all(&head)?;
+
+        let old_entries = entry_bytes(old)?;
+        let new_entries = entry_bytes(self)?;
+        let ops = diff_entries(&old_entries, &new_entries);
+        VariantInt::from(ops.len() as u64).write_to(&mut *delta)?;
+        for op in ops {
+            match op {
+                Op::Copy { start, count } => {
+                    for n in [OP_COPY, start as u64, count as u64] {
+                        VariantInt::from(n).write_to(&mut *delta)?;
+                    }
+                }
+                Op::Insert(entries) => {
+                    VariantInt::from(OP_INSERT).write_to(&mut *delta)?;
+                    VariantInt::from(entries.len() as u64).write_to(&mut *delta)?;
+                    for entry in entries {
+                        delta.write_all(entry)?;
+                    }
+                }
+            }
+        }
+
+        self.signatures.write_to(&mut *delta)
+    }
+
+    /// Rebuild the header encoded by `encode_delta` against `old`
+    ///
+    /// The result is byte for byte the encoded header, which is checked by the digest in the
+    /// delta: a delta applied to another old header fails with `HeaderChecksumNotMatch` or
+    /// `InvalidHeaderDelta`.
+    pub fn apply_delta(old: &Header, delta: &[u8]) -> Result<Header, ZchunkError> {
+        let (_, payload) = open_envelope(delta, SidecarKind::HeaderDelta, DELTA_VERSION)?;
+        let mut reader = payload.as_slice();
+        let mut digest = [0; 32];
+        reader.read_exact(&mut digest)?;
+
+        let head_size = reader.read_variant_int()?.to_u64()?;
+        if head_size > reader.len() as u64 {
+            return Err(ZchunkError::InvalidHeaderDelta);
+        }
+        let (head, rest) = reader.split_at(head_size as usize);
+        reader = rest;
+        let mut bytes = head.to_vec();
+
+        // the flags and the checksum type tell how the data chunk entries are laid out
+        let mut head_reader = head;
+        Lead::from_reader(&mut head_reader)?;
+        let flags = Preface::from_reader(&mut head_reader)?.flags;
+        head_reader.read_variant_int()?;
+        let checksum_size = ChecksumType::from_u8(checked_cast(
+            head_reader.read_variant_int()?.to_u64()?,
+            "checksum type",
+        )?)?
+        .digest_size();
+        // the ops may not write more entries than the new index declares, the count includes
+        // the dict chunk
+        let declared = head_reader
+            .read_variant_int()?
+            .to_u64()?
+            .checked_sub(1)
+            .ok_or(ZchunkError::InvalidHeaderDelta)?;
+        let mut written = 0u64;
+        let mut add_entries = |count: u64| match written.checked_add(count) {
+            Some(total) if total <= declared => {
+                written = total;
+                Ok(())
+            }
+            _ => Err(ZchunkError::InvalidHeaderDelta),
+        };
+
+        let old_chunks = &old.index.data_chunks;
+        for _ in 0..reader.read_variant_int()?.to_u64()? {
+            match reader.read_variant_int()?.to_u64()? {
+                OP_COPY => {
+                    let start = reader.read_variant_int()?.to_u64()?;
+                    let count = reader.read_variant_int()?.to_u64()?;
+                    add_entries(count)?;
+                    let end = checked_add(start, count)?;
+                    let run = usize::try_from(start)
+                        .ok()
+                        .zip(usize::try_from(end).ok())
+                        .and_then(|(start, end)| old_chunks.get(start..end))
+                        .ok_or(ZchunkError::InvalidHeaderDelta)?;
+                    for (chunk, _) in run {
+                        chunk.write_to(&mut bytes)?;
+                    }
+                }
+                OP_INSERT => {
+                    let count = reader.read_variant_int()?.to_u64()?;
+                    add_entries(count)?;
+                    for _ in 0..count {
+                        Chunk::from_reader(&mut reader, flags.clone(), checksum_size)?
+                            .write_to(&mut bytes)?;
+                    }
+                }
+                _ => return Err(ZchunkError::InvalidHeaderDelta),
+            }
+        }
+        bytes.extend_from_slice(reader);
+
+        let header_size = bytes.len() as u64;
+        let header = Decoder::new(Cursor::new(bytes))?.header;
+        if header.data_offset()? != header_size {
+            return Err(ZchunkError::InvalidHeaderDelta);
+        }
+        let found = header.content_digest()?;
+        if found != digest {
+            return Err(ZchunkError::HeaderChecksumNotMatch {
+                expected: Box::new(Checksum::from_bytes(&digest)?),
+                found: Box::new(Checksum::from_bytes(&found)?),
+            });
+        }
+
+        Ok(header)
+    }
+}
+
+/// The data chunk entries of `header` as written in the file
+fn entry_bytes(header: &Header) -> Result<Vec<Vec<u8>>, io::Error> {
+    header
+        .index
+        .data_chunks
+        .iter()
+        .map(|(chunk, _)| {
+            let mut entry = Vec::with_capacity(chunk.byte_size());
+            chunk.write_to(&mut entry)?;
+            Ok(entry)
+        })
+        .collect()
+}
+
+/// Cover the new entries with runs copied from the old ones where possible
+///
+/// A run starts at the first old position holding the entry and extends as long as the
+/// following entries match.
+fn diff_entries<'a>(old: &[Vec<u8>], new: &'a [Vec<u8>]) -> Vec<Op<'a>> {
+    let mut positions = HashMap::new();
+    for (i, entry) in old.iter().enumerate() {
+        positions.entry(entry.as_slice()).or_insert(i);
+    }
+
+    let mut ops = Vec::new();
+    let mut i = 0;
+    while i < new.len() {
+        match positions.get(new[i].as_slice()) {
+            Some(&start) => {
+                let count = new[i..]
+                    .iter()
+                    .zip(&old[start..])
+                    .take_while(|(new, old)| new == old)
+                    .count();
+                ops.push(Op::Copy { start, count });
+                i += count;
+            }
+            None => {
+                match ops.last_mut() {
+                    Some(Op::Insert(entries)) => entries.push(&new[i]),
+                    _ => ops.push(Op::Insert(vec![&new[i]])),
+                }
+                i += 1;
+            }
+        }
+    }
+    ops
+}
+
+#[cfg(all(test, feature = "sha512"))]
+mod tests {
+    use std::{fs, io::Cursor};
+
+    use crate::{
+        sidecar::{envelope, ENVELOPE_HEADER_LEN},
+        types::VariantInt,
+        Decoder, Header, SidecarKind, ZchunkError,
+    };
+
+    const OLD: &str = "testdata/3c6181c789ef9e8ed23f4072eb2f8f529002abd5166273a9734d7d39f7a810ae-comps-Server.x86_64.xml.zck";
+    const NEW: &str = "testdata/c25ffa05cf1fdeb67801847df96c33933b1ee1ea081af52edff4ff371a1c814c-comps-Server.x86_64.xml.zck";
+
+    fn header(path: &str) -> Header {
+        Decoder::new(Cursor::new(fs::read(path).unwrap()))
+            .unwrap()
+            .header
+    }
+
+    fn header_bytes(header: &Header) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        header.write_to(&mut bytes, false).unwrap();
+        bytes
+    }
+
+    fn assert_round_trip(old: &Header, new: &Header) -> Vec<u8> {
+        let delta = new.encode_delta(old);
+        let applied = Header::apply_delta(old, &delta).unwrap();
+        assert_eq!(
+            applied.computed_checksum().unwrap(),
+            new.computed_checksum().unwrap()
+        );
+        assert_eq!(applied.lead.header_checksum, new.lead.header_checksum);
+        assert_eq!(header_bytes(&applied), header_bytes(new));
+        delta
+    }
+
+    #[test]
+    fn test_delta_round_trip() {
+        let (old, new) = (header(OLD), header(NEW));
+        assert_round_trip(&old, &new);
+        assert_round_trip(&new, &old);
+
+        // an unchanged header copies its whole index in one run
+        let delta = assert_round_trip(&new, &new);
+        let entries: usize = new
+            .index
+            .data_chunks
+            .iter()
+            .map(|(c, _)| c.byte_size())
+            .sum();
+        let full = header_bytes(&new).len();
+        assert!(
+            delta.len() < full - entries + ENVELOPE_HEADER_LEN + 43,
+            "{}",
+            delta.len()
+        );
+    }
+
+    #[test]
+    fn test_delta_errors() {
+        let (old, new) = (header(OLD), header(NEW));
+        let delta = new.encode_delta(&old);
+
+        let mut corrupt = delta.clone();
+        corrupt[ENVELOPE_HEADER_LEN] ^= 0xff;
+        assert!(matches!(
+            Header::apply_delta(&old, &corrupt),
+            Err(ZchunkError::HeaderChecksumNotMatch { .. })
+        ));
+
+        let mut future = delta.clone();
+        future[6] = 2;
+        assert!(matches!(
+            Header::apply_delta(&old, &future),
+            Err(ZchunkError::UnsupportedSidecarVersion {
+                kind: SidecarKind::HeaderDelta,
+                version: 2,
+                newest: 1,
+            })
+        ));
+
+        assert!(matches!(
+            Header::apply_delta(&old, &[b"\0ZCK1".as_slice(), &[0; 11]].concat()),
+            Err(ZchunkError::InvalidSidecar)
+        ));
+        assert!(Header::apply_delta(&old, &delta[..delta.len() - 1]).is_err());
+
+        // runs may not add up to more entries than the new index declares
+        let mut head = Vec::new();
+        new.lead.write_to(&mut head, false).unwrap();
+        new.preface.write_to(&mut head).unwrap();
+        new.index.write_head_to(&mut head).unwrap();
+        let copies = new.index.data_chunks.len().min(old.index.data_chunks.len()) as u64;
+        let mut payload = vec![0; 32];
+        VariantInt::from(head.len() as u64)
+            .write_to(&mut payload)
+            .unwrap();
+        payload.extend_from_slice(&head);
+        for n in [2, 0, 0, copies, 0, 0, copies] {
+            VariantInt::from(n).write_to(&mut payload).unwrap();
+        }
+        let repeated = envelope(SidecarKind::HeaderDelta, 1, &payload);
+        assert!(matches!(
+            Header::apply_delta(&old, &repeated),
+            Err(ZchunkError::InvalidHeaderDelta)
+        ));
+
+        // copied runs refer to the old header the delta was made against
+        let same = new.encode_delta(&new);
+        let err = Header::apply_delta(&old, &same).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                ZchunkError::HeaderChecksumNotMatch { .. } | ZchunkError::InvalidHeaderDelta
+            ),
+            "{err:?}"
+        );
+    }
+}
@@ -1,21 +1,148 @@
-use std::{array::TryFromSliceError, io};
+use std::{
+    array::TryFromSliceError,
+    fmt, io,
+    path::{Path, PathBuf},
+};
 
 use thiserror::Error;
 
+use crate::{
+    checksum::{Checksum, ChecksumType},
+    format::ChunkId,
+    hex::Hex,
+    sidecar::SidecarKind,
+    sniff::KnownFormat,
+    verify::FailureReason,
+};
+
+/// The part of the output being written when a writer failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteStage {
+    Header,
+    Dict,
+    Chunk(ChunkId),
+}
+
+/// Bytes of a path shown in error messages, longer paths are cut to their end
+const MAX_PATH_LEN: usize = 200;
+
+/// A path named in an error
+///
+/// Display and Debug show at most the last 200 bytes of the path, so messages stay short
+/// enough for log records. `path` has the whole path.
+#[derive(Clone, PartialEq, Eq)]
+pub struct ErrorPath(PathBuf);
+
+impl ErrorPath {
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    fn shortened(&self) -> String {
+        let path = self.0.display().to_string();
+        if path.len() <= MAX_PATH_LEN {
+            return path;
+        }
+        let mut start = path.len() - MAX_PATH_LEN;
+        while !path.is_char_boundary(start) {
+            start += 1;
+        }
+        format!("...{}", &path[start..])
+    }
+}
+
+impl From<&Path> for ErrorPath {
+    fn from(path: &Path) -> Self {
+        Self(path.to_path_buf())
+    }
+}
+
+impl fmt::Display for ErrorPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.shortened())
+    }
+}
+
+impl fmt::Debug for ErrorPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.shortened())
+    }
+}
+
+/// Names a data chunk in error messages, or a chunk in general when the id is unknown
+struct ChunkName<'a>(&'a Option<ChunkId>);
+
+impl fmt::Display for ChunkName<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(id) => write!(f, "chunk {id}"),
+            None => f.write_str("chunk"),
+        }
+    }
+}
+
+/// Renders bytes as ASCII, with `.` for anything not printable
+struct Printable<'a>(&'a [u8]);
+
+impl fmt::Debug for Printable<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text: String = self
+            .0
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        write!(f, "\"{text}\"")
+    }
+}
+
+/// Explains what the input looks like instead of a zchunk file
+struct FormatHint<'a>(&'a Option<KnownFormat>);
+
+impl fmt::Display for FormatHint<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(format) => write!(
+                f,
+                ", the input is {format} compressed, decompress it with `{}` instead",
+                format.tool()
+            ),
+            None => f.write_str(", the input is too short"),
+        }
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum ZchunkError {
     #[error(transparent)]
     Io(#[from] io::Error),
 
+    #[error("{path}: {source}")]
+    PathIo { path: ErrorPath, source: io::Error },
+
     #[error(transparent)]
     TryFromSlice(#[from] TryFromSliceError),
 
-    #[error("invalid leader id: {0:?}")]
+    #[error("invalid leader id: {} ({:?})", Hex(.0), Printable(.0))]
     InvalidLeaderID([u8; 5]),
 
+    #[error("not a zchunk file{}", FormatHint(.detected))]
+    NotAZchunkFile {
+        /// `None` when the input ends before the leader id
+        detected: Option<KnownFormat>,
+    },
+
     #[error("invalid checksum type: {0}")]
     InvalidChecksumType(u8),
 
+    #[error("checksum type {0:?} is not supported by this build")]
+    UnsupportedChecksumType(ChecksumType),
+
     #[error("invalid compression type: {0}")]
     InvalidCompresionType(u8),
 
@@ -25,11 +152,152 @@ pub enum ZchunkError {
     #[error("invalid header size (expected {expected}, found {found})")]
     InvalidHeaderSize { expected: u64, found: u64 },
 
+    #[error("chunk data at offset {offset} overlaps the header ending at {header_end}")]
+    HeaderDataOverlap { offset: u64, header_end: u64 },
+
     #[error("invalid index size (expected {expected}, found {found})")]
     InvalidIndexSize { expected: u64, found: u64 },
 
     #[error("the size of footer and entries does not match (expected {expected}, found {found})")]
-    SizeNotMatch { expected: u32, found: u32 },
+    SizeNotMatch { expected: u64, found: u64 },
+
+    #[error("invalid dict chunk (length {length}, uncompressed length {uncompressed_length})")]
+    InvalidDictChunk {
+        length: u64,
+        uncompressed_length: u64,
+    },
+
+    #[error("index entries with and without a stream")]
+    InconsistentChunkStreams,
+
+    #[error("size computation overflowed")]
+    SizeOverflow,
+
+    /// A number from the file does not fit the type it is used as, such as a checksum type
+    /// past 255
+    #[error("{what} {value} is out of range")]
+    Overflow { what: &'static str, value: u64 },
+
+    /// A size, count or offset from the file is valid, but past what this platform can
+    /// address, such as a chunk longer than `usize::MAX` on a 32-bit target
+    #[error("{what} {value} exceeds the limit of this platform")]
+    PlatformLimit { what: &'static str, value: u64 },
+
+    #[error("invalid chunker params (min {min}, max {max}, bitmask {bitmask:#x})")]
+    InvalidChunkerParams {
+        min: usize,
+        max: usize,
+        bitmask: u32,
+    },
+
+    #[error("invalid option {name} {value}: {reason}")]
+    InvalidOption {
+        name: &'static str,
+        value: i64,
+        reason: String,
+    },
+
+    #[error("zstd refused {parameter} {value}: {source}")]
+    InvalidZstdParameter {
+        parameter: &'static str,
+        value: u32,
+        source: io::Error,
+    },
+
+    #[error("invalid chunk key encoding")]
+    InvalidChunkKey,
+
+    #[error("invalid chunk bloom filter encoding")]
+    InvalidChunkBloom,
+
+    #[error("failed to write {stage:?} after {bytes_written} bytes: {source}")]
+    WriteFailed {
+        stage: WriteStage,
+        bytes_written: u64,
+        source: io::Error,
+    },
+
+    #[error("{annotations} chunk annotations given for {chunks} chunks")]
+    TooManyChunkAnnotations { chunks: usize, annotations: usize },
+
+    #[error("the input makes more than {max} data chunks")]
+    TooManyChunks { max: usize },
+
+    #[error("a dict needs compressed chunks")]
+    DictWithoutCompression,
+
+    #[error(
+        "failed to read input after {bytes_consumed} bytes and {chunks_completed} chunks: {source}"
+    )]
+    ReadFailed {
+        bytes_consumed: u64,
+        chunks_completed: usize,
+        source: io::Error,
+    },
+
+    #[error("prepare_chunks was already called on this encoder")]
+    AlreadyPrepared,
+
+    #[error("an earlier write to the EncoderSink failed")]
+    SinkFailed,
+
+    #[error("no interrupted prepare_chunks to resume")]
+    NothingToResume,
+
+    #[error("cancelled")]
+    Cancelled,
+
+    #[error("expected {expected} checksums, found {found}")]
+    ChecksumCountMismatch { expected: usize, found: usize },
+
+    #[error("the synced file does not match the source header")]
+    SyncedFileMismatch,
+
+    #[error("invalid checksum length {0}")]
+    InvalidChecksumLength(usize),
+
+    #[error("header checksum not match (expected {}, found {})", Hex(.expected), Hex(.found))]
+    HeaderChecksumNotMatch {
+        expected: Box<Checksum>,
+        found: Box<Checksum>,
+    },
+
+    #[error("no unused signature placeholder in the header")]
+    SignaturePlaceholderMissing,
+
+    #[error("signature of {len} bytes does not fit the {capacity} byte placeholder")]
+    SignatureTooLarge { len: usize, capacity: usize },
+
+    #[error("the scrub state was made for another file header")]
+    ScrubStateMismatch,
+
+    #[error("invalid header delta")]
+    InvalidHeaderDelta,
+
+    #[error("invalid sidecar envelope")]
+    InvalidSidecar,
+
+    #[error("unknown sidecar kind: {0}")]
+    UnknownSidecarKind(u16),
+
+    #[error("expected a {expected:?} sidecar, found {found:?}")]
+    SidecarKindMismatch {
+        expected: SidecarKind,
+        found: SidecarKind,
+    },
+
+    #[error("unsupported {kind:?} sidecar version {version}, the newest supported is {newest}")]
+    UnsupportedSidecarVersion {
+        kind: SidecarKind,
+        version: u16,
+        newest: u16,
+    },
+
+    #[error("invalid encode checkpoint")]
+    InvalidCheckpoint,
+
+    #[error("the checkpoint was made with other encoder options")]
+    CheckpointMismatch,
 
     #[error("header not found")]
     HeaderNotFound,
@@ -37,10 +305,289 @@ pub enum ZchunkError {
     #[error("chunk not found, index: {0}")]
     ChunkNotFound(usize),
 
-    #[error("chunk checksum not match (len {len} expected {expected:?}, found {found:?})")]
+    #[error("range not found, index: {0}")]
+    RangeNotFound(usize),
+
+    #[error("range {range_id} should have {expected} bytes, found {found}")]
+    RangeSizeMismatch {
+        range_id: usize,
+        expected: u64,
+        found: u64,
+    },
+
+    #[error("range {range_id} does not fit the reorder buffer ({buffered} of {max} bytes used)")]
+    ReorderBufferFull {
+        range_id: usize,
+        buffered: u64,
+        max: u64,
+    },
+
+    #[error("range {next_needed} and later ones were never submitted")]
+    AssemblyIncomplete { next_needed: usize },
+
+    #[error("chunk is not available in the partial file, index: {id}")]
+    ChunkUnavailable { id: usize },
+
+    #[error("dict chunk is not available in the partial file")]
+    DictUnavailable,
+
+    #[error("failed to fetch chunk {id} after {attempts} attempts: {last_error}")]
+    ChunkFetchFailed {
+        id: ChunkId,
+        attempts: u32,
+        #[source]
+        last_error: Box<ZchunkError>,
+    },
+
+    #[error(
+        "{} checksum not match (len {len} expected {}, found {})",
+        ChunkName(.id),
+        Hex(.expected),
+        Hex(.found)
+    )]
     ChunkChecksumNotMatch {
+        /// `None` for the dict chunk, or when the caller did not name the chunk
+        id: Option<ChunkId>,
         len: usize,
-        expected: [u8; 16],
-        found: [u8; 16],
+        expected: Box<Checksum>,
+        found: Box<Checksum>,
+    },
+
+    /// The decompressed data does not match the SHA-256 recorded with
+    /// `EncoderOptions::uncompressed_checksum`
+    #[error(
+        "uncompressed data checksum not match (expected {}, found {})",
+        Hex(.expected),
+        Hex(.found)
+    )]
+    UncompressedChecksumNotMatch {
+        expected: Box<Checksum>,
+        found: Box<Checksum>,
+    },
+
+    #[error(
+        "{} decompresses to more than its uncompressed length {uncompressed_length}",
+        ChunkName(.id)
+    )]
+    ChunkTooLarge {
+        /// `None` for the dict chunk
+        id: Option<ChunkId>,
+        uncompressed_length: u64,
     },
+
+    /// A chunk of the file written with `EncoderOptions::verify_output` does not verify
+    #[error("written {} failed verification: {reason:?}", ChunkName(.id))]
+    OutputVerificationFailed {
+        /// `None` for the dict chunk
+        id: Option<ChunkId>,
+        reason: Box<FailureReason>,
+    },
+}
+
+impl ZchunkError {
+    /// Name the path an io error happened on
+    #[cfg(any(test, feature = "zstd"))]
+    pub(crate) fn path_io(path: &Path, source: io::Error) -> Self {
+        Self::PathIo {
+            path: path.into(),
+            source,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, path::Path};
+
+    use super::{WriteStage, ZchunkError};
+    use crate::{Checksum, ChecksumType, FailureReason, KnownFormat, SidecarKind};
+
+    /// Log records get truncated past this length
+    const MAX_LEN: usize = 512;
+
+    fn worst_cases() -> Vec<ZchunkError> {
+        let io = || io::Error::from(io::ErrorKind::UnexpectedEof);
+        let mismatch = || ZchunkError::ChunkChecksumNotMatch {
+            id: Some(usize::MAX),
+            len: usize::MAX,
+            expected: Box::new(Checksum::from_bytes(&[0xff; 64]).unwrap()),
+            found: Box::new(Checksum::from_bytes(&[0xff; 64]).unwrap()),
+        };
+        vec![
+            ZchunkError::Io(io()),
+            ZchunkError::path_io(Path::new(&"ä".repeat(4096)), io()),
+            ZchunkError::TryFromSlice(<[u8; 16]>::try_from(&[0u8; 3][..]).unwrap_err()),
+            ZchunkError::InvalidLeaderID([0xff; 5]),
+            ZchunkError::NotAZchunkFile {
+                detected: Some(KnownFormat::Bzip2),
+            },
+            ZchunkError::InvalidChecksumType(u8::MAX),
+            ZchunkError::UnsupportedChecksumType(ChecksumType::Sha512_128),
+            ZchunkError::InvalidCompresionType(u8::MAX),
+            ZchunkError::InvalidHeaderMagic {
+                expected: u32::MAX,
+                found: u32::MAX,
+            },
+            ZchunkError::InvalidHeaderSize {
+                expected: u64::MAX,
+                found: u64::MAX,
+            },
+            ZchunkError::HeaderDataOverlap {
+                offset: u64::MAX,
+                header_end: u64::MAX,
+            },
+            ZchunkError::InvalidIndexSize {
+                expected: u64::MAX,
+                found: u64::MAX,
+            },
+            ZchunkError::SizeNotMatch {
+                expected: u64::MAX,
+                found: u64::MAX,
+            },
+            ZchunkError::InvalidDictChunk {
+                length: u64::MAX,
+                uncompressed_length: u64::MAX,
+            },
+            ZchunkError::InconsistentChunkStreams,
+            ZchunkError::SizeOverflow,
+            ZchunkError::Overflow {
+                what: "checksum type",
+                value: u64::MAX,
+            },
+            ZchunkError::PlatformLimit {
+                what: "uncompressed chunk length",
+                value: u64::MAX,
+            },
+            ZchunkError::InvalidOption {
+                name: "compression_level",
+                value: i64::MIN,
+                reason: "x".repeat(64),
+            },
+            ZchunkError::InvalidZstdParameter {
+                parameter: "long_distance_matching",
+                value: u32::MAX,
+                source: io(),
+            },
+            ZchunkError::InvalidChunkerParams {
+                min: usize::MAX,
+                max: usize::MAX,
+                bitmask: u32::MAX,
+            },
+            ZchunkError::InvalidChunkKey,
+            ZchunkError::InvalidChunkBloom,
+            ZchunkError::WriteFailed {
+                stage: WriteStage::Chunk(usize::MAX),
+                bytes_written: u64::MAX,
+                source: io(),
+            },
+            ZchunkError::DictWithoutCompression,
+            ZchunkError::TooManyChunkAnnotations {
+                chunks: usize::MAX,
+                annotations: usize::MAX,
+            },
+            ZchunkError::TooManyChunks { max: usize::MAX },
+            ZchunkError::ReadFailed {
+                bytes_consumed: u64::MAX,
+                chunks_completed: usize::MAX,
+                source: io(),
+            },
+            ZchunkError::AlreadyPrepared,
+            ZchunkError::SinkFailed,
+            ZchunkError::NothingToResume,
+            ZchunkError::Cancelled,
+            ZchunkError::ChecksumCountMismatch {
+                expected: usize::MAX,
+                found: usize::MAX,
+            },
+            ZchunkError::SyncedFileMismatch,
+            ZchunkError::HeaderChecksumNotMatch {
+                expected: Box::new(Checksum::from_bytes(&[0xff; 64]).unwrap()),
+                found: Box::new(Checksum::from_bytes(&[0xff; 64]).unwrap()),
+            },
+            ZchunkError::ChunkTooLarge {
+                id: Some(usize::MAX),
+                uncompressed_length: u64::MAX,
+            },
+            ZchunkError::OutputVerificationFailed {
+                id: Some(usize::MAX),
+                reason: Box::new(FailureReason::ChecksumMismatch {
+                    expected: Checksum::from_bytes(&[0xff; 64]).unwrap(),
+                    found: Checksum::from_bytes(&[0xff; 64]).unwrap(),
+                }),
+            },
+            ZchunkError::UncompressedChecksumNotMatch {
+                expected: Box::new(Checksum::from_bytes(&[0xff; 32]).unwrap()),
+                found: Box::new(Checksum::from_bytes(&[0xff; 32]).unwrap()),
+            },
+            ZchunkError::InvalidChecksumLength(usize::MAX),
+            ZchunkError::SignaturePlaceholderMissing,
+            ZchunkError::SignatureTooLarge {
+                len: usize::MAX,
+                capacity: usize::MAX,
+            },
+            ZchunkError::ScrubStateMismatch,
+            ZchunkError::InvalidHeaderDelta,
+            ZchunkError::InvalidSidecar,
+            ZchunkError::UnknownSidecarKind(u16::MAX),
+            ZchunkError::SidecarKindMismatch {
+                expected: SidecarKind::HeaderDelta,
+                found: SidecarKind::ChunkBloom,
+            },
+            ZchunkError::UnsupportedSidecarVersion {
+                kind: SidecarKind::HeaderDelta,
+                version: u16::MAX,
+                newest: u16::MAX,
+            },
+            ZchunkError::InvalidCheckpoint,
+            ZchunkError::CheckpointMismatch,
+            ZchunkError::HeaderNotFound,
+            ZchunkError::ChunkNotFound(usize::MAX),
+            ZchunkError::RangeNotFound(usize::MAX),
+            ZchunkError::RangeSizeMismatch {
+                range_id: usize::MAX,
+                expected: u64::MAX,
+                found: u64::MAX,
+            },
+            ZchunkError::ReorderBufferFull {
+                range_id: usize::MAX,
+                buffered: u64::MAX,
+                max: u64::MAX,
+            },
+            ZchunkError::AssemblyIncomplete {
+                next_needed: usize::MAX,
+            },
+            ZchunkError::ChunkUnavailable { id: usize::MAX },
+            ZchunkError::DictUnavailable,
+            ZchunkError::ChunkFetchFailed {
+                id: usize::MAX,
+                attempts: u32::MAX,
+                last_error: Box::new(mismatch()),
+            },
+            mismatch(),
+        ]
+    }
+
+    #[test]
+    fn test_error_format_length() {
+        for err in worst_cases() {
+            let display = err.to_string();
+            let debug = format!("{err:?}");
+            assert!(display.len() <= MAX_LEN, "{display}");
+            assert!(debug.len() <= MAX_LEN, "{debug}");
+        }
+
+        assert_eq!(
+            ZchunkError::InvalidLeaderID(*b"\0ZCK2").to_string(),
+            "invalid leader id: 005a434b32 (\".ZCK2\")"
+        );
+
+        // the path is cut at a char boundary, and kept whole for the caller
+        let long = format!("x{}", "ä".repeat(200));
+        let err = ZchunkError::path_io(Path::new(&long), io::ErrorKind::NotFound.into());
+        let ZchunkError::PathIo { path, .. } = err else {
+            unreachable!()
+        };
+        assert_eq!(path.to_string(), format!("...{}", "ä".repeat(100)));
+        assert_eq!(path.path(), Path::new(&long));
+    }
 }
@@ -1,42 +1,277 @@
+//! The zchunk file layout, and the encoder and decoder working on it
+//!
+//! The structures making up a header are hidden from the docs, their shape follows the file
+//! format and may change with it. Use the `Header` accessors instead.
+
 use std::{
+    borrow::BorrowMut,
     collections::HashMap,
-    io::{self, BufRead, Cursor, Read, Seek, SeekFrom, Write},
+    fmt,
+    hash::{Hash, Hasher},
+    io::{self, BufRead, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    sync::OnceLock,
 };
+#[cfg(feature = "zstd")]
+use std::{collections::VecDeque, io::Cursor, sync::Arc};
 
-use sha2::{Digest, Sha256, Sha512};
+use sha2::{Digest, Sha256};
 
+#[cfg(feature = "zstd")]
+use crate::{
+    annotation::chunk_annotations_element,
+    chain::ChainedReader,
+    checkpoint::Checkpoint,
+    checksum::MultiHasher,
+    chunker::{Chunker, ChunkerParams},
+    compression::Compression,
+    manifest::{write_chunk_line, write_trailer},
+    options::{EncoderOptions, RestoreOptions, VerificationLevel},
+    report::{ChunkSizeSummary, DictEffectiveness, EncodeReport, EncodeStats},
+    temp::{MemoryTemp, TempStore, TempWriter},
+    uncompressed_checksum::{uncompressed_checksum_element, UNCOMPRESSED_CHECKSUM_ELEMENT_ID},
+    verify::VerifyOptions,
+};
 use crate::{
-    chunker::Chunker,
-    errors::ZchunkError,
+    availability::ChunkAvailability,
+    bloom::ChunkBloom,
+    checksum::{
+        compute_checksum, Checksum, ChecksumType, ChunkHasher, CHECKSUM_SHA1, CHECKSUM_SHA256,
+        CHECKSUM_SHA512, CHECKSUM_SHA512_128, DEFAULT_CHECKSUM_TYPE,
+    },
+    chunk_key::ChunkKey,
+    compression::CUSTOM_COMPRESSION_TYPES,
+    errors::{WriteStage, ZchunkError},
+    hex::{Hex, HexPrefix},
+    options::DecodeOptions,
+    report::{ChunkStats, RatioPercentiles, SyncStats},
+    sniff::{read_magic, KnownFormat, MAGIC_LEN},
     types::{ReadVariantInt, VariantInt},
 };
 
-const ZCHUNK_VERSION_1: &[u8] = b"\0ZCK1";
-const ZCHUNK_DETACHED_VERSION_1: &[u8] = b"\0ZHR1";
+pub(crate) const ZCHUNK_VERSION_1: &[u8] = b"\0ZCK1";
+pub(crate) const ZCHUNK_DETACHED_VERSION_1: &[u8] = b"\0ZHR1";
+
+pub(crate) const COMPRESSION_NONE: u8 = 0;
+pub(crate) const COMPRESSION_ZSTD: u8 = 2;
+
+/// The header checksum types this build computes, SHA-512 needs the `sha512` feature
+pub(crate) const SUPPORTED_HEADER_CHECKSUM_TYPES: &[ChecksumType] = &[
+    ChecksumType::Sha256,
+    #[cfg(feature = "sha512")]
+    ChecksumType::Sha512,
+];
+
+pub(crate) const PREFACE_FLAG_STREAM: u64 = 0x01;
+pub(crate) const PREFACE_FLAG_OPTIONAL: u64 = 0x02;
+/// The preface flags this crate understands, chunks are always stored compressed
+pub(crate) const SUPPORTED_PREFACE_FLAGS: u64 = PREFACE_FLAG_STREAM | PREFACE_FLAG_OPTIONAL;
+
+/// Compression types supported by this crate
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CompressionType {
+    None,
+    Zstd,
+    /// A non-standard type from `CUSTOM_COMPRESSION_TYPES`, whose `Compression` backend is
+    /// registered with a `CompressionRegistry`
+    Custom(u8),
+}
+
+impl CompressionType {
+    /// The type id stored in the file
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Self::None => COMPRESSION_NONE,
+            Self::Zstd => COMPRESSION_ZSTD,
+            Self::Custom(t) => t,
+        }
+    }
+
+    /// Load the compression type from the type id stored in the file
+    pub fn from_u8(t: u8) -> Result<Self, ZchunkError> {
+        match t {
+            COMPRESSION_NONE => Ok(Self::None),
+            COMPRESSION_ZSTD => Ok(Self::Zstd),
+            t if CUSTOM_COMPRESSION_TYPES.contains(&t) => Ok(Self::Custom(t)),
+            t => Err(ZchunkError::InvalidCompresionType(t)),
+        }
+    }
+}
 
-const CHECKSUM_SHA1: u8 = 0;
-const CHECKSUM_SHA256: u8 = 1;
-const CHECKSUM_SHA512: u8 = 2;
-const CHECKSUM_SHA512_128: u8 = 3; //first 128 bits of SHA-512 checksum
+/// Every Nth data chunk is also compressed without the dict to estimate the dict effectiveness
+#[cfg(feature = "zstd")]
+const DICT_SAMPLE_INTERVAL: usize = 8;
 
-const COMPRESSION_NONE: u8 = 0;
-const COMPRESSION_ZSTD: u8 = 2;
+/// The zstd level chunks are compressed at unless `EncoderOptions::compression_level` is set
+#[cfg(feature = "zstd")]
+pub(crate) const DEFAULT_COMPRESSION_LEVEL: i32 = 3;
 
-#[derive(Debug)]
+/// The window logs zstd accepts, `ZSTD_WINDOWLOG_MIN` to `ZSTD_WINDOWLOG_MAX`
+#[cfg(feature = "zstd")]
+const ZSTD_WINDOW_LOGS: std::ops::RangeInclusive<u32> = if cfg!(target_pointer_width = "32") {
+    10..=30
+} else {
+    10..=31
+};
+
+/// The most zstd workers, `ZSTDMT_NBWORKERS_MAX`
+#[cfg(feature = "zstdmt")]
+const ZSTD_MAX_WORKERS: u32 = if cfg!(target_pointer_width = "32") {
+    64
+} else {
+    256
+};
+
+/// Refuse a level the linked zstd does not support, before any work is done
+#[cfg(feature = "zstd")]
+pub(crate) fn check_compression_level(level: i32) -> Result<(), ZchunkError> {
+    let range = zstd::compression_level_range();
+    if !range.contains(&level) {
+        return Err(ZchunkError::InvalidOption {
+            name: "compression_level",
+            value: level.into(),
+            reason: format!("zstd supports {} to {}", range.start(), range.end()),
+        });
+    }
+    Ok(())
+}
+
+/// The zstd settings chunks are compressed with
+#[cfg(feature = "zstd")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ZstdParams {
+    pub(crate) level: i32,
+    pub(crate) window_log: Option<u32>,
+    pub(crate) long_distance_matching: bool,
+    pub(crate) workers: Option<u32>,
+    /// Set the frame parameters to their defaults explicitly, see
+    /// `EncoderOptions::deterministic`
+    pub(crate) pinned: bool,
+}
+
+#[cfg(feature = "zstd")]
+impl ZstdParams {
+    /// Compress at `level`, everything else at the zstd defaults
+    pub(crate) fn with_level(level: i32) -> Self {
+        Self {
+            level,
+            window_log: None,
+            long_distance_matching: false,
+            workers: None,
+            pinned: false,
+        }
+    }
+
+    /// Refuse settings the linked zstd does not support, before any work is done
+    ///
+    /// The known bounds fail with `InvalidOption`, whatever zstd still refuses when the
+    /// settings are applied to a compressor with `InvalidZstdParameter`.
+    pub(crate) fn check(&self) -> Result<(), ZchunkError> {
+        check_compression_level(self.level)?;
+        if let Some(window_log) = self.window_log {
+            if !ZSTD_WINDOW_LOGS.contains(&window_log) {
+                return Err(ZchunkError::InvalidOption {
+                    name: "window_log",
+                    value: window_log.into(),
+                    reason: format!(
+                        "zstd supports {} to {}",
+                        ZSTD_WINDOW_LOGS.start(),
+                        ZSTD_WINDOW_LOGS.end()
+                    ),
+                });
+            }
+        }
+        match self.workers {
+            #[cfg(feature = "zstdmt")]
+            Some(workers) if workers > ZSTD_MAX_WORKERS => {
+                return Err(ZchunkError::InvalidOption {
+                    name: "zstd_workers",
+                    value: workers.into(),
+                    reason: format!("zstd supports at most {ZSTD_MAX_WORKERS}"),
+                });
+            }
+            #[cfg(not(feature = "zstdmt"))]
+            Some(workers) if workers > 0 => {
+                return Err(ZchunkError::InvalidOption {
+                    name: "zstd_workers",
+                    value: workers.into(),
+                    reason: "workers need the zstdmt feature".to_string(),
+                });
+            }
+            _ => {}
+        }
+        self.apply(&mut zstd::bulk::Compressor::new(self.level)?)
+    }
+
+    fn apply(&self, compressor: &mut zstd::bulk::Compressor<'_>) -> Result<(), ZchunkError> {
+        let refused = |parameter, value| {
+            move |source| ZchunkError::InvalidZstdParameter {
+                parameter,
+                value,
+                source,
+            }
+        };
+        if let Some(window_log) = self.window_log {
+            compressor
+                .window_log(window_log)
+                .map_err(refused("window_log", window_log))?;
+        }
+        if self.long_distance_matching {
+            compressor
+                .long_distance_matching(true)
+                .map_err(refused("long_distance_matching", 1))?;
+        }
+        if let Some(workers) = self.workers {
+            compressor
+                .set_parameter(zstd::stream::raw::CParameter::NbWorkers(workers))
+                .map_err(refused("zstd_workers", workers))?;
+        }
+        if self.pinned {
+            // a frame checksum would duplicate the chunk checksum, the dict id tells
+            // decoders which dict a chunk needs
+            compressor.include_checksum(false)?;
+            compressor.include_dictid(true)?;
+        }
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
 pub struct Lead {
     id: [u8; 5],
     checksum_type: VariantInt,
     header_size: VariantInt,
-    header_checksum: [u8; 32],
+    pub(crate) header_checksum: Checksum,
+}
+
+impl fmt::Debug for Lead {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Lead")
+            .field("id", &String::from_utf8_lossy(&self.id))
+            .field("checksum_type", &self.checksum_type)
+            .field("header_size", &self.header_size)
+            .field("header_checksum", &Hex(&self.header_checksum))
+            .finish()
+    }
 }
 
 impl Lead {
+    /// A lead with a SHA-256 header checksum
     pub fn new(header_size: usize) -> Result<Self, ZchunkError> {
+        Self::with_checksum_type(header_size, ChecksumType::Sha256)
+    }
+
+    /// A lead with a header checksum of `checksum_type`, SHA-256 or SHA-512
+    pub fn with_checksum_type(
+        header_size: usize,
+        checksum_type: ChecksumType,
+    ) -> Result<Self, ZchunkError> {
+        check_header_checksum_type(checksum_type)?;
         Ok(Self {
             id: ZCHUNK_VERSION_1.try_into()?,
-            checksum_type: (CHECKSUM_SHA256 as u64).into(),
+            checksum_type: (checksum_type.to_u8() as u64).into(),
             header_size: (header_size as u64).into(),
-            header_checksum: [0; 32],
+            header_checksum: Checksum::zeroed(checksum_type.digest_size()),
         })
     }
 
@@ -55,7 +290,12 @@ impl Lead {
         Ok(())
     }
 
-    pub fn set_header_checksum(&mut self, header_checksum: [u8; 32]) {
+    /// The checksum type of the header checksum
+    pub(crate) fn checksum_type(&self) -> Result<ChecksumType, ZchunkError> {
+        ChecksumType::from_u8(checked_cast(self.checksum_type.to_u64()?, "checksum type")?)
+    }
+
+    pub fn set_header_checksum(&mut self, header_checksum: Checksum) {
         self.header_checksum = header_checksum;
     }
 
@@ -67,22 +307,26 @@ impl Lead {
     }
 
     pub fn from_reader(mut reader: impl Read) -> Result<Self, ZchunkError> {
-        let mut id = [0; 5];
-        reader.read_exact(&mut id)?;
-
+        let (id, len) = read_magic(&mut reader)?;
         if id != ZCHUNK_VERSION_1 && id != ZCHUNK_DETACHED_VERSION_1 {
-            return Err(ZchunkError::InvalidLeaderID(id.clone()));
+            let detected = KnownFormat::detect(&id[..len]);
+            if detected.is_some() || len < MAGIC_LEN {
+                return Err(ZchunkError::NotAZchunkFile { detected });
+            }
+            return Err(ZchunkError::InvalidLeaderID(id));
         }
 
         let checksum_type = reader.read_variant_int()?;
-        match checksum_type.to_u64()? as u8 {
-            CHECKSUM_SHA1 | CHECKSUM_SHA256 => {}
+        let digest_size = match checked_cast(checksum_type.to_u64()?, "checksum type")? {
+            t @ (CHECKSUM_SHA1 | CHECKSUM_SHA256 | CHECKSUM_SHA512) => {
+                ChecksumType::from_u8(t)?.digest_size()
+            }
             t => return Err(ZchunkError::InvalidChecksumType(t)),
-        }
+        };
 
         let header_size = reader.read_variant_int()?;
 
-        let mut header_checksum = [0; 32];
+        let mut header_checksum = Checksum::zeroed(digest_size);
         reader.read_exact(&mut header_checksum)?;
 
         Ok(Lead {
@@ -95,6 +339,7 @@ impl Lead {
 }
 
 #[derive(Debug, Clone)]
+#[doc(hidden)]
 pub struct PrefaceFlags {
     vint: VariantInt,
     uint: u64,
@@ -121,12 +366,27 @@ impl PrefaceFlags {
         self.vint.byte_size()
     }
 
-    fn has_stream(&self) -> bool {
-        self.uint & 0x01 != 0
+    pub(crate) fn bits(&self) -> u64 {
+        self.uint
+    }
+
+    pub(crate) fn has_stream(&self) -> bool {
+        self.uint & PREFACE_FLAG_STREAM != 0
+    }
+
+    pub(crate) fn has_optional(&self) -> bool {
+        self.uint & PREFACE_FLAG_OPTIONAL != 0
+    }
+
+    /// The same flags with the stream flag set
+    #[cfg(feature = "zstd")]
+    pub(crate) fn with_stream(&self) -> Self {
+        Self::from_u64(self.uint | PREFACE_FLAG_STREAM)
     }
 
-    fn has_optional(&self) -> bool {
-        self.uint & 0x02 != 0
+    /// The same flags with the optional elements flag cleared
+    pub(crate) fn without_optional(&self) -> Self {
+        Self::from_u64(self.uint & !PREFACE_FLAG_OPTIONAL)
     }
 
     // fn has_uncompressed(&self) -> bool {
@@ -134,22 +394,98 @@ impl PrefaceFlags {
     // }
 }
 
-#[derive(Debug)]
+/// An optional element of the preface, identified by `id`
+///
+/// Readers skip elements whose id they do not know, see `EncoderOptions::optional_element`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct OptionalElement {
+    pub id: u64,
+    pub data: Vec<u8>,
+}
+
+impl fmt::Debug for OptionalElement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OptionalElement")
+            .field("id", &self.id)
+            .field("data", &HexPrefix(&self.data))
+            .finish()
+    }
+}
+
+impl OptionalElement {
+    pub fn new(id: u64, data: Vec<u8>) -> Self {
+        Self { id, data }
+    }
+
+    fn write_to(&self, mut writer: impl Write) -> Result<(), std::io::Error> {
+        VariantInt::from(self.id).write_to(&mut writer)?;
+        VariantInt::from(self.data.len() as u64).write_to(&mut writer)?;
+        writer.write_all(&self.data)
+    }
+
+    fn byte_size(&self) -> usize {
+        VariantInt::from(self.id).byte_size()
+            + VariantInt::from(self.data.len() as u64).byte_size()
+            + self.data.len()
+    }
+
+    fn from_reader(mut reader: impl Read) -> Result<Self, ZchunkError> {
+        let id = reader.read_variant_int()?.to_u64()?;
+        let size = reader.read_variant_int()?.to_u64()?;
+        let mut data = Vec::new();
+        reader.by_ref().take(size).read_to_end(&mut data)?;
+        if data.len() as u64 != size {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+
+        Ok(Self { id, data })
+    }
+}
+
+#[doc(hidden)]
 pub struct Preface {
-    data_checksum: [u8; 32],
-    flags: PrefaceFlags,
-    compression_type: VariantInt,
-    optional_element_count: Option<VariantInt>,
+    pub(crate) data_checksum: [u8; 32],
+    pub(crate) flags: PrefaceFlags,
+    pub(crate) compression_type: VariantInt,
+    pub(crate) optional_elements: Vec<OptionalElement>,
+}
+
+impl fmt::Debug for Preface {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Preface")
+            .field("data_checksum", &Hex(&self.data_checksum))
+            .field("flags", &self.flags)
+            .field("compression_type", &self.compression_type)
+            .field("optional_elements", &self.optional_elements)
+            .finish()
+    }
 }
 
 impl Preface {
     pub fn new(data_checksum: [u8; 32]) -> Self {
         Self {
-            data_checksum: data_checksum,
+            data_checksum,
             flags: PrefaceFlags::from_u64(0),
             compression_type: (COMPRESSION_ZSTD as u64).into(),
-            optional_element_count: None,
+            optional_elements: Vec::new(),
+        }
+    }
+
+    /// Append an optional element, setting the optional elements flag
+    #[cfg(feature = "zstd")]
+    pub(crate) fn push_optional_element(&mut self, element: OptionalElement) {
+        if !self.flags.has_optional() {
+            self.flags = PrefaceFlags::from_u64(self.flags.uint | PREFACE_FLAG_OPTIONAL);
         }
+        self.optional_elements.push(element);
+    }
+
+    /// The payload of the first optional element with `id`
+    pub(crate) fn optional_element(&self, id: u64) -> Option<&[u8]> {
+        self.optional_elements
+            .iter()
+            .find(|e| e.id == id)
+            .map(|e| e.data.as_slice())
     }
 
     pub fn write_to(&self, mut writer: impl Write) -> Result<(), std::io::Error> {
@@ -157,8 +493,11 @@ impl Preface {
         self.flags.write_to(&mut writer)?;
         self.compression_type.write_to(&mut writer)?;
 
-        if let Some(count) = &self.optional_element_count {
-            count.write_to(writer)?;
+        if self.flags.has_optional() {
+            VariantInt::from(self.optional_elements.len() as u64).write_to(&mut writer)?;
+            for element in &self.optional_elements {
+                element.write_to(&mut writer)?;
+            }
         }
 
         Ok(())
@@ -167,8 +506,13 @@ impl Preface {
     pub fn byte_size(&self) -> usize {
         let mut n =
             self.data_checksum.len() + self.flags.byte_size() + self.compression_type.byte_size();
-        if let Some(count) = &self.optional_element_count {
-            n += count.byte_size();
+        if self.flags.has_optional() {
+            n += VariantInt::from(self.optional_elements.len() as u64).byte_size();
+            n += self
+                .optional_elements
+                .iter()
+                .map(|e| e.byte_size())
+                .sum::<usize>();
         }
         n
     }
@@ -180,43 +524,178 @@ impl Preface {
         let flags = PrefaceFlags::from_variant_int(reader.read_variant_int()?)?;
         let compression_type = reader.read_variant_int()?;
 
-        let compression_type_u8 = compression_type.to_u64()? as u8;
-        if compression_type_u8 != COMPRESSION_NONE && compression_type_u8 != COMPRESSION_ZSTD {
-            return Err(ZchunkError::InvalidCompresionType(compression_type_u8));
-        }
+        CompressionType::from_u8(checked_cast(
+            compression_type.to_u64()?,
+            "compression type",
+        )?)?;
 
-        let optional_element_count = if flags.has_optional() {
-            Some(reader.read_variant_int()?)
-        } else {
-            None
-        };
+        let mut optional_elements = Vec::new();
+        if flags.has_optional() {
+            let count = reader.read_variant_int()?.to_u64()?;
+            for _ in 0..count {
+                optional_elements.push(OptionalElement::from_reader(&mut reader)?);
+            }
+        }
 
         Ok(Preface {
             data_checksum,
             flags,
             compression_type,
-            optional_element_count,
+            optional_elements,
         })
     }
 }
 
-type ChunkOffset = u32;
-// type ChunkIndex = usize;
+/// Offset of a chunk from the end of the header
+type ChunkOffset = u64;
+
+/// Fail for header checksum types other than SHA-256 and SHA-512, or compiled out ones
+pub(crate) fn check_header_checksum_type(checksum_type: ChecksumType) -> Result<(), ZchunkError> {
+    if !SUPPORTED_HEADER_CHECKSUM_TYPES.contains(&checksum_type) {
+        checksum_type.check_enabled()?;
+        return Err(ZchunkError::InvalidChecksumType(checksum_type.to_u8()));
+    }
+    Ok(())
+}
+
+/// Add two sizes read from a header, which may be anywhere up to `u64::MAX`
+pub(crate) fn checked_add(a: u64, b: u64) -> Result<u64, ZchunkError> {
+    a.checked_add(b).ok_or(ZchunkError::SizeOverflow)
+}
+
+/// Upper bound of the buffer preallocated for a chunk from its lengths in the index, which a
+/// crafted file can set to anything
+const MAX_PREALLOCATED_CHUNK: u64 = 16 << 20;
+
+/// The largest offset a file can be read or written at, the range of a 64-bit `off_t`
+pub const MAX_FILE_OFFSET: u64 = i64::MAX as u64;
+
+/// Convert a number read from a header to a narrower type, such as a checksum type to `u8`,
+/// failing with `Overflow` instead of truncating
+pub(crate) fn checked_cast<T: TryFrom<u64>>(
+    value: u64,
+    what: &'static str,
+) -> Result<T, ZchunkError> {
+    T::try_from(value).map_err(|_| ZchunkError::Overflow { what, value })
+}
+
+/// Convert a size or count read from a header to `usize`, which has 32 bits on some targets
+pub(crate) fn to_usize(value: u64, what: &'static str) -> Result<usize, ZchunkError> {
+    usize::try_from(value).map_err(|_| ZchunkError::PlatformLimit { what, value })
+}
+
+/// A seek to an offset read from a header, which may lie past what the platform can seek to
+pub(crate) fn seek_start(offset: u64) -> Result<SeekFrom, ZchunkError> {
+    if offset > MAX_FILE_OFFSET {
+        return Err(ZchunkError::PlatformLimit {
+            what: "file offset",
+            value: offset,
+        });
+    }
+    Ok(SeekFrom::Start(offset))
+}
+
+/// Position of a data chunk in the index, the dict chunk is not counted
+pub type ChunkId = usize;
 
 #[derive(Debug)]
+#[doc(hidden)]
 pub struct Index {
     size: VariantInt,
-    checksum_type: VariantInt,
+    pub(crate) checksum_type: VariantInt,
     chunks_count: VariantInt,
-    dict_chunk: Chunk,
-    data_chunks: Vec<(Chunk, ChunkOffset)>,
+    pub(crate) dict_chunk: Chunk,
+    pub(crate) data_chunks: Vec<(Chunk, ChunkOffset)>,
+}
+
+/// Builds an `Index` chunk by chunk, with the checksum type of the index
+///
+/// The dict chunk is present when its compressed length is non-zero, see `Index::has_dict`,
+/// and `build` refuses a dict entry where exactly one of the lengths is zero.
+#[derive(Debug, Clone)]
+#[doc(hidden)]
+pub struct IndexBuilder {
+    checksum_type: ChecksumType,
+    dict_chunk: Option<Chunk>,
+    chunks: Vec<Chunk>,
+}
+
+impl Default for IndexBuilder {
+    fn default() -> Self {
+        Self {
+            checksum_type: DEFAULT_CHECKSUM_TYPE,
+            dict_chunk: None,
+            chunks: Vec::new(),
+        }
+    }
+}
+
+impl IndexBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the checksum type of the chunk checksums, which must all have its width
+    pub fn checksum_type(mut self, checksum_type: ChecksumType) -> Self {
+        self.checksum_type = checksum_type;
+        self
+    }
+
+    /// Set the dict chunk, a zero-length dict entry is written without one
+    pub fn dict_chunk(mut self, dict_chunk: Chunk) -> Self {
+        self.dict_chunk = Some(dict_chunk);
+        self
+    }
+
+    /// Append a data chunk
+    pub fn chunk(mut self, chunk: Chunk) -> Self {
+        self.chunks.push(chunk);
+        self
+    }
+
+    /// Append data chunks
+    pub fn chunks(mut self, chunks: impl IntoIterator<Item = Chunk>) -> Self {
+        self.chunks.extend(chunks);
+        self
+    }
+
+    pub fn build(self) -> Result<Index, ZchunkError> {
+        Index::with_checksum_type(self.checksum_type, self.dict_chunk, self.chunks)
+    }
 }
 
 impl Index {
-    pub fn new(chunks: Vec<Chunk>) -> Result<Self, ZchunkError> {
-        let dict_chunk = Chunk::new([0; 16], 0, 0);
+    /// Construct an index from the dict chunk and data chunks, see `IndexBuilder`
+    ///
+    /// A zero-length dict entry is written when `dict_chunk` is `None`.
+    pub fn new(dict_chunk: Option<Chunk>, chunks: Vec<Chunk>) -> Result<Self, ZchunkError> {
+        Self::with_checksum_type(DEFAULT_CHECKSUM_TYPE, dict_chunk, chunks)
+    }
+
+    pub(crate) fn with_checksum_type(
+        checksum_type: ChecksumType,
+        dict_chunk: Option<Chunk>,
+        chunks: Vec<Chunk>,
+    ) -> Result<Self, ZchunkError> {
+        let checksum_size = checksum_type.digest_size();
+        let dict_chunk =
+            dict_chunk.unwrap_or_else(|| Chunk::new(Checksum::zeroed(checksum_size), 0, 0));
+        check_dict_chunk(&dict_chunk)?;
+        if chunks
+            .iter()
+            .any(|c| c.stream.is_some() != dict_chunk.stream.is_some())
+        {
+            return Err(ZchunkError::InconsistentChunkStreams);
+        }
+        // the index stores every checksum at the full width of its type
+        if let Some(c) = std::iter::once(&dict_chunk)
+            .chain(&chunks)
+            .find(|c| c.checksum.len() != checksum_size)
+        {
+            return Err(ZchunkError::InvalidChecksumLength(c.checksum.len()));
+        }
 
-        let checksum_type = VariantInt::from(CHECKSUM_SHA512_128 as u64);
+        let checksum_type = VariantInt::from(checksum_type.to_u8() as u64);
         let chunks_count = VariantInt::from(chunks.len() as u64 + 1);
         let size = checksum_type.byte_size()
             + chunks_count.byte_size()
@@ -224,14 +703,14 @@ impl Index {
             + chunks.iter().map(|c| c.byte_size()).sum::<usize>();
 
         // first data chunk offset is the end of dict chunk
-        let mut chunk_offset = dict_chunk.length.to_u64()? as u32;
+        let mut chunk_offset = dict_chunk.length.to_u64()?;
 
         // compute offset for each data chunk
         let mut data_chunks = Vec::new();
         for c in chunks {
-            let length = c.length.to_u64()? as u32;
+            let length = c.length.to_u64()?;
             data_chunks.push((c, chunk_offset));
-            chunk_offset += length;
+            chunk_offset = checked_add(chunk_offset, length)?;
         }
 
         Ok(Self {
@@ -243,11 +722,13 @@ impl Index {
         })
     }
 
+    /// Whether the index has a dict, which is decided by a non-zero compressed length
+    pub fn has_dict(&self) -> bool {
+        self.dict_chunk.length != VariantInt::from(0)
+    }
+
     pub fn write_to(&self, mut writer: impl Write) -> Result<(), std::io::Error> {
-        self.size.write_to(&mut writer)?;
-        self.checksum_type.write_to(&mut writer)?;
-        self.chunks_count.write_to(&mut writer)?;
-        self.dict_chunk.write_to(&mut writer)?;
+        self.write_head_to(&mut writer)?;
         for (chunk, _) in &self.data_chunks {
             chunk.write_to(&mut writer)?;
         }
@@ -255,6 +736,14 @@ impl Index {
         Ok(())
     }
 
+    /// Write the index up to the data chunk entries
+    pub(crate) fn write_head_to(&self, mut writer: impl Write) -> Result<(), std::io::Error> {
+        self.size.write_to(&mut writer)?;
+        self.checksum_type.write_to(&mut writer)?;
+        self.chunks_count.write_to(&mut writer)?;
+        self.dict_chunk.write_to(&mut writer)
+    }
+
     pub fn byte_size(&self) -> usize {
         self.checksum_type.byte_size()
             + self.chunks_count.byte_size()
@@ -272,7 +761,7 @@ impl Index {
         let checksum_type = reader.read_variant_int()?;
 
         // check checksum type
-        let checksum_type_u8 = checksum_type.to_u64()? as u8;
+        let checksum_type_u8 = checked_cast(checksum_type.to_u64()?, "checksum type")?;
         if ![
             CHECKSUM_SHA1,
             CHECKSUM_SHA256,
@@ -283,18 +772,30 @@ impl Index {
         {
             return Err(ZchunkError::InvalidChecksumType(checksum_type_u8));
         }
+        let checksum_size = ChecksumType::from_u8(checksum_type_u8)?
+            .check_enabled()?
+            .digest_size();
 
         let chunks_count = reader.read_variant_int()?;
 
-        let dict_chunk = Chunk::from_reader(&mut reader, flags.clone())?;
+        let dict_chunk = Chunk::from_reader(&mut reader, flags.clone(), checksum_size)?;
+        check_dict_chunk(&dict_chunk)?;
 
-        let mut chunk_offset = dict_chunk.length.to_u64()? as u32;
+        // the count includes the dict chunk
+        let data_chunks_count = chunks_count
+            .to_u64()?
+            .checked_sub(1)
+            .ok_or(ZchunkError::SizeOverflow)?;
+        // every data chunk gets a `ChunkId`
+        to_usize(data_chunks_count, "chunk count")?;
+
+        let mut chunk_offset = dict_chunk.length.to_u64()?;
         let mut data_chunks = Vec::new();
-        for _ in 0..(chunks_count.to_u64()? - 1) {
-            let chunk = Chunk::from_reader(&mut reader, flags.clone())?;
-            let length = chunk.length.to_u64()? as u32;
+        for _ in 0..data_chunks_count {
+            let chunk = Chunk::from_reader(&mut reader, flags.clone(), checksum_size)?;
+            let length = chunk.length.to_u64()?;
             data_chunks.push((chunk, chunk_offset));
-            chunk_offset += length;
+            chunk_offset = checked_add(chunk_offset, length)?;
         }
 
         // check index size
@@ -323,21 +824,57 @@ impl Index {
     }
 }
 
-#[derive(Debug, Clone, Hash)]
+/// The dict entry is either empty in both lengths or has both lengths set
+fn check_dict_chunk(dict_chunk: &Chunk) -> Result<(), ZchunkError> {
+    let length = dict_chunk.length.to_u64()?;
+    let uncompressed_length = dict_chunk.uncompressed_length.to_u64()?;
+    if (length == 0) != (uncompressed_length == 0) {
+        return Err(ZchunkError::InvalidDictChunk {
+            length,
+            uncompressed_length,
+        });
+    }
+
+    Ok(())
+}
+
+/// A data chunk of the index in plain numbers, see `Header::index_entries`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct IndexEntry {
+    pub checksum: Vec<u8>,
+    /// Offset from the start of the data region, which begins with the dict chunk
+    pub offset: u64,
+    pub length: u64,
+    pub uncompressed_length: u64,
+}
+
+#[derive(Clone)]
 pub struct Chunk {
-    stream: Option<VariantInt>, // if flag 0 is set to 1
-    checksum: [u8; 16],
-    length: VariantInt,
-    uncompressed_length: VariantInt,
+    pub(crate) stream: Option<VariantInt>, // if flag 0 is set to 1
+    pub(crate) checksum: Checksum,
+    pub(crate) length: VariantInt,
+    pub(crate) uncompressed_length: VariantInt,
+}
+
+impl fmt::Debug for Chunk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Chunk")
+            .field("stream", &self.stream)
+            .field("checksum", &Hex(&self.checksum))
+            .field("length", &self.length)
+            .field("uncompressed_length", &self.uncompressed_length)
+            .finish()
+    }
 }
 
 impl Chunk {
-    pub fn new(checksum: [u8; 16], length: u32, uncompressed_length: u32) -> Self {
+    pub fn new(checksum: Checksum, length: u64, uncompressed_length: u64) -> Self {
         Self {
             stream: None,
             checksum,
-            length: (length as u64).into(),
-            uncompressed_length: (uncompressed_length as u64).into(),
+            length: length.into(),
+            uncompressed_length: uncompressed_length.into(),
         }
     }
 
@@ -363,14 +900,38 @@ impl Chunk {
         n
     }
 
-    pub fn from_reader(mut reader: impl Read, flags: PrefaceFlags) -> Result<Self, ZchunkError> {
+    /// The stream of the chunk, `None` unless the preface stream flag is set
+    pub fn stream(&self) -> Option<u64> {
+        self.stream.as_ref().and_then(|s| s.to_u64().ok())
+    }
+
+    /// Compression ratio, the uncompressed length over the compressed length
+    ///
+    /// An empty chunk has the ratio 1.0, a chunk with uncompressed data but no compressed
+    /// bytes has an infinite ratio. Lengths too large for `u64` count as `u64::MAX`.
+    pub fn ratio(&self) -> f64 {
+        let length = self.length.to_u64().unwrap_or(u64::MAX);
+        let uncompressed_length = self.uncompressed_length.to_u64().unwrap_or(u64::MAX);
+        match (length, uncompressed_length) {
+            (0, 0) => 1.0,
+            (0, _) => f64::INFINITY,
+            (l, u) => u as f64 / l as f64,
+        }
+    }
+
+    /// Read a chunk from an index whose checksums are `checksum_size` bytes long
+    pub fn from_reader(
+        mut reader: impl Read,
+        flags: PrefaceFlags,
+        checksum_size: usize,
+    ) -> Result<Self, ZchunkError> {
         let stream = if flags.has_stream() {
             Some(reader.read_variant_int()?)
         } else {
             None
         };
 
-        let mut checksum = [0; 16];
+        let mut checksum = Checksum::zeroed(checksum_size);
         reader.read_exact(&mut checksum)?;
 
         let length = reader.read_variant_int()?;
@@ -395,10 +956,19 @@ impl PartialEq for Chunk {
 
 impl Eq for Chunk {}
 
+impl Hash for Chunk {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.checksum.hash(state);
+        self.length.hash(state);
+        self.uncompressed_length.hash(state);
+    }
+}
+
 #[derive(Debug)]
+#[doc(hidden)]
 pub struct Signatures {
-    count: VariantInt,
-    signatures: Vec<Signature>,
+    pub(crate) count: VariantInt,
+    pub(crate) signatures: Vec<Signature>,
 }
 
 impl Signatures {
@@ -435,11 +1005,21 @@ impl Signatures {
     }
 }
 
-#[derive(Debug)]
+#[doc(hidden)]
 pub struct Signature {
-    type_: VariantInt,
-    size: VariantInt,
-    signature: Vec<u8>,
+    pub(crate) type_: VariantInt,
+    pub(crate) size: VariantInt,
+    pub(crate) signature: Vec<u8>,
+}
+
+impl fmt::Debug for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Signature")
+            .field("type", &self.type_)
+            .field("size", &self.size)
+            .field("bytes", &HexPrefix(&self.signature))
+            .finish()
+    }
 }
 
 impl Signature {
@@ -451,6 +1031,21 @@ impl Signature {
     //     }
     // }
 
+    /// A signature of `size` zero bytes, reserving room for `sign_in_place`
+    #[cfg(feature = "zstd")]
+    pub(crate) fn placeholder(type_: u64, size: usize) -> Self {
+        Self {
+            type_: type_.into(),
+            size: (size as u64).into(),
+            signature: vec![0; size],
+        }
+    }
+
+    /// Whether the signature is an unused placeholder
+    pub(crate) fn is_placeholder(&self) -> bool {
+        !self.signature.is_empty() && self.signature.iter().all(|&b| b == 0)
+    }
+
     pub fn write_to(&self, mut writer: impl Write) -> Result<(), std::io::Error> {
         self.type_.write_to(&mut writer)?;
         self.size.write_to(&mut writer)?;
@@ -467,8 +1062,13 @@ impl Signature {
         let type_ = reader.read_variant_int()?;
         let size = reader.read_variant_int()?;
 
-        let mut signature = vec![0; size.to_u64()? as usize];
-        reader.read_exact(&mut signature)?;
+        // a bogus size fails when the header ends, instead of allocating it up front
+        let length = to_usize(size.to_u64()?, "signature size")?;
+        let mut signature = Vec::new();
+        reader.take(length as u64).read_to_end(&mut signature)?;
+        if signature.len() != length {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
 
         Ok(Signature {
             type_,
@@ -478,15 +1078,26 @@ impl Signature {
     }
 }
 
-#[derive(Debug)]
-struct DataChunk(Vec<u8>);
-
-#[derive(Debug)]
 pub struct Header {
-    lead: Lead,
-    preface: Preface,
-    index: Index,
-    signatures: Signatures,
+    pub(crate) lead: Lead,
+    pub(crate) preface: Preface,
+    pub(crate) index: Index,
+    pub(crate) signatures: Signatures,
+    pub(crate) sorted_chunk_keys: OnceLock<Vec<ChunkKey>>,
+    pub(crate) chunk_lookup: OnceLock<HashMap<Checksum, (ChunkId, u64)>>,
+    pub(crate) chunk_annotations: OnceLock<Option<Vec<u64>>>,
+}
+
+/// The lazily built lookup tables are left out, they only repeat the index
+impl fmt::Debug for Header {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Header")
+            .field("lead", &self.lead)
+            .field("preface", &self.preface)
+            .field("index", &self.index)
+            .field("signatures", &self.signatures)
+            .finish_non_exhaustive()
+    }
 }
 
 impl Header {
@@ -496,11 +1107,14 @@ impl Header {
             preface,
             index,
             signatures,
+            sorted_chunk_keys: OnceLock::new(),
+            chunk_lookup: OnceLock::new(),
+            chunk_annotations: OnceLock::new(),
         }
     }
 
     pub fn write_to(
-        &mut self,
+        &self,
         mut writer: impl Write,
         ignore_checksum: bool,
     ) -> Result<(), std::io::Error> {
@@ -512,342 +1126,5462 @@ impl Header {
         Ok(())
     }
 
-    /// compute header checksum, ignoring the header checksum field
-    pub fn compute_and_set_checksum(&mut self) -> Result<(), ZchunkError> {
-        let mut writer: Vec<u8> = Vec::with_capacity(self.lead.header_size.to_u64()? as usize);
-        self.write_to(&mut writer, true)?;
-
-        let mut hasher = Sha256::new();
-        hasher.update(&writer);
-        let result = hasher.finalize();
+    /// The bytes the signatures of the header sign: the lead without the header checksum,
+    /// the preface and the index
+    ///
+    /// The header checksum covers the signatures, so it is left out of the signed bytes.
+    pub fn signature_input(&self) -> Result<Vec<u8>, ZchunkError> {
+        let mut bytes =
+            Vec::with_capacity(to_usize(self.lead.header_size.to_u64()?, "header size")?);
+        self.lead.write_to(&mut bytes, true)?;
+        self.preface.write_to(&mut bytes)?;
+        self.index.write_to(&mut bytes)?;
+        Ok(bytes)
+    }
 
-        self.lead.set_header_checksum(result[..].try_into()?);
+    /// The optional elements of the preface, in file order
+    pub fn optional_elements(&self) -> &[OptionalElement] {
+        &self.preface.optional_elements
+    }
 
+    /// Write the header as a detached header, the same bytes behind the `\0ZHR1` magic
+    ///
+    /// As upstream does, the header checksum is computed over the detached magic, so the
+    /// detached header checks on its own. The preface, index and signatures are unchanged,
+    /// and the data region of the file verifies against them.
+    pub fn write_detached_to(&self, mut writer: impl Write) -> Result<(), ZchunkError> {
+        let mut bytes = self.unchecked_bytes()?;
+        bytes[..ZCHUNK_DETACHED_VERSION_1.len()].copy_from_slice(ZCHUNK_DETACHED_VERSION_1);
+        let checksum = compute_checksum(self.lead.checksum_type()?, &bytes)?;
+        let checksum_start = self.lead.byte_size() - self.lead.header_checksum.len();
+        writer.write_all(&bytes[..checksum_start])?;
+        writer.write_all(&checksum)?;
+        writer.write_all(&bytes[checksum_start..])?;
         Ok(())
     }
 
-    /// check if dict chunk is equal
-    pub fn has_dict_chunk(&self, chunk: &Chunk) -> bool {
-        self.index.dict_chunk == *chunk
+    /// The compression type of the chunks, checked when parsing the preface
+    pub fn compression_type(&self) -> Result<CompressionType, ZchunkError> {
+        CompressionType::from_u8(checked_cast(
+            self.preface.compression_type.to_u64()?,
+            "compression type",
+        )?)
     }
 
-    /// get chunk offset by data chunk
-    pub fn find_data_chunks(&self, chunks: Vec<Chunk>) -> HashMap<Chunk, ChunkOffset> {
-        self.index
-            .data_chunks
-            .clone()
-            .into_iter()
-            .filter(|(c, _)| chunks.contains(&c))
-            .collect()
-    }
-}
+    /// compute header checksum, ignoring the header checksum field
+    pub fn compute_and_set_checksum(&mut self) -> Result<(), ZchunkError> {
+        let checksum = self.computed_checksum()?;
+        self.lead.set_header_checksum(checksum);
 
-/// An encoder that compress input data from `Read` and write compressed data to `Write`
-///
-/// Require a temp `Read + Write + Seek` that store compressed chunks data, since building header is after the chunks data is generated
-pub struct Encoder<RW, R> {
-    header: Option<Header>,
-    temp: RW,
-    reader: R,
-}
+        Ok(())
+    }
 
-impl<RW: Read + Write + Seek, R: Read> Encoder<RW, R> {
-    /// Construct an encoder from a raw file reader and a temp reader&writer
-    pub fn new(reader: R, temp: RW) -> Result<Self, ZchunkError> {
-        Ok(Self {
-            header: None,
-            temp,
-            reader,
-        })
+    /// The header checksum computed from the header content, ignoring the header checksum field
+    ///
+    /// The field is left out of the hashed bytes, not zeroed, as upstream does: the digest
+    /// covers the lead up to the field, then the preface, index and signatures.
+    pub(crate) fn computed_checksum(&self) -> Result<Checksum, ZchunkError> {
+        let checksum_type = self.lead.checksum_type()?;
+        check_header_checksum_type(checksum_type)?;
+        compute_checksum(checksum_type, &self.unchecked_bytes()?)
     }
 
-    /// Split data of reader to chunks, and use zstd to compress chunks, write to temp writer [without header]
-    pub fn prepare_chunks(&mut self) -> Result<(), ZchunkError> {
-        let chunker = Chunker::default(&mut self.reader);
-        let mut chunks = Vec::new();
-        let mut total_hasher = Sha256::new();
-        for c in chunker {
-            let uncompressed_chunk_data = c?;
-            let compressed_chunk_data = zstd::encode_all(uncompressed_chunk_data.as_slice(), 3)?;
-
-            // compute chunk checksum
-            let mut hasher = Sha512::new();
-            hasher.update(&compressed_chunk_data);
-            let result = hasher.finalize();
-
-            // compute checksum of all chunks
-            total_hasher.update(&compressed_chunk_data);
-
-            // write compressed data to temp writer
-            self.temp.write_all(&compressed_chunk_data)?;
-
-            // compose chunk metadata
-            let chunk = Chunk::new(
-                result[..16].try_into()?,
-                compressed_chunk_data.len() as u32,
-                uncompressed_chunk_data.len() as u32,
-            );
-            // print!("{} ", uncompressed_chunk_data.len());
-            chunks.push(chunk);
+    /// Fail with `HeaderChecksumNotMatch` when the header checksum does not match the header
+    pub(crate) fn check_checksum(&self) -> Result<(), ZchunkError> {
+        let found = self.computed_checksum()?;
+        if found != self.lead.header_checksum {
+            return Err(ZchunkError::HeaderChecksumNotMatch {
+                expected: Box::new(self.lead.header_checksum),
+                found: Box::new(found),
+            });
         }
-
-        let data_checksum = total_hasher.finalize();
-
-        let signatures = Signatures::new(Vec::new());
-        let index = Index::new(chunks)?;
-        let preface = Preface::new(data_checksum[..].try_into()?);
-        let header_size = signatures.byte_size() + index.byte_size() + preface.byte_size();
-        let lead = Lead::new(header_size)?;
-
-        let mut header = Header::new(lead, preface, index, signatures);
-        header.compute_and_set_checksum()?;
-
-        self.header = Some(header);
-
         Ok(())
     }
 
-    /// Write header and chunks to `Write`, which require `prepare_chunks`
-    pub fn compress_to(&mut self, mut writer: impl Write) -> Result<(), ZchunkError> {
-        let header = self.header.as_mut().ok_or(ZchunkError::HeaderNotFound)?;
-        header.write_to(&mut writer, false)?;
+    /// SHA-256 of the header content, whatever the header checksum type
+    ///
+    /// Artifacts of this crate identify a header by it, so their layout does not depend on
+    /// the header checksum type.
+    pub(crate) fn content_digest(&self) -> Result<[u8; 32], ZchunkError> {
+        Ok(Sha256::digest(self.unchecked_bytes()?).into())
+    }
 
-        self.temp.seek(SeekFrom::Start(0))?;
-        io::copy(&mut self.temp, &mut writer)?;
+    /// The header as written to the file, without the header checksum field
+    fn unchecked_bytes(&self) -> Result<Vec<u8>, ZchunkError> {
+        let mut bytes =
+            Vec::with_capacity(to_usize(self.lead.header_size.to_u64()?, "header size")?);
+        self.write_to(&mut bytes, true)?;
+        Ok(bytes)
+    }
 
+    /// Find data chunks by checksum, answering in input order
+    ///
+    /// Each answer is the chunk id and its offset relative to the end of header, or `None`
+    /// when the checksum is not in the index. Duplicate checksums resolve to the lowest id.
+    pub fn lookup(
+        &self,
+        checksums: impl IntoIterator<Item = Checksum>,
+    ) -> Vec<Option<(ChunkId, u64)>> {
+        checksums
+            .into_iter()
+            .map(|checksum| self.lookup_one(&checksum))
+            .collect()
+    }
+
+    /// `lookup` that answers checksums `bloom` rules out without a lookup, the lookup table is
+    /// only built once a checksum gets past the filter
+    ///
+    /// `bloom` must be built from this header, as published next to it, checksums the
+    /// filter does not know are answered `None`.
+    pub fn lookup_filtered(
+        &self,
+        checksums: impl IntoIterator<Item = Checksum>,
+        bloom: Option<&ChunkBloom>,
+    ) -> Vec<Option<(ChunkId, u64)>> {
+        checksums
+            .into_iter()
+            .map(|checksum| match bloom {
+                Some(bloom) if !bloom.maybe_contains(&checksum) => None,
+                _ => self.lookup_one(&checksum),
+            })
+            .collect()
+    }
+
+    /// Find a data chunk by checksum, see `lookup`
+    pub fn lookup_one(&self, checksum: &[u8]) -> Option<(ChunkId, u64)> {
+        let map = self.chunk_lookup.get_or_init(|| {
+            let mut map = HashMap::with_capacity(self.index.data_chunks.len());
+            for (id, (chunk, offset)) in self.index.data_chunks.iter().enumerate() {
+                map.entry(chunk.checksum).or_insert((id, *offset));
+            }
+            map
+        });
+        map.get(checksum).copied()
+    }
+
+    /// Absolute file offset where the data region starts, right after the header
+    pub(crate) fn data_offset(&self) -> Result<u64, ZchunkError> {
+        checked_add(
+            self.lead.byte_size() as u64,
+            self.lead.header_size.to_u64()?,
+        )
+    }
+
+    /// The header checksum stored in the lead
+    pub fn header_checksum(&self) -> &[u8] {
+        &self.lead.header_checksum
+    }
+
+    /// The SHA-256 of the data region stored in the preface
+    pub fn data_checksum(&self) -> &[u8; 32] {
+        &self.preface.data_checksum
+    }
+
+    /// The size of the whole header, including the lead
+    pub fn header_size(&self) -> Result<u64, ZchunkError> {
+        self.data_offset()
+    }
+
+    /// The data chunks of the index in order, the dict chunk is left out
+    pub fn index_entries(&self) -> Result<Vec<IndexEntry>, ZchunkError> {
+        self.index
+            .data_chunks
+            .iter()
+            .map(|(chunk, offset)| {
+                Ok(IndexEntry {
+                    checksum: chunk.checksum.as_bytes().to_vec(),
+                    offset: *offset,
+                    length: chunk.length.to_u64()?,
+                    uncompressed_length: chunk.uncompressed_length.to_u64()?,
+                })
+            })
+            .collect()
+    }
+
+    /// Size of the data region, the compressed dict chunk and data chunks
+    pub fn data_size(&self) -> Result<u64, ZchunkError> {
+        match self.index.data_chunks.last() {
+            Some((chunk, offset)) => checked_add(*offset, chunk.length.to_u64()?),
+            None => Ok(self.index.dict_chunk.length.to_u64()?),
+        }
+    }
+
+    /// Size of the complete file described by the header
+    pub fn file_size(&self) -> Result<u64, ZchunkError> {
+        checked_add(self.data_offset()?, self.data_size()?)
+    }
+
+    /// The checksum type of the chunk checksums in the index
+    pub(crate) fn checksum_type(&self) -> Result<ChecksumType, ZchunkError> {
+        ChecksumType::from_u8(checked_cast(
+            self.index.checksum_type.to_u64()?,
+            "checksum type",
+        )?)
+    }
+
+    /// Verify the compressed data of a data chunk, e.g. received out of band, against its
+    /// checksum in the index
+    pub fn verify_chunk_bytes(&self, id: ChunkId, bytes: &[u8]) -> Result<(), ZchunkError> {
+        let (chunk, _) = self
+            .index
+            .data_chunks
+            .get(id)
+            .ok_or(ZchunkError::ChunkNotFound(id))?;
+        self.check_chunk_data(Some(id), chunk, bytes)
+    }
+
+    /// Verify chunk data against the checksum of `chunk`, which is the data chunk `id` or
+    /// the dict chunk
+    pub(crate) fn check_chunk_data(
+        &self,
+        id: Option<ChunkId>,
+        chunk: &Chunk,
+        bytes: &[u8],
+    ) -> Result<(), ZchunkError> {
+        let mut hasher = ChunkHasher::new(self.checksum_type()?)?;
+        hasher.update(bytes);
+        hasher.verify(id, &chunk.checksum, bytes.len())
+    }
+
+    /// Absolute byte range of a data chunk in the file
+    pub fn chunk_range(&self, id: ChunkId) -> Result<Range<u64>, ZchunkError> {
+        let (chunk, offset) = self
+            .index
+            .data_chunks
+            .get(id)
+            .ok_or(ZchunkError::ChunkNotFound(id))?;
+        let start = checked_add(self.data_offset()?, *offset)?;
+        Ok(start..checked_add(start, chunk.length.to_u64()?)?)
+    }
+
+    /// Split the data chunks into those with a ratio of at least `threshold` and the rest,
+    /// both in index order, see `Chunk::ratio`
+    pub fn partition_by_ratio(&self, threshold: f64) -> (Vec<ChunkId>, Vec<ChunkId>) {
+        (0..self.index.data_chunks.len())
+            .partition(|&id| self.index.data_chunks[id].0.ratio() >= threshold)
+    }
+
+    /// Size and compression ratio statistics of the data chunks, from the index alone
+    pub fn chunk_stats(&self) -> ChunkStats {
+        let chunks = &self.index.data_chunks;
+        let total = |length: fn(&Chunk) -> &VariantInt| {
+            chunks.iter().fold(0u64, |sum, (c, _)| {
+                sum.saturating_add(length(c).to_u64().unwrap_or(u64::MAX))
+            })
+        };
+
+        let mut ratios: Vec<f64> = chunks.iter().map(|(c, _)| c.ratio()).collect();
+        ratios.sort_by(f64::total_cmp);
+        // nearest rank
+        let percentile = |p: usize| ratios[(ratios.len() * p).div_ceil(100).max(1) - 1];
+        let ratio_percentiles = (!ratios.is_empty()).then(|| RatioPercentiles {
+            min: ratios[0],
+            p10: percentile(10),
+            p50: percentile(50),
+            p90: percentile(90),
+            max: ratios[ratios.len() - 1],
+        });
+
+        ChunkStats {
+            chunks: chunks.len(),
+            compressed_bytes: total(|c| &c.length),
+            uncompressed_bytes: total(|c| &c.uncompressed_length),
+            ratio_percentiles,
+        }
+    }
+
+    /// check if dict chunk is equal
+    pub fn has_dict_chunk(&self, chunk: &Chunk) -> bool {
+        self.index.dict_chunk == *chunk
+    }
+
+    /// get chunk offset by data chunk, in index order
+    #[deprecated(note = "use `Header::lookup` or `Header::lookup_one` instead")]
+    pub fn find_data_chunks(&self, chunks: Vec<Chunk>) -> Vec<(Chunk, ChunkOffset)> {
+        self.index
+            .data_chunks
+            .iter()
+            .filter(|(c, _)| chunks.contains(c))
+            .cloned()
+            .collect()
+    }
+}
+
+/// A writer adapter counting the bytes accepted by the inner writer
+pub(crate) struct CountingWriter<W> {
+    inner: W,
+    written: u64,
+}
+
+impl<W: Write> CountingWriter<W> {
+    pub(crate) fn new(inner: W) -> Self {
+        Self { inner, written: 0 }
+    }
+
+    #[cfg(feature = "zstd")]
+    pub(crate) fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// Wrap an output error with the stage and the bytes written so far
+    pub(crate) fn fail(&self, stage: WriteStage, source: io::Error) -> ZchunkError {
+        ZchunkError::WriteFailed {
+            stage,
+            bytes_written: self.written,
+            source,
+        }
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A reader that feeds everything read through it to a chunk hasher, if any
+#[cfg(feature = "zstd")]
+struct HashingReader<R> {
+    inner: R,
+    hasher: Option<ChunkHasher>,
+}
+
+#[cfg(feature = "zstd")]
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(&buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+/// A writer that holds back the last `max_held` bytes written to it, the caller writes them
+/// to `inner` once they may be released
+#[cfg(feature = "zstd")]
+struct HeldBackWriter<W> {
+    inner: W,
+    held: VecDeque<u8>,
+    max_held: usize,
+}
+
+#[cfg(feature = "zstd")]
+impl<W: Write> HeldBackWriter<W> {
+    fn new(inner: W, max_held: usize) -> Self {
+        Self {
+            inner,
+            held: VecDeque::new(),
+            max_held,
+        }
+    }
+
+    /// Write the oldest `n` held back bytes to `inner`
+    fn release(&mut self, n: usize) -> io::Result<()> {
+        let (front, back) = self.held.as_slices();
+        let from_front = n.min(front.len());
+        self.inner.write_all(&front[..from_front])?;
+        self.inner.write_all(&back[..n - from_front])?;
+        self.held.drain(..n);
+        Ok(())
+    }
+
+    /// Write everything still held back to `inner` and return it
+    fn finish(mut self) -> io::Result<W> {
+        self.release(self.held.len())?;
+        Ok(self.inner)
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl<W: Write> Write for HeldBackWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let total = self.held.len() + buf.len();
+        if total > self.max_held {
+            // the oldest bytes are held, then those at the start of `buf`
+            let released = total - self.max_held;
+            let from_held = released.min(self.held.len());
+            self.release(from_held)?;
+            let from_buf = released - from_held;
+            self.inner.write_all(&buf[..from_buf])?;
+            self.held.extend(&buf[from_buf..]);
+        } else {
+            self.held.extend(buf);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Compresses chunks one after another with one zstd context, which loads the dict once
+#[cfg(feature = "zstd")]
+pub(crate) struct ChunkCompressor {
+    compressor: zstd::bulk::Compressor<'static>,
+    /// The parameters and dict to create a context per chunk with, see
+    /// `EncoderOptions::fresh_zstd_context`
+    fresh: Option<(ZstdParams, Option<Vec<u8>>)>,
+}
+
+#[cfg(feature = "zstd")]
+impl ChunkCompressor {
+    pub(crate) fn new(params: &ZstdParams, dict: Option<&[u8]>) -> Result<Self, ZchunkError> {
+        let mut compressor =
+            zstd::bulk::Compressor::with_dictionary(params.level, dict.unwrap_or_default())?;
+        params.apply(&mut compressor)?;
+        compressor.include_contentsize(true)?;
+        Ok(Self {
+            compressor,
+            fresh: None,
+        })
+    }
+
+    /// A compressor for `options`, which creates a context per chunk when
+    /// `EncoderOptions::fresh_zstd_context` is set
+    pub(crate) fn for_options(
+        options: &EncoderOptions,
+        dict: Option<&[u8]>,
+    ) -> Result<Self, ZchunkError> {
+        let mut compressor = Self::new(&options.zstd_params(), dict)?;
+        if options.fresh_zstd_context {
+            compressor.fresh = Some((options.zstd_params(), dict.map(<[u8]>::to_vec)));
+        }
+        Ok(compressor)
+    }
+
+    /// Compress a chunk into a single zstd frame
+    ///
+    /// The whole chunk is handed to zstd at once, which pledges its size: the parameters
+    /// are picked for that size and the frame header records it as the content size.
+    pub(crate) fn compress(&mut self, data: &[u8]) -> Result<Vec<u8>, ZchunkError> {
+        let mut out = Vec::new();
+        self.compress_into(data, &mut out)?;
+        Ok(out)
+    }
+
+    /// `compress` into `out`, which is cleared first and grows to the compress bound of the
+    /// chunk, so a buffer reused for every chunk stops allocating after the largest one
+    pub(crate) fn compress_into(
+        &mut self,
+        data: &[u8],
+        out: &mut Vec<u8>,
+    ) -> Result<(), ZchunkError> {
+        if let Some((params, dict)) = &self.fresh {
+            self.compressor = Self::new(params, dict.as_deref())?.compressor;
+        }
+        out.clear();
+        out.reserve(zstd::zstd_safe::compress_bound(data.len()));
+        self.compressor.compress_to_buffer(data, out)?;
+        Ok(())
+    }
+}
+
+/// Compress a single chunk, see `ChunkCompressor::compress`
+#[cfg(feature = "zstd")]
+pub(crate) fn compress_chunk(
+    data: &[u8],
+    params: &ZstdParams,
+    dict: Option<&[u8]>,
+) -> Result<Vec<u8>, ZchunkError> {
+    ChunkCompressor::new(params, dict)?.compress(data)
+}
+
+/// Write a compressed chunk to the temp, computing the chunk checksum and feeding the
+/// checksum of all chunks in the same pass
+#[cfg(feature = "zstd")]
+fn store_chunk(
+    temp: &mut impl Write,
+    checksum_type: ChecksumType,
+    data: &[u8],
+    uncompressed_length: usize,
+    total_hasher: &mut Sha256,
+) -> Result<Chunk, ZchunkError> {
+    let mut hasher = ChunkHasher::new(checksum_type)?;
+    MultiHasher::new(temp, [&mut hasher, total_hasher]).write_all(data)?;
+
+    Ok(Chunk::new(
+        hasher.finalize(),
+        data.len() as u64,
+        uncompressed_length as u64,
+    ))
+}
+
+/// The checkpoint of a prepare that read `bytes_consumed` bytes of input, of which `pending`
+/// is not chunked yet
+#[cfg(feature = "zstd")]
+fn checkpoint_of(
+    options: &EncoderOptions,
+    data_start: u64,
+    state: &PrepareState,
+    bytes_consumed: u64,
+    pending: &[u8],
+) -> Result<Checkpoint, ZchunkError> {
+    if state.uncompressed_hasher.is_some() {
+        return Err(ZchunkError::InvalidOption {
+            name: "uncompressed_checksum",
+            value: 1,
+            reason: "an encode with it cannot be checkpointed".into(),
+        });
+    }
+    Ok(Checkpoint {
+        options_digest: options.stored_chunks_digest(),
+        data_start,
+        bytes_consumed,
+        stored_end: state.stored_end,
+        effectiveness: state.effectiveness.clone(),
+        pending: pending.to_vec(),
+        dict_chunk: state.dict_chunk.clone(),
+        chunks: state.chunks.clone(),
+    })
+}
+
+#[cfg(feature = "zstd")]
+#[cfg(feature = "bytes")]
+impl<RW: TempStore> Encoder<RW, io::Empty> {
+    /// Construct an encoder from data that is already split into chunks, each `Bytes`
+    /// becomes one data chunk as is and the chunker is not used
+    ///
+    /// The buffers are collected up front, which clones their handles but not their data,
+    /// and every chunk is compressed straight from its buffer.
+    pub fn from_chunks_bytes(
+        chunks: impl Iterator<Item = bytes::Bytes>,
+        temp: RW,
+        options: EncoderOptions,
+    ) -> Result<Self, ZchunkError> {
+        let mut encoder = Self::with_options(io::empty(), temp, options)?;
+        encoder.chunks_bytes = Some(chunks.collect::<Vec<_>>().into_iter());
+        Ok(encoder)
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl<R: Read> Encoder<Cursor<Vec<u8>>, R> {
+    /// Construct an encoder that keeps the compressed chunks in memory instead of a temp file
+    ///
+    /// The buffer grows to the size of the compressed data, which suits small inputs.
+    pub fn new_in_memory(reader: R) -> Result<Self, ZchunkError> {
+        Self::in_memory_with_options(reader, EncoderOptions::default())
+    }
+
+    /// `new_in_memory` with options
+    pub fn in_memory_with_options(reader: R, options: EncoderOptions) -> Result<Self, ZchunkError> {
+        Self::with_options(reader, Cursor::new(Vec::new()), options)
+    }
+}
+
+/// Progress of `prepare_chunks`, kept when the input fails so the work can be resumed
+#[cfg(feature = "zstd")]
+pub(crate) struct PrepareState {
+    total_hasher: Sha256,
+    dict_chunk: Option<Chunk>,
+    effectiveness: Option<DictEffectiveness>,
+    chunks: Vec<Chunk>,
+    /// input read but not chunked yet
+    pending: Vec<u8>,
+    bytes_consumed: u64,
+    /// end of the chunks stored in the temp
+    stored_end: u64,
+    /// `None` when the chunks are stored uncompressed
+    compressor: Option<ChunkCompressor>,
+    /// The compressed chunk, reused from one chunk to the next
+    buffer: Vec<u8>,
+    /// The digest of the uncompressed input, see `EncoderOptions::uncompressed_checksum`
+    uncompressed_hasher: Option<Sha256>,
+}
+
+/// Compress a data chunk and append it to the temp, updating the prepare state
+#[cfg(feature = "zstd")]
+fn store_data_chunk(
+    temp: &mut impl Write,
+    options: &EncoderOptions,
+    state: &mut PrepareState,
+    uncompressed_chunk_data: &[u8],
+) -> Result<(), ZchunkError> {
+    options.check_cancelled()?;
+    let id = state.chunks.len();
+    if let Some(hasher) = &mut state.uncompressed_hasher {
+        hasher.update(uncompressed_chunk_data);
+    }
+    let mut compressed_chunk_data = std::mem::take(&mut state.buffer);
+    match (&mut state.compressor, options.chunk_compression_type()) {
+        (Some(compressor), _) => {
+            compressor.compress_into(uncompressed_chunk_data, &mut compressed_chunk_data)?
+        }
+        (None, CompressionType::None) => {
+            compressed_chunk_data.clear();
+            compressed_chunk_data.extend_from_slice(uncompressed_chunk_data);
+        }
+        (None, t) => {
+            compressed_chunk_data = options
+                .compression_registry
+                .backend(t)?
+                .compress(uncompressed_chunk_data, options.dict.as_deref())?
+        }
+    }
+
+    // sample what the chunk would compress to without the dict
+    if let Some(e) = state.effectiveness.as_mut() {
+        e.data_with_dict += compressed_chunk_data.len() as u64;
+        if id.is_multiple_of(DICT_SAMPLE_INTERVAL) {
+            e.sampled_chunks += 1;
+            e.sampled_with_dict += compressed_chunk_data.len() as u64;
+            e.sampled_without_dict +=
+                compress_chunk(uncompressed_chunk_data, &options.zstd_params(), None)?.len() as u64;
+        }
+    }
+
+    if let Some(transform) = &options.transform {
+        compressed_chunk_data = transform.encode(id, &compressed_chunk_data);
+    }
+    let stream = options
+        .chunk_stream
+        .as_ref()
+        .map(|stream| stream(id, uncompressed_chunk_data));
+    let result = store_compressed_chunk(
+        temp,
+        options,
+        state,
+        &compressed_chunk_data,
+        uncompressed_chunk_data.len(),
+        stream,
+    );
+    state.buffer = compressed_chunk_data;
+    result
+}
+
+/// Append a data chunk that is compressed and transformed already to the temp, updating
+/// the prepare state
+#[cfg(feature = "zstd")]
+fn store_compressed_chunk(
+    temp: &mut impl Write,
+    options: &EncoderOptions,
+    state: &mut PrepareState,
+    compressed_chunk_data: &[u8],
+    uncompressed_length: usize,
+    stream: Option<u64>,
+) -> Result<(), ZchunkError> {
+    let id = state.chunks.len();
+    if let Some(max) = options.max_chunks.filter(|&max| id >= max) {
+        return Err(ZchunkError::TooManyChunks { max });
+    }
+    let mut chunk = store_chunk(
+        temp,
+        options.chunk_checksum_type(),
+        compressed_chunk_data,
+        uncompressed_length,
+        &mut state.total_hasher,
+    )?;
+    chunk.stream = stream.map(Into::into);
+
+    let offset = state.stored_end;
+    state.stored_end = checked_add(offset, chunk.length.to_u64()?)?;
+    if !options.chunks_may_change() {
+        if let Some(mut writer) = options.lock_manifest_writer() {
+            write_chunk_line(&mut *writer, id, offset, &chunk)?;
+        }
+    }
+    state.chunks.push(chunk);
+
+    Ok(())
+}
+
+/// An encoder that compress input data from `Read` and write compressed data to `Write`
+///
+/// Require a temp that store compressed chunks data, since building header is after the chunks data is generated, see `TempStore`
+#[cfg(feature = "zstd")]
+pub struct Encoder<RW, R> {
+    header: Option<Header>,
+    temp: RW,
+    /// Where the chunks start in temp, past the room for the header when temp is the output
+    data_start: u64,
+    reader: R,
+    options: EncoderOptions,
+    report: Option<EncodeReport>,
+    prepare_started: bool,
+    interrupted: Option<PrepareState>,
+    /// Uncompressed data chunks read ahead to train a dict, see `EncoderOptions::auto_dict`
+    trained_chunks: Option<Vec<Vec<u8>>>,
+    /// Whether the dict in the options was trained from the input rather than configured
+    dict_trained: bool,
+    #[cfg(feature = "bytes")]
+    chunks_bytes: Option<std::vec::IntoIter<bytes::Bytes>>,
+}
+
+#[cfg(feature = "zstd")]
+impl<RW: TempStore, R: Read> Encoder<RW, R> {
+    /// Construct an encoder from a raw file reader and a temp reader&writer
+    pub fn new(reader: R, temp: RW) -> Result<Self, ZchunkError> {
+        Self::with_options(reader, temp, EncoderOptions::default())
+    }
+
+    /// Construct an encoder with options
+    ///
+    /// Fails like `EncoderOptions::validate` for invalid options, without reading from
+    /// `reader`, so the rest of the encoder can rely on them.
+    pub fn with_options(
+        reader: R,
+        temp: RW,
+        mut options: EncoderOptions,
+    ) -> Result<Self, ZchunkError> {
+        options.tune_chunker_params();
+        options.validate()?;
+        Ok(Self {
+            header: None,
+            temp,
+            data_start: 0,
+            reader,
+            options,
+            report: None,
+            prepare_started: false,
+            interrupted: None,
+            trained_chunks: None,
+            dict_trained: false,
+            #[cfg(feature = "bytes")]
+            chunks_bytes: None,
+        })
+    }
+
+    /// Split data of reader to chunks, and use zstd to compress chunks, write to temp writer [without header]
+    ///
+    /// When the input reader fails, the error is `ZchunkError::ReadFailed` and the work done so
+    /// far is kept, see `resume_prepare`.
+    ///
+    /// The input is consumed, so this can run once per encoder: any later call returns
+    /// `ZchunkError::AlreadyPrepared`, even when the first call failed.
+    ///
+    /// Fails with `ZchunkError::Cancelled` between chunks once the
+    /// `EncoderOptions::cancel_token` is cancelled.
+    ///
+    /// An encoder made by `resume` continues from its checkpoint.
+    pub fn prepare_chunks(&mut self) -> Result<(), ZchunkError> {
+        self.start_once()?;
+        if let Some(state) = self.interrupted.take() {
+            return self.continue_prepare(state);
+        }
+        if let Some(max_size) = self.dict_to_train() {
+            self.train_dict(max_size)?;
+        }
+        let state = self.store_dict()?;
+        self.continue_prepare(state)
+    }
+
+    /// `prepare_chunks` with the data chunks given by `chunks` instead of chunking the input
+    ///
+    /// Every buffer becomes one data chunk as it is, for producers that know meaningful
+    /// boundaries such as package entries, and everything after chunking is the same. An error
+    /// from `chunks` is returned as is and cannot be resumed.
+    pub fn prepare_chunks_from(
+        &mut self,
+        chunks: impl Iterator<Item = Result<Vec<u8>, ZchunkError>>,
+    ) -> Result<(), ZchunkError> {
+        self.start_once()?;
+        let mut chunks = Some(chunks);
+        if let Some(max_size) = self.dict_to_train() {
+            let read = chunks
+                .take()
+                .into_iter()
+                .flatten()
+                .collect::<Result<_, _>>()?;
+            self.train_dict_from(read, max_size);
+        }
+        let state = self.store_dict()?;
+        match chunks {
+            Some(chunks) => self.store_chunks(state, chunks),
+            None => self.continue_prepare(state),
+        }
+    }
+
+    fn start_once(&mut self) -> Result<(), ZchunkError> {
+        if self.prepare_started {
+            return Err(ZchunkError::AlreadyPrepared);
+        }
+        self.prepare_started = true;
+        self.options.check_cancelled()
+    }
+
+    /// The max size of the dict to train, when `auto_dict` is set and no dict is given
+    fn dict_to_train(&self) -> Option<usize> {
+        self.options
+            .auto_dict_max_size
+            .filter(|_| self.options.dict.is_none())
+    }
+
+    /// Store the dict chunk at the start of the temp and set up the prepare state
+    fn store_dict(&mut self) -> Result<PrepareState, ZchunkError> {
+        self.temp.rewind_to(self.data_start)?;
+        let mut total_hasher = Sha256::new();
+        let compression_type = self.options.chunk_compression_type();
+
+        // the dict chunk is stored in front of the data chunks, compressed without a dict
+        let dict_chunk = match &self.options.dict {
+            Some(d) => {
+                let compressed_dict = match (&self.options.compressed_dict, compression_type) {
+                    (Some(c), _) => c.clone(),
+                    (None, CompressionType::Zstd) => {
+                        compress_chunk(d, &self.options.zstd_params(), None)?
+                    }
+                    (None, t) => self
+                        .options
+                        .compression_registry
+                        .backend(t)?
+                        .compress(d, None)?,
+                };
+                Some(store_chunk(
+                    &mut TempWriter(&mut self.temp),
+                    self.options.chunk_checksum_type(),
+                    &compressed_dict,
+                    d.len(),
+                    &mut total_hasher,
+                )?)
+            }
+            None => None,
+        };
+
+        let stored_end = match &dict_chunk {
+            Some(d) => d.length.to_u64()?,
+            None => 0,
+        };
+        self.prepare_state(total_hasher, dict_chunk, stored_end)
+    }
+
+    /// The prepare state once the dict chunk and the first `stored_end` bytes of chunks are
+    /// in the temp
+    fn prepare_state(
+        &self,
+        total_hasher: Sha256,
+        dict_chunk: Option<Chunk>,
+        stored_end: u64,
+    ) -> Result<PrepareState, ZchunkError> {
+        let compression_type = self.options.chunk_compression_type();
+        let compressor = match compression_type {
+            CompressionType::Zstd => Some(ChunkCompressor::for_options(
+                &self.options,
+                self.options.dict.as_deref(),
+            )?),
+            CompressionType::None | CompressionType::Custom(_) => None,
+        };
+        Ok(PrepareState {
+            total_hasher,
+            // the effectiveness is estimated by compressing samples with zstd
+            effectiveness: dict_chunk
+                .as_ref()
+                .filter(|_| compression_type == CompressionType::Zstd)
+                .map(|_| DictEffectiveness::default()),
+            dict_chunk,
+            chunks: Vec::with_capacity(self.options.estimated_chunk_count()),
+            pending: Vec::new(),
+            bytes_consumed: 0,
+            stored_end,
+            compressor,
+            buffer: Vec::new(),
+            uncompressed_hasher: self.options.uncompressed_checksum.then(Sha256::new),
+        })
+    }
+
+    /// Start over with `reader` as the input, keeping the options and the temp
+    ///
+    /// The prepared header, the report and any interrupted work are dropped, so
+    /// `prepare_chunks` can run again and `compress_to` fails with `HeaderNotFound` until it
+    /// does. A dict trained by `EncoderOptions::auto_dict` is dropped too and trained again
+    /// from the new input. The temp is overwritten from the start and, once the new input is
+    /// prepared, truncated after its chunks where the temp supports it, see
+    /// `TempStore::truncate_to`.
+    pub fn reset(&mut self, reader: R) {
+        self.reader = reader;
+        self.header = None;
+        self.report = None;
+        self.prepare_started = false;
+        self.interrupted = None;
+        self.trained_chunks = None;
+        if self.dict_trained {
+            self.options.dict = None;
+            self.dict_trained = false;
+        }
+        #[cfg(feature = "bytes")]
+        {
+            self.chunks_bytes = None;
+        }
+    }
+
+    /// Continue a `prepare_chunks` that stopped with `ZchunkError::ReadFailed`
+    ///
+    /// `reader` replaces the failed input and must be positioned at the `bytes_consumed` of
+    /// the error. Chunks already written to temp are kept, and the result is the same as an
+    /// uninterrupted `prepare_chunks`.
+    pub fn resume_prepare(&mut self, reader: R) -> Result<(), ZchunkError> {
+        let state = self
+            .interrupted
+            .take()
+            .ok_or(ZchunkError::NothingToResume)?;
+        self.reader = reader;
+        self.continue_prepare(state)
+    }
+
+    /// Write a checkpoint of a `prepare_chunks` that stopped with `ZchunkError::ReadFailed`,
+    /// which `resume` continues in another encoder, even in another process
+    ///
+    /// `EncoderOptions::checkpoint_every` saves checkpoints while the prepare is running.
+    ///
+    /// The checkpoint holds the chunk metadata and the input read but not chunked yet, the
+    /// chunks themselves stay in the temp, which must be kept for `resume`. It is a sidecar
+    /// envelope of `SidecarKind::EncodeCheckpoint`. The digest of the uncompressed input of
+    /// `EncoderOptions::uncompressed_checksum` cannot be saved, such encoders fail with
+    /// `InvalidOption`.
+    pub fn checkpoint(&self, mut writer: impl Write) -> Result<(), ZchunkError> {
+        let state = self
+            .interrupted
+            .as_ref()
+            .ok_or(ZchunkError::NothingToResume)?;
+        let checkpoint = checkpoint_of(
+            &self.options,
+            self.data_start,
+            state,
+            state.bytes_consumed,
+            &state.pending,
+        )?;
+        writer.write_all(&checkpoint.to_bytes()?)?;
+        Ok(())
+    }
+
+    /// Construct an encoder that continues the prepare a `checkpoint` was written from
+    ///
+    /// `temp` must hold the chunks stored before the checkpoint, they are read back and
+    /// checked against their checksums. `reader` must be positioned at the `bytes_consumed`
+    /// of the `ReadFailed` error, the caller seeks the input there. `prepare_chunks` then
+    /// continues, and the file is the same as an uninterrupted encode writes.
+    ///
+    /// `options` must store chunks like the options of the interrupted encoder, other
+    /// chunker parameters, compression or dict fail with `CheckpointMismatch`. A checkpoint
+    /// that cannot be parsed or does not match the temp fails with `InvalidCheckpoint`.
+    pub fn resume(
+        reader: R,
+        temp: RW,
+        mut checkpoint: impl Read,
+        options: EncoderOptions,
+    ) -> Result<Self, ZchunkError> {
+        let mut bytes = Vec::new();
+        checkpoint.read_to_end(&mut bytes)?;
+        let checkpoint = Checkpoint::from_bytes(&bytes)?;
+        let mut encoder = Self::with_options(reader, temp, options)?;
+        if checkpoint.options_digest != encoder.options.stored_chunks_digest() {
+            return Err(ZchunkError::CheckpointMismatch);
+        }
+        encoder.data_start = checkpoint.data_start;
+        let total_hasher = encoder.reread_stored(&checkpoint)?;
+
+        let mut state =
+            encoder.prepare_state(total_hasher, checkpoint.dict_chunk, checkpoint.stored_end)?;
+        state.effectiveness = checkpoint.effectiveness;
+        state.chunks = checkpoint.chunks;
+        state.pending = checkpoint.pending;
+        state.bytes_consumed = checkpoint.bytes_consumed;
+        encoder.interrupted = Some(state);
+        Ok(encoder)
+    }
+
+    /// Read the chunks of `checkpoint` back from the temp, checking their checksums, and
+    /// return the checksum of all of them
+    ///
+    /// The manifest writer gets the lines of these data chunks, like it would have during
+    /// the interrupted prepare. Chunk lengths that do not add up to the stored end, a temp
+    /// that ends early and a chunk that does not match its checksum are all
+    /// `InvalidCheckpoint`.
+    fn reread_stored(&mut self, checkpoint: &Checkpoint) -> Result<Sha256, ZchunkError> {
+        let dict_chunk = checkpoint.dict_chunk.iter().map(|chunk| (None, chunk));
+        let data_chunks = checkpoint.chunks.iter().enumerate();
+        let stored = || {
+            dict_chunk
+                .clone()
+                .chain(data_chunks.clone().map(|(id, chunk)| (Some(id), chunk)))
+        };
+        // the lengths come from the checkpoint, check them before reading anything
+        let mut stored_len = 0u64;
+        for (_, chunk) in stored() {
+            stored_len = checked_add(stored_len, chunk.length.to_u64()?)?;
+            if stored_len > checkpoint.stored_end {
+                return Err(ZchunkError::InvalidCheckpoint);
+            }
+        }
+        if stored_len != checkpoint.stored_end {
+            return Err(ZchunkError::InvalidCheckpoint);
+        }
+
+        self.temp.rewind_to(self.data_start)?;
+        let mut total_hasher = Sha256::new();
+        let mut offset = 0u64;
+        let mut data = Vec::new();
+        for (id, chunk) in stored() {
+            let length = to_usize(chunk.length.to_u64()?, "chunk length")?;
+            // grow the buffer as the temp has the bytes, the stored end can be anything
+            data.clear();
+            while data.len() < length {
+                let start = data.len();
+                data.resize(
+                    start + (length - start).min(MAX_PREALLOCATED_CHUNK as usize),
+                    0,
+                );
+                self.temp
+                    .read_back(&mut data[start..])
+                    .map_err(|e| match e.kind() {
+                        io::ErrorKind::UnexpectedEof => ZchunkError::InvalidCheckpoint,
+                        _ => e.into(),
+                    })?;
+            }
+            if compute_checksum(self.options.chunk_checksum_type(), &data)? != chunk.checksum {
+                return Err(ZchunkError::InvalidCheckpoint);
+            }
+            total_hasher.update(&data);
+            if let (Some(id), false) = (id, self.options.chunks_may_change()) {
+                if let Some(mut writer) = self.options.lock_manifest_writer() {
+                    write_chunk_line(&mut *writer, id, offset, chunk)?;
+                }
+            }
+            offset = checked_add(offset, data.len() as u64)?;
+        }
+        Ok(total_hasher)
+    }
+
+    /// Read all data chunks ahead and train a dict of at most `max_size` bytes from them
+    ///
+    /// The dict is left unset when zstd cannot train one, which happens for inputs with too
+    /// few or too small chunks.
+    fn train_dict(&mut self, max_size: usize) -> Result<(), ZchunkError> {
+        #[cfg(feature = "bytes")]
+        let chunks = match self.chunks_bytes.take() {
+            Some(chunks) => chunks.map(|data| data.to_vec()).collect(),
+            None => self.read_chunks()?,
+        };
+        #[cfg(not(feature = "bytes"))]
+        let chunks = self.read_chunks()?;
+
+        self.train_dict_from(chunks, max_size);
         Ok(())
     }
-}
 
-/// A decoder that decompress input data from `BufRead + Seek`, and write uncompressed data to `Write`
-pub struct Decoder<R> {
-    header: Header,
-    header_size: u64,
-    reader: R,
-}
+    /// Train the dict from `chunks`, which are kept to be stored, see `train_dict`
+    fn train_dict_from(&mut self, chunks: Vec<Vec<u8>>, max_size: usize) {
+        if let Ok(dict) = zstd::dict::from_samples(&chunks, max_size) {
+            self.options.dict = Some(dict);
+            self.dict_trained = true;
+        }
+        self.trained_chunks = Some(chunks);
+    }
+
+    fn read_chunks(&mut self) -> Result<Vec<Vec<u8>>, ZchunkError> {
+        let options = &self.options;
+        Chunker::with_params(options.chunker_params.clone(), &mut self.reader)?
+            .map(|chunk| options.check_cancelled().and(chunk))
+            .collect()
+    }
+
+    /// Store data chunks that were read before, instead of chunking the input
+    fn store_chunks(
+        &mut self,
+        mut state: PrepareState,
+        chunks: impl Iterator<Item = Result<impl AsRef<[u8]>, ZchunkError>>,
+    ) -> Result<(), ZchunkError> {
+        for data in chunks {
+            self.push_chunk(&mut state, data?.as_ref())?;
+        }
+        self.finish_prepare(state)
+    }
+
+    /// The chunker parameters, after `EncoderOptions::max_chunks` tuned them
+    pub(crate) fn chunker_params(&self) -> &ChunkerParams {
+        &self.options.chunker_params
+    }
+
+    /// Start `prepare_chunks` for chunks pushed one by one, see `EncoderSink`
+    ///
+    /// `None` when a dict is to be trained, then the chunks must be collected and given to
+    /// `prepare_chunks_from` instead.
+    pub(crate) fn start_push(&mut self) -> Result<Option<PrepareState>, ZchunkError> {
+        if self.dict_to_train().is_some() {
+            return Ok(None);
+        }
+        self.start_once()?;
+        self.store_dict().map(Some)
+    }
+
+    /// Store a data chunk that was read before
+    pub(crate) fn push_chunk(
+        &mut self,
+        state: &mut PrepareState,
+        data: &[u8],
+    ) -> Result<(), ZchunkError> {
+        store_data_chunk(&mut TempWriter(&mut self.temp), &self.options, state, data)?;
+        state.bytes_consumed += data.len() as u64;
+        self.options.report_progress(
+            state.bytes_consumed,
+            state.chunks.len(),
+            state.stored_end,
+            false,
+        );
+        Ok(())
+    }
+
+    fn continue_prepare(&mut self, mut state: PrepareState) -> Result<(), ZchunkError> {
+        if let Some(chunks) = self.trained_chunks.take() {
+            return self.store_chunks(state, chunks.into_iter().map(Ok));
+        }
+        #[cfg(feature = "bytes")]
+        if let Some(chunks) = self.chunks_bytes.take() {
+            return self.store_chunks(state, chunks.map(Ok));
+        }
+
+        let mut chunker =
+            Chunker::with_params(self.options.chunker_params.clone(), &mut self.reader)?
+                .with_pending(std::mem::take(&mut state.pending));
+        let mut next_checkpoint = self
+            .options
+            .checkpoint_every
+            .as_ref()
+            .map(|(interval, _)| state.bytes_consumed.saturating_add(*interval));
+        loop {
+            let uncompressed_chunk_data = match chunker.next() {
+                Some(Ok(data)) => data,
+                Some(Err(ZchunkError::Io(source))) => {
+                    state.bytes_consumed += chunker.consumed();
+                    state.pending = chunker.into_pending();
+                    let err = ZchunkError::ReadFailed {
+                        bytes_consumed: state.bytes_consumed,
+                        chunks_completed: state.chunks.len(),
+                        source,
+                    };
+                    self.interrupted = Some(state);
+                    return Err(err);
+                }
+                Some(Err(e)) => return Err(e),
+                None => break,
+            };
+
+            store_data_chunk(
+                &mut TempWriter(&mut self.temp),
+                &self.options,
+                &mut state,
+                &uncompressed_chunk_data,
+            )?;
+            self.options.report_progress(
+                state.bytes_consumed + chunker.consumed(),
+                state.chunks.len(),
+                state.stored_end,
+                false,
+            );
+
+            let bytes_consumed = state.bytes_consumed + chunker.consumed();
+            if let (Some(at), Some((interval, save))) =
+                (&mut next_checkpoint, &self.options.checkpoint_every)
+            {
+                if bytes_consumed >= *at {
+                    self.temp.flush_bytes()?;
+                    let checkpoint = checkpoint_of(
+                        &self.options,
+                        self.data_start,
+                        &state,
+                        bytes_consumed,
+                        chunker.pending(),
+                    )?;
+                    save(bytes_consumed, &checkpoint.to_bytes()?)?;
+                    *at = bytes_consumed.saturating_add(*interval);
+                }
+            }
+        }
+
+        state.bytes_consumed += chunker.consumed();
+        self.finish_prepare(state)
+    }
+
+    /// Build the header once all chunks are in the temp
+    pub(crate) fn finish_prepare(&mut self, state: PrepareState) -> Result<(), ZchunkError> {
+        let PrepareState {
+            mut total_hasher,
+            mut dict_chunk,
+            effectiveness,
+            mut chunks,
+            bytes_consumed,
+            stored_end,
+            uncompressed_hasher,
+            ..
+        } = state;
+        self.options
+            .report_progress(bytes_consumed, chunks.len(), stored_end, true);
+
+        let mut report = EncodeReport {
+            dict_trained: self.options.auto_dict_max_size.is_some() && dict_chunk.is_some(),
+            ..Default::default()
+        };
+        if let (Some(mut e), Some(d)) = (effectiveness, &dict_chunk) {
+            e.dict_chunk_size = d.length.to_u64()?;
+            if let Some(threshold) = self.options.auto_drop_dict_threshold {
+                if e.regression() > threshold {
+                    total_hasher = Sha256::new();
+                    chunks = self.recompress_stored(
+                        &chunks,
+                        Some(d),
+                        CompressionType::Zstd,
+                        &mut total_hasher,
+                    )?;
+                    dict_chunk = None;
+                    report.dict_dropped = true;
+                }
+            }
+            report.dict_effectiveness = Some(e);
+        }
+
+        let mut compression_type = self.options.chunk_compression_type();
+        if self.options.store_incompressible && compression_type == CompressionType::Zstd {
+            let mut stored = match &dict_chunk {
+                Some(d) => d.length.to_u64()?,
+                None => 0,
+            };
+            let mut raw = 0u64;
+            for chunk in &chunks {
+                stored = checked_add(stored, chunk.length.to_u64()?)?;
+                raw = checked_add(raw, chunk.uncompressed_length.to_u64()?)?;
+            }
+            if stored >= raw {
+                total_hasher = Sha256::new();
+                chunks = self.recompress_stored(
+                    &chunks,
+                    dict_chunk.as_ref(),
+                    CompressionType::None,
+                    &mut total_hasher,
+                )?;
+                dict_chunk = None;
+                compression_type = CompressionType::None;
+                report.stored_uncompressed = true;
+            }
+        }
+
+        let data_checksum = total_hasher.finalize();
+
+        let mut preface = Preface::new(data_checksum[..].try_into()?);
+        preface.compression_type = (compression_type.to_u8() as u64).into();
+        for element in &self.options.optional_elements {
+            if uncompressed_hasher.is_none() || element.id != UNCOMPRESSED_CHECKSUM_ELEMENT_ID {
+                preface.push_optional_element(element.clone());
+            }
+        }
+        if self.options.chunk_stream.is_some() {
+            preface.flags = preface.flags.with_stream();
+            let checksum_size = self.options.chunk_checksum_type().digest_size();
+            dict_chunk
+                .get_or_insert_with(|| Chunk::new(Checksum::zeroed(checksum_size), 0, 0))
+                .stream = Some(0.into());
+        }
+        if let Some(annotations) = &self.options.chunk_annotations {
+            if annotations.len() > chunks.len() {
+                return Err(ZchunkError::TooManyChunkAnnotations {
+                    chunks: chunks.len(),
+                    annotations: annotations.len(),
+                });
+            }
+            preface.push_optional_element(chunk_annotations_element(annotations));
+        }
+        if let Some(hasher) = uncompressed_hasher {
+            preface.push_optional_element(uncompressed_checksum_element(hasher.finalize().into()));
+        }
+
+        let reserved = self.options.reserved_signature;
+        let signatures = Signatures::new(
+            self.options
+                .signers
+                .iter()
+                .map(|s| Signature::placeholder(s.signature_type(), s.max_size()))
+                .chain(reserved.map(|(type_, size)| Signature::placeholder(type_, size)))
+                .collect(),
+        );
+        let index =
+            Index::with_checksum_type(self.options.chunk_checksum_type(), dict_chunk, chunks)?;
+        let header_size = signatures.byte_size() + index.byte_size() + preface.byte_size();
+        let lead = Lead::with_checksum_type(header_size, self.options.lead_checksum_type())?;
+
+        let mut header = Header::new(lead, preface, index, signatures);
+        if !self.options.signers.is_empty() {
+            let signed_region = header.signature_input()?;
+            for (signer, signature) in self
+                .options
+                .signers
+                .iter()
+                .zip(&mut header.signatures.signatures)
+            {
+                let bytes = signer.sign(&signed_region)?;
+                if bytes.len() > signature.signature.len() {
+                    return Err(ZchunkError::SignatureTooLarge {
+                        len: bytes.len(),
+                        capacity: signature.signature.len(),
+                    });
+                }
+                signature.signature[..bytes.len()].copy_from_slice(&bytes);
+            }
+        }
+        header.compute_and_set_checksum()?;
+
+        if let Some(mut writer) = self.options.lock_manifest_writer() {
+            if self.options.chunks_may_change() {
+                for (id, (chunk, offset)) in header.index.data_chunks.iter().enumerate() {
+                    write_chunk_line(&mut *writer, id, *offset, chunk)?;
+                }
+            }
+            write_trailer(&mut *writer, &header)?;
+        }
+
+        // a previous input may have left a longer temp
+        self.temp
+            .truncate_to(checked_add(self.data_start, header.data_size()?)?)?;
+        self.header = Some(header);
+        self.report = Some(report);
+
+        Ok(())
+    }
+
+    /// Store the data chunks in temp again without the dict, as `compression_type`, and
+    /// rewrite the temp
+    ///
+    /// The chunks are rewritten in place one at a time, so only a rewritten chunk that is
+    /// longer than the stored ones it replaces holds the chunks it overwrites in memory.
+    fn recompress_stored(
+        &mut self,
+        chunks: &[Chunk],
+        dict_chunk: Option<&Chunk>,
+        compression_type: CompressionType,
+        total_hasher: &mut Sha256,
+    ) -> Result<Vec<Chunk>, ZchunkError> {
+        /// Read the stored chunk at `at` back and move past it
+        fn read_stored(
+            temp: &mut impl TempStore,
+            at: &mut u64,
+            chunk: &Chunk,
+        ) -> Result<Vec<u8>, ZchunkError> {
+            let length = chunk.length.to_u64()?;
+            let mut data = vec![0; to_usize(length, "chunk length")?];
+            temp.rewind_to(*at)?;
+            temp.read_back(&mut data)?;
+            *at = checked_add(*at, length)?;
+            Ok(data)
+        }
+
+        let dict = self.options.dict.clone().unwrap_or_default();
+        let dict_length = match dict_chunk {
+            Some(d) => d.length.to_u64()?,
+            None => 0,
+        };
+        let mut read_at = checked_add(self.data_start, dict_length)?;
+        let mut write_at = self.data_start;
+        // stored chunks read before the rewritten ones in front of them overwrite them
+        let mut read_ahead = VecDeque::new();
+        let mut next_read = 0;
+
+        let mut compressor = ChunkCompressor::for_options(&self.options, None)?;
+        let checksum_type = self.options.chunk_checksum_type();
+        let mut recompressed = Vec::with_capacity(chunks.len());
+        for (id, chunk) in chunks.iter().enumerate() {
+            let mut data = match read_ahead.pop_front() {
+                Some(data) => data,
+                None => {
+                    next_read += 1;
+                    read_stored(&mut self.temp, &mut read_at, chunk)?
+                }
+            };
+            if let Some(transform) = &self.options.transform {
+                data = transform.decode(id, &data);
+            }
+
+            let mut uncompressed = Vec::with_capacity(to_usize(
+                chunk.uncompressed_length.to_u64()?,
+                "uncompressed chunk length",
+            )?);
+            let mut decoder = zstd::Decoder::with_dictionary(data.as_slice(), &dict)?;
+            // the chunks were compressed with the window of the options
+            if let Some(window_log) = self.options.window_log {
+                decoder.window_log_max(window_log)?;
+            }
+            io::copy(&mut decoder, &mut uncompressed)?;
+
+            let length = uncompressed.len();
+            let mut compressed = match compression_type {
+                CompressionType::None => uncompressed,
+                CompressionType::Zstd => compressor.compress(&uncompressed)?,
+                CompressionType::Custom(_) => self
+                    .options
+                    .compression_registry
+                    .backend(compression_type)?
+                    .compress(&uncompressed, None)?,
+            };
+            if let Some(transform) = &self.options.transform {
+                compressed = transform.encode(id, &compressed);
+            }
+
+            let end = checked_add(write_at, compressed.len() as u64)?;
+            while read_at < end && next_read < chunks.len() {
+                let next = &chunks[next_read];
+                read_ahead.push_back(read_stored(&mut self.temp, &mut read_at, next)?);
+                next_read += 1;
+            }
+            self.temp.rewind_to(write_at)?;
+            let mut stored = store_chunk(
+                &mut TempWriter(&mut self.temp),
+                checksum_type,
+                &compressed,
+                length,
+                total_hasher,
+            )?;
+            stored.stream = chunk.stream.clone();
+            recompressed.push(stored);
+            write_at = end;
+        }
+
+        Ok(recompressed)
+    }
+
+    /// The header of the file `compress_to` writes, once `prepare_chunks` built it
+    pub fn header(&self) -> Option<&Header> {
+        self.header.as_ref()
+    }
+
+    /// The report of the last `prepare_chunks`
+    pub fn report(&self) -> Option<&EncodeReport> {
+        self.report.as_ref()
+    }
+
+    /// Sizes of the file `compress_to` writes, which require `prepare_chunks`
+    pub fn stats(&self) -> Result<EncodeStats, ZchunkError> {
+        let header = self.header.as_ref().ok_or(ZchunkError::HeaderNotFound)?;
+        let ChunkStats {
+            chunks,
+            compressed_bytes,
+            uncompressed_bytes,
+            ..
+        } = header.chunk_stats();
+        let sizes = header
+            .index
+            .data_chunks
+            .iter()
+            .map(|(c, _)| c.uncompressed_length.to_u64())
+            .collect::<Result<Vec<u64>, _>>()?;
+        let chunk_sizes = (!sizes.is_empty()).then(|| ChunkSizeSummary {
+            min: sizes.iter().copied().min().unwrap_or(0),
+            average: uncompressed_bytes as f64 / chunks as f64,
+            max: sizes.iter().copied().max().unwrap_or(0),
+        });
+        Ok(EncodeStats {
+            chunks,
+            input_bytes: uncompressed_bytes,
+            compressed_bytes,
+            dict_bytes: header.index.dict_chunk.length.to_u64()?,
+            header_bytes: header.data_offset()?,
+            chunk_sizes,
+        })
+    }
+
+    /// Run `prepare_chunks`, unless it ran, and `compress_to`, and return the `stats`
+    pub fn compress(&mut self, writer: impl Write) -> Result<EncodeStats, ZchunkError> {
+        if self.header.is_none() {
+            self.prepare_chunks()?;
+        }
+        self.compress_to(writer)?;
+        self.stats()
+    }
+
+    /// Append the input to the zchunk file `existing` reads, write the longer file to
+    /// `writer` and return the `stats`
+    ///
+    /// The data chunks of `existing` are copied compressed as they are, except the last,
+    /// which ends where the old input ended rather than where the chunker found a boundary,
+    /// so it is chunked again in front of the input. With the chunker parameters and level
+    /// the old file was encoded with, the output is the same as encoding the old and new
+    /// data in one go. The dict, the chunk checksum type and the compression type are taken
+    /// from `existing`, see `EncoderOptions::dict_from`, the rest from the options.
+    ///
+    /// Like `prepare_chunks`, this runs once per encoder.
+    pub fn append<D: BufRead + Seek>(
+        &mut self,
+        existing: &mut Decoder<D>,
+        writer: impl Write,
+    ) -> Result<EncodeStats, ZchunkError> {
+        self.start_once()?;
+        let mut options = self.options.clone();
+        options.dict = None;
+        options.compressed_dict = None;
+        options.auto_dict_max_size = None;
+        self.options = options
+            .dict_from(existing)?
+            .compression_type(existing.header().compression_type()?);
+
+        let mut state = self.store_dict()?;
+        let chunks = existing.header().index.data_chunks.clone();
+        if let Some((_, copied)) = chunks.split_last() {
+            for (id, (chunk, offset)) in copied.iter().enumerate() {
+                let data = existing.get_chunk_data(Some(id), *offset, chunk)?;
+                let uncompressed =
+                    if self.options.chunk_stream.is_some() || state.uncompressed_hasher.is_some() {
+                        existing.decompress_chunk(id)?
+                    } else {
+                        Vec::new()
+                    };
+                if let Some(hasher) = &mut state.uncompressed_hasher {
+                    hasher.update(&uncompressed);
+                }
+                let stream = self
+                    .options
+                    .chunk_stream
+                    .as_ref()
+                    .map(|stream| stream(id, &uncompressed));
+                let uncompressed_length = chunk.uncompressed_length.to_u64()?;
+                store_compressed_chunk(
+                    &mut TempWriter(&mut self.temp),
+                    &self.options,
+                    &mut state,
+                    &data,
+                    to_usize(uncompressed_length, "uncompressed chunk length")?,
+                    stream,
+                )?;
+                state.bytes_consumed += uncompressed_length;
+                self.options.report_progress(
+                    state.bytes_consumed,
+                    state.chunks.len(),
+                    state.stored_end,
+                    false,
+                );
+            }
+            state.pending = existing.decompress_chunk(copied.len())?;
+            state.bytes_consumed += state.pending.len() as u64;
+        }
+        self.continue_prepare(state)?;
+        self.compress(writer)
+    }
+
+    /// Write header and chunks to `Write`, which require `prepare_chunks`
+    ///
+    /// The chunks are read back from the temp, which only `prepare_chunks` writes, so every
+    /// call writes the same file, also after a call that failed. A failing writer is
+    /// reported as `WriteFailed`.
+    pub fn compress_to(&mut self, writer: impl Write) -> Result<(), ZchunkError> {
+        let header = self.header.as_ref().ok_or(ZchunkError::HeaderNotFound)?;
+        let mut writer = CountingWriter::new(writer);
+        header
+            .write_to(&mut writer, false)
+            .map_err(|e| writer.fail(WriteStage::Header, e))?;
+
+        self.temp.rewind_to(self.data_start)?;
+        let dict_length = header.index.dict_chunk.length.to_u64()?;
+        let mut dict = vec![0; to_usize(dict_length, "dict length")?];
+        self.temp.read_back(&mut dict)?;
+        writer
+            .write_all(&dict)
+            .map_err(|e| writer.fail(WriteStage::Dict, e))?;
+
+        for (id, (chunk, _)) in header.index.data_chunks.iter().enumerate() {
+            let mut buf = vec![0; to_usize(chunk.length.to_u64()?, "chunk length")?];
+            self.temp.read_back(&mut buf)?;
+            writer
+                .write_all(&buf)
+                .map_err(|e| writer.fail(WriteStage::Chunk(id), e))?;
+        }
+        Ok(())
+    }
+
+    /// `compress_to` the start of `dest`, then read the file back and verify it when
+    /// `EncoderOptions::verify_output` is set
+    ///
+    /// Bytes of `dest` past the file are left as they are, a reused file should be
+    /// truncated to `stats().file_size`.
+    pub fn compress_to_seekable(
+        &mut self,
+        mut dest: impl Read + Write + Seek,
+    ) -> Result<(), ZchunkError> {
+        dest.rewind()?;
+        self.compress_to(&mut dest)?;
+        dest.flush()?;
+        self.check_output(dest)
+    }
+
+    /// Parse the file written to the start of `file` and check the header and every chunk
+    /// checksum, when `EncoderOptions::verify_output` is set
+    pub(crate) fn check_output(&self, mut file: impl Read + Seek) -> Result<(), ZchunkError> {
+        if !self.options.verify_output {
+            return Ok(());
+        }
+        file.rewind()?;
+        let options =
+            DecodeOptions::new().compression_registry(self.options.compression_registry.clone());
+        let mut decoder = Decoder::with_options(io::BufReader::new(file), options)?;
+        decoder.header().check_checksum()?;
+        let report = decoder.verify(&VerifyOptions::new())?;
+        let first_failure = match report.dict_failure {
+            Some(reason) => Some((None, reason)),
+            None => report
+                .failures
+                .into_iter()
+                .next()
+                .map(|f| (Some(f.id), f.reason)),
+        };
+        match first_failure {
+            Some((id, reason)) => Err(ZchunkError::OutputVerificationFailed {
+                id,
+                reason: Box::new(reason),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Write the header as a detached header, see `Header::write_detached_to`, which
+    /// requires `prepare_chunks`
+    ///
+    /// Mirrors serve it next to the file written by `compress_to`, so clients can fetch the
+    /// header on its own.
+    pub fn write_detached_header(&self, writer: impl Write) -> Result<(), ZchunkError> {
+        self.header
+            .as_ref()
+            .ok_or(ZchunkError::HeaderNotFound)?
+            .write_detached_to(writer)
+    }
+
+    /// Encode `reader` straight into `out`, without a temp
+    ///
+    /// The chunks are written past room for the header, estimated from
+    /// `EncoderOptions::input_size_hint`, and the header is written in front of them once
+    /// all chunks are stored. When the header does not fit the room exactly, the chunks are
+    /// moved within `out` first, which costs the copy a temp would have. The output is the
+    /// same as `prepare_chunks` and `compress_to` write.
+    ///
+    /// The file is written from the start of `out` and its size is returned. Bytes of `out`
+    /// past it are stale, which happens when `out` was not empty or the room was too large,
+    /// so a reused file should be truncated to the size.
+    pub fn compress_into_seekable(
+        reader: R,
+        out: RW,
+        options: EncoderOptions,
+    ) -> Result<u64, ZchunkError> {
+        let data_start = options.estimated_header_size();
+        let mut encoder = Self::with_options(reader, out, options)?;
+        encoder.data_start = data_start;
+        encoder.prepare_chunks()?;
+
+        let header = encoder.header.as_ref().ok_or(ZchunkError::HeaderNotFound)?;
+        let mut header_bytes = Vec::new();
+        header.write_to(&mut header_bytes, false)?;
+        let data_offset = header.data_offset()?;
+        move_region(
+            &mut encoder.temp,
+            data_start,
+            data_offset,
+            header.data_size()?,
+        )?;
+
+        encoder.temp.rewind_to(0)?;
+        encoder.temp.write_bytes(&header_bytes)?;
+        encoder.temp.flush_bytes()?;
+        header.file_size()
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl<RW: TempStore, I: Iterator> Encoder<RW, ChainedReader<I>>
+where
+    I::Item: Read,
+{
+    /// Construct an encoder whose input is `readers` one after another
+    ///
+    /// Chunks are cut across the ends of the readers, so the output is the same as encoding
+    /// their concatenation. `with_options` takes a `ChainedReader` to set options too.
+    pub fn from_readers(
+        readers: impl IntoIterator<IntoIter = I>,
+        temp: RW,
+    ) -> Result<Self, ZchunkError> {
+        Self::new(ChainedReader::new(readers), temp)
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl<'a> Encoder<MemoryTemp, &'a [u8]> {
+    /// Encode `input` held in memory to `writer`, and return the `stats`
+    ///
+    /// An input shorter than the minimum chunk size of `EncoderOptions::chunker_params` is
+    /// one chunk, so it is stored as such without running the chunker, and the chunks are
+    /// kept in a `MemoryTemp` instead of a temp file. The output is the same as
+    /// `prepare_chunks` and `compress_to` write with any temp, for inputs of every size.
+    pub fn compress_small(
+        input: &'a [u8],
+        writer: impl Write,
+        options: EncoderOptions,
+    ) -> Result<EncodeStats, ZchunkError> {
+        let small = input.len() < options.chunker_params.min;
+        let dict_len = options.dict.as_ref().map_or(0, Vec::len);
+        let temp = MemoryTemp::with_capacity(input.len() + dict_len);
+        let mut encoder = Self::with_options(input, temp, options)?;
+        if small {
+            // an empty input has no chunk at all
+            let chunk = (!input.is_empty()).then(|| Ok(input.to_vec()));
+            encoder.prepare_chunks_from(chunk.into_iter())?;
+        }
+        encoder.compress(writer)
+    }
+}
+
+/// Move `len` bytes at offset `from` of `file` to offset `to`, the ranges may overlap
+#[cfg(feature = "zstd")]
+fn move_region(file: &mut impl TempStore, from: u64, to: u64, len: u64) -> Result<(), ZchunkError> {
+    const BLOCK: u64 = 1 << 20;
+    if from == to {
+        return Ok(());
+    }
+    let mut buf = vec![0; to_usize(len.min(BLOCK), "block length")?];
+    // copy the blocks in the order that reads every byte before it is overwritten
+    let blocks = len.div_ceil(BLOCK);
+    for i in 0..blocks {
+        let block = if to > from { blocks - 1 - i } else { i };
+        let start = block * BLOCK;
+        let buf = &mut buf[..(len - start).min(BLOCK) as usize];
+        file.rewind_to(checked_add(from, start)?)?;
+        file.read_back(buf)?;
+        file.rewind_to(checked_add(to, start)?)?;
+        file.write_bytes(buf)?;
+    }
+    Ok(())
+}
+
+/// The start of a decoder that has only parsed the lead and preface of a zchunk file
+///
+/// Peeking is cheap compared to `Decoder::new`, which also parses the index, and is enough
+/// to check the digests or the compression type of many files.
+pub struct PartialDecoder<R> {
+    lead: Lead,
+    preface: Preface,
+    reader: R,
+}
+
+impl<R: BufRead + Seek> PartialDecoder<R> {
+    /// Parse the lead and preface of a zchunk file
+    pub fn peek(mut reader: R) -> Result<Self, ZchunkError> {
+        let lead = Lead::from_reader(&mut reader)?;
+        let preface = Preface::from_reader(&mut reader)?;
+        Ok(Self {
+            lead,
+            preface,
+            reader,
+        })
+    }
+
+    /// The header checksum stored in the lead
+    pub fn header_checksum(&self) -> &[u8] {
+        &self.lead.header_checksum
+    }
+
+    /// The checksum of all chunk data stored in the preface
+    pub fn data_checksum(&self) -> &[u8; 32] {
+        &self.preface.data_checksum
+    }
+
+    /// The size of the whole header, including the lead
+    pub fn header_size(&self) -> Result<u64, ZchunkError> {
+        checked_add(
+            self.lead.header_size.to_u64()?,
+            self.lead.byte_size() as u64,
+        )
+    }
+
+    /// The compression type of the chunks
+    pub fn compression_type(&self) -> Result<CompressionType, ZchunkError> {
+        CompressionType::from_u8(checked_cast(
+            self.preface.compression_type.to_u64()?,
+            "compression type",
+        )?)
+    }
+
+    /// Parse the rest of the header, the result is the same as `Decoder::new`
+    pub fn into_full(self) -> Result<Decoder<R>, ZchunkError> {
+        self.into_full_with_options(DecodeOptions::default())
+    }
+
+    /// Parse the rest of the header, the result is the same as `Decoder::with_options`
+    pub fn into_full_with_options(self, options: DecodeOptions) -> Result<Decoder<R>, ZchunkError> {
+        Decoder::from_partial(self, options)
+    }
+}
+
+/// A decoder that decompress input data from `BufRead + Seek`, and write uncompressed data to `Write`
+pub struct Decoder<R> {
+    pub(crate) header: Header,
+    header_size: u64,
+    reader: R,
+    pub(crate) options: DecodeOptions,
+    availability: Option<ChunkAvailability>,
+    header_bytes: Option<Vec<u8>>,
+    /// Whether decompression checked the header checksum already
+    #[cfg(feature = "zstd")]
+    header_checked: bool,
+    /// The allocation `decompress_chunk_bytes` splits chunks from, reclaimed once the chunks
+    /// split before are dropped
+    #[cfg(all(feature = "zstd", feature = "bytes"))]
+    bytes_buffer: bytes::BytesMut,
+}
+
+impl<R: BufRead + Seek> Decoder<R> {
+    /// Construct a decoder from a zchunk file reader
+    pub fn new(reader: R) -> Result<Self, ZchunkError> {
+        Self::with_options(reader, DecodeOptions::default())
+    }
+
+    /// Construct a decoder from a zchunk file reader with options
+    pub fn with_options(reader: R, options: DecodeOptions) -> Result<Self, ZchunkError> {
+        PartialDecoder::peek(reader)?.into_full_with_options(options)
+    }
+
+    /// Finish parsing a header whose lead and preface were read by `PartialDecoder`
+    fn from_partial(
+        partial: PartialDecoder<R>,
+        options: DecodeOptions,
+    ) -> Result<Self, ZchunkError> {
+        let PartialDecoder {
+            lead,
+            preface,
+            mut reader,
+        } = partial;
+        let index = Index::from_reader(&mut reader, preface.flags.clone())?;
+        let signatures = Signatures::from_reader(&mut reader)?;
+
+        // the lead declares where the data starts, which must be where the header ended
+        let expect_header_size = checked_add(lead.header_size.to_u64()?, lead.byte_size() as u64)?;
+        let header_size = reader.stream_position()?;
+        if expect_header_size < header_size {
+            return Err(ZchunkError::HeaderDataOverlap {
+                offset: expect_header_size,
+                header_end: header_size,
+            });
+        }
+        if expect_header_size != header_size {
+            return Err(ZchunkError::InvalidHeaderSize {
+                expected: expect_header_size,
+                found: header_size,
+            });
+        }
+
+        // read the header again instead of serializing it, so the bytes are exactly the file's
+        let header_bytes = if options.keep_header_bytes {
+            let mut bytes = vec![0; to_usize(header_size, "header size")?];
+            reader.seek(SeekFrom::Start(0))?;
+            reader.read_exact(&mut bytes)?;
+            Some(bytes)
+        } else {
+            None
+        };
+
+        let header = Header::new(lead, preface, index, signatures);
+
+        Ok(Self {
+            header,
+            header_size,
+            reader,
+            options,
+            availability: None,
+            header_bytes,
+            #[cfg(feature = "zstd")]
+            header_checked: false,
+            #[cfg(all(feature = "zstd", feature = "bytes"))]
+            bytes_buffer: bytes::BytesMut::new(),
+        })
+    }
+
+    /// Construct a decoder from a partial zchunk file, where only the chunks marked in
+    /// `available` contain valid data
+    ///
+    /// Reading an absent chunk fails with `ChunkUnavailable` instead of a checksum mismatch.
+    pub fn with_availability(reader: R, available: ChunkAvailability) -> Result<Self, ZchunkError> {
+        let mut decoder = Self::new(reader)?;
+        decoder.availability = Some(available);
+        Ok(decoder)
+    }
+
+    /// The header parsed from the file
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// The raw header bytes, lead to signatures, when `DecodeOptions::keep_header_bytes` is set
+    pub fn header_bytes(&self) -> Option<&[u8]> {
+        self.header_bytes.as_deref()
+    }
+
+    /// The raw lead, preface and index, everything in the header before the signatures,
+    /// when `DecodeOptions::keep_header_bytes` is set
+    pub fn signed_region(&self) -> Option<&[u8]> {
+        let bytes = self.header_bytes.as_deref()?;
+        Some(&bytes[..bytes.len() - self.header.signatures.byte_size()])
+    }
+
+    /// Whether the data chunk holds valid data, always true for complete files
+    pub fn is_chunk_available(&self, id: ChunkId) -> bool {
+        match &self.availability {
+            Some(availability) => availability.is_available(id),
+            None => true,
+        }
+    }
+
+    /// Whether the dict chunk holds valid data, always true for complete files
+    pub(crate) fn dict_available(&self) -> bool {
+        match &self.availability {
+            Some(availability) => availability.dict_available(),
+            None => true,
+        }
+    }
+
+    #[cfg(feature = "zstd")]
+    fn check_chunk_available(&self, id: ChunkId) -> Result<(), ZchunkError> {
+        if !self.is_chunk_available(id) {
+            return Err(ZchunkError::ChunkUnavailable { id });
+        }
+        Ok(())
+    }
+
+    /// Get chunk data by offset and chunk, no decompression
+    ///
+    /// Offset is relative to the end of header, so seeking reader need plus header size
+    pub(crate) fn get_chunk_data(
+        &mut self,
+        id: Option<ChunkId>,
+        offset: u64,
+        chunk: &Chunk,
+    ) -> Result<Vec<u8>, ZchunkError> {
+        self.read_chunk_data(id, offset, chunk, true)
+    }
+
+    /// `get_chunk_data`, checking the chunk checksum only when `verify` is set
+    pub(crate) fn read_chunk_data(
+        &mut self,
+        id: Option<ChunkId>,
+        offset: u64,
+        chunk: &Chunk,
+        verify: bool,
+    ) -> Result<Vec<u8>, ZchunkError> {
+        let length = chunk.length.to_u64()?;
+        to_usize(length, "chunk length")?;
+        let mut buf = Vec::with_capacity(length.min(MAX_PREALLOCATED_CHUNK) as usize);
+        if length == 0 {
+            return Ok(buf);
+        }
+
+        self.reader
+            .seek(seek_start(checked_add(self.header_size, offset)?)?)?;
+        // a truncated file ends before a chunk it declares to be longer
+        if (&mut self.reader).take(length).read_to_end(&mut buf)? as u64 != length {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        if verify {
+            self.header.check_chunk_data(id, chunk, &buf)?;
+        }
+
+        Ok(buf)
+    }
+
+    /// Read at most `max` leading bytes of chunk data, no verification
+    ///
+    /// Offset is relative to the end of header, like `get_chunk_data`
+    pub(crate) fn read_chunk_prefix(
+        &mut self,
+        offset: u64,
+        chunk: &Chunk,
+        max: usize,
+    ) -> Result<Vec<u8>, ZchunkError> {
+        let length = chunk.length.to_u64()?.min(max as u64) as usize;
+        let mut buf = vec![0; length];
+        if length == 0 {
+            return Ok(buf);
+        }
+
+        self.reader
+            .seek(seek_start(checked_add(self.header_size, offset)?)?)?;
+        self.reader.read_exact(&mut buf)?;
+
+        Ok(buf)
+    }
+
+    /// Copy current zchunk reader to another writer, which using a cache zchunk file
+    ///
+    /// The cache can be passed by value or by reference, when passed by reference, both decoders
+    /// stay usable after a `WriteFailed` error so the sync can be retried into another writer.
+    ///
+    /// Cache chunks that fail their checksum or cannot be read completely are taken from this
+    /// decoder instead and recorded in `SyncStats::cache_fallbacks`, only failures on this
+    /// side abort the sync. Chunks are written and reported in index order, when a checksum
+    /// occurs more than once in the cache its lowest chunk id is read.
+    pub fn sync_to<C: BufRead + Seek>(
+        &mut self,
+        mut cache: impl BorrowMut<Decoder<C>>,
+        writer: impl Write,
+    ) -> Result<SyncStats, ZchunkError> {
+        self.sync_with(Some(cache.borrow_mut()), None, writer)
+    }
+
+    /// `sync_to` that skips the cache lookup of checksums `bloom` rules out
+    ///
+    /// `bloom` must be built from the header of the cache, see `Header::lookup_filtered`.
+    /// Chunks it rules out are taken from this decoder.
+    pub fn sync_to_filtered<C: BufRead + Seek>(
+        &mut self,
+        mut cache: impl BorrowMut<Decoder<C>>,
+        bloom: Option<&ChunkBloom>,
+        writer: impl Write,
+    ) -> Result<SyncStats, ZchunkError> {
+        self.sync_with(Some(cache.borrow_mut()), bloom, writer)
+    }
+
+    /// `sync_to` with an optional cache, without one every chunk comes from this decoder
+    pub(crate) fn sync_with<C: BufRead + Seek>(
+        &mut self,
+        mut cache: Option<&mut Decoder<C>>,
+        bloom: Option<&ChunkBloom>,
+        writer: impl Write,
+    ) -> Result<SyncStats, ZchunkError> {
+        let mut writer = CountingWriter::new(writer);
+        let mut stats = SyncStats::default();
+
+        // write header
+        self.header
+            .write_to(&mut writer, false)
+            .map_err(|e| writer.fail(WriteStage::Header, e))?;
+
+        // write dict
+        let dict_chunk = self.header.index.dict_chunk.clone();
+        let cached_dict = match cache.as_deref_mut() {
+            Some(cache) if cache.header.has_dict_chunk(&dict_chunk) => {
+                cache.get_chunk_data(None, 0, &dict_chunk).ok()
+            }
+            _ => None,
+        };
+        let dict = match cached_dict {
+            Some(dict) => dict,
+            None => self.get_chunk_data(None, 0, &dict_chunk)?,
+        };
+        writer
+            .write_all(&dict)
+            .map_err(|e| writer.fail(WriteStage::Dict, e))?;
+
+        // find existed chunks in cache
+        let checksums = self
+            .header
+            .index
+            .data_chunks
+            .iter()
+            .map(|(c, _)| c.checksum);
+        let cache_chunks = match cache.as_deref() {
+            Some(cache) => cache.header.lookup_filtered(checksums, bloom),
+            None => vec![None; self.header.index.data_chunks.len()],
+        };
+
+        // write chunks
+        for (id, (chunk, offset)) in self
+            .header
+            .index
+            .data_chunks
+            .clone()
+            .into_iter()
+            .enumerate()
+        {
+            let fetched = match (cache_chunks[id], cache.as_deref_mut()) {
+                // reuse the cache chunk only when the lengths agree as well
+                (Some((cache_id, o)), Some(cache))
+                    if cache.header.index.data_chunks[cache_id].0 == chunk =>
+                {
+                    Some(cache.get_chunk_data(Some(cache_id), o, &chunk))
+                }
+                _ => None,
+            };
+
+            // a corrupt or truncated cache chunk falls back to the source
+            let data = match fetched {
+                Some(Ok(data)) => {
+                    stats.chunks_from_cache += 1;
+                    data
+                }
+                fetched => {
+                    if fetched.is_some() {
+                        stats.cache_fallbacks.push(id);
+                    }
+                    stats.chunks_from_source += 1;
+                    self.get_chunk_data(Some(id), offset, &chunk)?
+                }
+            };
+            writer
+                .write_all(&data)
+                .map_err(|e| writer.fail(WriteStage::Chunk(id), e))?;
+        }
+
+        Ok(stats)
+    }
+
+    /// Copy the compressed data of the data chunks `ids` to `writer`, in the given order
+    ///
+    /// Each chunk is verified against its checksum before it is written, the ids are all
+    /// checked up front so an unknown id writes nothing.
+    pub fn copy_chunks_to(
+        &mut self,
+        ids: &[ChunkId],
+        writer: impl Write,
+    ) -> Result<(), ZchunkError> {
+        let chunks = ids
+            .iter()
+            .map(|&id| {
+                self.header
+                    .index
+                    .data_chunks
+                    .get(id)
+                    .cloned()
+                    .ok_or(ZchunkError::ChunkNotFound(id))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut writer = CountingWriter::new(writer);
+        for (&id, (chunk, offset)) in ids.iter().zip(chunks) {
+            let data = self.get_chunk_data(Some(id), offset, &chunk)?;
+            writer
+                .write_all(&data)
+                .map_err(|e| writer.fail(WriteStage::Chunk(id), e))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl<R: BufRead + Seek> Decoder<R> {
+    /// Get uncompressed dict chunk
+    ///
+    /// The dict is present only when the compressed length of the dict chunk is non-zero,
+    /// the uncompressed length is checked to agree when parsing the index.
+    pub(crate) fn get_uncompressed_dict(&mut self) -> Result<Option<Vec<u8>>, ZchunkError> {
+        self.uncompressed_dict(true)
+    }
+
+    /// Whether decompression checks chunk checksums, see `DecodeOptions::verification`
+    fn verifies_chunks(&self) -> bool {
+        self.options.verification == VerificationLevel::Full
+    }
+
+    /// Check the header checksum the first time a chunk is decompressed, unless the
+    /// verification level is `None`, and get the uncompressed dict
+    fn start_decompression(&mut self) -> Result<Option<Vec<u8>>, ZchunkError> {
+        if !self.header_checked && self.options.verification != VerificationLevel::None {
+            self.header.check_checksum()?;
+            self.header_checked = true;
+        }
+        self.uncompressed_dict(self.verifies_chunks())
+    }
+
+    fn uncompressed_dict(&mut self, verify: bool) -> Result<Option<Vec<u8>>, ZchunkError> {
+        if !self.header.index.has_dict() {
+            return Ok(None);
+        }
+        if !self.dict_available() {
+            return Err(ZchunkError::DictUnavailable);
+        }
+
+        let dict_chunk = self.header.index.dict_chunk.clone();
+        let data = self.read_chunk_data(None, 0, &dict_chunk, verify)?;
+
+        let backend = self.options.backend(self.header.compression_type()?)?;
+        let mut dict = Vec::new();
+        decompress_with(backend, data.as_slice(), None, &mut dict, None, &dict_chunk)?;
+        Ok(Some(dict))
+    }
+
+    /// Decompress and assemble chunks, and write chunks to `Write`
+    ///
+    /// Chunks are verified and streamed like in `decompress_range`.
+    pub fn decompress_to(&mut self, mut writer: impl Write) -> Result<(), ZchunkError> {
+        let dict = self.start_decompression()?;
+        for id in 0..self.header.index.data_chunks.len() {
+            self.stream_chunk_to(id, dict.as_deref(), &mut writer)?;
+        }
+
+        Ok(())
+    }
+
+    /// Decompress and verify every data chunk, writing each one at its uncompressed offset
+    ///
+    /// Offsets are relative to the position of `writer` when called and follow from the
+    /// uncompressed lengths in the index, so chunks do not depend on the ones before them.
+    /// Skipped zero chunks read back as zeros, trailing ones extend the output by writing
+    /// its last byte.
+    pub fn decompress_to_seekable(
+        &mut self,
+        mut writer: impl Write + Seek,
+        options: RestoreOptions,
+    ) -> Result<(), ZchunkError> {
+        let base = writer.stream_position()?;
+        let mut offset = base;
+        let mut written_end = base;
+        for id in 0..self.header.index.data_chunks.len() {
+            let uncompressed_length = self.header.index.data_chunks[id]
+                .0
+                .uncompressed_length
+                .to_u64()?;
+            let data = self.decompress_chunk(id)?;
+            if data.len() as u64 != uncompressed_length {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "chunk {id} decompressed to {} bytes, expected {uncompressed_length}",
+                        data.len()
+                    ),
+                )
+                .into());
+            }
+
+            let end = checked_add(offset, uncompressed_length)?;
+            if !(options.skip_zero_chunks && data.iter().all(|&b| b == 0)) {
+                writer.seek(seek_start(offset)?)?;
+                writer.write_all(&data)?;
+                written_end = end;
+            }
+            offset = end;
+        }
+
+        if offset > written_end {
+            writer.seek(seek_start(offset - 1)?)?;
+            writer.write_all(&[0])?;
+        }
+
+        Ok(())
+    }
+
+    /// Decompress a single data chunk after verifying its checksum
+    pub fn decompress_chunk(&mut self, id: ChunkId) -> Result<Vec<u8>, ZchunkError> {
+        if let Some(data) = self.cached_chunk(id)? {
+            return Ok(data);
+        }
+        let dict = self.start_decompression()?;
+        self.decompress_and_cache(id, dict.as_deref())
+    }
+
+    /// Look up a data chunk in the `DecodeOptions::cache`, if any
+    fn cached_chunk(&self, id: ChunkId) -> Result<Option<Vec<u8>>, ZchunkError> {
+        let Some(cache) = &self.options.cache else {
+            return Ok(None);
+        };
+        let (chunk, _) = self
+            .header
+            .index
+            .data_chunks
+            .get(id)
+            .ok_or(ZchunkError::ChunkNotFound(id))?;
+        Ok(cache.get(&chunk.checksum).map(|data| data.to_vec()))
+    }
+
+    /// Decompress a data chunk into a `Bytes`
+    ///
+    /// Chunks are split off one buffer of the decoder, whose allocation is reused once the
+    /// chunks returned before are dropped, so a loop over the chunks that drops each one does
+    /// not allocate per chunk.
+    #[cfg(feature = "bytes")]
+    pub fn decompress_chunk_bytes(&mut self, id: ChunkId) -> Result<bytes::Bytes, ZchunkError> {
+        use bytes::BufMut;
+
+        let dict = self.start_decompression()?;
+        let mut buffer = std::mem::take(&mut self.bytes_buffer);
+        let output = self.decompress_chunk_into(id, dict.as_deref(), |len| {
+            buffer.reserve(len);
+            buffer.writer()
+        })?;
+        let mut buffer = output.into_inner();
+        let chunk = buffer.split().freeze();
+        self.bytes_buffer = buffer;
+        Ok(chunk)
+    }
+
+    /// Decompress a range of data chunks after verifying their checksums, and write them to `Write`
+    ///
+    /// Chunks are streamed to the writer, see `DecodeOptions::max_buffered_output` for how
+    /// much of a chunk is held back until it is verified.
+    pub fn decompress_range(
+        &mut self,
+        ids: Range<ChunkId>,
+        mut writer: impl Write,
+    ) -> Result<(), ZchunkError> {
+        let dict = self.start_decompression()?;
+        for id in ids {
+            self.decompress_chunk_with_dict_to(id, dict.as_deref(), &mut writer)?;
+        }
+
+        Ok(())
+    }
+
+    /// Decompress a single data chunk after verifying its checksum, and write it to `Write`
+    ///
+    /// The chunk is streamed like in `decompress_range`.
+    pub fn decompress_chunk_to(
+        &mut self,
+        id: ChunkId,
+        mut writer: impl Write,
+    ) -> Result<(), ZchunkError> {
+        if let Some(data) = self.cached_chunk(id)? {
+            writer.write_all(&data)?;
+            return Ok(());
+        }
+        let dict = self.start_decompression()?;
+        self.decompress_chunk_with_dict_to(id, dict.as_deref(), &mut writer)
+    }
+
+    fn decompress_chunk_with_dict_to(
+        &mut self,
+        id: ChunkId,
+        dict: Option<&[u8]>,
+        writer: &mut impl Write,
+    ) -> Result<(), ZchunkError> {
+        match self.cached_chunk(id)? {
+            Some(data) => writer.write_all(&data)?,
+            // the cache needs the whole chunk anyway
+            None if self.options.cache.is_some() => {
+                writer.write_all(&self.decompress_and_cache(id, dict)?)?
+            }
+            None => self.stream_chunk_to(id, dict, writer)?,
+        }
+
+        Ok(())
+    }
+
+    /// Decompress a data chunk into `writer` while its compressed data is hashed, withholding
+    /// the output allowed by `DecodeOptions::max_buffered_output` until the checksum matches
+    fn stream_chunk_to(
+        &mut self,
+        id: ChunkId,
+        dict: Option<&[u8]>,
+        writer: &mut impl Write,
+    ) -> Result<(), ZchunkError> {
+        // a transform needs the whole chunk at once
+        if self.options.transform.is_some() {
+            let data = self.decompress_chunk_into(id, dict, Vec::with_capacity)?;
+            writer.write_all(&data)?;
+            return Ok(());
+        }
+
+        let (chunk, offset) = self
+            .header
+            .index
+            .data_chunks
+            .get(id)
+            .cloned()
+            .ok_or(ZchunkError::ChunkNotFound(id))?;
+        self.check_chunk_available(id)?;
+
+        let checksum_type = self.header.checksum_type()?;
+        let backend = self.options.backend(self.header.compression_type()?)?;
+        let length = chunk.length.to_u64()?;
+        self.reader
+            .seek(seek_start(checked_add(self.header_size, offset)?)?)?;
+        let hasher = match self.verifies_chunks() {
+            true => Some(ChunkHasher::new(checksum_type)?),
+            false => None,
+        };
+        let mut input = HashingReader {
+            inner: (&mut self.reader).take(length),
+            hasher,
+        };
+        let mut output = HeldBackWriter::new(
+            writer,
+            self.options.max_buffered_output.unwrap_or(usize::MAX),
+        );
+
+        let decoded = decompress_with(backend, &mut input, dict, &mut output, Some(id), &chunk);
+
+        // corrupt data may stop the zstd decoder early, the checksum error is more telling
+        io::copy(&mut input, &mut io::sink())?;
+        if let Some(hasher) = input.hasher {
+            hasher.verify(Some(id), &chunk.checksum, length as usize)?;
+        }
+        decoded?;
+
+        output.finish()?;
+        Ok(())
+    }
+
+    /// Decompress a data chunk and store it in the `DecodeOptions::cache`, if any
+    fn decompress_and_cache(
+        &mut self,
+        id: ChunkId,
+        dict: Option<&[u8]>,
+    ) -> Result<Vec<u8>, ZchunkError> {
+        let data = self.decompress_chunk_into(id, dict, Vec::with_capacity)?;
+        if let Some(cache) = &self.options.cache {
+            let checksum = self.header.index.data_chunks[id].0.checksum;
+            cache.put(checksum, Arc::new(data.clone()));
+        }
+        Ok(data)
+    }
+
+    /// Decompress a data chunk into the writer built by `new_output` from the uncompressed length
+    fn decompress_chunk_into<W: Write>(
+        &mut self,
+        id: ChunkId,
+        dict: Option<&[u8]>,
+        new_output: impl FnOnce(usize) -> W,
+    ) -> Result<W, ZchunkError> {
+        let (chunk, offset) = self
+            .header
+            .index
+            .data_chunks
+            .get(id)
+            .cloned()
+            .ok_or(ZchunkError::ChunkNotFound(id))?;
+        self.check_chunk_available(id)?;
+
+        let verify = self.verifies_chunks();
+        let mut data = self.read_chunk_data(Some(id), offset, &chunk, verify)?;
+        if let Some(transform) = &self.options.transform {
+            data = transform.decode(id, &data);
+        }
+
+        let capacity = chunk
+            .uncompressed_length
+            .to_u64()?
+            .min(MAX_PREALLOCATED_CHUNK);
+        let mut output = new_output(to_usize(capacity, "uncompressed chunk length")?);
+        let backend = self.options.backend(self.header.compression_type()?)?;
+        decompress_with(
+            backend,
+            data.as_slice(),
+            dict,
+            &mut output,
+            Some(id),
+            &chunk,
+        )?;
+
+        Ok(output)
+    }
+}
+
+/// Decompress what `input` reads to `output` with `backend`, failing with `ChunkTooLarge`
+/// once the output grows past the uncompressed length of `chunk`
+#[cfg(feature = "zstd")]
+pub(crate) fn decompress_with<'a>(
+    backend: &dyn Compression,
+    input: impl Read + 'a,
+    dict: Option<&'a [u8]>,
+    mut output: impl Write,
+    id: Option<ChunkId>,
+    chunk: &Chunk,
+) -> Result<u64, ZchunkError> {
+    let uncompressed_length = chunk.uncompressed_length.to_u64()?;
+    let decoder = backend.decompressor(Box::new(input), dict)?;
+    let written = io::copy(
+        &mut decoder.take(uncompressed_length.saturating_add(1)),
+        &mut output,
+    )?;
+    if written > uncompressed_length {
+        return Err(ZchunkError::ChunkTooLarge {
+            id,
+            uncompressed_length,
+        });
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(any(feature = "zstd", feature = "sha512"))]
+    use std::io::Write;
+    #[cfg(feature = "zstd")]
+    use std::io::{Read, Seek, SeekFrom};
+    use std::{
+        fs::File,
+        io::{self, BufReader, Cursor},
+    };
+
+    #[cfg(any(feature = "zstd", feature = "sha512"))]
+    use sha2::{Digest, Sha256};
+    #[cfg(feature = "zstd")]
+    use tempfile::Builder;
+
+    #[cfg(feature = "zstd")]
+    use super::{
+        compress_chunk, compute_checksum, Encoder, HeldBackWriter, IndexBuilder, OptionalElement,
+    };
+    use super::{
+        to_usize, Chunk, Decoder, Header, Index, Lead, Preface, PrefaceFlags, Signature,
+        Signatures, MAX_FILE_OFFSET,
+    };
+    #[cfg(any(feature = "zstd", feature = "sha512"))]
+    use super::{CompressionType, PartialDecoder};
+    use crate::{
+        checksum::DEFAULT_CHECKSUM_TYPE, test_utils::HeaderBuilder, Checksum, ChecksumType,
+        RatioPercentiles, VariantInt, ZchunkError,
+    };
+    #[cfg(feature = "sha512")]
+    use crate::{AnomalyOptions, ChunkAvailability, CoalescePolicy, RangePlanner, ReadVariantInt};
+    #[cfg(feature = "zstd")]
+    use crate::{ChainedReader, ChunkerParams, EncodeReport, MemoryTemp, TempStore};
+    #[cfg(any(feature = "zstd", feature = "sha512"))]
+    use crate::{DecodeOptions, VerifyOptions, WriteStage};
+    #[cfg(feature = "zstd")]
+    use crate::{EncoderOptions, RestoreOptions};
+    #[cfg(feature = "zstd")]
+    fn test_decoder_inner(path: &str, checksum: &str) {
+        let file = File::open(path).unwrap();
+        let mut reader = BufReader::new(file);
+
+        let mut decoder = Decoder::new(&mut reader).unwrap();
+
+        let mut hasher = Sha256::new();
+        decoder.decompress_to(&mut hasher).unwrap();
+
+        assert_eq!(hex::encode(hasher.finalize()), checksum);
+    }
+
+    #[cfg(all(feature = "zstd", feature = "sha512"))]
+    #[test]
+    fn test_decompress() {
+        test_decoder_inner("testdata/c25ffa05cf1fdeb67801847df96c33933b1ee1ea081af52edff4ff371a1c814c-comps-Server.x86_64.xml.zck",
+        "14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68");
+        test_decoder_inner("testdata/3c6181c789ef9e8ed23f4072eb2f8f529002abd5166273a9734d7d39f7a810ae-comps-Server.x86_64.xml.zck",
+        "4a1a7a9d98dd9764f67d4a608828fa8afca99889afe8b178228f5d37959c1ebf");
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_compress() {
+        let input = File::open(
+            "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml",
+        )
+        .unwrap();
+
+        let output = Builder::new()
+            .prefix("unittest-")
+            .suffix(".zck")
+            .tempfile_in("testdata/")
+            .unwrap();
+
+        let temp = Builder::new()
+            .prefix("unittest-")
+            .tempfile_in("testdata/")
+            .unwrap();
+
+        let mut encoder = Encoder::new(input, temp).unwrap();
+        encoder.prepare_chunks().unwrap();
+        encoder.compress_to(output.as_file()).unwrap();
+
+        test_decoder_inner(
+            output.path().to_str().unwrap(),
+            "14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68",
+        );
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_compress_in_memory() {
+        let path = "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml";
+        let input = std::fs::read(path).unwrap();
+        let mut encoder = Encoder::new_in_memory(input.as_slice()).unwrap();
+        encoder.prepare_chunks().unwrap();
+        let mut in_memory = Vec::new();
+        encoder.compress_to(&mut in_memory).unwrap();
+
+        let temp = Builder::new()
+            .prefix("unittest-")
+            .tempfile_in("testdata/")
+            .unwrap();
+        let mut encoder = Encoder::new(File::open(path).unwrap(), temp).unwrap();
+        encoder.prepare_chunks().unwrap();
+        let mut file_backed = Vec::new();
+        encoder.compress_to(&mut file_backed).unwrap();
+        assert_eq!(in_memory, file_backed);
+
+        let options = EncoderOptions::new().dict(b"<group>".repeat(64));
+        let mut encoder = Encoder::in_memory_with_options(input.as_slice(), options).unwrap();
+        encoder.prepare_chunks().unwrap();
+        let mut file = Vec::new();
+        encoder.compress_to(&mut file).unwrap();
+        let mut output = Vec::new();
+        Decoder::new(Cursor::new(file))
+            .unwrap()
+            .decompress_to(&mut output)
+            .unwrap();
+        assert_eq!(output, input);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_chunk_streams() {
+        let path = "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml";
+        let input = std::fs::read(path).unwrap();
+        let stream = |id: usize| id as u64 % 2 + 1;
+        for options in [
+            EncoderOptions::new(),
+            EncoderOptions::new().dict(b"<group>".repeat(64)),
+            EncoderOptions::new()
+                .dict(pseudo_random_dict(64 * 1024))
+                .auto_drop_ineffective_dict(0.0),
+        ] {
+            let options = options.chunk_streams(std::sync::Arc::new(move |id, _| stream(id)));
+            let mut encoder = Encoder::in_memory_with_options(input.as_slice(), options).unwrap();
+            encoder.prepare_chunks().unwrap();
+            let mut file = Vec::new();
+            encoder.compress_to(&mut file).unwrap();
+
+            let mut decoder = Decoder::new(Cursor::new(file.as_slice())).unwrap();
+            let header = decoder.header();
+            assert!(header.preface.flags.has_stream());
+            assert_eq!(header.index.dict_chunk.stream(), Some(0));
+            let streams: Vec<Option<u64>> = header
+                .index
+                .data_chunks
+                .iter()
+                .map(|(c, _)| c.stream())
+                .collect();
+            assert!(streams.len() > 2);
+            assert!(streams
+                .iter()
+                .enumerate()
+                .all(|(id, s)| *s == Some(stream(id))));
+            assert!(decoder.verify(&VerifyOptions::new()).unwrap().is_ok());
+            let mut output = Vec::new();
+            decoder.decompress_to(&mut output).unwrap();
+            assert_eq!(output, input);
+        }
+
+        let mut chunk = Chunk::new(Checksum::zeroed(DEFAULT_CHECKSUM_TYPE.digest_size()), 1, 1);
+        chunk.stream = Some(1.into());
+        assert!(matches!(
+            Index::new(None, vec![chunk]),
+            Err(ZchunkError::InconsistentChunkStreams)
+        ));
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_optional_elements() {
+        let path = "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml";
+        let input = std::fs::read(path).unwrap();
+        let elements = vec![
+            OptionalElement::new(1, Vec::new()),
+            OptionalElement::new(0x1234, (0..5000).map(|i| i as u8).collect()),
+        ];
+        let options = elements
+            .iter()
+            .fold(EncoderOptions::new(), |o, e| o.optional_element(e.clone()));
+        let mut encoder = Encoder::in_memory_with_options(input.as_slice(), options).unwrap();
+        encoder.prepare_chunks().unwrap();
+        let mut file = Vec::new();
+        encoder.compress_to(&mut file).unwrap();
+
+        let mut decoder = Decoder::new(Cursor::new(file.as_slice())).unwrap();
+        let header = decoder.header();
+        header.check_checksum().unwrap();
+        assert!(header.preface.flags.has_optional());
+        assert_eq!(header.optional_elements(), elements.as_slice());
+
+        // the preface is written and sized with its elements
+        let preface_start = header.lead.byte_size();
+        let mut preface = Vec::new();
+        header.preface.write_to(&mut preface).unwrap();
+        assert_eq!(preface.len(), header.preface.byte_size());
+        assert_eq!(
+            file[preface_start..preface_start + preface.len()],
+            preface[..]
+        );
+        let parsed = Preface::from_reader(preface.as_slice()).unwrap();
+        assert_eq!(parsed.optional_elements, elements);
+
+        let mut output = Vec::new();
+        decoder.decompress_to(&mut output).unwrap();
+        assert_eq!(output, input);
+
+        // no elements, no flag
+        let mut encoder = Encoder::new_in_memory(input.as_slice()).unwrap();
+        encoder.prepare_chunks().unwrap();
+        let mut file = Vec::new();
+        encoder.compress_to(&mut file).unwrap();
+        let header = Decoder::new(Cursor::new(file)).unwrap().header;
+        assert!(!header.preface.flags.has_optional());
+        assert!(header.optional_elements().is_empty());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_encode_stats() {
+        let path = "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml";
+        let input = std::fs::read(path).unwrap();
+        for options in [
+            EncoderOptions::new(),
+            EncoderOptions::new().dict(b"<group>".repeat(64)),
+        ] {
+            let mut encoder = Encoder::in_memory_with_options(input.as_slice(), options).unwrap();
+            assert!(matches!(encoder.stats(), Err(ZchunkError::HeaderNotFound)));
+            encoder.prepare_chunks().unwrap();
+            let mut file = Vec::new();
+            encoder.compress_to(&mut file).unwrap();
+            let stats = encoder.stats().unwrap();
+
+            let header = Decoder::new(Cursor::new(file.as_slice())).unwrap().header;
+            let sizes: Vec<u64> = header
+                .index
+                .data_chunks
+                .iter()
+                .map(|(c, _)| c.uncompressed_length.to_u64().unwrap())
+                .collect();
+            assert_eq!(stats.chunks, sizes.len());
+            assert!(stats.chunks > 1);
+            assert_eq!(stats.input_bytes, input.len() as u64);
+            assert_eq!(stats.header_bytes, header.data_offset().unwrap());
+            assert_eq!(
+                stats.dict_bytes,
+                header.index.dict_chunk.length.to_u64().unwrap()
+            );
+            assert_eq!(stats.file_size(), file.len() as u64);
+            let chunk_sizes = stats.chunk_sizes.unwrap();
+            assert_eq!(chunk_sizes.min, *sizes.iter().min().unwrap());
+            assert_eq!(chunk_sizes.max, *sizes.iter().max().unwrap());
+            assert_eq!(chunk_sizes.average, input.len() as f64 / sizes.len() as f64);
+        }
+
+        let mut encoder = Encoder::new_in_memory(&[][..]).unwrap();
+        encoder.prepare_chunks().unwrap();
+        let stats = encoder.stats().unwrap();
+        assert_eq!((stats.chunks, stats.input_bytes), (0, 0));
+        assert_eq!(stats.chunk_sizes, None);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_encoder_header() {
+        let path = "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml";
+        let input = std::fs::read(path).unwrap();
+        let options = EncoderOptions::new().dict(b"<group>".repeat(64));
+        let mut encoder = Encoder::in_memory_with_options(input.as_slice(), options).unwrap();
+        assert!(encoder.header().is_none());
+        encoder.prepare_chunks().unwrap();
+        let mut file = Vec::new();
+        encoder.compress_to(&mut file).unwrap();
+        let header = encoder.header().unwrap();
+
+        let partial = PartialDecoder::peek(Cursor::new(file.as_slice())).unwrap();
+        assert_eq!(header.header_checksum(), partial.header_checksum());
+        assert_eq!(header.data_checksum(), partial.data_checksum());
+        assert_eq!(
+            header.header_size().unwrap(),
+            partial.header_size().unwrap()
+        );
+        assert_eq!(header.file_size().unwrap(), file.len() as u64);
+        let data_start = header.header_size().unwrap() as usize;
+        assert_eq!(
+            header.data_size().unwrap(),
+            (file.len() - data_start) as u64
+        );
+        assert_eq!(
+            header.data_checksum()[..],
+            Sha256::digest(&file[data_start..])[..]
+        );
+
+        let decoded = Decoder::new(Cursor::new(file.as_slice())).unwrap().header;
+        let entries = header.index_entries().unwrap();
+        assert_eq!(entries, decoded.index_entries().unwrap());
+        assert!(entries.len() > 1);
+        let checksum_type = decoded.checksum_type().unwrap();
+        let mut uncompressed = 0;
+        for (id, entry) in entries.iter().enumerate() {
+            let range = decoded.chunk_range(id).unwrap();
+            assert_eq!(range.start, data_start as u64 + entry.offset);
+            assert_eq!(range.end - range.start, entry.length);
+            let bytes = &file[range.start as usize..range.end as usize];
+            let checksum = compute_checksum(checksum_type, bytes).unwrap();
+            assert_eq!(checksum.as_bytes(), entry.checksum);
+            uncompressed += entry.uncompressed_length;
+        }
+        assert_eq!(uncompressed, input.len() as u64);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_detached_header() {
+        let path = "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml";
+        let input = std::fs::read(path).unwrap();
+        let mut encoder = Encoder::new_in_memory(input.as_slice()).unwrap();
+        assert!(matches!(
+            encoder.write_detached_header(Vec::new()),
+            Err(ZchunkError::HeaderNotFound)
+        ));
+        encoder.prepare_chunks().unwrap();
+        let mut file = Vec::new();
+        encoder.compress_to(&mut file).unwrap();
+        let mut detached = Vec::new();
+        encoder.write_detached_header(&mut detached).unwrap();
+
+        let header = Decoder::new(Cursor::new(file.as_slice())).unwrap().header;
+        let data_offset = header.data_offset().unwrap() as usize;
+        assert_eq!(detached.len(), data_offset);
+        assert_eq!(&detached[..5], b"\0ZHR1");
+        // only the magic and the header checksum differ
+        let checksum_start = header.lead.byte_size() - 32;
+        assert_eq!(detached[5..checksum_start], file[5..checksum_start]);
+        assert_eq!(
+            detached[header.lead.byte_size()..],
+            file[header.lead.byte_size()..data_offset]
+        );
+
+        let decoder = Decoder::new(Cursor::new(detached.as_slice())).unwrap();
+        decoder.header().check_checksum().unwrap();
+        assert_eq!(
+            decoder.header().export_chunk_keys().unwrap(),
+            header.export_chunk_keys().unwrap()
+        );
+
+        let joined = [detached.as_slice(), &file[data_offset..]].concat();
+        let mut output = Vec::new();
+        Decoder::new(Cursor::new(joined))
+            .unwrap()
+            .decompress_to(&mut output)
+            .unwrap();
+        assert_eq!(output, input);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_compress_into_seekable() {
+        let path = "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml";
+        let input = std::fs::read(path).unwrap();
+        let size = input.len() as u64;
+        // no hint leaves too little room, a huge one too much
+        for options in [
+            EncoderOptions::new(),
+            EncoderOptions::new().input_size_hint(size),
+            EncoderOptions::new().input_size_hint(u64::MAX),
+            EncoderOptions::new()
+                .input_size_hint(size)
+                .reserve_signature(7, 600),
+            EncoderOptions::new()
+                .dict(pseudo_random_dict(64 * 1024))
+                .auto_drop_ineffective_dict(0.0),
+        ] {
+            let mut encoder =
+                Encoder::in_memory_with_options(input.as_slice(), options.clone()).unwrap();
+            encoder.prepare_chunks().unwrap();
+            let mut expected = Vec::new();
+            encoder.compress_to(&mut expected).unwrap();
+
+            let mut out = Cursor::new(Vec::new());
+            let len = Encoder::compress_into_seekable(input.as_slice(), &mut out, options).unwrap();
+            let mut single_pass = out.into_inner();
+            assert!(single_pass.len() as u64 >= len);
+            single_pass.truncate(len as usize);
+            assert_eq!(single_pass, expected);
+        }
+
+        // a reused file keeps stale bytes past the returned size
+        let mut file = Builder::new()
+            .prefix("unittest-")
+            .tempfile_in("testdata/")
+            .unwrap();
+        file.write_all(&[0xff; 1 << 20]).unwrap();
+        let len = Encoder::compress_into_seekable(
+            input.as_slice(),
+            file.as_file_mut(),
+            EncoderOptions::new(),
+        )
+        .unwrap();
+        assert_eq!(file.as_file().metadata().unwrap().len(), 1 << 20);
+        file.as_file().set_len(len).unwrap();
+        let mut output = Vec::new();
+        Decoder::new(BufReader::new(file.reopen().unwrap()))
+            .unwrap()
+            .decompress_to(&mut output)
+            .unwrap();
+        assert_eq!(output, input);
+    }
+
+    #[cfg(feature = "sha512")]
+    #[test]
+    fn test_sync() {
+        let source_file = File::open("testdata/c25ffa05cf1fdeb67801847df96c33933b1ee1ea081af52edff4ff371a1c814c-comps-Server.x86_64.xml.zck").unwrap();
+        let mut source_reader = BufReader::new(source_file);
+
+        let cache_file = File::open("testdata/3c6181c789ef9e8ed23f4072eb2f8f529002abd5166273a9734d7d39f7a810ae-comps-Server.x86_64.xml.zck").unwrap();
+        let mut cache_reader = BufReader::new(cache_file);
+
+        let mut source_decoder = Decoder::new(&mut source_reader).unwrap();
+        let cache_decoder = Decoder::new(&mut cache_reader).unwrap();
+        let mut hasher = Sha256::new();
+        source_decoder.sync_to(cache_decoder, &mut hasher).unwrap();
+        assert_eq!(
+            hex::encode(hasher.finalize()),
+            "c25ffa05cf1fdeb67801847df96c33933b1ee1ea081af52edff4ff371a1c814c"
+        );
+    }
+
+    #[cfg(all(feature = "zstd", feature = "sha512"))]
+    #[test]
+    fn test_sync_corrupt_cache() {
+        let source = std::fs::read("testdata/c25ffa05cf1fdeb67801847df96c33933b1ee1ea081af52edff4ff371a1c814c-comps-Server.x86_64.xml.zck").unwrap();
+        let mut cache = std::fs::read("testdata/3c6181c789ef9e8ed23f4072eb2f8f529002abd5166273a9734d7d39f7a810ae-comps-Server.x86_64.xml.zck").unwrap();
+
+        let mut source_decoder = Decoder::new(Cursor::new(source)).unwrap();
+        let cache_decoder = Decoder::new(Cursor::new(cache.clone())).unwrap();
+        let cached = cache_decoder.header.lookup(
+            source_decoder
+                .header
+                .index
+                .data_chunks
+                .iter()
+                .map(|(c, _)| c.checksum),
+        );
+        let shared = cached.iter().position(Option::is_some).unwrap();
+        let (_, offset) = cached[shared].unwrap();
+
+        // flip a byte inside the shared chunk of the cache
+        cache[(cache_decoder.header_size + offset) as usize + 1] ^= 0xff;
+        let mut cache_decoder = Decoder::new(Cursor::new(cache)).unwrap();
+
+        let mut output = Vec::new();
+        let stats = source_decoder
+            .sync_to(&mut cache_decoder, &mut output)
+            .unwrap();
+        assert_eq!(stats.cache_fallbacks, vec![shared]);
+        assert_eq!(
+            stats.chunks_from_cache + stats.chunks_from_source,
+            source_decoder.header.index.data_chunks.len()
+        );
+
+        let mut hasher = Sha256::new();
+        Decoder::new(Cursor::new(output))
+            .unwrap()
+            .decompress_to(&mut hasher)
+            .unwrap();
+        assert_eq!(
+            hex::encode(hasher.finalize()),
+            "14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68"
+        );
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_decompress_with_dict() {
+        let dict = b"<group><id>core</id><name>Core</name></group>".to_vec();
+        let compress = |data: &[u8]| {
+            let mut encoder = zstd::Encoder::with_dictionary(Vec::new(), 3, &dict).unwrap();
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+        };
+        let payloads = vec![
+            zstd::encode_all(dict.as_slice(), 3).unwrap(),
+            compress(b"<group><id>base</id>"),
+            compress(b"<name>Base</name></group>"),
+        ];
+
+        let bytes = HeaderBuilder::new()
+            .dict("00000000000000000000000000000000", 0, dict.len() as u64)
+            .chunk("00000000000000000000000000000000", 0, 20)
+            .chunk("00000000000000000000000000000000", 0, 26)
+            .auto_checksums()
+            .to_file_bytes(&payloads)
+            .unwrap();
+
+        let mut decoder = Decoder::new(Cursor::new(bytes)).unwrap();
+        assert!(decoder.header.index.has_dict());
+        let mut output = Vec::new();
+        decoder.decompress_to(&mut output).unwrap();
+        assert_eq!(output, b"<group><id>base</id><name>Base</name></group>");
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_index_builder_dict() {
+        // an index built from the chunks of an encoded file with a dict is the index of the file
+        let input = std::fs::read(
+            "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml",
+        )
+        .unwrap();
+        let dict = b"<group><id>core</id><name>Core</name></group>".repeat(8);
+        let options = EncoderOptions::new()
+            .checksum_type(ChecksumType::Sha256)
+            .dict(dict.clone());
+        let mut file = Vec::new();
+        Encoder::compress_small(&input, &mut file, options).unwrap();
+        let decoder = Decoder::new(Cursor::new(file.as_slice())).unwrap();
+        let index = &decoder.header.index;
+
+        let built = IndexBuilder::new()
+            .checksum_type(ChecksumType::Sha256)
+            .dict_chunk(index.dict_chunk.clone())
+            .chunks(index.data_chunks.iter().map(|(chunk, _)| chunk.clone()))
+            .build()
+            .unwrap();
+        assert!(built.has_dict());
+        assert_eq!(
+            built.dict_chunk.uncompressed_length.to_u64().unwrap(),
+            dict.len() as u64
+        );
+        let (mut expected, mut found) = (Vec::new(), Vec::new());
+        index.write_to(&mut expected).unwrap();
+        built.write_to(&mut found).unwrap();
+        assert_eq!(found, expected);
+        assert_eq!(
+            built.data_chunks[0].1,
+            built.dict_chunk.length.to_u64().unwrap()
+        );
+
+        // without a dict chunk the entry is written empty, and the data starts at 0
+        let built = IndexBuilder::new()
+            .checksum_type(ChecksumType::Sha256)
+            .chunk(index.data_chunks[0].0.clone())
+            .build()
+            .unwrap();
+        assert!(!built.has_dict());
+        assert_eq!(built.data_chunks[0].1, 0);
+
+        let mut dict_chunk = index.dict_chunk.clone();
+        dict_chunk.uncompressed_length = 0.into();
+        assert!(matches!(
+            IndexBuilder::new()
+                .checksum_type(ChecksumType::Sha256)
+                .dict_chunk(dict_chunk)
+                .build(),
+            Err(ZchunkError::InvalidDictChunk {
+                uncompressed_length: 0,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_invalid_dict_chunk() {
+        let err = HeaderBuilder::new()
+            .dict("00000000000000000000000000000000", 10, 0)
+            .build()
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ZchunkError::InvalidDictChunk {
+                length: 10,
+                uncompressed_length: 0
+            }
+        ));
+
+        let err = HeaderBuilder::new()
+            .dict("00000000000000000000000000000000", 0, 10)
+            .build()
+            .unwrap_err();
+        assert!(matches!(err, ZchunkError::InvalidDictChunk { .. }));
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_encoder_checksum_type() {
+        let input = b"<group><id>base</id></group>".repeat(100);
+        let encode = |options: EncoderOptions| {
+            Encoder::with_options(input.as_slice(), Cursor::new(Vec::new()), options).map(
+                |mut encoder| {
+                    encoder.prepare_chunks().unwrap();
+                    let mut output = Vec::new();
+                    encoder.compress_to(&mut output).unwrap();
+                    output
+                },
+            )
+        };
+
+        let output = encode(EncoderOptions::new().checksum_type(ChecksumType::Sha256)).unwrap();
+        let mut decoder = Decoder::new(Cursor::new(output)).unwrap();
+        assert_eq!(
+            decoder.header().checksum_type().unwrap(),
+            ChecksumType::Sha256
+        );
+        assert!(decoder.verify(&VerifyOptions::new()).unwrap().is_ok());
+        let mut decompressed = Vec::new();
+        decoder.decompress_to(&mut decompressed).unwrap();
+        assert_eq!(decompressed, input);
+
+        assert!(matches!(
+            encode(EncoderOptions::new().checksum_type(ChecksumType::Sha1)),
+            Err(ZchunkError::InvalidChecksumType(0))
+        ));
+
+        let output = encode(EncoderOptions::new()).unwrap();
+        let default = Decoder::new(Cursor::new(output))
+            .unwrap()
+            .header()
+            .checksum_type()
+            .unwrap();
+        if cfg!(feature = "sha512") {
+            assert_eq!(default, ChecksumType::Sha512_128);
+            encode(EncoderOptions::new().checksum_type(ChecksumType::Sha512)).unwrap();
+        } else {
+            assert_eq!(default, ChecksumType::Sha256);
+            assert!(matches!(
+                encode(EncoderOptions::new().checksum_type(ChecksumType::Sha512)),
+                Err(ZchunkError::UnsupportedChecksumType(ChecksumType::Sha512))
+            ));
+        }
+    }
+
+    #[cfg(not(feature = "sha512"))]
+    #[test]
+    fn test_sha512_compiled_out() {
+        let file = File::open("testdata/c25ffa05cf1fdeb67801847df96c33933b1ee1ea081af52edff4ff371a1c814c-comps-Server.x86_64.xml.zck").unwrap();
+        assert!(matches!(
+            Decoder::new(BufReader::new(file)),
+            Err(ZchunkError::UnsupportedChecksumType(
+                ChecksumType::Sha512_128
+            ))
+        ));
+
+        // SHA-256 chunks are still verified
+        let payloads = vec![b"first".to_vec(), b"second".to_vec()];
+        let bytes = HeaderBuilder::new()
+            .checksum_type(ChecksumType::Sha256)
+            .chunk("00000000000000000000000000000000", 0, 5)
+            .chunk("00000000000000000000000000000000", 0, 6)
+            .auto_checksums()
+            .to_file_bytes(&payloads)
+            .unwrap();
+        let mut decoder = Decoder::new(Cursor::new(bytes)).unwrap();
+        let mut output = Vec::new();
+        decoder.copy_chunks_to(&[0, 1], &mut output).unwrap();
+        assert_eq!(output, b"firstsecond");
+    }
+
+    /// Everything the map- and list-returning APIs report about the test files, serialized
+    #[cfg(feature = "sha512")]
+    fn report_all() -> String {
+        const NEW: &str = "testdata/c25ffa05cf1fdeb67801847df96c33933b1ee1ea081af52edff4ff371a1c814c-comps-Server.x86_64.xml.zck";
+        const OLD: &str = "testdata/3c6181c789ef9e8ed23f4072eb2f8f529002abd5166273a9734d7d39f7a810ae-comps-Server.x86_64.xml.zck";
+        let open = |path| Decoder::new(BufReader::new(File::open(path).unwrap())).unwrap();
+        let mut new = open(NEW);
+        let mut old = open(OLD);
+        let header = new.header();
+        let checksums: Vec<_> = old
+            .header()
+            .index
+            .data_chunks
+            .iter()
+            .map(|(c, _)| c.checksum)
+            .collect();
+
+        let mut report = Vec::new();
+        report.push(format!("{:?}", header.lookup(checksums)));
+        #[allow(deprecated)]
+        let found = header.find_data_chunks(
+            old.header()
+                .index
+                .data_chunks
+                .iter()
+                .map(|(c, _)| c.clone())
+                .collect(),
+        );
+        report.push(format!("{found:?}"));
+        report.push(format!("{:?}", header.anomalies(AnomalyOptions::new())));
+        report.push(format!("{:?}", header.export_chunk_keys().unwrap()));
+        report.push(format!("{:?}", header.partition_by_ratio(3.0)));
+        report.push(format!("{:?}", header.chunk_stats()));
+        let mut availability = ChunkAvailability::all(header.index.data_chunks.len());
+        availability.set(0, false);
+        availability.set(2, false);
+        let missing = header.missing_ranges(&availability).unwrap();
+        report.push(format!(
+            "{:?}",
+            RangePlanner::coalesce(&missing, &CoalescePolicy::default())
+        ));
+        report.push(format!("{:?}", new.verify(&VerifyOptions::new()).unwrap()));
+        let mut synced = Vec::new();
+        report.push(format!("{:?}", new.sync_to(&mut old, &mut synced).unwrap()));
+        report.push(hex::encode(Sha256::digest(&synced)));
+        report.join("\n")
+    }
+
+    #[cfg(feature = "sha512")]
+    #[test]
+    fn test_deterministic_output() {
+        let first = report_all();
+        for _ in 0..3 {
+            assert_eq!(report_all(), first);
+        }
+    }
+
+    #[test]
+    fn test_chunk_ratios() {
+        const ZERO: &str = "00000000000000000000000000000000";
+        let header = HeaderBuilder::new()
+            .chunk(ZERO, 10, 10)
+            .chunk(ZERO, 10, 20)
+            .chunk(ZERO, 10, 40)
+            .chunk(ZERO, 20, 10)
+            .chunk(ZERO, 0, 0)
+            .build()
+            .unwrap();
+
+        assert_eq!(header.index.data_chunks[2].0.ratio(), 4.0);
+        assert_eq!(header.index.data_chunks[4].0.ratio(), 1.0);
+        assert_eq!(
+            Chunk::new(Checksum::zeroed(16), 0, 5).ratio(),
+            f64::INFINITY
+        );
+
+        assert_eq!(header.partition_by_ratio(2.0), (vec![1, 2], vec![0, 3, 4]));
+        assert_eq!(header.partition_by_ratio(0.0).0.len(), 5);
+        assert_eq!(header.partition_by_ratio(f64::INFINITY).1.len(), 5);
+
+        let stats = header.chunk_stats();
+        assert_eq!(stats.chunks, 5);
+        assert_eq!(stats.compressed_bytes, 50);
+        assert_eq!(stats.uncompressed_bytes, 80);
+        assert_eq!(
+            stats.ratio_percentiles,
+            Some(RatioPercentiles {
+                min: 0.5,
+                p10: 0.5,
+                p50: 1.0,
+                p90: 4.0,
+                max: 4.0,
+            })
+        );
+        assert_eq!(
+            HeaderBuilder::new()
+                .build()
+                .unwrap()
+                .chunk_stats()
+                .ratio_percentiles,
+            None
+        );
+    }
+
+    #[test]
+    fn test_copy_chunks_to() {
+        const ZERO: &str = "00000000000000000000000000000000";
+        let payloads = vec![b"first".to_vec(), b"second".to_vec(), b"third".to_vec()];
+        let builder = HeaderBuilder::new()
+            .chunk(ZERO, 0, 5)
+            .chunk(ZERO, 0, 6)
+            .chunk(ZERO, 0, 5)
+            .auto_checksums();
+        let mut bytes = builder.to_file_bytes(&payloads).unwrap();
+
+        let mut decoder = Decoder::new(Cursor::new(bytes.clone())).unwrap();
+        let mut output = Vec::new();
+        decoder.copy_chunks_to(&[2, 0], &mut output).unwrap();
+        assert_eq!(output, b"thirdfirst");
+
+        let mut output = Vec::new();
+        let err = decoder.copy_chunks_to(&[0, 3], &mut output).unwrap_err();
+        assert!(matches!(err, ZchunkError::ChunkNotFound(3)));
+        assert!(output.is_empty());
+
+        // a corrupt chunk stops the copy before it is written
+        let start = decoder.header().chunk_range(1).unwrap().start as usize;
+        bytes[start] ^= 0xff;
+        let mut decoder = Decoder::new(Cursor::new(bytes)).unwrap();
+        let mut output = Vec::new();
+        let err = decoder.copy_chunks_to(&[0, 1], &mut output).unwrap_err();
+        assert!(matches!(
+            err,
+            ZchunkError::ChunkChecksumNotMatch { id: Some(1), .. }
+        ));
+        assert_eq!(output, b"first");
+    }
+
+    #[test]
+    fn test_max_size_offsets() {
+        let chunk = |length: u64| Chunk {
+            stream: None,
+            checksum: Checksum::zeroed(DEFAULT_CHECKSUM_TYPE.digest_size()),
+            length: length.into(),
+            uncompressed_length: length.into(),
+        };
+
+        // chunks ending exactly at u64::MAX are accepted
+        let index = Index::new(None, vec![chunk(u64::MAX - 1), chunk(1)]).unwrap();
+        let mut bytes = Vec::new();
+        index.write_to(&mut bytes).unwrap();
+        let read = Index::from_reader(bytes.as_slice(), PrefaceFlags::from_u64(0)).unwrap();
+        assert_eq!(read.data_chunks[1].1, u64::MAX - 1);
+
+        // but the header still sits in front of them
+        let header = HeaderBuilder::new().build().unwrap();
+        let header = Header::new(header.lead, header.preface, index, header.signatures);
+        assert!(matches!(
+            header.chunk_range(1),
+            Err(ZchunkError::SizeOverflow)
+        ));
+
+        // one more byte overflows
+        assert!(matches!(
+            Index::new(None, vec![chunk(u64::MAX - 1), chunk(2)]),
+            Err(ZchunkError::SizeOverflow)
+        ));
+        let mut overflowing = header.index;
+        overflowing.data_chunks[1].0 = chunk(2);
+        let mut bytes = Vec::new();
+        overflowing.write_to(&mut bytes).unwrap();
+        assert!(matches!(
+            Index::from_reader(bytes.as_slice(), PrefaceFlags::from_u64(0)),
+            Err(ZchunkError::SizeOverflow)
+        ));
+    }
+
+    #[test]
+    fn test_chunks_past_u32() {
+        let checksum_size = DEFAULT_CHECKSUM_TYPE.digest_size();
+        let chunk = |length: u64| Chunk::new(Checksum::zeroed(checksum_size), length, length + 1);
+        let index = Index::new(None, vec![chunk(5 << 30), chunk(1), chunk(6 << 30)]).unwrap();
+        let mut bytes = Vec::new();
+        index.write_to(&mut bytes).unwrap();
+        let read = Index::from_reader(bytes.as_slice(), PrefaceFlags::from_u64(0)).unwrap();
+        let entries: Vec<(u64, u64, u64)> = read
+            .data_chunks
+            .iter()
+            .map(|(c, offset)| {
+                (
+                    *offset,
+                    c.length.to_u64().unwrap(),
+                    c.uncompressed_length.to_u64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                (0, 5 << 30, (5 << 30) + 1),
+                (5 << 30, 1, 2),
+                ((5 << 30) + 1, 6 << 30, (6 << 30) + 1),
+            ]
+        );
+
+        // a checksum or compression type past a byte is not truncated to a valid one
+        let sha256 = super::CHECKSUM_SHA256 as u64;
+        let mut bytes = Vec::new();
+        VariantInt::from(0).write_to(&mut bytes).unwrap();
+        VariantInt::from(sha256 + 256).write_to(&mut bytes).unwrap();
+        assert!(matches!(
+            Index::from_reader(bytes.as_slice(), PrefaceFlags::from_u64(0)),
+            Err(ZchunkError::Overflow { what: "checksum type", value }) if value == sha256 + 256
+        ));
+
+        let mut bytes = vec![0; 32];
+        VariantInt::from(0).write_to(&mut bytes).unwrap();
+        VariantInt::from(1 << 8).write_to(&mut bytes).unwrap();
+        assert!(matches!(
+            Preface::from_reader(bytes.as_slice()),
+            Err(ZchunkError::Overflow {
+                what: "compression type",
+                value: 256
+            })
+        ));
+    }
+
+    /// A writer that accepts `limit` bytes and then fails
+    #[cfg(any(feature = "zstd", feature = "sha512"))]
+    struct FailingWriter {
+        limit: usize,
+        data: Vec<u8>,
+    }
+
+    #[cfg(any(feature = "zstd", feature = "sha512"))]
+    impl Write for FailingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let n = buf.len().min(self.limit - self.data.len());
+            if n == 0 && !buf.is_empty() {
+                return Err(std::io::Error::other("disk full"));
+            }
+            self.data.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[cfg(any(feature = "zstd", feature = "sha512"))]
+    fn assert_write_failed(err: ZchunkError, expected_stage: WriteStage, expected_bytes: u64) {
+        match err {
+            ZchunkError::WriteFailed {
+                stage,
+                bytes_written,
+                ..
+            } => {
+                assert_eq!(stage, expected_stage);
+                assert_eq!(bytes_written, expected_bytes);
+            }
+            e => panic!("unexpected error: {e:?}"),
+        }
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_compress_write_failed() {
+        let input = || {
+            File::open(
+                "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml",
+            )
+            .unwrap()
+        };
+        let mut encoder = Encoder::new(input(), Cursor::new(Vec::new())).unwrap();
+        encoder.prepare_chunks().unwrap();
+
+        let mut expected = Vec::new();
+        encoder.compress_to(&mut expected).unwrap();
+
+        let header = encoder.header.as_ref().unwrap();
+        let data_offset = header.data_offset().unwrap() as usize;
+        let second_chunk = data_offset + header.index.data_chunks[1].1 as usize;
+
+        for (limit, stage) in [
+            (10, WriteStage::Header),
+            (data_offset + 5, WriteStage::Chunk(0)),
+            (second_chunk, WriteStage::Chunk(1)),
+        ] {
+            encoder.reset(input());
+            encoder.prepare_chunks().unwrap();
+
+            let mut writer = FailingWriter {
+                limit,
+                data: Vec::new(),
+            };
+            let err = encoder.compress_to(&mut writer).unwrap_err();
+            assert_write_failed(err, stage, limit as u64);
+            assert_eq!(writer.data, expected[..limit]);
+
+            // retry into a sound writer
+            let mut output = Vec::new();
+            encoder.compress_to(&mut output).unwrap();
+            assert_eq!(output, expected);
+        }
+    }
+
+    #[cfg(feature = "sha512")]
+    #[test]
+    fn test_sync_write_failed() {
+        let source_file = File::open("testdata/c25ffa05cf1fdeb67801847df96c33933b1ee1ea081af52edff4ff371a1c814c-comps-Server.x86_64.xml.zck").unwrap();
+        let cache_file = File::open("testdata/3c6181c789ef9e8ed23f4072eb2f8f529002abd5166273a9734d7d39f7a810ae-comps-Server.x86_64.xml.zck").unwrap();
+        let mut source_decoder = Decoder::new(BufReader::new(source_file)).unwrap();
+        let mut cache_decoder = Decoder::new(BufReader::new(cache_file)).unwrap();
+
+        let header_size = source_decoder.header_size as usize;
+        for (limit, stage) in [
+            (header_size - 1, WriteStage::Header),
+            (header_size + 100, WriteStage::Chunk(0)),
+        ] {
+            let mut writer = FailingWriter {
+                limit,
+                data: Vec::new(),
+            };
+            let err = source_decoder
+                .sync_to(&mut cache_decoder, &mut writer)
+                .unwrap_err();
+            assert_write_failed(err, stage, limit as u64);
+
+            let mut hasher = Sha256::new();
+            source_decoder
+                .sync_to(&mut cache_decoder, &mut hasher)
+                .unwrap();
+            assert_eq!(
+                hex::encode(hasher.finalize()),
+                "c25ffa05cf1fdeb67801847df96c33933b1ee1ea081af52edff4ff371a1c814c"
+            );
+        }
+    }
+
+    #[test]
+    fn test_lookup() {
+        let header = HeaderBuilder::new()
+            .checksum_type(ChecksumType::Sha256)
+            .chunk(&"01".repeat(32), 10, 20)
+            .chunk(&"02".repeat(32), 30, 40)
+            .chunk(&"01".repeat(32), 10, 20)
+            .build()
+            .unwrap();
+
+        let checksum = |b: u8| Checksum::from_bytes(&[b; 32]).unwrap();
+        assert_eq!(
+            header.lookup([checksum(2), checksum(1), checksum(3)]),
+            vec![Some((1, 10)), Some((0, 0)), None]
+        );
+        assert_eq!(header.lookup_one(&[1; 32]), Some((0, 0)));
+        // a truncated checksum is not found
+        assert_eq!(header.lookup_one(&[1; 16]), None);
+
+        #[allow(deprecated)]
+        let found = header.find_data_chunks(vec![header.index.data_chunks[1].0.clone()]);
+        assert_eq!(found, vec![(header.index.data_chunks[1].0.clone(), 10)]);
+    }
+
+    #[cfg(feature = "zstd")]
+    fn compress_with_options(options: EncoderOptions) -> (Vec<u8>, EncodeReport) {
+        let input = File::open(
+            "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml",
+        )
+        .unwrap();
+
+        let mut encoder = Encoder::with_options(input, Cursor::new(Vec::new()), options).unwrap();
+        encoder.prepare_chunks().unwrap();
+        let mut output = Vec::new();
+        encoder.compress_to(&mut output).unwrap();
+
+        (output, encoder.report().unwrap().clone())
+    }
+
+    #[cfg(feature = "zstd")]
+    fn pseudo_random_dict(len: usize) -> Vec<u8> {
+        let mut state = 0x2545f4914f6cdd1du64;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[cfg(feature = "zstd")]
+    fn decode_and_check_has_dict(output: Vec<u8>) -> bool {
+        let mut decoder = Decoder::new(Cursor::new(output)).unwrap();
+        let has_dict = decoder.header.index.has_dict();
+        let mut hasher = Sha256::new();
+        decoder.decompress_to(&mut hasher).unwrap();
+        assert_eq!(
+            hex::encode(hasher.finalize()),
+            "14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68"
+        );
+        has_dict
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_compress_with_dict_file() {
+        let dict_file = Builder::new()
+            .prefix("unittest-")
+            .suffix(".dict")
+            .tempfile_in("testdata/")
+            .unwrap();
+        std::fs::write(dict_file.path(), b"<group>".repeat(64)).unwrap();
+        let options = EncoderOptions::new().dict_file(dict_file.path()).unwrap();
+        let (output, _) = compress_with_options(options);
+
+        // data chunks follow the dict chunk, the first one at the dict length
+        let decoder = Decoder::new(Cursor::new(output.as_slice())).unwrap();
+        let header = decoder.header();
+        let dict_length = header.index.dict_chunk.length.to_u64().unwrap();
+        assert!(dict_length > 0);
+        assert_eq!(header.index.data_chunks[0].1, dict_length);
+        let data_offset = header.data_offset().unwrap();
+        for id in 0..header.index.data_chunks.len() {
+            let range = header.chunk_range(id).unwrap();
+            assert!(range.start >= data_offset + dict_length);
+            header
+                .verify_chunk_bytes(id, &output[range.start as usize..range.end as usize])
+                .unwrap();
+        }
+        assert!(decode_and_check_has_dict(output));
+
+        assert!(matches!(
+            EncoderOptions::new().dict_file("testdata/missing.dict"),
+            Err(ZchunkError::Io(_))
+        ));
+    }
+
+    #[cfg(all(feature = "zstd", feature = "sha512"))]
+    #[test]
+    fn test_header_checksum_types() {
+        for checksum_type in [ChecksumType::Sha256, ChecksumType::Sha512] {
+            let options = EncoderOptions::new().header_checksum_type(checksum_type);
+            let (mut output, _) = compress_with_options(options);
+
+            let decoder_of = |bytes: &[u8]| Decoder::new(Cursor::new(bytes.to_vec())).unwrap();
+            let decoder = decoder_of(&output);
+            let lead = &decoder.header().lead;
+            assert_eq!(lead.checksum_type().unwrap(), checksum_type);
+            assert_eq!(lead.header_checksum.len(), checksum_type.digest_size());
+            assert_eq!(
+                decoder.header().computed_checksum().unwrap(),
+                lead.header_checksum
+            );
+            let data_offset = decoder.header().data_offset().unwrap() as usize;
+            let mut decompressed = Sha256::new();
+            decoder_of(&output)
+                .decompress_to(&mut decompressed)
+                .unwrap();
+            assert_eq!(
+                hex::encode(decompressed.finalize()),
+                "14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68"
+            );
+
+            // a chunk checksum in the index is covered by the header checksum
+            let chunk_checksum = decoder.header().index.data_chunks[0].0.checksum;
+            let at = output[..data_offset]
+                .windows(chunk_checksum.len())
+                .position(|w| chunk_checksum == *w)
+                .unwrap();
+            output[at] ^= 0xff;
+            let mut decoder = Decoder::new(Cursor::new(output)).unwrap();
+            assert!(matches!(
+                decoder.decompress_to(Vec::new()),
+                Err(ZchunkError::HeaderChecksumNotMatch { expected, found })
+                    if expected.len() == checksum_type.digest_size() && found != expected
+            ));
+        }
+
+        for checksum_type in [ChecksumType::Sha1, ChecksumType::Sha512_128] {
+            let options = EncoderOptions::new().header_checksum_type(checksum_type);
+            assert!(matches!(
+                Encoder::with_options(std::io::empty(), Cursor::new(Vec::new()), options),
+                Err(ZchunkError::InvalidChecksumType(_))
+            ));
+        }
+    }
+
+    #[cfg(all(feature = "zstd", feature = "sha512"))]
+    #[test]
+    fn test_chunk_checksum_types() {
+        for checksum_type in [
+            ChecksumType::Sha256,
+            ChecksumType::Sha512,
+            ChecksumType::Sha512_128,
+        ] {
+            let options = EncoderOptions::new()
+                .checksum_type(checksum_type)
+                .dict(b"<group>".repeat(64));
+            let (output, _) = compress_with_options(options);
+
+            let mut decoder = Decoder::new(Cursor::new(output.clone())).unwrap();
+            let header = decoder.header();
+            assert_eq!(header.checksum_type().unwrap(), checksum_type);
+            let width = checksum_type.digest_size();
+            assert_eq!(header.index.dict_chunk.checksum.len(), width);
+            assert!(header
+                .index
+                .data_chunks
+                .iter()
+                .all(|(c, _)| c.checksum.len() == width));
+
+            // a chunk checksum is the digest at the full width of the type
+            let range = header.chunk_range(0).unwrap();
+            let data = &output[range.start as usize..range.end as usize];
+            let checksum = header.index.data_chunks[0].0.checksum;
+            assert_eq!(checksum, compute_checksum(checksum_type, data).unwrap());
+            header.verify_chunk_bytes(0, data).unwrap();
+
+            assert!(decoder.verify(&VerifyOptions::new()).unwrap().is_ok());
+            let mut decompressed = Sha256::new();
+            decoder.decompress_to(&mut decompressed).unwrap();
+            assert_eq!(
+                hex::encode(decompressed.finalize()),
+                "14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68"
+            );
+
+            // a corrupt chunk is caught with checksums of the declared width
+            let mut corrupt = output.clone();
+            corrupt[range.start as usize] ^= 0xff;
+            let mut decoder = Decoder::new(Cursor::new(corrupt)).unwrap();
+            assert!(matches!(
+                decoder.decompress_to(Vec::new()),
+                Err(ZchunkError::ChunkChecksumNotMatch { id: Some(0), expected, found, .. })
+                    if *expected == checksum && found.len() == width
+            ));
+        }
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_encoder_reset() {
+        let paths = [
+            "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml",
+            "testdata/4a1a7a9d98dd9764f67d4a608828fa8afca99889afe8b178228f5d37959c1ebf-comps-Server.x86_64.xml",
+        ];
+        let inputs = paths.map(|path| std::fs::read(path).unwrap());
+        let encode = |options: EncoderOptions, input: &[u8]| {
+            let mut encoder = Encoder::in_memory_with_options(input, options).unwrap();
+            encoder.prepare_chunks().unwrap();
+            let mut file = Vec::new();
+            encoder.compress_to(&mut file).unwrap();
+            file
+        };
+
+        let small_chunks = ChunkerParams::new(1024, 8192, 2047);
+        for options in [
+            EncoderOptions::new(),
+            EncoderOptions::new().dict(b"<group>".repeat(64)),
+            EncoderOptions::new()
+                .chunker_params(small_chunks)
+                .auto_dict(16 * 1024),
+        ] {
+            // the temp still holds the chunks of the previous input
+            let mut encoder =
+                Encoder::in_memory_with_options(inputs[0].as_slice(), options.clone()).unwrap();
+            for (i, input) in inputs.iter().enumerate() {
+                if i > 0 {
+                    encoder.reset(input.as_slice());
+                    assert!(matches!(
+                        encoder.compress_to(Vec::new()),
+                        Err(ZchunkError::HeaderNotFound)
+                    ));
+                    assert!(encoder.report().is_none());
+                }
+                encoder.prepare_chunks().unwrap();
+                let mut file = Vec::new();
+                encoder.compress_to(&mut file).unwrap();
+                assert_eq!(file, encode(options.clone(), input));
+
+                let mut output = Vec::new();
+                Decoder::new(Cursor::new(file))
+                    .unwrap()
+                    .decompress_to(&mut output)
+                    .unwrap();
+                assert_eq!(&output, input);
+            }
+        }
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_dict_from() {
+        let chunker_params = ChunkerParams::new(1024, 8192, 2047);
+        let (old, _) = compress_with_options(
+            EncoderOptions::new()
+                .chunker_params(chunker_params.clone())
+                .checksum_type(ChecksumType::Sha256)
+                .auto_dict(16 * 1024),
+        );
+        let mut old = Decoder::new(Cursor::new(old)).unwrap();
+        let options = EncoderOptions::new()
+            .chunker_params(chunker_params)
+            .dict_from(&mut old)
+            .unwrap();
+
+        // the next version of the input, compressed with the dict of the previous one
+        let input = File::open(
+            "testdata/4a1a7a9d98dd9764f67d4a608828fa8afca99889afe8b178228f5d37959c1ebf-comps-Server.x86_64.xml",
+        )
+        .unwrap();
+        let mut encoder = Encoder::with_options(input, Cursor::new(Vec::new()), options).unwrap();
+        encoder.prepare_chunks().unwrap();
+        let mut new = Vec::new();
+        encoder.compress_to(&mut new).unwrap();
+        let new = Decoder::new(Cursor::new(new)).unwrap();
+
+        assert_eq!(new.header().index.dict_chunk, old.header().index.dict_chunk);
+        let new_chunks: Vec<Chunk> = new
+            .header()
+            .index
+            .data_chunks
+            .iter()
+            .map(|(c, _)| c.clone())
+            .collect();
+        #[allow(deprecated)]
+        let shared = old.header().find_data_chunks(new_chunks.clone()).len();
+        assert!(
+            shared * 10 > new_chunks.len() * 8,
+            "{shared} of {}",
+            new_chunks.len()
+        );
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_compress_auto_dict() {
+        // upstream trains on the small chunks of repodata, the default ones are too few
+        let options = EncoderOptions::new().chunker_params(ChunkerParams::new(1024, 8192, 2047));
+        let (plain, _) = compress_with_options(options.clone());
+        let (trained, report) = compress_with_options(options.auto_dict(16 * 1024));
+        assert!(report.dict_trained);
+        assert!(!report.dict_dropped);
+        assert!(
+            trained.len() < plain.len(),
+            "{} {}",
+            trained.len(),
+            plain.len()
+        );
+        let dict_length = Decoder::new(Cursor::new(trained.as_slice()))
+            .unwrap()
+            .header()
+            .index
+            .dict_chunk
+            .uncompressed_length
+            .to_u64()
+            .unwrap();
+        assert!(dict_length > 0 && dict_length <= 16 * 1024);
+        assert!(decode_and_check_has_dict(trained));
+
+        // a single chunk is too little to train from
+        let input = b"<group><id>core</id></group>".to_vec();
+        let options = EncoderOptions::new().auto_dict(16 * 1024);
+        let mut encoder =
+            Encoder::with_options(input.as_slice(), Cursor::new(Vec::new()), options).unwrap();
+        encoder.prepare_chunks().unwrap();
+        assert!(!encoder.report().unwrap().dict_trained);
+        let mut output = Vec::new();
+        encoder.compress_to(&mut output).unwrap();
+        let mut decoder = Decoder::new(Cursor::new(output)).unwrap();
+        assert!(!decoder.header.index.has_dict());
+        let mut decompressed = Vec::new();
+        decoder.decompress_to(&mut decompressed).unwrap();
+        assert_eq!(decompressed, input);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_compress_reports_ineffective_dict() {
+        let (output, report) =
+            compress_with_options(EncoderOptions::new().dict(pseudo_random_dict(64 * 1024)));
+
+        let effectiveness = report.dict_effectiveness.unwrap();
+        assert!(effectiveness.sampled_chunks > 0);
+        assert!(effectiveness.estimated_saving() < 0);
+        assert!(effectiveness.regression() > 0.0);
+        assert!(!report.dict_dropped);
+        assert!(decode_and_check_has_dict(output));
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_compress_drops_ineffective_dict() {
+        let (output, report) = compress_with_options(
+            EncoderOptions::new()
+                .dict(pseudo_random_dict(64 * 1024))
+                .auto_drop_ineffective_dict(0.0),
+        );
+
+        assert!(report.dict_dropped);
+        assert!(report.dict_effectiveness.is_some());
+        assert!(!decode_and_check_has_dict(output));
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_compress_keeps_effective_dict() {
+        let mut dict = Vec::new();
+        File::open(
+            "testdata/4a1a7a9d98dd9764f67d4a608828fa8afca99889afe8b178228f5d37959c1ebf-comps-Server.x86_64.xml",
+        )
+        .unwrap()
+        .read_to_end(&mut dict)
+        .unwrap();
+
+        let (output, report) = compress_with_options(
+            EncoderOptions::new()
+                .dict(dict)
+                .auto_drop_ineffective_dict(0.5),
+        );
+
+        assert!(!report.dict_dropped);
+        assert!(decode_and_check_has_dict(output));
+        assert_eq!(
+            compress_with_options(EncoderOptions::new()).1,
+            EncodeReport::default()
+        );
+    }
+
+    /// Reads the fixture input, failing once the first `limit` bytes were returned
+    #[cfg(feature = "zstd")]
+    struct InterruptedReader {
+        inner: File,
+        remaining: usize,
+    }
+
+    #[cfg(feature = "zstd")]
+    impl Read for InterruptedReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.remaining == 0 {
+                return Err(std::io::Error::other("input went away"));
+            }
+            let len = buf.len().min(self.remaining);
+            let n = self.inner.read(&mut buf[..len])?;
+            self.remaining -= n;
+            Ok(n)
+        }
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_resume_prepare() {
+        const INPUT: &str = "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml";
+        let encode = |reader: Box<dyn Read>| {
+            Encoder::new(reader, Cursor::new(Vec::new())).map(|mut encoder| {
+                let result = encoder.prepare_chunks();
+                (encoder, result)
+            })
+        };
+
+        let (mut encoder, result) = encode(Box::new(File::open(INPUT).unwrap())).unwrap();
+        result.unwrap();
+        let mut expected = Vec::new();
+        encoder.compress_to(&mut expected).unwrap();
+
+        let input_len = std::fs::metadata(INPUT).unwrap().len() as usize;
+        for limit in [0, 1000, input_len / 2, input_len - 1] {
+            let reader = InterruptedReader {
+                inner: File::open(INPUT).unwrap(),
+                remaining: limit,
+            };
+            let (mut encoder, result) = encode(Box::new(reader)).unwrap();
+            let Err(ZchunkError::ReadFailed {
+                bytes_consumed,
+                chunks_completed,
+                ..
+            }) = result
+            else {
+                panic!("expected ReadFailed at {limit}, got {result:?}");
+            };
+            assert_eq!(bytes_consumed, limit as u64);
+            assert!(chunks_completed <= 3);
+
+            let mut input = File::open(INPUT).unwrap();
+            input.seek(SeekFrom::Start(bytes_consumed)).unwrap();
+            encoder.resume_prepare(Box::new(input)).unwrap();
+
+            let mut output = Vec::new();
+            encoder.compress_to(&mut output).unwrap();
+            assert_eq!(output, expected, "resumed at {limit}");
+        }
+
+        let (mut encoder, _) = encode(Box::new(File::open(INPUT).unwrap())).unwrap();
+        assert!(matches!(
+            encoder.resume_prepare(Box::new(std::io::empty())),
+            Err(ZchunkError::NothingToResume)
+        ));
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn test_bytes_round_trip() {
+        let file = File::open("testdata/c25ffa05cf1fdeb67801847df96c33933b1ee1ea081af52edff4ff371a1c814c-comps-Server.x86_64.xml.zck").unwrap();
+        let mut decoder = Decoder::new(BufReader::new(file)).unwrap();
+
+        let count = decoder.header.index.data_chunks.len();
+        let mut chunks = Vec::new();
+        for id in 0..count {
+            let chunk = decoder.decompress_chunk_bytes(id).unwrap();
+            assert_eq!(chunk, decoder.decompress_chunk(id).unwrap());
+            chunks.push(chunk);
+        }
+
+        let lengths: Vec<usize> = chunks.iter().map(|c| c.len()).collect();
+
+        // the encoder reads every chunk from its buffer, without a copy
+        let seen = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let record = seen.clone();
+        let options =
+            EncoderOptions::new().chunk_streams(std::sync::Arc::new(move |_, data: &[u8]| {
+                record.lock().unwrap().push(data.as_ptr() as usize);
+                0
+            }));
+        Encoder::from_chunks_bytes(
+            chunks.clone().into_iter(),
+            io::Cursor::new(Vec::new()),
+            options,
+        )
+        .unwrap()
+        .prepare_chunks()
+        .unwrap();
+        let pointers: Vec<usize> = chunks.iter().map(|c| c.as_ptr() as usize).collect();
+        assert_eq!(*seen.lock().unwrap(), pointers);
+
+        let mut encoder = Encoder::from_chunks_bytes(
+            chunks.into_iter(),
+            Cursor::new(Vec::new()),
+            EncoderOptions::new(),
+        )
+        .unwrap();
+        encoder.prepare_chunks().unwrap();
+        let mut output = Vec::new();
+        encoder.compress_to(&mut output).unwrap();
+
+        let mut decoder = Decoder::new(Cursor::new(output)).unwrap();
+        let decoded_lengths: Vec<usize> = (0..count)
+            .map(|id| decoder.decompress_chunk_bytes(id).unwrap().len())
+            .collect();
+        assert_eq!(decoded_lengths, lengths);
+
+        let mut hasher = Sha256::new();
+        decoder.decompress_to(&mut hasher).unwrap();
+        assert_eq!(
+            hex::encode(hasher.finalize()),
+            "14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68"
+        );
+
+        // a chunk dropped before the next is decompressed leaves its allocation to it, a
+        // chunk still held does not
+        let first = decoder.decompress_chunk_bytes(0).unwrap();
+        let at = first.as_ptr();
+        drop(first);
+        let again = decoder.decompress_chunk_bytes(0).unwrap();
+        assert_eq!(again.as_ptr(), at);
+        let held = decoder.decompress_chunk_bytes(0).unwrap();
+        assert_ne!(held.as_ptr(), again.as_ptr());
+        assert_eq!(held, again);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_prepare_chunks_from() {
+        let input = std::fs::read(
+            "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml",
+        )
+        .unwrap();
+        // one chunk in front of every group
+        let text = std::str::from_utf8(&input).unwrap();
+        let mut boundaries: Vec<usize> = text.match_indices("<group>").map(|(i, _)| i).collect();
+        boundaries.insert(0, 0);
+        boundaries.push(input.len());
+        let lengths: Vec<u64> = boundaries
+            .windows(2)
+            .map(|w| (w[1] - w[0]) as u64)
+            .collect();
+        assert!(lengths.len() > 10);
+        let chunks = || {
+            boundaries
+                .windows(2)
+                .map(|w| Ok(input[w[0]..w[1]].to_vec()))
+                .collect::<Vec<_>>()
+                .into_iter()
+        };
+
+        for options in [EncoderOptions::new(), EncoderOptions::new().auto_dict(4096)] {
+            let mut encoder = Encoder::in_memory_with_options(io::empty(), options).unwrap();
+            encoder.prepare_chunks_from(chunks()).unwrap();
+            let mut output = Vec::new();
+            encoder.compress_to(&mut output).unwrap();
+
+            let mut decoder = Decoder::new(Cursor::new(output)).unwrap();
+            let index_lengths: Vec<u64> = decoder
+                .header()
+                .index
+                .data_chunks
+                .iter()
+                .map(|(c, _)| c.uncompressed_length.to_u64().unwrap())
+                .collect();
+            assert_eq!(index_lengths, lengths);
+            let mut decompressed = Vec::new();
+            decoder.decompress_to(&mut decompressed).unwrap();
+            assert!(decompressed == input);
+            assert!(matches!(
+                encoder.prepare_chunks_from(chunks()),
+                Err(ZchunkError::AlreadyPrepared)
+            ));
+        }
+
+        let failing = chunks().take(3).chain([Err(ZchunkError::InvalidChunkKey)]);
+        let mut encoder = Encoder::new_in_memory(io::empty()).unwrap();
+        assert!(matches!(
+            encoder.prepare_chunks_from(failing),
+            Err(ZchunkError::InvalidChunkKey)
+        ));
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_encoder_misuse() {
+        let input = File::open(
+            "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml",
+        )
+        .unwrap();
+        let mut encoder = Encoder::new(input, Cursor::new(Vec::new())).unwrap();
+
+        assert!(matches!(
+            encoder.compress_to(Vec::new()),
+            Err(ZchunkError::HeaderNotFound)
+        ));
+
+        encoder.prepare_chunks().unwrap();
+        assert!(matches!(
+            encoder.prepare_chunks(),
+            Err(ZchunkError::AlreadyPrepared)
+        ));
+
+        let mut first = Vec::new();
+        encoder.compress_to(&mut first).unwrap();
+        let mut second = Vec::new();
+        encoder.compress_to(&mut second).unwrap();
+        assert!(second == first);
+        // a failed write does not change the next output either
+        assert!(matches!(
+            encoder.compress_to(FailingWriter {
+                limit: 100,
+                data: Vec::new(),
+            }),
+            Err(ZchunkError::WriteFailed { .. })
+        ));
+        let mut third = Vec::new();
+        encoder.compress_to(&mut third).unwrap();
+        assert!(third == first);
+        assert!(matches!(
+            encoder.prepare_chunks(),
+            Err(ZchunkError::AlreadyPrepared)
+        ));
+        assert_eq!(
+            encoder.temp.get_ref().len() as u64
+                + encoder.header.as_ref().unwrap().data_offset().unwrap(),
+            first.len() as u64
+        );
+
+        // a zero bitmask would cut a chunk at every byte past the minimum
+        let options = EncoderOptions::new().chunker_params(ChunkerParams::new(64, 1 << 20, 0));
+        assert!(matches!(
+            Encoder::with_options(std::io::empty(), Cursor::new(Vec::new()), options),
+            Err(ZchunkError::InvalidChunkerParams { bitmask: 0, .. })
+        ));
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_encoder_compress() {
+        let input = std::fs::read(
+            "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml",
+        )
+        .unwrap();
+        let two_phase = |input: &[u8]| {
+            let mut encoder = Encoder::new(input, Cursor::new(Vec::new())).unwrap();
+            encoder.prepare_chunks().unwrap();
+            let mut output = Vec::new();
+            encoder.compress_to(&mut output).unwrap();
+            output
+        };
+
+        // compress prepares an unprepared encoder
+        let mut encoder = Encoder::new(input.as_slice(), MemoryTemp::new()).unwrap();
+        let mut output = Vec::new();
+        let stats = encoder.compress(&mut output).unwrap();
+        assert!(output == two_phase(&input));
+        assert_eq!(stats, encoder.stats().unwrap());
+        assert_eq!(stats.file_size(), output.len() as u64);
+
+        // the output can be written again, without preparing again
+        let mut again = Vec::new();
+        assert_eq!(encoder.compress(&mut again).unwrap(), stats);
+        assert!(again == output);
+
+        // a shorter input after reset leaves no bytes of the longer one in the temp
+        let shorter = &input[..input.len() / 3];
+        encoder.reset(shorter);
+        encoder.prepare_chunks().unwrap();
+        let header = encoder.header().unwrap();
+        assert_eq!(
+            encoder.temp.len() as u64,
+            encoder.data_start + header.data_size().unwrap()
+        );
+        let mut output = Vec::new();
+        encoder.compress(&mut output).unwrap();
+        assert!(output == two_phase(shorter));
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_compress_small() {
+        let input = std::fs::read(
+            "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml",
+        )
+        .unwrap();
+        let min = 1024;
+        let options = || {
+            EncoderOptions::new()
+                .chunker_params(ChunkerParams::new(min, 8192, 2047))
+                .reserve_signature(7, 16)
+        };
+        let full = |input: &[u8], options: EncoderOptions| {
+            let mut encoder =
+                Encoder::with_options(input, Cursor::new(Vec::new()), options).unwrap();
+            encoder.prepare_chunks().unwrap();
+            let mut output = Vec::new();
+            encoder.compress_to(&mut output).unwrap();
+            (output, encoder.stats().unwrap())
+        };
+
+        for len in [0, 1, min - 1, min, 3 * min] {
+            let input = &input[..len];
+            for options in [options(), options().dict(b"<group>".repeat(64))] {
+                let mut output = Vec::new();
+                let stats = Encoder::compress_small(input, &mut output, options.clone()).unwrap();
+                let (expected, expected_stats) = full(input, options);
+                assert!(output == expected, "{len}");
+                assert_eq!(stats, expected_stats, "{len}");
+            }
+        }
+
+        let mut output = Vec::new();
+        let stats = Encoder::compress_small(&[], &mut output, options()).unwrap();
+        assert_eq!(stats.chunks, 0);
+        let mut decoder = Decoder::new(Cursor::new(output)).unwrap();
+        let mut decoded = Vec::new();
+        decoder.decompress_to(&mut decoded).unwrap();
+        assert!(decoded.is_empty());
+
+        let mut output = Vec::new();
+        let stats = Encoder::compress_small(b"x", &mut output, options()).unwrap();
+        assert_eq!(stats.chunks, 1);
+        let mut decoder = Decoder::new(Cursor::new(output)).unwrap();
+        let mut decoded = Vec::new();
+        decoder.decompress_to(&mut decoded).unwrap();
+        assert_eq!(decoded, b"x");
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_encoder_append() {
+        let input = std::fs::read(
+            "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml",
+        )
+        .unwrap();
+        let (old, new) = input.split_at(input.len() * 2 / 3);
+        let encode = |input: &[u8], options: EncoderOptions| {
+            let mut output = Vec::new();
+            Encoder::compress_small(input, &mut output, options).unwrap();
+            output
+        };
+        let params = ChunkerParams::new(1024, 8192, 2047);
+        for options in [
+            EncoderOptions::new().chunker_params(params.clone()),
+            EncoderOptions::new()
+                .chunker_params(params.clone())
+                .dict(b"<group>".repeat(64)),
+        ] {
+            let old_file = encode(old, options.clone());
+            let mut existing = Decoder::new(Cursor::new(old_file)).unwrap();
+            // the dict of the existing file wins over the options
+            let mut encoder = Encoder::with_options(
+                new,
+                MemoryTemp::new(),
+                EncoderOptions::new()
+                    .chunker_params(params.clone())
+                    .dict(vec![0xa5; 4096]),
+            )
+            .unwrap();
+            let mut appended = Vec::new();
+            let stats = encoder.append(&mut existing, &mut appended).unwrap();
+            assert_eq!(stats.input_bytes, input.len() as u64);
+            assert!(appended == encode(&input, options));
+
+            let mut decoder = Decoder::new(Cursor::new(appended)).unwrap();
+            let mut output = Vec::new();
+            decoder.decompress_to(&mut output).unwrap();
+            assert!(output == input);
+
+            // only the last old chunk is chunked again
+            let old_chunks = &existing.header().index.data_chunks;
+            let new_chunks = &decoder.header().index.data_chunks;
+            let preserved = old_chunks
+                .iter()
+                .zip(new_chunks)
+                .take_while(|((old, _), (new, _))| old.checksum == new.checksum)
+                .count();
+            assert!(old_chunks.len() > 10);
+            assert_eq!(preserved, old_chunks.len() - 1);
+        }
+
+        // appending to an empty file encodes the input
+        let mut existing = Decoder::new(Cursor::new(encode(&[], EncoderOptions::new()))).unwrap();
+        let mut encoder = Encoder::new(input.as_slice(), MemoryTemp::new()).unwrap();
+        let mut appended = Vec::new();
+        encoder.append(&mut existing, &mut appended).unwrap();
+        assert!(appended == encode(&input, EncoderOptions::new()));
+        assert!(matches!(
+            encoder.append(&mut existing, Vec::new()),
+            Err(ZchunkError::AlreadyPrepared)
+        ));
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_encode_from_readers() {
+        let input = std::fs::read(
+            "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml",
+        )
+        .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        fn encode<R: Read>(encoder: &mut Encoder<Cursor<Vec<u8>>, R>) -> Vec<u8> {
+            let mut output = Vec::new();
+            encoder.compress(&mut output).unwrap();
+            output
+        }
+
+        // sources ending mid-chunk, an empty one and one shorter than the minimum chunk
+        let mut paths = Vec::new();
+        let mut rest = input.as_slice();
+        for (i, len) in [10_000, 0, 100, 5_000, 77_777].into_iter().enumerate() {
+            let (source, tail) = rest.split_at(len);
+            let path = dir.path().join(i.to_string());
+            std::fs::write(&path, source).unwrap();
+            paths.push(path);
+            rest = tail;
+        }
+        let path = dir.path().join("rest");
+        std::fs::write(&path, rest).unwrap();
+        paths.push(path);
+
+        let concatenated = dir.path().join("concatenated");
+        std::fs::write(&concatenated, &input).unwrap();
+        let mut encoder =
+            Encoder::new(File::open(&concatenated).unwrap(), Cursor::new(Vec::new())).unwrap();
+        let expected = encode(&mut encoder);
+
+        let files = paths.iter().map(|p| File::open(p).unwrap());
+        let mut encoder = Encoder::from_readers(files, Cursor::new(Vec::new())).unwrap();
+        assert!(encode(&mut encoder) == expected);
+
+        let options = EncoderOptions::new().chunker_params(ChunkerParams::new(1024, 8192, 2047));
+        let mut encoder = Encoder::with_options(
+            File::open(&concatenated).unwrap(),
+            Cursor::new(Vec::new()),
+            options.clone(),
+        )
+        .unwrap();
+        let expected = encode(&mut encoder);
+        let files = paths.iter().map(|p| File::open(p).unwrap());
+        let mut encoder =
+            Encoder::with_options(ChainedReader::new(files), Cursor::new(Vec::new()), options)
+                .unwrap();
+        assert!(encode(&mut encoder) == expected);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_pledged_chunk_size() {
+        let input = std::fs::read(
+            "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml",
+        )
+        .unwrap();
+        let options = EncoderOptions::new().chunker_params(ChunkerParams::new(1024, 8192, 2047));
+        let mut encoder =
+            Encoder::with_options(input.as_slice(), Cursor::new(Vec::new()), options).unwrap();
+        let mut file = Vec::new();
+        encoder.compress(&mut file).unwrap();
+        let mut decoder = Decoder::new(Cursor::new(file)).unwrap();
+
+        // the same chunks streamed to zstd without their size, as `zstd::encode_all` does
+        let (mut pledged, mut streamed) = (0, 0);
+        let mut payloads = Vec::new();
+        let mut builder = HeaderBuilder::new().auto_checksums();
+        for id in 0..decoder.header().index.data_chunks.len() {
+            let (chunk, offset) = decoder.header().index.data_chunks[id].clone();
+            let frame = decoder.get_chunk_data(Some(id), offset, &chunk).unwrap();
+            let uncompressed = decoder.decompress_chunk(id).unwrap();
+            assert_eq!(
+                zstd::zstd_safe::get_frame_content_size(&frame).unwrap(),
+                Some(uncompressed.len() as u64)
+            );
+
+            let unsized_frame = zstd::encode_all(uncompressed.as_slice(), 3).unwrap();
+            assert_eq!(
+                zstd::zstd_safe::get_frame_content_size(&unsized_frame).unwrap(),
+                None
+            );
+            pledged += frame.len();
+            streamed += unsized_frame.len();
+            builder = builder.chunk(
+                "00000000000000000000000000000000",
+                unsized_frame.len() as u64,
+                uncompressed.len() as u64,
+            );
+            payloads.push(unsized_frame);
+        }
+        assert!(pledged <= streamed, "{pledged} > {streamed}");
+
+        // frames without a content size still decode
+        let mut decoder =
+            Decoder::new(Cursor::new(builder.to_file_bytes(&payloads).unwrap())).unwrap();
+        let mut output = Vec::new();
+        decoder.decompress_to(&mut output).unwrap();
+        assert!(output == input);
+        assert!(decoder.verify(&VerifyOptions::new()).unwrap().is_ok());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_fresh_zstd_context() {
+        let input = std::fs::read(
+            "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml",
+        )
+        .unwrap();
+        let encode = |options: EncoderOptions| {
+            let options = options.chunker_params(ChunkerParams::new(256, 2048, 511));
+            let mut file = Vec::new();
+            Encoder::compress_small(&input, &mut file, options).unwrap();
+            file
+        };
+
+        for options in [
+            EncoderOptions::new(),
+            EncoderOptions::new().dict(b"<group>".repeat(64)),
+            EncoderOptions::new().compression_level(19),
+        ] {
+            let reused = encode(options.clone());
+            let fresh = encode(options.clone().fresh_zstd_context(true));
+            assert!(reused == fresh);
+
+            // every chunk is the frame a one-off compression of it gives
+            let mut decoder = Decoder::new(Cursor::new(reused)).unwrap();
+            assert!(decoder.header().index.data_chunks.len() > 100);
+            let dict = decoder.get_uncompressed_dict().unwrap();
+            for id in 0..decoder.header().index.data_chunks.len() {
+                let (chunk, offset) = decoder.header().index.data_chunks[id].clone();
+                let frame = decoder.get_chunk_data(Some(id), offset, &chunk).unwrap();
+                let uncompressed = decoder.decompress_chunk(id).unwrap();
+                assert_eq!(
+                    frame,
+                    compress_chunk(&uncompressed, &options.zstd_params(), dict.as_deref()).unwrap()
+                );
+            }
+        }
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_deterministic_encoding() {
+        let input = std::fs::read(
+            "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml",
+        )
+        .unwrap();
+        let options = || {
+            let options = EncoderOptions::new()
+                .deterministic(true)
+                .chunker_params(ChunkerParams::new(1024, 8192, 2047))
+                .dict(b"<group>".repeat(64));
+            if cfg!(feature = "sha512") {
+                options
+            } else {
+                options.checksum_type(ChecksumType::Sha256)
+            }
+        };
+        fn digest<RW: TempStore>(encoder: &mut Encoder<RW, &[u8]>) -> String {
+            let mut hasher = Sha256::new();
+            encoder.compress(&mut hasher).unwrap();
+            hex::encode(hasher.finalize())
+        }
 
-impl<R: BufRead + Seek> Decoder<R> {
-    /// Construct a decoder from a zchunk file reader
-    pub fn new(mut reader: R) -> Result<Self, ZchunkError> {
-        let lead = Lead::from_reader(&mut reader)?;
-        let preface = Preface::from_reader(&mut reader)?;
-        let index = Index::from_reader(&mut reader, preface.flags.clone())?;
-        let signatures = Signatures::from_reader(&mut reader)?;
+        let mut encoder =
+            Encoder::with_options(input.as_slice(), Cursor::new(Vec::new()), options()).unwrap();
+        let expected = digest(&mut encoder);
+
+        // a fresh encoder on another temp backend
+        let dir = tempfile::tempdir().unwrap();
+        let temp = crate::FileTemp::create_in(dir.path()).unwrap();
+        let mut encoder = Encoder::with_options(input.as_slice(), temp, options()).unwrap();
+        assert_eq!(digest(&mut encoder), expected);
+
+        // an encoder whose temp held a longer input
+        let mut doubled = input.clone();
+        doubled.extend_from_slice(&input);
+        let mut encoder =
+            Encoder::with_options(doubled.as_slice(), MemoryTemp::new(), options()).unwrap();
+        digest(&mut encoder);
+        encoder.reset(input.as_slice());
+        assert_eq!(digest(&mut encoder), expected);
+
+        // the level is part of the output
+        let mut encoder = Encoder::with_options(
+            input.as_slice(),
+            Cursor::new(Vec::new()),
+            options().compression_level(19),
+        )
+        .unwrap();
+        assert_ne!(digest(&mut encoder), expected);
+
+        // without SHA-512 the pinned checksum type must be replaced explicitly
+        if cfg!(not(feature = "sha512")) {
+            assert!(matches!(
+                Encoder::with_options(
+                    input.as_slice(),
+                    Cursor::new(Vec::new()),
+                    EncoderOptions::new().deterministic(true)
+                ),
+                Err(ZchunkError::UnsupportedChecksumType(
+                    ChecksumType::Sha512_128
+                ))
+            ));
+        }
+    }
 
-        let expect_header_size = lead.header_size.to_u64()? + lead.byte_size() as u64;
-        let header_size = reader.stream_position()?;
-        if expect_header_size != header_size {
-            return Err(ZchunkError::InvalidHeaderSize {
-                expected: expect_header_size,
-                found: header_size,
-            });
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_chunker_target_size() {
+        let input = std::fs::read(
+            "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml",
+        )
+        .unwrap();
+        let encode = |size: usize| {
+            let options =
+                EncoderOptions::new().chunker_params(ChunkerParams::with_target_size(size));
+            let mut encoder =
+                Encoder::with_options(input.as_slice(), Cursor::new(Vec::new()), options).unwrap();
+            encoder.prepare_chunks().unwrap();
+            let mut file = Vec::new();
+            encoder.compress_to(&mut file).unwrap();
+
+            let mut decoder = Decoder::new(Cursor::new(file)).unwrap();
+            let mut output = Vec::new();
+            decoder.decompress_to(&mut output).unwrap();
+            assert_eq!(output, input);
+            decoder
+                .header()
+                .index
+                .data_chunks
+                .iter()
+                .map(|(c, _)| c.uncompressed_length.to_u64().unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        // a smaller bitmask cuts more, smaller chunks
+        let coarse = encode(16 << 10);
+        let fine = encode(2 << 10);
+        assert!(
+            fine.len() > 2 * coarse.len(),
+            "{} {}",
+            fine.len(),
+            coarse.len()
+        );
+        assert!(fine.iter().max() < coarse.iter().max());
+
+        let options = EncoderOptions::new().chunker_params(ChunkerParams::with_target_size(128));
+        assert!(matches!(
+            Encoder::with_options(std::io::empty(), Cursor::new(Vec::new()), options),
+            Err(ZchunkError::InvalidChunkerParams { min: 32, .. })
+        ));
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_max_chunks() {
+        let input = pseudo_random_dict(4 << 20);
+        let encode = |options: EncoderOptions| {
+            let mut file = Vec::new();
+            Encoder::compress_small(&input, &mut file, options).map(|stats| (stats, file))
+        };
+
+        // the default parameters cut about a hundred chunks
+        let err = encode(EncoderOptions::new().max_chunks(20)).unwrap_err();
+        assert!(matches!(err, ZchunkError::TooManyChunks { max: 20 }));
+
+        // with a size hint the parameters are scaled up front
+        let options = EncoderOptions::new()
+            .input_size_hint(input.len() as u64)
+            .max_chunks(20);
+        let mut encoder =
+            Encoder::with_options(input.as_slice(), MemoryTemp::new(), options.clone()).unwrap();
+        assert!(encoder.chunker_params().average_size() >= (4 << 20) / 20);
+        let mut file = Vec::new();
+        let stats = encoder.compress(&mut file).unwrap();
+        assert!(stats.chunks <= 20, "{}", stats.chunks);
+        let mut output = Vec::new();
+        Decoder::new(Cursor::new(file))
+            .unwrap()
+            .decompress_to(&mut output)
+            .unwrap();
+        assert_eq!(output, input);
+
+        // a limit the parameters already meet leaves them alone
+        let (stats, _) = encode(
+            EncoderOptions::new()
+                .input_size_hint(input.len() as u64)
+                .max_chunks(1000),
+        )
+        .unwrap();
+        let (expected, _) = encode(EncoderOptions::new()).unwrap();
+        assert_eq!(stats, expected);
+
+        // a hint too large for any chunk size still tunes to parameters that chunk
+        let options = EncoderOptions::new()
+            .input_size_hint(u64::MAX)
+            .max_chunks(1);
+        options.validate().unwrap();
+        let mut file = Vec::new();
+        Encoder::compress_small(&input[..1000], &mut file, options).unwrap();
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_compression_level() {
+        let input = std::fs::read(
+            "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml",
+        )
+        .unwrap();
+        let encode = |level: i32| {
+            let options = EncoderOptions::new().compression_level(level);
+            let mut encoder =
+                Encoder::with_options(input.as_slice(), Cursor::new(Vec::new()), options).unwrap();
+            encoder.prepare_chunks().unwrap();
+            let mut file = Vec::new();
+            encoder.compress_to(&mut file).unwrap();
+            file
+        };
+
+        let fast = encode(1);
+        let small = encode(19);
+        assert!(small.len() < fast.len());
+        for file in [fast, small] {
+            let mut output = Vec::new();
+            Decoder::new(Cursor::new(file))
+                .unwrap()
+                .decompress_to(&mut output)
+                .unwrap();
+            assert_eq!(Sha256::digest(&output), Sha256::digest(&input));
         }
 
-        let header = Header::new(lead, preface, index, signatures);
+        let max = *zstd::compression_level_range().end();
+        let options = EncoderOptions::new().compression_level(max + 1);
+        assert!(matches!(
+            Encoder::with_options(std::io::empty(), Cursor::new(Vec::new()), options),
+            Err(ZchunkError::InvalidOption { name: "compression_level", value, .. })
+                if value == i64::from(max) + 1
+        ));
+    }
 
-        Ok(Self {
-            header,
-            header_size,
-            reader,
-        })
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_zstd_workers() {
+        // large chunks of repeated XML, 4 MiB each
+        let xml = std::fs::read(
+            "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml",
+        )
+        .unwrap();
+        let input = xml.repeat(80);
+        let encode = |options: EncoderOptions| {
+            let options = options.chunker_params(ChunkerParams::new(4 << 20, 4 << 20, 1));
+            let mut encoder = Encoder::in_memory_with_options(input.as_slice(), options).unwrap();
+            encoder.prepare_chunks().unwrap();
+            let mut file = Vec::new();
+            encoder.compress_to(&mut file).unwrap();
+            file
+        };
+
+        let single = encode(EncoderOptions::new());
+        assert!(encode(EncoderOptions::new().zstd_workers(0)) == single);
+
+        #[cfg(feature = "zstdmt")]
+        {
+            let threaded = encode(EncoderOptions::new().zstd_workers(2));
+            assert!(encode(EncoderOptions::new().zstd_workers(2)) == threaded);
+            let mut decoder = Decoder::new(Cursor::new(threaded)).unwrap();
+            assert!(decoder.header().index.data_chunks.len() > 1);
+            let mut output = Vec::new();
+            decoder.decompress_to(&mut output).unwrap();
+            assert!(output == input);
+        }
     }
 
-    /// Get chunk data by offset and chunk, no decompression
-    ///
-    /// Offset is relative to the end of header, so seeking reader need plus header size
-    fn get_chunk_data(&mut self, offset: u64, chunk: &Chunk) -> Result<Vec<u8>, ZchunkError> {
-        let length = chunk.length.to_u64()? as usize;
-        let mut buf = vec![0; length];
-        if length == 0 {
-            return Ok(buf);
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_long_distance_matching() {
+        // one chunk whose second half repeats the first, further back than the level 1 window
+        let half = pseudo_random_dict(2 << 20);
+        let input = [half.as_slice(), half.as_slice()].concat();
+        let encode = |options: EncoderOptions| {
+            let options = options
+                .compression_level(1)
+                .chunker_params(ChunkerParams::new(4 << 20, 4 << 20, 1));
+            let mut encoder = Encoder::in_memory_with_options(input.as_slice(), options).unwrap();
+            encoder.prepare_chunks().unwrap();
+            let mut file = Vec::new();
+            encoder.compress_to(&mut file).unwrap();
+            file
+        };
+
+        let plain = encode(EncoderOptions::new());
+        let ldm = encode(EncoderOptions::new().long_distance_matching(true));
+        let windowed = encode(EncoderOptions::new().window_log(23));
+        assert!(plain.len() > input.len());
+        assert!(ldm.len() < half.len() + 4096, "{}", ldm.len());
+        assert!(windowed.len() < half.len() + 4096, "{}", windowed.len());
+        for file in [ldm, windowed] {
+            let mut decoder = Decoder::new(Cursor::new(file)).unwrap();
+            assert_eq!(decoder.header().index.data_chunks.len(), 1);
+            let mut output = Vec::new();
+            decoder.decompress_to(&mut output).unwrap();
+            assert!(output == input);
         }
 
-        self.reader
-            .seek(SeekFrom::Start(self.header_size + offset))?;
-        self.reader.read_exact(&mut buf)?;
+        let refused = |options| {
+            Encoder::with_options(std::io::empty(), Cursor::new(Vec::new()), options).err()
+        };
+        assert!(matches!(
+            refused(EncoderOptions::new().window_log(99)),
+            Some(ZchunkError::InvalidOption {
+                name: "window_log",
+                value: 99,
+                ..
+            })
+        ));
+        #[cfg(not(feature = "zstdmt"))]
+        assert!(matches!(
+            refused(EncoderOptions::new().zstd_workers(2)),
+            Some(ZchunkError::InvalidOption {
+                name: "zstd_workers",
+                value: 2,
+                ..
+            })
+        ));
+        #[cfg(feature = "zstdmt")]
+        assert!(matches!(
+            refused(EncoderOptions::new().zstd_workers(1000)),
+            Some(ZchunkError::InvalidOption {
+                name: "zstd_workers",
+                value: 1000,
+                ..
+            })
+        ));
+        #[cfg(feature = "zstdmt")]
+        assert!(refused(EncoderOptions::new().zstd_workers(2)).is_none());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_zstd_window_log_max() {
+        // a chunk longer than its window, which repeats itself a window back
+        let input = pseudo_random_dict(1 << 20).repeat(3);
+        let options = EncoderOptions::new().compression_level(1).window_log(21);
+        let mut encoder = Encoder::in_memory_with_options(io::empty(), options).unwrap();
+        encoder
+            .prepare_chunks_from(std::iter::once(Ok(input.clone())))
+            .unwrap();
+        let mut file = Vec::new();
+        encoder.compress_to(&mut file).unwrap();
+        assert!(file.len() < (1 << 20) + 4096, "{}", file.len());
+
+        let decompress = |options: DecodeOptions| {
+            let mut decoder = Decoder::with_options(Cursor::new(file.as_slice()), options)?;
+            let mut output = Vec::new();
+            decoder.decompress_to(&mut output).map(|_| output)
+        };
+        assert!(decompress(DecodeOptions::new()).unwrap() == input);
+        assert!(decompress(DecodeOptions::new().zstd_window_log_max(21)).unwrap() == input);
+        // a limit below the window of the chunk refuses it
+        assert!(decompress(DecodeOptions::new().zstd_window_log_max(20)).is_err());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_verify_output() {
+        /// A file that flips the byte at `at` when it is written, like a bad device, and
+        /// drops everything written past `len`, like a full disk that does not report it
+        struct FaultyFile {
+            inner: Cursor<Vec<u8>>,
+            at: Option<u64>,
+            len: u64,
+        }
 
-        let result: [u8; 16] = match self.header.index.checksum_type.to_u64()? as u8 {
-            CHECKSUM_SHA256 => {
-                let mut hasher = Sha256::new();
-                hasher.update(&buf);
-                hasher.finalize()[..16].try_into()?
+        impl Write for FaultyFile {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                let start = self.inner.position();
+                let mut data = buf.to_vec();
+                if let Some(at) = self
+                    .at
+                    .filter(|at| (start..start + buf.len() as u64).contains(at))
+                {
+                    data[(at - start) as usize] ^= 0xff;
+                }
+                let kept = self.len.saturating_sub(start).min(buf.len() as u64) as usize;
+                self.inner.write_all(&data[..kept])?;
+                Ok(buf.len())
             }
-            CHECKSUM_SHA512 | CHECKSUM_SHA512_128 => {
-                let mut hasher = Sha512::new();
-                hasher.update(&buf);
-                let checksum: &[u8] = &hasher.finalize()[..];
-                checksum[..16].try_into()?
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
             }
-            t => {
-                return Err(ZchunkError::InvalidChecksumType(t));
+        }
+
+        impl Read for FaultyFile {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.inner.read(buf)
             }
-        };
+        }
 
-        if chunk.checksum != result {
-            return Err(ZchunkError::ChunkChecksumNotMatch {
-                len: length,
-                expected: chunk.checksum,
-                found: result,
-            });
+        impl Seek for FaultyFile {
+            fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+                self.inner.seek(pos)
+            }
         }
 
-        Ok(buf)
+        let input = std::fs::read(
+            "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml",
+        )
+        .unwrap();
+        let encode = |verify: bool, at: Option<u64>, len: u64| {
+            let options = EncoderOptions::new()
+                .chunker_params(ChunkerParams::new(1024, 8192, 2047))
+                .verify_output(verify);
+            let mut encoder = Encoder::in_memory_with_options(input.as_slice(), options).unwrap();
+            encoder.prepare_chunks().unwrap();
+            let mut file = FaultyFile {
+                inner: Cursor::new(Vec::new()),
+                at,
+                len,
+            };
+            encoder
+                .compress_to_seekable(&mut file)
+                .map(|_| file.inner.into_inner())
+        };
+
+        let file = encode(true, None, u64::MAX).unwrap();
+        let decoder = Decoder::new(Cursor::new(file.as_slice())).unwrap();
+        let data_offset = decoder.header().data_offset().unwrap();
+        let (first, _) = &decoder.header().index.data_chunks[0];
+        let second_chunk = data_offset + first.length.to_u64().unwrap();
+
+        // without the option the damage goes unnoticed
+        assert!(encode(false, Some(second_chunk), u64::MAX).is_ok());
+        assert!(encode(false, None, data_offset + 100).is_ok());
+
+        assert!(matches!(
+            encode(true, Some(second_chunk), u64::MAX),
+            Err(ZchunkError::OutputVerificationFailed { id: Some(1), reason })
+                if matches!(*reason, crate::FailureReason::ChecksumMismatch { .. })
+        ));
+        let header_checksum_at = file
+            .windows(decoder.header().lead.header_checksum.as_bytes().len())
+            .position(|w| w == decoder.header().lead.header_checksum.as_bytes())
+            .unwrap() as u64;
+        assert!(matches!(
+            encode(true, Some(header_checksum_at), u64::MAX),
+            Err(ZchunkError::HeaderChecksumNotMatch { .. })
+        ));
+        assert!(matches!(
+            encode(true, None, file.len() as u64 - 1),
+            Err(ZchunkError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof
+        ));
     }
 
-    /// Get uncompressed dict chunk
-    fn get_uncompressed_dict(&mut self) -> Result<Option<Vec<u8>>, ZchunkError> {
-        let dict_chunk = self.header.index.dict_chunk.clone();
-        let data = self.get_chunk_data(0, &dict_chunk)?;
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_invalid_options_read_nothing() {
+        struct Unread;
+        impl Read for Unread {
+            fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+                panic!("read before the options were validated");
+            }
+        }
 
-        let dict = if data.len() != 0 {
-            Some(zstd::decode_all(Cursor::new(data))?)
-        } else {
-            None
+        let max = *zstd::compression_level_range().end();
+        for options in [
+            EncoderOptions::new().compression_level(max + 1),
+            EncoderOptions::new().compression_level(i32::MIN),
+            EncoderOptions::new().window_log(9),
+            EncoderOptions::new()
+                .compression_level(max + 1)
+                .auto_dict(4096),
+        ] {
+            assert!(matches!(
+                options.validate(),
+                Err(ZchunkError::InvalidOption { .. })
+            ));
+            assert!(matches!(
+                Encoder::with_options(Unread, Cursor::new(Vec::new()), options),
+                Err(ZchunkError::InvalidOption { .. })
+            ));
+        }
+        assert!(EncoderOptions::new().validate().is_ok());
+        assert!(EncoderOptions::new()
+            .compression_level(max)
+            .window_log(31)
+            .validate()
+            .is_ok());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_store_incompressible() {
+        let encode = |input: &[u8], options: EncoderOptions| {
+            let mut encoder = Encoder::in_memory_with_options(input, options).unwrap();
+            encoder.prepare_chunks().unwrap();
+            let mut file = Vec::new();
+            encoder.compress_to(&mut file).unwrap();
+            (file, encoder.report().unwrap().clone())
         };
+        let small_chunks = ChunkerParams::new(1024, 8192, 2047);
+
+        let random = pseudo_random_dict(256 * 1024);
+        for options in [
+            EncoderOptions::new(),
+            EncoderOptions::new().dict(b"<group>".repeat(64)),
+        ] {
+            let options = options.chunker_params(small_chunks.clone());
+            let (compressed, report) = encode(&random, options.clone());
+            assert!(!report.stored_uncompressed);
+            let (file, report) = encode(&random, options.store_incompressible(true));
+            assert!(report.stored_uncompressed);
+            assert!(
+                file.len() < compressed.len(),
+                "{} {}",
+                file.len(),
+                compressed.len()
+            );
 
-        Ok(dict)
-    }
+            let mut decoder = Decoder::new(Cursor::new(file.as_slice())).unwrap();
+            let header = decoder.header();
+            assert_eq!(header.compression_type().unwrap(), CompressionType::None);
+            assert!(!header.index.has_dict());
+            assert!(header.index.data_chunks.len() > 1);
+            assert_eq!(
+                file.len(),
+                header.data_offset().unwrap() as usize + random.len()
+            );
+            assert!(decoder.verify(&VerifyOptions::new()).unwrap().is_ok());
+            let mut output = Vec::new();
+            decoder.decompress_to(&mut output).unwrap();
+            assert_eq!(output, random);
+        }
 
-    /// Decompress and assemble chunks, and write chunks to `Write`
-    pub fn decompress_to(&mut self, mut writer: impl Write) -> Result<(), ZchunkError> {
-        let dict = self.get_uncompressed_dict()?;
+        // the compressible first chunk grows when stored and overwrites the next one
+        let mut input = vec![b'a'; 2048];
+        input.extend(pseudo_random_dict(1024 * 1024));
+        let options = EncoderOptions::new()
+            .chunker_params(small_chunks)
+            .store_incompressible(true);
+        let (file, report) = encode(&input, options);
+        assert!(report.stored_uncompressed);
+        let mut decoder = Decoder::new(Cursor::new(file.as_slice())).unwrap();
+        let (first, _) = &decoder.header().index.data_chunks[0];
+        assert!(first.length.to_u64().unwrap() > 2048);
+        assert!(decoder.verify(&VerifyOptions::new()).unwrap().is_ok());
+        let mut output = Vec::new();
+        decoder.decompress_to(&mut output).unwrap();
+        assert!(output == input);
+
+        // compressible input keeps zstd
+        let xml = std::fs::read("testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml").unwrap();
+        let (file, report) = encode(&xml, EncoderOptions::new().store_incompressible(true));
+        assert!(!report.stored_uncompressed);
+        assert_eq!(file, encode(&xml, EncoderOptions::new()).0);
+    }
 
-        // decompress data chunks
-        for (chunk, _) in &self.header.index.data_chunks {
-            let length = chunk.length.to_u64()?;
-            let reader = &mut self.reader;
-            let input = reader.take(length);
-            // println!(
-            //     "{} {} {:?}",
-            //     chunk.uncompressed_length.to_u64()?,
-            //     chunk.length.to_u64()?,
-            //     chunk.checksum
-            // );
-
-            match dict {
-                Some(ref d) => {
-                    let mut decoder = zstd::Decoder::with_dictionary(input, &d)?;
-                    io::copy(&mut decoder, &mut writer)?;
-                }
-                None => {
-                    zstd::stream::copy_decode(input, &mut writer)?;
-                }
-            };
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_compression_none() {
+        let input = std::fs::read("testdata/comps-Server.x86_64.xml.gz").unwrap();
+        let options = EncoderOptions::new()
+            .compression_type(CompressionType::None)
+            .chunker_params(ChunkerParams::new(1024, 8192, 2047));
+        let mut encoder =
+            Encoder::with_options(input.as_slice(), Cursor::new(Vec::new()), options).unwrap();
+        encoder.prepare_chunks().unwrap();
+        let mut file = Vec::new();
+        encoder.compress_to(&mut file).unwrap();
+
+        // chunks are stored as they are, nothing but the header is added
+        let mut decoder = Decoder::new(Cursor::new(file.clone())).unwrap();
+        let header = decoder.header();
+        assert_eq!(header.compression_type().unwrap(), CompressionType::None);
+        assert!(header.index.data_chunks.len() > 1);
+        assert!(header
+            .index
+            .data_chunks
+            .iter()
+            .all(|(c, _)| c.length == c.uncompressed_length));
+        let data_offset = header.data_offset().unwrap() as usize;
+        assert_eq!(file.len(), data_offset + input.len());
+        assert_eq!(file[data_offset..], input);
+
+        let mut output = Vec::new();
+        decoder.decompress_to(&mut output).unwrap();
+        assert_eq!(output, input);
+        let mut output = Vec::new();
+        decoder.decompress_range(1..3, &mut output).unwrap();
+        let chunk = decoder.decompress_chunk(2).unwrap();
+        assert!(output.ends_with(&chunk));
+        assert!(decoder.verify(&VerifyOptions::new()).unwrap().is_ok());
+
+        // a corrupt chunk is still caught
+        let mut corrupt = file;
+        corrupt[data_offset] ^= 0xff;
+        assert!(matches!(
+            Decoder::new(Cursor::new(corrupt))
+                .unwrap()
+                .decompress_to(Vec::new()),
+            Err(ZchunkError::ChunkChecksumNotMatch { id: Some(0), .. })
+        ));
+
+        for options in [
+            EncoderOptions::new().dict(b"<group>".to_vec()),
+            EncoderOptions::new().auto_dict(1024),
+        ] {
+            let options = options.compression_type(CompressionType::None);
+            assert!(matches!(
+                Encoder::with_options(std::io::empty(), Cursor::new(Vec::new()), options),
+                Err(ZchunkError::DictWithoutCompression)
+            ));
         }
+    }
 
-        Ok(())
+    #[cfg(feature = "sha512")]
+    #[test]
+    fn test_peek() {
+        for path in [
+            "testdata/c25ffa05cf1fdeb67801847df96c33933b1ee1ea081af52edff4ff371a1c814c-comps-Server.x86_64.xml.zck",
+            "testdata/3c6181c789ef9e8ed23f4072eb2f8f529002abd5166273a9734d7d39f7a810ae-comps-Server.x86_64.xml.zck",
+        ] {
+            let full = Decoder::new(BufReader::new(File::open(path).unwrap())).unwrap();
+            let partial = PartialDecoder::peek(BufReader::new(File::open(path).unwrap())).unwrap();
+            assert_eq!(partial.header_checksum(), &full.header.lead.header_checksum[..]);
+            assert_eq!(partial.data_checksum(), &full.header.preface.data_checksum);
+            assert_eq!(partial.header_size().unwrap(), full.header_size);
+            assert_eq!(partial.compression_type().unwrap(), CompressionType::Zstd);
+
+            let upgraded = partial.into_full().unwrap();
+            assert_eq!(upgraded.header_size, full.header_size);
+        }
     }
 
-    /// Copy current zchunk reader to another writer, which using a cache zchunk file
-    pub fn sync_to(
-        &mut self,
-        mut cache: Decoder<R>,
-        mut writer: impl Write,
-    ) -> Result<(), ZchunkError> {
-        // write header
-        self.header.write_to(&mut writer, false)?;
+    #[cfg(all(feature = "zstd", feature = "sha512"))]
+    #[test]
+    fn test_peek_into_full() {
+        for (path, checksum) in [
+            ("testdata/c25ffa05cf1fdeb67801847df96c33933b1ee1ea081af52edff4ff371a1c814c-comps-Server.x86_64.xml.zck",
+            "14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68"),
+            ("testdata/3c6181c789ef9e8ed23f4072eb2f8f529002abd5166273a9734d7d39f7a810ae-comps-Server.x86_64.xml.zck",
+            "4a1a7a9d98dd9764f67d4a608828fa8afca99889afe8b178228f5d37959c1ebf"),
+        ] {
+            let partial = PartialDecoder::peek(BufReader::new(File::open(path).unwrap())).unwrap();
+            let mut hasher = Sha256::new();
+            partial.into_full().unwrap().decompress_to(&mut hasher).unwrap();
+            assert_eq!(hex::encode(hasher.finalize()), checksum);
+        }
+    }
 
-        // write dict
-        let dict_chunk = self.header.index.dict_chunk.clone();
-        let dict = if cache.header.has_dict_chunk(&dict_chunk) {
-            cache.get_chunk_data(0, &dict_chunk)?
-        } else {
-            self.get_chunk_data(0, &dict_chunk)?
-        };
-        writer.write_all(&dict)?;
+    #[cfg(feature = "sha512")]
+    #[test]
+    fn test_verify_chunk_bytes() {
+        let payloads = vec![b"first chunk".to_vec(), b"second chunk".to_vec()];
+        for checksum_type in [
+            ChecksumType::Sha256,
+            ChecksumType::Sha512,
+            ChecksumType::Sha512_128,
+        ] {
+            let bytes = HeaderBuilder::new()
+                .checksum_type(checksum_type)
+                .chunk("00000000000000000000000000000000", 0, 0)
+                .chunk("00000000000000000000000000000000", 0, 0)
+                .auto_checksums()
+                .to_file_bytes(&payloads)
+                .unwrap();
+            let header = Decoder::new(Cursor::new(bytes)).unwrap().header;
+
+            header.verify_chunk_bytes(1, &payloads[1]).unwrap();
+            let mut flipped = payloads[1].clone();
+            flipped[0] ^= 0x01;
+            assert!(matches!(
+                header.verify_chunk_bytes(1, &flipped),
+                Err(ZchunkError::ChunkChecksumNotMatch { id: Some(1), .. })
+            ));
+            assert!(matches!(
+                header.verify_chunk_bytes(2, &payloads[1]),
+                Err(ZchunkError::ChunkNotFound(2))
+            ));
+        }
+    }
 
-        // find existed chunks in cache
-        let cache_chunk_offset_map = cache.header.find_data_chunks(
-            self.header
-                .index
-                .data_chunks
-                .clone()
-                .into_iter()
-                .map(|(c, _)| c)
-                .collect(),
-        );
+    #[cfg(feature = "sha512")]
+    #[test]
+    fn test_header_data_overlap() {
+        let mut bytes = std::fs::read("testdata/c25ffa05cf1fdeb67801847df96c33933b1ee1ea081af52edff4ff371a1c814c-comps-Server.x86_64.xml.zck").unwrap();
+        let header_end = Decoder::new(Cursor::new(bytes.clone()))
+            .unwrap()
+            .header_size;
+
+        // the header size follows the 5 bytes id and the 1 byte checksum type
+        let mut size_bytes = &bytes[6..];
+        let header_size = size_bytes.read_variant_int().unwrap();
+        let doctored = VariantInt::from(header_size.to_u64().unwrap() - 8);
+        assert_eq!(doctored.byte_size(), header_size.byte_size());
+        let mut lead_field = Vec::new();
+        doctored.write_to(&mut lead_field).unwrap();
+        bytes[6..6 + lead_field.len()].copy_from_slice(&lead_field);
+
+        // the data would start 8 bytes into the index or signatures
+        assert!(matches!(
+            Decoder::new(Cursor::new(bytes)),
+            Err(ZchunkError::HeaderDataOverlap { offset, header_end: end })
+                if end == header_end && offset == header_end - 8
+        ));
+    }
 
-        // write chunks
-        for (chunk, offset) in self.header.index.data_chunks.clone() {
-            let data = match cache_chunk_offset_map.get(&chunk) {
-                Some(&o) => cache.get_chunk_data(o as u64, &chunk)?,
-                None => self.get_chunk_data(offset as u64, &chunk)?,
-            };
-            writer.write_all(&data)?;
+    #[cfg(feature = "sha512")]
+    #[test]
+    fn test_header_bytes() {
+        for path in [
+            "testdata/c25ffa05cf1fdeb67801847df96c33933b1ee1ea081af52edff4ff371a1c814c-comps-Server.x86_64.xml.zck",
+            "testdata/3c6181c789ef9e8ed23f4072eb2f8f529002abd5166273a9734d7d39f7a810ae-comps-Server.x86_64.xml.zck",
+        ] {
+            let reader = BufReader::new(File::open(path).unwrap());
+            let decoder =
+                Decoder::with_options(reader, DecodeOptions::new().keep_header_bytes(true)).unwrap();
+            let bytes = decoder.header_bytes().unwrap();
+            let lead = &decoder.header().lead;
+            assert_eq!(bytes.len() as u64, decoder.header_size);
+
+            // the header checksum covers the header without the checksum field
+            let checksum_start = lead.byte_size() - lead.header_checksum.len();
+            let mut hasher = Sha256::new();
+            hasher.update(&bytes[..checksum_start]);
+            hasher.update(&bytes[lead.byte_size()..]);
+            assert_eq!(hasher.finalize()[..], lead.header_checksum[..]);
+
+            let signed = decoder.signed_region().unwrap();
+            assert!(bytes.starts_with(signed));
+            assert_eq!(
+                bytes.len() - signed.len(),
+                decoder.header().signatures.byte_size()
+            );
         }
 
-        Ok(())
+        let reader = BufReader::new(File::open("testdata/c25ffa05cf1fdeb67801847df96c33933b1ee1ea081af52edff4ff371a1c814c-comps-Server.x86_64.xml.zck").unwrap());
+        let decoder = Decoder::new(reader).unwrap();
+        assert_eq!(decoder.header_bytes(), None);
+        assert_eq!(decoder.signed_region(), None);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::{fs::File, io::BufReader};
+    /// A writer remembering the largest single write
+    #[cfg(feature = "zstd")]
+    #[derive(Default)]
+    struct RecordingWriter {
+        data: Vec<u8>,
+        max_write: usize,
+    }
 
-    use sha2::{Digest, Sha256};
-    use tempfile::Builder;
+    #[cfg(feature = "zstd")]
+    impl Write for RecordingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.max_write = self.max_write.max(buf.len());
+            self.data.extend_from_slice(buf);
+            Ok(buf.len())
+        }
 
-    use super::{Decoder, Encoder};
-    fn test_decoder_inner(path: &str, checksum: &str) {
-        let file = File::open(path).unwrap();
-        let mut reader = BufReader::new(file);
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
 
-        let mut decoder = Decoder::new(&mut reader).unwrap();
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_stream_huge_chunk() {
+        // a single 16 MiB chunk, far above the held back output
+        let input: Vec<u8> = (0..16u32 << 20)
+            .map(|i| ((i / 7) ^ (i / 4093)) as u8)
+            .collect();
+        let payload = zstd::encode_all(input.as_slice(), 3).unwrap();
+        let mut bytes = HeaderBuilder::new()
+            .chunk("00000000000000000000000000000000", 0, input.len() as u64)
+            .auto_checksums()
+            .to_file_bytes(std::slice::from_ref(&payload))
+            .unwrap();
 
-        let mut hasher = Sha256::new();
-        decoder.decompress_to(&mut hasher).unwrap();
+        let max_held = 64 << 10;
+        let options = DecodeOptions::new().max_buffered_output(max_held);
+        let mut decoder =
+            Decoder::with_options(Cursor::new(bytes.clone()), options.clone()).unwrap();
+        let mut writer = RecordingWriter::default();
+        decoder.decompress_range(0..1, &mut writer).unwrap();
+        assert!(writer.data == input);
+        assert!(writer.max_write <= max_held);
+
+        // the leading output of a corrupt chunk is written before the checksum fails
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        let mut decoder = Decoder::with_options(Cursor::new(bytes.clone()), options).unwrap();
+        let mut writer = RecordingWriter::default();
+        assert!(matches!(
+            decoder.decompress_chunk_to(0, &mut writer),
+            Err(ZchunkError::ChunkChecksumNotMatch { .. })
+        ));
+        assert!(!writer.data.is_empty() && writer.data.len() < input.len());
+
+        // by default nothing of it is
+        let mut decoder = Decoder::new(Cursor::new(bytes)).unwrap();
+        let mut writer = RecordingWriter::default();
+        assert!(matches!(
+            decoder.decompress_chunk_to(0, &mut writer),
+            Err(ZchunkError::ChunkChecksumNotMatch { .. })
+        ));
+        assert!(writer.data.is_empty());
+    }
 
-        assert_eq!(hex::encode(hasher.finalize()), checksum);
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_held_back_writer() {
+        let input: Vec<u8> = (0..8u32 << 20).map(|i| (i % 253) as u8).collect();
+        let max_held = 1 << 20;
+        let mut output = HeldBackWriter::new(Vec::new(), max_held);
+        let mut sizes = [1, 8191, 8192, 3 << 20, 17, 65536].iter().cycle();
+        let mut rest = input.as_slice();
+        while !rest.is_empty() {
+            let (buf, tail) = rest.split_at((*sizes.next().unwrap()).min(rest.len()));
+            output.write_all(buf).unwrap();
+            rest = tail;
+            // only the tail is held, and without keeping what was released
+            assert!(output.held.len() <= max_held);
+            assert!(output.held.capacity() <= 2 * (max_held + (3 << 20)));
+            assert!(output.inner == input[..input.len() - rest.len() - output.held.len()]);
+        }
+        assert_eq!(output.held.len(), max_held);
+        assert!(output.finish().unwrap() == input);
+
+        let mut output = HeldBackWriter::new(Vec::new(), max_held);
+        output.write_all(&input).unwrap();
+        assert!(output.inner == input[..input.len() - max_held]);
+        assert!(output.finish().unwrap() == input);
     }
 
+    #[cfg(feature = "zstd")]
     #[test]
-    fn test_decompress() {
-        test_decoder_inner("testdata/c25ffa05cf1fdeb67801847df96c33933b1ee1ea081af52edff4ff371a1c814c-comps-Server.x86_64.xml.zck",
-        "14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68");
-        test_decoder_inner("testdata/3c6181c789ef9e8ed23f4072eb2f8f529002abd5166273a9734d7d39f7a810ae-comps-Server.x86_64.xml.zck",
-        "4a1a7a9d98dd9764f67d4a608828fa8afca99889afe8b178228f5d37959c1ebf");
+    fn test_decompress_to_seekable() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8 | 1).collect();
+        let zeros = vec![0; 1 << 20];
+        let chunks = [&data[..], &zeros, &data, &zeros, &zeros];
+        let payloads: Vec<Vec<u8>> = chunks
+            .iter()
+            .map(|c| zstd::encode_all(*c, 3).unwrap())
+            .collect();
+        let mut builder = HeaderBuilder::new();
+        for c in chunks {
+            builder = builder.chunk("00000000000000000000000000000000", 0, c.len() as u64);
+        }
+        let bytes = builder.auto_checksums().to_file_bytes(&payloads).unwrap();
+
+        let mut expected = Vec::new();
+        Decoder::new(Cursor::new(bytes.clone()))
+            .unwrap()
+            .decompress_to(&mut expected)
+            .unwrap();
+        assert_eq!(expected, chunks.concat());
+
+        for skip in [false, true] {
+            let mut file = Builder::new().tempfile().unwrap();
+            let mut decoder = Decoder::new(Cursor::new(bytes.clone())).unwrap();
+            let options = RestoreOptions::new().skip_zero_chunks(skip);
+            decoder
+                .decompress_to_seekable(file.as_file_mut(), options)
+                .unwrap();
+
+            let restored = std::fs::read(file.path()).unwrap();
+            assert_eq!(
+                hex::encode(Sha256::digest(&restored)),
+                hex::encode(Sha256::digest(&expected))
+            );
+
+            // holes are only allocated lazily where the file system supports them
+            #[cfg(target_os = "linux")]
+            if skip {
+                use std::os::unix::fs::MetadataExt;
+
+                let metadata = file.as_file().metadata().unwrap();
+                assert!(metadata.blocks() * 512 < metadata.len());
+            }
+        }
     }
 
+    #[cfg(all(feature = "zstd", feature = "sha512"))]
     #[test]
-    fn test_compress() {
-        let input = File::open(
-            "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml",
-        )
-        .unwrap();
+    fn test_verification_levels() {
+        use crate::VerificationLevel::{self, Full, HeaderOnly, None};
+
+        let file = "testdata/c25ffa05cf1fdeb67801847df96c33933b1ee1ea081af52edff4ff371a1c814c-comps-Server.x86_64.xml.zck";
+        let good = std::fs::read(file).unwrap();
+        let decoder = |bytes: &[u8], level: VerificationLevel| {
+            let options = DecodeOptions::new().verification(level);
+            Decoder::with_options(Cursor::new(bytes.to_vec()), options).unwrap()
+        };
+        let decompress_all = |bytes: &[u8], level| -> Result<Vec<Vec<u8>>, ZchunkError> {
+            let mut decoder = decoder(bytes, level);
+            let mut to = Vec::new();
+            decoder.decompress_to(&mut to)?;
+            let mut range = Vec::new();
+            decoder.decompress_range(0..3, &mut range)?;
+            let mut chunk = Vec::new();
+            decoder.decompress_chunk_to(1, &mut chunk)?;
+            Ok(vec![to, range, chunk, decoder.decompress_chunk(1)?])
+        };
 
-        let path = "testdata/unittest-comps-Server.x86_64.xml.zck";
-        let output = File::create(path).unwrap();
+        let expected = decompress_all(&good, Full).unwrap();
+        assert_eq!(expected[0], expected[1]);
+        for level in [HeaderOnly, None] {
+            assert_eq!(decompress_all(&good, level).unwrap(), expected);
+        }
 
-        let temp = Builder::new()
-            .prefix("unittest-")
-            .tempfile_in("testdata/")
+        // a header whose chunk checksum does not match its chunk
+        let mut header = decoder(&good, Full).header;
+        header.index.data_chunks[1].0.checksum[0] ^= 0xff;
+        let mut tampered = Vec::new();
+        header.write_to(&mut tampered, false).unwrap();
+        let header_size = tampered.len();
+        tampered.extend_from_slice(&good[header_size..]);
+
+        // consistent with its header checksum, only chunk checksums find it
+        let mut consistent = tampered.clone();
+        header.compute_and_set_checksum().unwrap();
+        header
+            .write_to(&mut consistent[..header_size], false)
             .unwrap();
+        for (bytes, level, caught) in [
+            (&consistent, Full, true),
+            (&consistent, HeaderOnly, false),
+            (&consistent, None, false),
+            (&tampered, Full, true),
+            (&tampered, HeaderOnly, true),
+            (&tampered, None, false),
+        ] {
+            match decompress_all(bytes, level) {
+                Ok(output) => {
+                    assert!(!caught, "{level:?}");
+                    assert_eq!(output, expected);
+                }
+                Err(err) => {
+                    assert!(caught, "{level:?}: {err}");
+                    let header_err = matches!(err, ZchunkError::HeaderChecksumNotMatch { .. });
+                    assert_eq!(header_err, bytes == &tampered, "{level:?}: {err}");
+                }
+            }
+        }
 
-        let mut encoder = Encoder::new(input, temp).unwrap();
-        encoder.prepare_chunks().unwrap();
-        encoder.compress_to(output).unwrap();
+        // syncing always verifies, the tampered chunk comes from the cache instead
+        let cache = decoder(&good, Full);
+        let mut output = Vec::new();
+        let err = decoder(&consistent, None)
+            .sync_to(cache, &mut output)
+            .unwrap_err();
+        assert!(
+            matches!(err, ZchunkError::ChunkChecksumNotMatch { id: Some(1), .. }),
+            "{err}"
+        );
+    }
 
-        test_decoder_inner(
-            "testdata/unittest-primary.xml.zck",
-            "14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68",
+    #[test]
+    fn test_debug_redacts_bytes() {
+        let mut bytes = Vec::new();
+        VariantInt::from(0).write_to(&mut bytes).unwrap();
+        VariantInt::from(543).write_to(&mut bytes).unwrap();
+        bytes.extend([0x30, 0x45]);
+        bytes.resize(bytes.len() + 541, 0x01);
+        let signature = Signature::from_reader(bytes.as_slice()).unwrap();
+        assert_eq!(
+            format!("{signature:?}"),
+            "Signature { type: 0, size: 543, bytes: \"3045010101010101...\" (543 bytes) }"
+        );
+
+        let chunk = Chunk::new(Checksum::from_bytes(&[0xab; 16]).unwrap(), 10, 20);
+        assert_eq!(
+            format!("{chunk:?}"),
+            "Chunk { stream: None, checksum: \"abababababababababababababababab\", length: 10, uncompressed_length: 20 }"
         );
     }
 
+    /// A header with a dict chunk of `dict_length` and a data chunk after it, and a signature
+    /// declaring `signature_size` bytes of which 8 follow
+    fn near_limit_header(dict_length: u64, signature_size: u64) -> Vec<u8> {
+        let checksum_size = DEFAULT_CHECKSUM_TYPE.digest_size();
+        let mut dict = Chunk::new(Checksum::zeroed(checksum_size), 0, 1);
+        dict.length = dict_length.into();
+        let chunk = Chunk::new(Checksum::zeroed(checksum_size), 4, 4);
+        let index = Index::new(Some(dict), vec![chunk]).unwrap();
+        let preface = Preface::new([0; 32]);
+        let signatures = Signatures::new(vec![Signature {
+            type_: 0.into(),
+            size: signature_size.into(),
+            signature: vec![0; 8],
+        }]);
+        let header_size = signatures.byte_size() + index.byte_size() + preface.byte_size();
+        let mut header = Header::new(Lead::new(header_size).unwrap(), preface, index, signatures);
+        header.compute_and_set_checksum().unwrap();
+        let mut bytes = Vec::new();
+        header.write_to(&mut bytes, false).unwrap();
+        bytes
+    }
+
     #[test]
-    fn test_sync() {
-        let source_file = File::open("testdata/c25ffa05cf1fdeb67801847df96c33933b1ee1ea081af52edff4ff371a1c814c-comps-Server.x86_64.xml.zck").unwrap();
-        let mut source_reader = BufReader::new(source_file);
+    fn test_platform_limit_offsets() {
+        // the data chunk starts at 2^63, past the largest offset a seek reaches
+        let bytes = near_limit_header(1 << 63, 8);
+        let mut decoder = Decoder::new(Cursor::new(bytes)).unwrap();
+        let (chunk, offset) = decoder.header.index.data_chunks[0].clone();
+        assert!(matches!(
+            decoder.get_chunk_data(Some(0), offset, &chunk),
+            Err(ZchunkError::PlatformLimit { what: "file offset", value }) if value > MAX_FILE_OFFSET
+        ));
+
+        // just below the limit the seek succeeds and the read finds the end of the file
+        let bytes = near_limit_header(MAX_FILE_OFFSET - 1024, 8);
+        let mut decoder = Decoder::new(Cursor::new(bytes)).unwrap();
+        let (chunk, offset) = decoder.header.index.data_chunks[0].clone();
+        assert!(matches!(
+            decoder.get_chunk_data(Some(0), offset, &chunk),
+            Err(ZchunkError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof
+        ));
+    }
 
-        let cache_file = File::open("testdata/3c6181c789ef9e8ed23f4072eb2f8f529002abd5166273a9734d7d39f7a810ae-comps-Server.x86_64.xml.zck").unwrap();
-        let mut cache_reader = BufReader::new(cache_file);
+    #[test]
+    fn test_platform_limit_sizes() {
+        // a signature size past the end of the header is not allocated up front
+        let size = 1 << 40;
+        let result = Decoder::new(Cursor::new(near_limit_header(1, size)));
+        #[cfg(target_pointer_width = "64")]
+        assert!(matches!(
+            result.err().unwrap(),
+            ZchunkError::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof
+        ));
+        #[cfg(target_pointer_width = "32")]
+        assert!(matches!(
+            result.err().unwrap(),
+            ZchunkError::PlatformLimit { what: "signature size", value } if value == size
+        ));
 
-        let mut source_decoder = Decoder::new(&mut source_reader).unwrap();
-        let cache_decoder = Decoder::new(&mut cache_reader).unwrap();
-        let mut hasher = Sha256::new();
-        source_decoder.sync_to(cache_decoder, &mut hasher).unwrap();
         assert_eq!(
-            hex::encode(hasher.finalize()),
-            "c25ffa05cf1fdeb67801847df96c33933b1ee1ea081af52edff4ff371a1c814c"
+            to_usize(u32::MAX as u64, "chunk length").unwrap(),
+            u32::MAX as usize
         );
+        #[cfg(target_pointer_width = "32")]
+        assert!(matches!(
+            to_usize(1 << 32, "chunk length"),
+            Err(ZchunkError::PlatformLimit {
+                what: "chunk length",
+                value: 0x1_0000_0000
+            })
+        ));
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_declared_uncompressed_length() {
+        let file_with = |uncompressed_length: u64, options: DecodeOptions| {
+            let bytes = HeaderBuilder::new()
+                .chunk("00", 0, uncompressed_length)
+                .auto_checksums()
+                .to_file_bytes(&[zstd::encode_all(&b"<group>"[..], 3).unwrap()])
+                .unwrap();
+            Decoder::with_options(Cursor::new(bytes), options).unwrap()
+        };
+        let file = |uncompressed_length| file_with(uncompressed_length, DecodeOptions::new());
+
+        // a length far beyond the chunk is not allocated up front
+        assert_eq!(file(1 << 46).decompress_chunk(0).unwrap(), b"<group>");
+        assert_eq!(file(7).decompress_chunk(0).unwrap(), b"<group>");
+
+        // output past the declared length is refused on every path
+        let too_large = |result: Result<(), ZchunkError>| {
+            assert!(matches!(
+                result,
+                Err(ZchunkError::ChunkTooLarge {
+                    id: Some(0),
+                    uncompressed_length: 6
+                })
+            ));
+        };
+        too_large(file(6).decompress_chunk(0).map(|_| ()));
+        too_large(file(6).decompress_to(io::sink()));
+        // a transform decompresses the whole chunk at once
+        let options = DecodeOptions::new().transform(std::sync::Arc::new(crate::IdentityTransform));
+        too_large(file_with(6, options).decompress_chunk_to(0, io::sink()));
+
+        // neither is a compressed length past the end of the file
+        let mut bytes = Vec::new();
+        HeaderBuilder::new()
+            .chunk("00000000000000000000000000000000", 1 << 46, 7)
+            .build()
+            .unwrap()
+            .write_to(&mut bytes, false)
+            .unwrap();
+        bytes.extend_from_slice(b"<group>");
+        let mut decoder = Decoder::new(Cursor::new(bytes)).unwrap();
+        assert!(matches!(
+            decoder.decompress_chunk(0),
+            Err(ZchunkError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof
+        ));
     }
 }
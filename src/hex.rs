@@ -0,0 +1,50 @@
+use std::fmt;
+
+/// Number of leading bytes shown by `HexPrefix`
+const PREFIX_LEN: usize = 8;
+
+/// Formats bytes as one hex string, meant for short values such as checksums
+pub(crate) struct Hex<'a>(pub(crate) &'a [u8]);
+
+impl fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|b| write!(f, "{b:02x}"))
+    }
+}
+
+impl fmt::Debug for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{self}\"")
+    }
+}
+
+/// Debug formats a byte buffer of any size as a short hex prefix and its length, so large
+/// buffers do not end up in logs
+pub(crate) struct HexPrefix<'a>(pub(crate) &'a [u8]);
+
+impl fmt::Debug for HexPrefix<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ellipsis = if self.0.len() > PREFIX_LEN { "..." } else { "" };
+        let prefix = &self.0[..self.0.len().min(PREFIX_LEN)];
+        write!(f, "\"{}{ellipsis}\" ({} bytes)", Hex(prefix), self.0.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Hex, HexPrefix};
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(format!("{:?}", Hex(&[0x30, 0x45, 0x0a])), "\"30450a\"");
+        assert_eq!(format!("{}", Hex(&[])), "");
+        assert_eq!(
+            format!("{:?}", HexPrefix(&[0x30, 0x45])),
+            "\"3045\" (2 bytes)"
+        );
+        assert_eq!(
+            format!("{:?}", HexPrefix(&[0xab; 543])),
+            "\"abababababababab...\" (543 bytes)"
+        );
+    }
+}
@@ -1,8 +1,311 @@
-mod chunker;
+mod annotation;
+mod anomaly;
+mod assembler;
+#[cfg(feature = "async")]
+mod async_encoder;
+mod audit;
+mod availability;
+mod bloom;
+#[cfg(feature = "zstd")]
+mod cache;
+mod cancel;
+mod capabilities;
+mod chain;
+#[cfg(feature = "zstd")]
+mod checkpoint;
+mod checksum;
+mod chunk_key;
+pub mod chunker;
+#[cfg(feature = "zstd")]
+mod compress;
+mod compression;
+#[cfg(feature = "zstd")]
+mod decompress;
+mod delta;
 mod errors;
-mod format;
+pub mod format;
+mod hex;
+mod manifest;
+#[cfg(feature = "zstd")]
+mod migrate;
+mod options;
+mod partial;
+pub mod plan;
+mod planner;
+pub mod prelude;
+#[cfg(feature = "zstd")]
+mod recompress;
+mod report;
+mod scrub;
+pub mod sidecar;
+mod sign;
+#[cfg(feature = "zstd")]
+mod sink;
+mod sniff;
+mod source;
+mod sync;
+mod temp;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
+mod transform;
 mod types;
+mod uncompressed_checksum;
+pub mod verify;
 
-pub use errors::ZchunkError;
-pub use format::{Decoder, Encoder};
+pub use anomaly::{Anomaly, AnomalyOptions, AnomalyReason};
+pub use assembler::PipelinedAssembler;
+#[cfg(feature = "async")]
+pub use async_encoder::AsyncEncoder;
+pub use audit::{boundary_audit, BoundaryAudit};
+pub use availability::ChunkAvailability;
+pub use bloom::ChunkBloom;
+#[cfg(feature = "zstd")]
+pub use cache::{DecompressedCache, LruChunkCache};
+pub use cancel::CancelToken;
+pub use capabilities::{capabilities, capabilities_with, Capabilities, UnsupportedFeature};
+pub use chain::ChainedReader;
+pub use checksum::{verify_chunk_checksum, Checksum, ChecksumType};
+pub use chunk_key::ChunkKey;
+pub use chunker::{estimate_chunker_params, ChunkerParams};
+#[cfg(feature = "zstd")]
+pub use compress::{compress_file, compress_file_to};
+#[cfg(feature = "zstd")]
+pub use compression::ZstdCompression;
+pub use compression::{Compression, CompressionRegistry, NoCompression, CUSTOM_COMPRESSION_TYPES};
+#[cfg(feature = "zstd")]
+pub use decompress::decompress_file;
+pub use errors::{ErrorPath, WriteStage, ZchunkError};
+#[cfg(feature = "zstd")]
+pub use format::Encoder;
+pub use format::{
+    Chunk, ChunkId, CompressionType, Decoder, Header, IndexEntry, OptionalElement, PartialDecoder,
+};
+pub use manifest::ChunkManifestEntry;
+#[cfg(feature = "zstd")]
+pub use migrate::{migrate, migrate_with_options, MigrationReport, Quirk};
+pub use options::{AssemblerOptions, DecodeOptions, SyncFileOptions};
+#[cfg(feature = "zstd")]
+pub use options::{EncoderOptions, RestoreOptions, VerificationLevel};
+pub use partial::PartialManifest;
+pub use planner::{CoalescePolicy, CoalescedRequest, RangePlanner};
+#[cfg(feature = "zstd")]
+pub use recompress::recompress;
+pub use report::{
+    ChunkSizeSummary, ChunkStats, DictEffectiveness, EncodeProgress, EncodeReport, EncodeStats,
+    RatioPercentiles, SyncStats,
+};
+pub use scrub::{ScrubBudget, ScrubProgress, ScrubState, Scrubber};
+pub use sidecar::{read_envelope, write_envelope, SidecarKind};
+pub use sign::{sign_in_place, Signer};
+#[cfg(feature = "zstd")]
+pub use sink::EncoderSink;
+pub use sniff::{is_zchunk, KnownFormat};
+pub use source::{ChunkSource, RetryingSource};
+pub use sync::sync_file;
+pub use temp::{FileTemp, MemoryTemp, SpillingTemp, TempStore};
+pub use transform::{ChunkTransform, IdentityTransform};
 pub use types::{ReadVariantInt, VariantInt, WriteVariantInt};
+pub use verify::{FailureReason, VerifyFailure, VerifyOptions, VerifyReport};
+
+#[cfg(test)]
+mod tests {
+    use std::any::type_name;
+
+    /// Every type exported from the crate root, with the module it is defined in
+    #[test]
+    fn test_root_exports() {
+        let mut expected = vec![
+            "dyn zchunk::sign::Signer",
+            "dyn zchunk::source::ChunkSource",
+            "dyn zchunk::temp::TempStore",
+            "dyn zchunk::transform::ChunkTransform",
+            "dyn zchunk::types::ReadVariantInt",
+            "dyn zchunk::types::WriteVariantInt",
+            "usize",
+            "zchunk::anomaly::Anomaly",
+            "zchunk::anomaly::AnomalyOptions",
+            "zchunk::anomaly::AnomalyReason",
+            "zchunk::audit::BoundaryAudit",
+            "zchunk::availability::ChunkAvailability",
+            "zchunk::bloom::ChunkBloom",
+            "zchunk::cancel::CancelToken",
+            "zchunk::capabilities::Capabilities",
+            "zchunk::capabilities::UnsupportedFeature",
+            "zchunk::chain::ChainedReader<core::option::IntoIter<()>>",
+            "zchunk::checksum::Checksum",
+            "zchunk::checksum::ChecksumType",
+            "zchunk::chunk_key::ChunkKey",
+            "zchunk::chunker::ChunkerParams",
+            "dyn zchunk::compression::Compression",
+            "zchunk::compression::CompressionRegistry",
+            "zchunk::compression::NoCompression",
+            "zchunk::errors::ErrorPath",
+            "zchunk::errors::WriteStage",
+            "zchunk::errors::ZchunkError",
+            "zchunk::format::Chunk",
+            "zchunk::format::CompressionType",
+            "zchunk::format::Decoder<()>",
+            "zchunk::format::Header",
+            "zchunk::format::IndexEntry",
+            "zchunk::format::OptionalElement",
+            "zchunk::format::PartialDecoder<()>",
+            "zchunk::assembler::PipelinedAssembler<alloc::vec::Vec<u8>>",
+            "zchunk::manifest::ChunkManifestEntry",
+            "zchunk::options::AssemblerOptions",
+            "zchunk::options::DecodeOptions",
+            "zchunk::options::SyncFileOptions",
+            "zchunk::partial::PartialManifest",
+            "zchunk::planner::CoalescePolicy",
+            "zchunk::planner::CoalescedRequest",
+            "zchunk::planner::RangePlanner",
+            "zchunk::report::ChunkSizeSummary",
+            "zchunk::report::ChunkStats",
+            "zchunk::report::DictEffectiveness",
+            "zchunk::report::EncodeProgress",
+            "zchunk::report::EncodeReport",
+            "zchunk::report::EncodeStats",
+            "zchunk::report::RatioPercentiles",
+            "zchunk::report::SyncStats",
+            "zchunk::scrub::ScrubBudget",
+            "zchunk::scrub::ScrubProgress",
+            "zchunk::scrub::ScrubState",
+            "zchunk::scrub::Scrubber<()>",
+            "zchunk::sidecar::SidecarKind",
+            "zchunk::sniff::KnownFormat",
+            "zchunk::source::RetryingSource<'_, ()>",
+            "zchunk::temp::FileTemp",
+            "zchunk::temp::MemoryTemp",
+            "zchunk::temp::SpillingTemp",
+            "zchunk::transform::IdentityTransform",
+            "zchunk::types::VariantInt",
+            "zchunk::verify::FailureReason",
+            "zchunk::verify::VerifyFailure",
+            "zchunk::verify::VerifyOptions",
+            "zchunk::verify::VerifyReport",
+        ];
+        let mut exports = vec![
+            type_name::<crate::Anomaly>(),
+            type_name::<crate::AnomalyOptions>(),
+            type_name::<crate::AnomalyReason>(),
+            type_name::<crate::BoundaryAudit>(),
+            type_name::<crate::ChunkAvailability>(),
+            type_name::<crate::ChunkBloom>(),
+            type_name::<crate::CancelToken>(),
+            type_name::<crate::Capabilities>(),
+            type_name::<crate::UnsupportedFeature>(),
+            type_name::<crate::ChainedReader<std::option::IntoIter<()>>>(),
+            type_name::<crate::Checksum>(),
+            type_name::<crate::ChecksumType>(),
+            type_name::<crate::ChunkKey>(),
+            type_name::<crate::ChunkerParams>(),
+            type_name::<dyn crate::Compression>(),
+            type_name::<crate::CompressionRegistry>(),
+            type_name::<crate::NoCompression>(),
+            type_name::<crate::ErrorPath>(),
+            type_name::<crate::WriteStage>(),
+            type_name::<crate::ZchunkError>(),
+            type_name::<crate::Chunk>(),
+            type_name::<crate::ChunkId>(),
+            type_name::<crate::CompressionType>(),
+            type_name::<crate::Decoder<()>>(),
+            type_name::<crate::Header>(),
+            type_name::<crate::IndexEntry>(),
+            type_name::<crate::OptionalElement>(),
+            type_name::<crate::PartialDecoder<()>>(),
+            type_name::<crate::PipelinedAssembler<Vec<u8>>>(),
+            type_name::<crate::ChunkManifestEntry>(),
+            type_name::<crate::AssemblerOptions>(),
+            type_name::<crate::DecodeOptions>(),
+            type_name::<crate::SyncFileOptions>(),
+            type_name::<crate::PartialManifest>(),
+            type_name::<crate::CoalescePolicy>(),
+            type_name::<crate::CoalescedRequest>(),
+            type_name::<crate::RangePlanner>(),
+            type_name::<crate::ChunkSizeSummary>(),
+            type_name::<crate::ChunkStats>(),
+            type_name::<crate::DictEffectiveness>(),
+            type_name::<crate::EncodeProgress>(),
+            type_name::<crate::EncodeReport>(),
+            type_name::<crate::EncodeStats>(),
+            type_name::<crate::RatioPercentiles>(),
+            type_name::<crate::SyncStats>(),
+            type_name::<crate::ScrubBudget>(),
+            type_name::<crate::ScrubProgress>(),
+            type_name::<crate::ScrubState>(),
+            type_name::<crate::Scrubber<()>>(),
+            type_name::<crate::SidecarKind>(),
+            type_name::<crate::KnownFormat>(),
+            type_name::<dyn crate::Signer>(),
+            type_name::<dyn crate::ChunkSource>(),
+            type_name::<crate::RetryingSource<()>>(),
+            type_name::<crate::FileTemp>(),
+            type_name::<crate::MemoryTemp>(),
+            type_name::<crate::SpillingTemp>(),
+            type_name::<dyn crate::TempStore>(),
+            type_name::<dyn crate::ChunkTransform>(),
+            type_name::<crate::IdentityTransform>(),
+            type_name::<dyn crate::ReadVariantInt>(),
+            type_name::<crate::VariantInt>(),
+            type_name::<dyn crate::WriteVariantInt>(),
+            type_name::<crate::FailureReason>(),
+            type_name::<crate::VerifyFailure>(),
+            type_name::<crate::VerifyOptions>(),
+            type_name::<crate::VerifyReport>(),
+        ];
+        let _: fn(_, _, &'static [u8], _) -> _ = crate::boundary_audit;
+        let _: fn(_, _, _) -> _ = crate::verify_chunk_checksum;
+        let _: fn(_, _, _, Vec<u8>) -> _ = crate::write_envelope;
+        let _: fn(std::io::Empty) -> _ = crate::read_envelope;
+        let _: fn() -> _ = crate::capabilities;
+        let _: fn(&crate::CompressionRegistry) -> _ = crate::capabilities_with;
+        let _: fn(_, _) -> _ = crate::estimate_chunker_params;
+        let _: std::ops::RangeInclusive<u8> = crate::CUSTOM_COMPRESSION_TYPES;
+        let _: fn(std::io::Empty) -> _ = crate::is_zchunk;
+        let _: fn(&mut std::io::Cursor<Vec<u8>>, _) -> _ = crate::sign_in_place;
+        let _: fn(&mut crate::Decoder<std::io::Empty>, _, _) -> _ = crate::sync_file;
+
+        #[cfg(feature = "zstd")]
+        {
+            expected.extend([
+                "dyn zchunk::cache::DecompressedCache",
+                "zchunk::cache::LruChunkCache",
+                "zchunk::format::Encoder<(), ()>",
+                "zchunk::migrate::MigrationReport",
+                "zchunk::migrate::Quirk",
+                "zchunk::options::EncoderOptions",
+                "zchunk::options::RestoreOptions",
+                "zchunk::options::VerificationLevel",
+                "zchunk::sink::EncoderSink<()>",
+                "zchunk::compression::ZstdCompression",
+            ]);
+            exports.extend([
+                type_name::<dyn crate::DecompressedCache>(),
+                type_name::<crate::LruChunkCache>(),
+                type_name::<crate::Encoder<(), ()>>(),
+                type_name::<crate::MigrationReport>(),
+                type_name::<crate::Quirk>(),
+                type_name::<crate::EncoderOptions>(),
+                type_name::<crate::RestoreOptions>(),
+                type_name::<crate::VerificationLevel>(),
+                type_name::<crate::EncoderSink<()>>(),
+                type_name::<crate::ZstdCompression>(),
+            ]);
+            let _: fn(&mut crate::Decoder<std::io::Empty>, _, Vec<u8>) -> _ = crate::recompress;
+            let _: fn(&std::path::Path, Vec<u8>, _) -> _ = crate::compress_file_to;
+            let _: fn(_, _, _) -> _ = crate::compress_file;
+            let _: fn(_, _) -> _ = crate::decompress_file;
+            let _: fn(std::io::Empty, Vec<u8>) -> _ = crate::migrate;
+            let _: fn(std::io::Empty, Vec<u8>, _) -> _ = crate::migrate_with_options;
+        }
+
+        #[cfg(feature = "async")]
+        {
+            expected.push("zchunk::async_encoder::AsyncEncoder<(), ()>");
+            exports.push(type_name::<crate::AsyncEncoder<(), ()>>());
+        }
+
+        exports.sort();
+        expected.sort();
+        assert_eq!(exports, expected);
+    }
+}
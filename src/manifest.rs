@@ -0,0 +1,238 @@
+#[cfg(feature = "zstd")]
+use std::io::Read;
+use std::io::{BufRead, Seek, Write};
+
+use crate::{
+    errors::ZchunkError,
+    format::{Chunk, ChunkId, Decoder, Header},
+    hex::Hex,
+};
+#[cfg(feature = "zstd")]
+use crate::{format::Encoder, temp::TempStore};
+
+/// A data chunk of `Encoder::manifest`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ChunkManifestEntry {
+    pub index: ChunkId,
+    /// Where the chunk starts in the uncompressed data
+    pub uncompressed_offset: u64,
+    pub uncompressed_len: u64,
+    pub compressed_len: u64,
+    pub checksum_hex: String,
+}
+
+/// Write the manifest line of a data chunk, `offset` is relative to the end of the header
+pub(crate) fn write_chunk_line(
+    mut writer: impl Write,
+    id: ChunkId,
+    offset: u64,
+    chunk: &Chunk,
+) -> Result<(), ZchunkError> {
+    writeln!(
+        writer,
+        "{id}\t{offset}\t{}\t{}\t{}",
+        chunk.length.to_u64()?,
+        chunk.uncompressed_length.to_u64()?,
+        Hex(&chunk.checksum)
+    )?;
+    Ok(())
+}
+
+/// Write the manifest lines that follow the data chunks, the dict and the header digests
+pub(crate) fn write_trailer(mut writer: impl Write, header: &Header) -> Result<(), ZchunkError> {
+    let dict = &header.index.dict_chunk;
+    writeln!(
+        writer,
+        "dict\t0\t{}\t{}\t{}",
+        dict.length.to_u64()?,
+        dict.uncompressed_length.to_u64()?,
+        Hex(&dict.checksum)
+    )?;
+    writeln!(
+        writer,
+        "header\t{}\t{}",
+        Hex(&header.preface.data_checksum),
+        Hex(&header.lead.header_checksum)
+    )?;
+    Ok(())
+}
+
+impl<R: BufRead + Seek> Decoder<R> {
+    /// Write a plain text manifest of the chunks in the file
+    ///
+    /// The format is stable. Each line ends with `\n` and holds tab separated fields:
+    ///
+    /// - one line per data chunk, in index order:
+    ///   `chunk_index  offset  length  uncompressed_length  checksum_hex`, where `offset` is
+    ///   relative to the end of the header
+    /// - the dict chunk, in the same form with the index `dict` and offset 0, all zero when
+    ///   the file has no dict
+    /// - `header  data_checksum_hex  header_checksum_hex`
+    ///
+    /// `EncoderOptions::manifest_writer` produces the same manifest while encoding.
+    pub fn write_manifest(&self, mut writer: impl Write) -> Result<(), ZchunkError> {
+        let header = self.header();
+        for (id, (chunk, offset)) in header.index.data_chunks.iter().enumerate() {
+            write_chunk_line(&mut writer, id, *offset, chunk)?;
+        }
+        write_trailer(writer, header)
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl<RW: TempStore, R: Read> Encoder<RW, R> {
+    /// The data chunks of the file `compress_to` writes, which requires `prepare_chunks`
+    ///
+    /// The entries are taken from the index of the prepared header, so they are what a
+    /// `Decoder` of the file reads. With the `serde` feature they serialize, to JSON for
+    /// example, for pipelines that diff the chunks of two builds.
+    pub fn manifest(&self) -> Result<Vec<ChunkManifestEntry>, ZchunkError> {
+        let header = self.header().ok_or(ZchunkError::HeaderNotFound)?;
+        let mut uncompressed_offset = 0u64;
+        header
+            .index
+            .data_chunks
+            .iter()
+            .enumerate()
+            .map(|(index, (chunk, _))| {
+                let uncompressed_len = chunk.uncompressed_length.to_u64()?;
+                let entry = ChunkManifestEntry {
+                    index,
+                    uncompressed_offset,
+                    uncompressed_len,
+                    compressed_len: chunk.length.to_u64()?,
+                    checksum_hex: Hex(&chunk.checksum).to_string(),
+                };
+                uncompressed_offset += uncompressed_len;
+                Ok(entry)
+            })
+            .collect()
+    }
+}
+
+#[cfg(all(test, feature = "zstd"))]
+mod tests {
+    use std::{
+        fs::File,
+        io::{self, Cursor, Read, Write},
+        sync::{Arc, Mutex},
+    };
+
+    use crate::{
+        checksum::DEFAULT_CHECKSUM_TYPE, ChunkerParams, Decoder, Encoder, EncoderOptions,
+        ZchunkError,
+    };
+
+    const INPUT: &str = "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml";
+
+    /// A writer whose output stays readable after it is boxed away
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn assert_manifests_match(options: EncoderOptions) -> String {
+        let streamed = SharedBuffer::default();
+        let options = options.manifest_writer(Box::new(streamed.clone()));
+        let input = File::open(INPUT).unwrap();
+        let mut encoder = Encoder::with_options(input, Cursor::new(Vec::new()), options).unwrap();
+        encoder.prepare_chunks().unwrap();
+        let mut output = Vec::new();
+        encoder.compress_to(&mut output).unwrap();
+
+        let mut manifest = Vec::new();
+        Decoder::new(Cursor::new(output))
+            .unwrap()
+            .write_manifest(&mut manifest)
+            .unwrap();
+        let streamed = streamed.0.lock().unwrap().clone();
+        assert_eq!(
+            String::from_utf8(streamed).unwrap(),
+            String::from_utf8(manifest.clone()).unwrap()
+        );
+
+        String::from_utf8(manifest).unwrap()
+    }
+
+    #[test]
+    fn test_manifest_round_trip() {
+        let manifest = assert_manifests_match(EncoderOptions::new());
+        let lines: Vec<&str> = manifest.lines().collect();
+        assert!(lines.len() > 2);
+        assert!(lines[0].starts_with("0\t0\t"));
+        assert_eq!(lines[0].split('\t').count(), 5);
+        assert_eq!(
+            lines[lines.len() - 2],
+            format!(
+                "dict\t0\t0\t0\t{}",
+                "00".repeat(DEFAULT_CHECKSUM_TYPE.digest_size())
+            )
+        );
+        assert!(lines[lines.len() - 1].starts_with("header\t"));
+
+        // with a dict, once streamed and once held back until the dict is kept or dropped
+        let mut dict = vec![0; 4096];
+        File::open(INPUT).unwrap().read_exact(&mut dict).unwrap();
+        let manifest = assert_manifests_match(EncoderOptions::new().dict(dict.clone()));
+        assert!(!manifest.contains("dict\t0\t0\t"));
+        assert_manifests_match(
+            EncoderOptions::new()
+                .dict(dict)
+                .auto_drop_ineffective_dict(0.0),
+        );
+    }
+
+    #[test]
+    fn test_encoder_manifest() {
+        let options = EncoderOptions::new().chunker_params(ChunkerParams::new(1024, 8192, 2047));
+        let mut encoder =
+            Encoder::with_options(File::open(INPUT).unwrap(), Cursor::new(Vec::new()), options)
+                .unwrap();
+        assert!(matches!(
+            encoder.manifest(),
+            Err(ZchunkError::HeaderNotFound)
+        ));
+        encoder.prepare_chunks().unwrap();
+        let manifest = encoder.manifest().unwrap();
+        let mut output = Vec::new();
+        encoder.compress_to(&mut output).unwrap();
+
+        let decoder = Decoder::new(Cursor::new(output)).unwrap();
+        let chunks = &decoder.header().index.data_chunks;
+        assert!(chunks.len() > 1);
+        assert_eq!(manifest.len(), chunks.len());
+        let mut uncompressed_offset = 0;
+        for (i, (entry, (chunk, _))) in manifest.iter().zip(chunks).enumerate() {
+            assert_eq!(entry.index, i);
+            assert_eq!(entry.uncompressed_offset, uncompressed_offset);
+            assert_eq!(
+                entry.uncompressed_len,
+                chunk.uncompressed_length.to_u64().unwrap()
+            );
+            assert_eq!(entry.compressed_len, chunk.length.to_u64().unwrap());
+            assert_eq!(entry.checksum_hex, hex::encode(chunk.checksum.as_bytes()));
+            uncompressed_offset += entry.uncompressed_len;
+        }
+        assert_eq!(uncompressed_offset, std::fs::metadata(INPUT).unwrap().len());
+
+        #[cfg(feature = "serde")]
+        {
+            let json = serde_json::to_string(&manifest).unwrap();
+            assert!(json.starts_with(r#"[{"index":0,"uncompressed_offset":0,"#));
+            assert_eq!(
+                serde_json::from_str::<Vec<super::ChunkManifestEntry>>(&json).unwrap(),
+                manifest
+            );
+        }
+    }
+}
@@ -0,0 +1,391 @@
+use std::io::{BufRead, Seek, Write};
+
+use sha2::{Digest, Sha256};
+
+use crate::{
+    checksum::{compute_checksum, Checksum},
+    errors::{WriteStage, ZchunkError},
+    format::{
+        compress_chunk, decompress_with, Chunk, ChunkId, CompressionType, CountingWriter, Decoder,
+        Header, Index, Lead, Preface, Signatures, ZstdParams, DEFAULT_COMPRESSION_LEVEL,
+    },
+    options::DecodeOptions,
+};
+
+/// A deviation from what this crate writes today, found and fixed by `migrate`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum Quirk {
+    /// The header checksum did not match the header, it was recomputed
+    HeaderChecksumMismatch,
+    /// The data checksum in the preface did not match the chunks, it was recomputed
+    DataChecksumMismatch,
+    /// The empty dict entry had a non-zero checksum, it was set to zero
+    EmptyDictChecksum,
+    /// Zstd frames of these data chunks did not record their content size, they were
+    /// compressed again
+    MissingFrameContentSize(Vec<ChunkId>),
+}
+
+/// What `migrate` found and did
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MigrationReport {
+    /// Every quirk that was fixed, empty when the file was already conformant
+    pub quirks: Vec<Quirk>,
+    /// Data chunks in the file, the boundaries are the ones of the old index
+    pub chunks: usize,
+    /// Data chunks copied as they were, the others were compressed again
+    pub chunks_copied: usize,
+}
+
+impl MigrationReport {
+    /// Whether the output differs from the input only in ways that need no fixing
+    pub fn is_clean(&self) -> bool {
+        self.quirks.is_empty()
+    }
+}
+
+/// Rewrite a file written by an earlier version of this crate as a conformant one
+///
+/// The header is parsed without checking the header and data checksums, which are only
+/// reported when they do not match. Every chunk is verified against its chunk checksum and
+/// uncompressed length, a chunk failing those is corrupt and fails the migration. The old
+/// chunk boundaries are kept, and chunks that are already conformant are copied as they are,
+/// so migrated files dedup against each other and against files of the current `Encoder`.
+/// Signatures no longer match the new header and are dropped. The chunks are held in memory
+/// until the header is written.
+///
+/// Chunks are decompressed by the backend of the compression type of the file, only zstd
+/// chunks are compressed again. Files of a `CompressionType::Custom` need
+/// `migrate_with_options` with the backend registered.
+pub fn migrate(
+    input: impl BufRead + Seek,
+    output: impl Write,
+) -> Result<MigrationReport, ZchunkError> {
+    migrate_with_options(input, output, DecodeOptions::new())
+}
+
+/// `migrate`, decoding `input` with `options`, for the `DecodeOptions::compression_registry`
+pub fn migrate_with_options(
+    input: impl BufRead + Seek,
+    output: impl Write,
+    options: DecodeOptions,
+) -> Result<MigrationReport, ZchunkError> {
+    let mut decoder = Decoder::with_options(input, options)?;
+    let mut report = MigrationReport {
+        chunks: decoder.header.index.data_chunks.len(),
+        ..Default::default()
+    };
+    if decoder.header.computed_checksum()? != decoder.header.lead.header_checksum {
+        report.quirks.push(Quirk::HeaderChecksumMismatch);
+    }
+
+    let checksum_type = decoder.header.checksum_type()?;
+    let compression_type = decoder.header.compression_type()?;
+    let mut dict_chunk = decoder.header.index.dict_chunk.clone();
+    let dict_data = decoder.get_chunk_data(None, 0, &dict_chunk)?;
+    if !decoder.header.index.has_dict() && dict_chunk.checksum.iter().any(|&b| b != 0) {
+        dict_chunk.checksum = Checksum::zeroed(checksum_type.digest_size());
+        report.quirks.push(Quirk::EmptyDictChecksum);
+    }
+    let dict = decoder.get_uncompressed_dict()?;
+
+    let mut old_hasher = Sha256::new();
+    let mut new_hasher = Sha256::new();
+    old_hasher.update(&dict_data);
+    new_hasher.update(&dict_data);
+
+    let mut recompressed = Vec::new();
+    let mut chunks = Vec::with_capacity(report.chunks);
+    let mut payloads = Vec::with_capacity(report.chunks);
+    for id in 0..report.chunks {
+        let (chunk, offset) = decoder.header.index.data_chunks[id].clone();
+        let data = decoder.get_chunk_data(Some(id), offset, &chunk)?;
+        old_hasher.update(&data);
+        let backend = decoder.options.backend(compression_type)?;
+        let mut uncompressed = Vec::new();
+        let found = decompress_with(
+            backend,
+            data.as_slice(),
+            dict.as_deref(),
+            &mut uncompressed,
+            Some(id),
+            &chunk,
+        )?;
+        let expected = chunk.uncompressed_length.to_u64()?;
+        if found != expected {
+            return Err(ZchunkError::SizeNotMatch { expected, found });
+        }
+
+        // only zstd frames record a content size, other chunks are copied once verified
+        let conformant = compression_type != CompressionType::Zstd
+            || matches!(zstd::zstd_safe::get_frame_content_size(&data), Ok(Some(_)));
+        let (chunk, data) = if conformant {
+            (chunk, data)
+        } else {
+            recompressed.push(id);
+            let compressed = compress_chunk(
+                &uncompressed,
+                &ZstdParams::with_level(DEFAULT_COMPRESSION_LEVEL),
+                dict.as_deref(),
+            )?;
+            let mut new_chunk = Chunk::new(
+                compute_checksum(checksum_type, &compressed)?,
+                compressed.len() as u64,
+                expected,
+            );
+            new_chunk.stream = chunk.stream;
+            (new_chunk, compressed)
+        };
+        new_hasher.update(&data);
+        chunks.push(chunk);
+        payloads.push(data);
+    }
+
+    let old = &decoder.header.preface;
+    if old_hasher.finalize()[..] != old.data_checksum {
+        report.quirks.push(Quirk::DataChecksumMismatch);
+    }
+    report.chunks_copied = report.chunks - recompressed.len();
+    if !recompressed.is_empty() {
+        report
+            .quirks
+            .push(Quirk::MissingFrameContentSize(recompressed));
+    }
+
+    let preface = Preface {
+        data_checksum: new_hasher.finalize()[..].try_into()?,
+        flags: old.flags.clone(),
+        compression_type: old.compression_type.clone(),
+        optional_elements: old.optional_elements.clone(),
+    };
+    let index = Index::with_checksum_type(checksum_type, Some(dict_chunk), chunks)?;
+    let signatures = Signatures::new(Vec::new());
+    let header_size = signatures.byte_size() + index.byte_size() + preface.byte_size();
+    let lead = Lead::with_checksum_type(header_size, decoder.header.lead.checksum_type()?)?;
+    let mut header = Header::new(lead, preface, index, signatures);
+    header.compute_and_set_checksum()?;
+
+    let mut writer = CountingWriter::new(output);
+    header
+        .write_to(&mut writer, false)
+        .map_err(|e| writer.fail(WriteStage::Header, e))?;
+    writer
+        .write_all(&dict_data)
+        .map_err(|e| writer.fail(WriteStage::Dict, e))?;
+    for (id, payload) in payloads.iter().enumerate() {
+        writer
+            .write_all(payload)
+            .map_err(|e| writer.fail(WriteStage::Chunk(id), e))?;
+    }
+
+    Ok(report)
+}
+
+#[cfg(all(test, feature = "sha512"))]
+mod tests {
+    use std::{fs, io::Cursor, sync::Arc};
+
+    use super::{migrate, migrate_with_options, Quirk};
+    use crate::{
+        CompressionRegistry, CompressionType, DecodeOptions, Decoder, Encoder, EncoderOptions,
+        NoCompression, VerifyOptions, ZchunkError,
+    };
+
+    /// Written by the `Encoder` of 0.2.0 from the comps XML in testdata, before chunks
+    /// recorded their content size
+    const LEGACY: &str = "testdata/legacy-0.2.0-comps-Server.x86_64.xml.zck";
+    const UPSTREAM: &str = "testdata/c25ffa05cf1fdeb67801847df96c33933b1ee1ea081af52edff4ff371a1c814c-comps-Server.x86_64.xml.zck";
+
+    fn decompressed(bytes: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+        Decoder::new(Cursor::new(bytes))
+            .unwrap()
+            .decompress_to(&mut output)
+            .unwrap();
+        output
+    }
+
+    fn assert_conformant(bytes: &[u8]) {
+        let mut decoder = Decoder::new(Cursor::new(bytes)).unwrap();
+        let report = decoder
+            .verify(&VerifyOptions::new().frame_headers_only(false))
+            .unwrap();
+        assert!(report.is_ok(), "{report:?}");
+        for id in 0..decoder.header().index.data_chunks.len() {
+            let range = decoder.header().chunk_range(id).unwrap();
+            let frame = &bytes[range.start as usize..range.end as usize];
+            assert!(zstd::zstd_safe::get_frame_content_size(frame)
+                .unwrap()
+                .is_some());
+        }
+    }
+
+    #[test]
+    fn test_migrate_legacy() {
+        let legacy = fs::read(LEGACY).unwrap();
+        let mut output = Vec::new();
+        let report = migrate(Cursor::new(&legacy), &mut output).unwrap();
+        assert_eq!(report.chunks, 3);
+        assert_eq!(report.chunks_copied, 0);
+        assert_eq!(
+            report.quirks,
+            vec![Quirk::MissingFrameContentSize(vec![0, 1, 2])]
+        );
+        assert_conformant(&output);
+        assert_eq!(decompressed(&output), decompressed(&legacy));
+
+        // the old boundaries are kept
+        let lengths = |bytes: &[u8]| -> Vec<u64> {
+            let decoder = Decoder::new(Cursor::new(bytes)).unwrap();
+            decoder
+                .header()
+                .index
+                .data_chunks
+                .iter()
+                .map(|(c, _)| c.uncompressed_length.to_u64().unwrap())
+                .collect()
+        };
+        assert_eq!(lengths(&output), lengths(&legacy));
+
+        // a migrated file is conformant and migrates to itself
+        let mut again = Vec::new();
+        let report = migrate(Cursor::new(&output), &mut again).unwrap();
+        assert!(report.is_clean());
+        assert_eq!(report.chunks_copied, 3);
+        assert_eq!(again, output);
+    }
+
+    #[test]
+    fn test_migrate_checksums() {
+        let mut bytes = fs::read(UPSTREAM).unwrap();
+        let mut output = Vec::new();
+        assert!(migrate(Cursor::new(&bytes), &mut output)
+            .unwrap()
+            .is_clean());
+        assert_eq!(output, bytes);
+
+        // the data checksum follows the header checksum in the lead, then the preface
+        let decoder = Decoder::new(Cursor::new(&bytes)).unwrap();
+        let data_checksum = decoder.header().lead.byte_size();
+        bytes[data_checksum] ^= 0xff;
+        let report = migrate(Cursor::new(&bytes), &mut output).unwrap();
+        assert_eq!(
+            report.quirks,
+            vec![Quirk::HeaderChecksumMismatch, Quirk::DataChecksumMismatch]
+        );
+
+        let mut migrated = Vec::new();
+        migrate(Cursor::new(&bytes), &mut migrated).unwrap();
+        assert_conformant(&migrated);
+        assert_eq!(migrated, fs::read(UPSTREAM).unwrap());
+    }
+
+    #[test]
+    fn test_migrate_empty_dict_checksum() {
+        let mut bytes = fs::read(LEGACY).unwrap();
+        let header_size = Decoder::new(Cursor::new(&bytes))
+            .unwrap()
+            .header()
+            .data_offset()
+            .unwrap() as usize;
+        let dict_checksum = bytes[..header_size]
+            .windows(16)
+            .position(|w| w == [0; 16])
+            .unwrap();
+        bytes[dict_checksum] = 1;
+
+        let mut output = Vec::new();
+        let report = migrate(Cursor::new(&bytes), &mut output).unwrap();
+        assert_eq!(
+            report.quirks[..2],
+            [Quirk::HeaderChecksumMismatch, Quirk::EmptyDictChecksum]
+        );
+        assert_conformant(&output);
+        let decoder = Decoder::new(Cursor::new(&output)).unwrap();
+        assert_eq!(decoder.header().index.dict_chunk.checksum, [0; 16]);
+    }
+
+    #[test]
+    fn test_migrate_not_zstd() {
+        let input = fs::read(
+            "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml",
+        )
+        .unwrap();
+        let encode = |options: EncoderOptions| {
+            let mut file = Vec::new();
+            Encoder::compress_small(&input, &mut file, options).unwrap();
+            file
+        };
+
+        // stored chunks are copied as they are
+        let stored = encode(EncoderOptions::new().compression_type(CompressionType::None));
+        let mut output = Vec::new();
+        let report = migrate(Cursor::new(&stored), &mut output).unwrap();
+        assert!(report.is_clean(), "{report:?}");
+        assert_eq!(report.chunks_copied, report.chunks);
+        assert!(output == stored);
+
+        // custom chunks too, given the backend
+        let registry = CompressionRegistry::new()
+            .register(0xf0, Arc::new(NoCompression))
+            .unwrap();
+        let custom = encode(
+            EncoderOptions::new()
+                .compression_registry(registry.clone())
+                .compression_type(CompressionType::Custom(0xf0))
+                .dict(b"<group>".repeat(64)),
+        );
+        assert!(matches!(
+            migrate(Cursor::new(&custom), Vec::new()),
+            Err(ZchunkError::InvalidCompresionType(0xf0))
+        ));
+        let mut output = Vec::new();
+        let options = DecodeOptions::new().compression_registry(registry);
+        let report = migrate_with_options(Cursor::new(&custom), &mut output, options).unwrap();
+        assert!(report.is_clean(), "{report:?}");
+        assert!(output == custom);
+
+        // a stored chunk is still checked against its uncompressed length
+        let header = Decoder::new(Cursor::new(&stored)).unwrap();
+        let (chunk, _) = &header.header().index.data_chunks[0];
+        let mut entry = Vec::new();
+        chunk.write_to(&mut entry).unwrap();
+        let mut doctored_chunk = chunk.clone();
+        let length = chunk.uncompressed_length.to_u64().unwrap();
+        doctored_chunk.uncompressed_length = (length ^ 1).into();
+        let mut doctored = Vec::new();
+        doctored_chunk.write_to(&mut doctored).unwrap();
+        assert_eq!(doctored.len(), entry.len());
+        let at = stored
+            .windows(entry.len())
+            .position(|window| window == entry)
+            .unwrap();
+        let mut bytes = stored.clone();
+        bytes[at..at + entry.len()].copy_from_slice(&doctored);
+        assert!(migrate(Cursor::new(&bytes), Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_migrate_corrupt_chunk() {
+        let mut bytes = fs::read(LEGACY).unwrap();
+        let decoder = Decoder::new(Cursor::new(&bytes)).unwrap();
+        let start = decoder.header().chunk_range(1).unwrap().start as usize;
+        bytes[start + 20] ^= 0xff;
+        assert!(migrate(Cursor::new(&bytes), Vec::new()).is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_migration_report_serde() {
+        let mut output = Vec::new();
+        let report = migrate(Cursor::new(fs::read(LEGACY).unwrap()), &mut output).unwrap();
+        let json = serde_json::to_string(&report).unwrap();
+        assert_eq!(
+            serde_json::from_str::<super::MigrationReport>(&json).unwrap(),
+            report
+        );
+    }
+}
@@ -0,0 +1,812 @@
+use std::sync::Arc;
+#[cfg(feature = "zstd")]
+use std::{
+    fs,
+    io::{self, BufRead, Seek, Write},
+    path::Path,
+    sync::{Mutex, MutexGuard},
+};
+
+#[cfg(feature = "zstd")]
+use sha2::{Digest, Sha256};
+
+#[cfg(feature = "zstd")]
+use crate::{
+    cache::DecompressedCache,
+    cancel::CancelToken,
+    checksum::{ChecksumType, ChunkHasher, DEFAULT_CHECKSUM_TYPE},
+    chunker::{estimate_chunker_params, ChunkerParams},
+    compression::{Compression, LargeWindowZstd},
+    errors::ZchunkError,
+    format::{
+        check_header_checksum_type, ChunkId, CompressionType, Decoder, OptionalElement, ZstdParams,
+        DEFAULT_COMPRESSION_LEVEL,
+    },
+    report::EncodeProgress,
+    sign::Signer,
+};
+use crate::{compression::CompressionRegistry, transform::ChunkTransform};
+
+/// Upper bound of the chunk list preallocated from the input size hint
+#[cfg(feature = "zstd")]
+const MAX_PREALLOCATED_CHUNKS: usize = 1 << 16;
+
+/// Picks the stream of a data chunk from its id and uncompressed data
+#[cfg(feature = "zstd")]
+pub(crate) type ChunkStreamFn = dyn Fn(ChunkId, &[u8]) -> u64 + Send + Sync;
+
+/// Saves a checkpoint, given the input offset to resume from and the checkpoint
+#[cfg(feature = "zstd")]
+pub(crate) type SaveCheckpointFn = dyn Fn(u64, &[u8]) -> io::Result<()> + Send + Sync;
+
+/// Options that control how `Encoder` produces a zchunk file
+#[cfg(feature = "zstd")]
+#[derive(Clone, Default)]
+pub struct EncoderOptions {
+    pub(crate) transform: Option<Arc<dyn ChunkTransform>>,
+    pub(crate) chunker_params: ChunkerParams,
+    pub(crate) compression_level: Option<i32>,
+    pub(crate) window_log: Option<u32>,
+    pub(crate) long_distance_matching: bool,
+    pub(crate) workers: Option<u32>,
+    pub(crate) compression_type: Option<CompressionType>,
+    pub(crate) compression_registry: CompressionRegistry,
+    pub(crate) store_incompressible: bool,
+    pub(crate) dict: Option<Vec<u8>>,
+    /// The dict chunk to store instead of compressing `dict`, see `dict_from`
+    pub(crate) compressed_dict: Option<Vec<u8>>,
+    pub(crate) auto_drop_dict_threshold: Option<f64>,
+    pub(crate) auto_dict_max_size: Option<usize>,
+    pub(crate) chunk_annotations: Option<Vec<u64>>,
+    pub(crate) chunk_stream: Option<Arc<ChunkStreamFn>>,
+    pub(crate) manifest_writer: Option<Arc<Mutex<Box<dyn Write + Send>>>>,
+    pub(crate) reserved_signature: Option<(u64, usize)>,
+    pub(crate) signers: Vec<Arc<dyn Signer>>,
+    pub(crate) optional_elements: Vec<OptionalElement>,
+    pub(crate) checksum_type: Option<ChecksumType>,
+    pub(crate) header_checksum_type: Option<ChecksumType>,
+    pub(crate) input_size_hint: Option<u64>,
+    pub(crate) max_chunks: Option<usize>,
+    pub(crate) deterministic: bool,
+    pub(crate) fresh_zstd_context: bool,
+    pub(crate) uncompressed_checksum: bool,
+    pub(crate) cancel_token: Option<CancelToken>,
+    pub(crate) verify_output: bool,
+    pub(crate) progress: Option<Arc<dyn Fn(EncodeProgress) + Send + Sync>>,
+    pub(crate) checkpoint_every: Option<(u64, Arc<SaveCheckpointFn>)>,
+}
+
+#[cfg(feature = "zstd")]
+impl EncoderOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Transform every compressed data chunk before it is stored, chunks are stored as is by default
+    pub fn transform(mut self, transform: Arc<dyn ChunkTransform>) -> Self {
+        self.transform = Some(transform);
+        self
+    }
+
+    /// Compress data chunks with a zstd dict, which is stored as the dict chunk
+    pub fn dict(mut self, dict: Vec<u8>) -> Self {
+        self.dict = Some(dict);
+        self.compressed_dict = None;
+        self
+    }
+
+    /// Compress data chunks with the dict of the zchunk file `decoder` reads, and store its
+    /// dict chunk byte for byte
+    ///
+    /// The chunk checksum type is taken from the file too, so the dict chunk has the same
+    /// checksum as in the old index. Re-encoding a changed input with the dict of its
+    /// previous version keeps the chunks that did not change identical, which is what a
+    /// client syncing from the old file reuses. A file without a dict only sets the checksum
+    /// type.
+    pub fn dict_from<R: BufRead + Seek>(
+        mut self,
+        decoder: &mut Decoder<R>,
+    ) -> Result<Self, ZchunkError> {
+        self.checksum_type = Some(decoder.header().checksum_type()?);
+        let Some(dict) = decoder.get_uncompressed_dict()? else {
+            return Ok(self);
+        };
+        let compressed_dict = match decoder.header().compression_type()? {
+            CompressionType::None => None,
+            CompressionType::Zstd | CompressionType::Custom(_) => {
+                let dict_chunk = decoder.header().index.dict_chunk.clone();
+                Some(decoder.get_chunk_data(None, 0, &dict_chunk)?)
+            }
+        };
+        self = self.dict(dict);
+        self.compressed_dict = compressed_dict;
+        Ok(self)
+    }
+
+    /// Compress data chunks with the zstd dict read from `path`, see `dict`
+    pub fn dict_file(self, path: impl AsRef<Path>) -> Result<Self, ZchunkError> {
+        Ok(self.dict(fs::read(path)?))
+    }
+
+    /// Train a zstd dict of at most `max_size` bytes from the chunks of the input, and
+    /// compress the data chunks with it, unless a dict is set with `dict`
+    ///
+    /// The whole input is chunked and held in memory before the first chunk is compressed,
+    /// and a failing reader cannot be resumed. Inputs too small to train a dict from are
+    /// encoded without one, `EncodeReport::dict_trained` tells which happened.
+    pub fn auto_dict(mut self, max_size: usize) -> Self {
+        self.auto_dict_max_size = Some(max_size);
+        self
+    }
+
+    /// Re-encode without the dict when the sampled estimate shows it wastes more than
+    /// `threshold` (a fraction of the dict-less size, 0.0 drops on any regression)
+    ///
+    /// The chunks are recompressed in the temp one at a time, only those a longer
+    /// recompressed chunk overwrites are held in memory.
+    pub fn auto_drop_ineffective_dict(mut self, threshold: f64) -> Self {
+        self.auto_drop_dict_threshold = Some(threshold);
+        self
+    }
+
+    /// Store one application-defined value per data chunk in the preface, read back with
+    /// `Header::chunk_annotation`
+    ///
+    /// The table may be shorter than the chunk list, but not longer.
+    pub fn chunk_annotations(mut self, annotations: Vec<u64>) -> Self {
+        self.chunk_annotations = Some(annotations);
+        self
+    }
+
+    /// Put every data chunk in the stream `stream` returns for its id and uncompressed data,
+    /// which sets the preface stream flag and writes the stream of every index entry
+    ///
+    /// The dict chunk is in stream 0.
+    pub fn chunk_streams(mut self, stream: Arc<ChunkStreamFn>) -> Self {
+        self.chunk_stream = Some(stream);
+        self
+    }
+
+    /// Set the parameters used to split the input into chunks
+    pub fn chunker_params(mut self, params: ChunkerParams) -> Self {
+        self.chunker_params = params;
+        self
+    }
+
+    /// Set the zstd level the dict and data chunks are compressed at, 3 by default
+    ///
+    /// Higher levels trade encoding time for smaller chunks, decoding speed hardly changes.
+    /// Levels zstd does not support are refused by `Encoder::with_options`.
+    pub fn compression_level(mut self, level: i32) -> Self {
+        self.compression_level = Some(level);
+        self
+    }
+
+    pub(crate) fn chunk_compression_level(&self) -> i32 {
+        self.compression_level.unwrap_or(DEFAULT_COMPRESSION_LEVEL)
+    }
+
+    /// Set the log2 of the zstd window, how far back a chunk may reference its own data
+    ///
+    /// Larger windows only help chunks larger than the window the level picks. Readers keep
+    /// zstd's default limit of 2^27 bytes, upstream zchunk among them, and so does `Decoder`
+    /// unless `DecodeOptions::zstd_window_log_max` raises it. Values zstd does not support
+    /// are refused by `Encoder::with_options`.
+    pub fn window_log(mut self, window_log: u32) -> Self {
+        self.window_log = Some(window_log);
+        self
+    }
+
+    /// Enable zstd long distance matching, which finds repeats far back in large chunks
+    ///
+    /// It raises the window to 2^27 bytes unless `window_log` is set. Files encoded with it
+    /// decode like any other.
+    pub fn long_distance_matching(mut self, enable: bool) -> Self {
+        self.long_distance_matching = enable;
+        self
+    }
+
+    /// Compress every chunk with `workers` zstd threads, which only pays off for chunks of
+    /// several MiB, see `ChunkerParams`
+    ///
+    /// 0 compresses in the calling thread, like leaving it unset. The output is the same for
+    /// the same number of workers, but may differ between 0 and other numbers. Needs the
+    /// `zstdmt` feature, without it `Encoder::with_options` refuses any workers but 0.
+    pub fn zstd_workers(mut self, workers: u32) -> Self {
+        self.workers = Some(workers);
+        self
+    }
+
+    pub(crate) fn zstd_params(&self) -> ZstdParams {
+        ZstdParams {
+            level: self.chunk_compression_level(),
+            window_log: self.window_log,
+            long_distance_matching: self.long_distance_matching,
+            workers: match self.deterministic {
+                true => Some(self.workers.unwrap_or(0)),
+                false => self.workers,
+            },
+            pinned: self.deterministic,
+        }
+    }
+
+    /// Set how the data chunks are stored, zstd by default
+    ///
+    /// `CompressionType::None` stores every chunk as it is, for inputs that are compressed
+    /// already, where zstd costs time without saving space. Such files cannot have a dict,
+    /// `Encoder::with_options` refuses one with `DictWithoutCompression`.
+    /// `CompressionType::Custom` compresses with the backend of `compression_registry`.
+    pub fn compression_type(mut self, compression_type: CompressionType) -> Self {
+        self.compression_type = Some(compression_type);
+        self
+    }
+
+    /// Compress with the backends of `registry`, for a `CompressionType::Custom` set with
+    /// `compression_type`
+    ///
+    /// Zstd chunks are still compressed with the level and parameters of these options.
+    pub fn compression_registry(mut self, registry: CompressionRegistry) -> Self {
+        self.compression_registry = registry;
+        self
+    }
+
+    /// Store the whole file uncompressed, without its dict, when zstd makes the data chunks
+    /// and the dict chunk no smaller than the input
+    ///
+    /// A file has one compression type for all its chunks, so the choice is made once per
+    /// file, after all chunks are compressed. Falling back stores the chunks again in the
+    /// temp one at a time, see `EncodeReport::stored_uncompressed`.
+    pub fn store_incompressible(mut self, enable: bool) -> Self {
+        self.store_incompressible = enable;
+        self
+    }
+
+    pub(crate) fn chunk_compression_type(&self) -> CompressionType {
+        self.compression_type.unwrap_or(CompressionType::Zstd)
+    }
+
+    /// Write a manifest of the chunks to `writer` during `prepare_chunks`, in the format of
+    /// `Decoder::write_manifest`
+    ///
+    /// Data chunk lines are written as the chunks are stored, unless an ineffective dict may
+    /// still be dropped or the chunks stored uncompressed, then they follow once that is
+    /// decided. The dict and header lines come last.
+    pub fn manifest_writer(mut self, writer: Box<dyn Write + Send>) -> Self {
+        self.manifest_writer = Some(Arc::new(Mutex::new(writer)));
+        self
+    }
+
+    /// Set the checksum type of the chunk checksums in the index
+    ///
+    /// The default is SHA-512/128, or SHA-256 when the `sha512` feature is off. Types this
+    /// build cannot compute are refused by `Encoder::with_options`.
+    pub fn checksum_type(mut self, checksum_type: ChecksumType) -> Self {
+        self.checksum_type = Some(checksum_type);
+        self
+    }
+
+    pub(crate) fn chunk_checksum_type(&self) -> ChecksumType {
+        match (self.checksum_type, self.deterministic) {
+            (Some(checksum_type), _) => checksum_type,
+            (None, true) => ChecksumType::Sha512_128,
+            (None, false) => DEFAULT_CHECKSUM_TYPE,
+        }
+    }
+
+    /// Set the checksum type of the header checksum in the lead, SHA-256 by default
+    ///
+    /// Only SHA-256 and SHA-512 are allowed, SHA-512 needs the `sha512` feature.
+    pub fn header_checksum_type(mut self, checksum_type: ChecksumType) -> Self {
+        self.header_checksum_type = Some(checksum_type);
+        self
+    }
+
+    pub(crate) fn lead_checksum_type(&self) -> ChecksumType {
+        self.header_checksum_type.unwrap_or(ChecksumType::Sha256)
+    }
+
+    /// Write a placeholder signature of `type_` with `size` zero bytes, which `sign_in_place`
+    /// later overwrites without moving the data
+    pub fn reserve_signature(mut self, type_: u64, size: usize) -> Self {
+        self.reserved_signature = Some((type_, size));
+        self
+    }
+
+    /// Write `element` to the preface and set the optional elements flag
+    ///
+    /// Every call adds an element, they are written in order and before the chunk
+    /// annotations, whose element id is taken.
+    pub fn optional_element(mut self, element: OptionalElement) -> Self {
+        self.optional_elements.push(element);
+        self
+    }
+
+    /// Record the SHA-256 of the uncompressed input in an optional element, see
+    /// `Decoder::decompress_to_verified`
+    ///
+    /// The data checksum of the preface covers the stored chunks, the element lets a reader
+    /// check the decompressed output against a digest published for it. The digest is
+    /// computed while chunking, an element of the same id given to `optional_element` is
+    /// replaced.
+    ///
+    /// The running digest cannot be saved, so an encode with it cannot be checkpointed:
+    /// `Encoder::checkpoint` fails with `InvalidOption`, and so does `validate` together
+    /// with `checkpoint_every`.
+    pub fn uncompressed_checksum(mut self, enable: bool) -> Self {
+        self.uncompressed_checksum = enable;
+        self
+    }
+
+    /// Read the written file back and verify it, in `Encoder::compress_to_seekable` and
+    /// `compress_file`
+    ///
+    /// The file is parsed again, then the header checksum and the checksum of every chunk
+    /// are checked like `Decoder::verify` does, which catches output that a full disk or a
+    /// bad device truncated or corrupted. The first mismatch fails with
+    /// `HeaderChecksumNotMatch` or `OutputVerificationFailed`, `compress_file` then leaves
+    /// the destination untouched. Costs reading the whole file once more.
+    pub fn verify_output(mut self, enable: bool) -> Self {
+        self.verify_output = enable;
+        self
+    }
+
+    /// Sign the header with `signer` once the index is built
+    ///
+    /// Every call adds a signer, their signatures are written in order and before a reserved
+    /// placeholder.
+    pub fn signer(mut self, signer: Arc<dyn Signer>) -> Self {
+        self.signers.push(signer);
+        self
+    }
+
+    /// The expected input size in bytes, used to preallocate and to report progress fractions
+    ///
+    /// With `max_chunks` the hint also picks the chunker parameters, so the output depends
+    /// on it. Otherwise it does not, and a wrong hint only costs allocations and skews the
+    /// fractions. `compress_file` and `compress_file_to` take it from the file metadata
+    /// unless set.
+    pub fn input_size_hint(mut self, bytes: u64) -> Self {
+        self.input_size_hint = Some(bytes);
+        self
+    }
+
+    /// Fail with `TooManyChunks` as soon as the input makes more than `max` data chunks
+    ///
+    /// With an `input_size_hint`, chunker parameters that expect more than `max` chunks from
+    /// the hinted size are replaced by `estimate_chunker_params` before any input is read, so
+    /// a huge input gets larger chunks instead of failing halfway. The normalization level
+    /// is kept. Without a hint the chunker parameters are kept and the limit only fails fast.
+    /// Chunks are at most `MAX_CHUNK_SIZE`, so a hint past `max` times that still fails.
+    pub fn max_chunks(mut self, max: usize) -> Self {
+        self.max_chunks = Some(max);
+        self
+    }
+
+    /// Check the options without encoding anything, `Encoder::with_options` does the same
+    ///
+    /// Fails with `InvalidChunkerParams` when the chunker parameters do not validate, with
+    /// `InvalidOption` for a compression level, window log or number of zstd workers out of
+    /// zstd's bounds, with `InvalidZstdParameter` when zstd refuses the settings anyway, with
+    /// `InvalidCompresionType` for a compression type without a backend, with
+    /// `DictWithoutCompression` for a dict on uncompressed chunks, and with
+    /// `InvalidChecksumType` for a header checksum type other than SHA-256 and SHA-512 or a
+    /// chunk checksum type that is compiled out.
+    pub fn validate(&self) -> Result<(), ZchunkError> {
+        self.chunker_params.validate()?;
+        self.tuned_chunker_params().validate()?;
+        self.zstd_params().check()?;
+        self.compression_registry
+            .backend(self.chunk_compression_type())?;
+        if self.chunk_compression_type() == CompressionType::None
+            && (self.dict.is_some() || self.auto_dict_max_size.is_some())
+        {
+            return Err(ZchunkError::DictWithoutCompression);
+        }
+        check_header_checksum_type(self.lead_checksum_type())?;
+        ChunkHasher::new(self.chunk_checksum_type())?;
+        if self.uncompressed_checksum && self.checkpoint_every.is_some() {
+            return Err(ZchunkError::InvalidOption {
+                name: "uncompressed_checksum",
+                value: 1,
+                reason: "an encode with it cannot be checkpointed".into(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Stop `prepare_chunks` with `Cancelled` once `token` is cancelled
+    ///
+    /// The token is checked before every chunk is stored, and while the chunks to train a
+    /// dict from are read. A cancelled prepare cannot be resumed: the temp holds the chunks
+    /// stored so far, which `Encoder::reset` lets the next prepare overwrite.
+    pub fn cancel_token(mut self, token: CancelToken) -> Self {
+        self.cancel_token = Some(token);
+        self
+    }
+
+    /// `Cancelled` once the `cancel_token` is cancelled
+    pub(crate) fn check_cancelled(&self) -> Result<(), ZchunkError> {
+        match &self.cancel_token {
+            Some(token) => token.check(),
+            None => Ok(()),
+        }
+    }
+
+    /// Scale the chunker parameters up when the size hint expects more than `max_chunks`
+    pub(crate) fn tune_chunker_params(&mut self) {
+        self.chunker_params = self.tuned_chunker_params();
+    }
+
+    /// The chunker parameters `tune_chunker_params` picks, which `validate` checks too
+    fn tuned_chunker_params(&self) -> ChunkerParams {
+        let (Some(max), Some(hint)) = (self.max_chunks, self.input_size_hint) else {
+            return self.chunker_params.clone();
+        };
+        let average = self.chunker_params.average_size().max(1) as u64;
+        if hint / average <= max as u64 {
+            return self.chunker_params.clone();
+        }
+        estimate_chunker_params(hint, max)
+            .normalization_level(self.chunker_params.normalization_level)
+    }
+
+    /// Pin every parameter the output depends on, so the same input and options give the
+    /// same bytes on every build and run
+    ///
+    /// The encoder holds no state that differs between runs, and the temp is only read back
+    /// where it was written, but some defaults depend on the build: the chunk checksum type
+    /// is SHA-512/128 here even without the `sha512` feature, where `Encoder::with_options`
+    /// then refuses it unless `checksum_type` is set. The zstd frame flags and the number of
+    /// zstd workers are set explicitly instead of left to the zstd crate.
+    ///
+    /// The output still changes with the options: the level, `window_log`,
+    /// `long_distance_matching`, `zstd_workers`, the chunker parameters, the dict, the
+    /// checksum types, the optional elements and the signature placeholder, and with
+    /// whatever a transform, chunk stream function or signer returns. It also depends on
+    /// the version of the linked zstd library, whose matchers may change between releases.
+    pub fn deterministic(mut self, enable: bool) -> Self {
+        self.deterministic = enable;
+        self
+    }
+
+    /// Compress every data chunk with a zstd context of its own, instead of one context
+    /// reused for all chunks of the input
+    ///
+    /// The output is the same, creating a context and loading the dict for every chunk is
+    /// only slower. It is kept to rule out state carried from one chunk to the next when
+    /// debugging.
+    pub fn fresh_zstd_context(mut self, enable: bool) -> Self {
+        self.fresh_zstd_context = enable;
+        self
+    }
+
+    /// Call `progress` after every data chunk stored by `prepare_chunks`, and once more when
+    /// all input is chunked
+    pub fn progress(mut self, progress: Arc<dyn Fn(EncodeProgress) + Send + Sync>) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// Call `save` with a checkpoint between data chunks every `interval` bytes of input
+    /// read by `prepare_chunks`, so an encode that dies can be continued with
+    /// `Encoder::resume`
+    ///
+    /// `save` gets the input offset the resumed encoder's reader must be positioned at, and
+    /// the checkpoint `Encoder::checkpoint` would write. The temp is flushed before, and must
+    /// be kept with the checkpoint. An error from `save` stops the prepare. Checkpoints are
+    /// saved while the input is chunked, not for chunks read ahead to train a dict or given
+    /// to `prepare_chunks_from`.
+    pub fn checkpoint_every(mut self, interval: u64, save: Arc<SaveCheckpointFn>) -> Self {
+        self.checkpoint_every = Some((interval, save));
+        self
+    }
+
+    /// The number of data chunks expected from the input size hint, bounded so a wrong hint
+    /// cannot allocate much
+    pub(crate) fn estimated_chunk_count(&self) -> usize {
+        let hint = self.input_size_hint.unwrap_or(0);
+        let average = self.chunker_params.average_size().max(1) as u64;
+        (hint / average).min(MAX_PREALLOCATED_CHUNKS as u64) as usize
+    }
+
+    /// The header size expected from the input size hint, see
+    /// `Encoder::compress_into_seekable`
+    pub(crate) fn estimated_header_size(&self) -> u64 {
+        // the magic, varints and data checksum of the lead, preface and index
+        let fixed = 5 + 12 + 32 + self.lead_checksum_type().digest_size();
+        // a checksum and two lengths per chunk, the dict chunk included
+        let entry = self.chunk_checksum_type().digest_size() + 5;
+        let signature = self.reserved_signature.map_or(0, |(_, size)| size + 4)
+            + self.signers.iter().map(|s| s.max_size() + 4).sum::<usize>();
+        (fixed + entry * (self.estimated_chunk_count() + 1) + signature) as u64
+    }
+
+    pub(crate) fn report_progress(
+        &self,
+        bytes_consumed: u64,
+        chunks: usize,
+        bytes_written: u64,
+        done: bool,
+    ) {
+        let Some(progress) = &self.progress else {
+            return;
+        };
+        let fraction = self.input_size_hint.map(|hint| match (done, hint) {
+            (true, _) => 1.0,
+            (false, 0) => 0.0,
+            (false, hint) => (bytes_consumed as f64 / hint as f64).min(1.0),
+        });
+        progress(EncodeProgress {
+            bytes_consumed,
+            chunks,
+            bytes_written,
+            fraction,
+        });
+    }
+
+    /// Whether data chunks may still change after they are stored
+    pub(crate) fn chunks_may_change(&self) -> bool {
+        (self.dict.is_some() && self.auto_drop_dict_threshold.is_some())
+            || self.store_incompressible
+    }
+
+    /// A digest of the options the stored chunks depend on, which an `Encoder::checkpoint`
+    /// records so `Encoder::resume` refuses other options
+    ///
+    /// A transform and a chunk stream function cannot be compared, only whether they are set.
+    pub(crate) fn stored_chunks_digest(&self) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        let params = &self.chunker_params;
+        for n in [params.min as u64, params.max as u64, params.bitmask.into()] {
+            hasher.update(n.to_le_bytes());
+        }
+        hasher.update([params.normalization_level]);
+        let zstd = self.zstd_params();
+        hasher.update(zstd.level.to_le_bytes());
+        for n in [zstd.window_log, zstd.workers] {
+            hasher.update(n.map_or(u64::MAX, u64::from).to_le_bytes());
+        }
+        hasher.update([
+            self.chunk_compression_type().to_u8(),
+            self.chunk_checksum_type().to_u8(),
+            zstd.long_distance_matching.into(),
+            zstd.pinned.into(),
+            self.store_incompressible.into(),
+            self.uncompressed_checksum.into(),
+            self.transform.is_some().into(),
+            self.chunk_stream.is_some().into(),
+        ]);
+        hasher.update(
+            self.auto_drop_dict_threshold
+                .map_or(u64::MAX, f64::to_bits)
+                .to_le_bytes(),
+        );
+        hasher.update(self.max_chunks.map_or(u64::MAX, |n| n as u64).to_le_bytes());
+        for data in [&self.dict, &self.compressed_dict] {
+            match data {
+                Some(data) => {
+                    hasher.update((data.len() as u64).to_le_bytes());
+                    hasher.update(data);
+                }
+                None => hasher.update(u64::MAX.to_le_bytes()),
+            }
+        }
+        hasher.finalize().into()
+    }
+
+    pub(crate) fn lock_manifest_writer(&self) -> Option<MutexGuard<'_, Box<dyn Write + Send>>> {
+        // a poisoned writer may have lost a partial line, which the caller sees from the panic
+        self.manifest_writer
+            .as_ref()
+            .map(|w| w.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+}
+
+/// Options that control how `Decoder::decompress_to_seekable` writes the uncompressed data
+#[cfg(feature = "zstd")]
+#[derive(Debug, Clone, Default)]
+pub struct RestoreOptions {
+    pub(crate) skip_zero_chunks: bool,
+}
+
+#[cfg(feature = "zstd")]
+impl RestoreOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Seek past data chunks that decompress to all zeros instead of writing them, which
+    /// leaves holes in writers backed by sparse files
+    pub fn skip_zero_chunks(mut self, enable: bool) -> Self {
+        self.skip_zero_chunks = enable;
+        self
+    }
+}
+
+/// Options that control how `sync_file` replaces the cache file
+#[derive(Debug, Clone)]
+pub struct SyncFileOptions {
+    pub(crate) fsync: bool,
+}
+
+impl Default for SyncFileOptions {
+    fn default() -> Self {
+        Self { fsync: true }
+    }
+}
+
+impl SyncFileOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Flush the new file and the rename to disk before returning, enabled by default
+    ///
+    /// Without it a power loss shortly after the sync may leave the old cache, but never a
+    /// partially written one.
+    pub fn fsync(mut self, enable: bool) -> Self {
+        self.fsync = enable;
+        self
+    }
+}
+
+/// Default bound of the reorder buffer of `PipelinedAssembler`
+const DEFAULT_MAX_BUFFERED_BYTES: u64 = 64 << 20;
+
+/// Options that control how `PipelinedAssembler` splits the file into ranges and buffers
+/// them
+#[derive(Debug, Clone)]
+pub struct AssemblerOptions {
+    pub(crate) max_buffered_bytes: u64,
+    pub(crate) max_range_bytes: u64,
+}
+
+impl Default for AssemblerOptions {
+    fn default() -> Self {
+        Self {
+            max_buffered_bytes: DEFAULT_MAX_BUFFERED_BYTES,
+            max_range_bytes: 0,
+        }
+    }
+}
+
+impl AssemblerOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hold at most `bytes` of ranges that arrived ahead of the write frontier, 64 MiB by
+    /// default
+    pub fn max_buffered_bytes(mut self, bytes: u64) -> Self {
+        self.max_buffered_bytes = bytes;
+        self
+    }
+
+    /// Group consecutive chunks into ranges of up to `bytes`, a larger chunk gets a range of
+    /// its own
+    ///
+    /// By default every chunk is a range.
+    pub fn max_range_bytes(mut self, bytes: u64) -> Self {
+        self.max_range_bytes = bytes;
+        self
+    }
+}
+
+/// How much of a file `Decoder` checks while decompressing, see
+/// `DecodeOptions::verification`
+#[cfg(feature = "zstd")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum VerificationLevel {
+    /// Check the header checksum and the checksum of every chunk that is read
+    #[default]
+    Full,
+    /// Check the header checksum once, chunks are decompressed without checking them
+    HeaderOnly,
+    /// Check nothing, the data is decompressed as it is found
+    None,
+}
+
+/// Options that control how `Decoder` reads a zchunk file
+#[derive(Clone, Default)]
+pub struct DecodeOptions {
+    pub(crate) transform: Option<Arc<dyn ChunkTransform>>,
+    pub(crate) keep_header_bytes: bool,
+    pub(crate) compression_registry: CompressionRegistry,
+    #[cfg(feature = "zstd")]
+    pub(crate) cache: Option<Arc<dyn DecompressedCache>>,
+    #[cfg(feature = "zstd")]
+    pub(crate) max_buffered_output: Option<usize>,
+    #[cfg(feature = "zstd")]
+    pub(crate) verification: VerificationLevel,
+    #[cfg(feature = "zstd")]
+    pub(crate) large_window_zstd: Option<LargeWindowZstd>,
+}
+
+impl DecodeOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reverse the transform the file was encoded with before decompressing data chunks
+    pub fn transform(mut self, transform: Arc<dyn ChunkTransform>) -> Self {
+        self.transform = Some(transform);
+        self
+    }
+
+    /// Keep the raw header bytes as read from the file, see `Decoder::header_bytes`
+    pub fn keep_header_bytes(mut self, enable: bool) -> Self {
+        self.keep_header_bytes = enable;
+        self
+    }
+
+    /// Decompress files of a `CompressionType::Custom` with the backends of `registry`,
+    /// without it their chunks fail with `InvalidCompresionType`
+    pub fn compression_registry(mut self, registry: CompressionRegistry) -> Self {
+        self.compression_registry = registry;
+        self
+    }
+
+    /// Look up decompressed data chunks in `cache` before decompressing them, and store
+    /// them there afterwards
+    #[cfg(feature = "zstd")]
+    pub fn cache(mut self, cache: Arc<dyn DecompressedCache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Hold back at most `bytes` of a chunk's decompressed data until its checksum is verified
+    ///
+    /// Verified decompression streams the compressed data through the checksum and the zstd
+    /// decoder. By default the whole decompressed chunk is buffered, so nothing of a corrupt
+    /// chunk reaches the writer. With a limit, memory use no longer grows with the chunk size,
+    /// but only the trailing `bytes` of the chunk are written after verification: a corrupt
+    /// chunk still fails with `ChunkChecksumNotMatch`, after its leading data was written.
+    #[cfg(feature = "zstd")]
+    pub fn max_buffered_output(mut self, bytes: usize) -> Self {
+        self.max_buffered_output = Some(bytes);
+        self
+    }
+
+    /// Choose what decompression checks, `VerificationLevel::Full` by default
+    ///
+    /// Below `Full`, corrupt or tampered chunks are decompressed without an error: the output
+    /// may be garbage, or data an attacker chose, and nothing tells. Only lower the level for
+    /// files from a trusted source whose integrity is ensured otherwise, such as artifacts
+    /// this process wrote itself. With `None` even a header that does not match its
+    /// checksum is used as it is.
+    ///
+    /// The level applies to the `decompress_*` methods. `sync_to` and `verify` always check
+    /// every chunk they read.
+    #[cfg(feature = "zstd")]
+    pub fn verification(mut self, level: VerificationLevel) -> Self {
+        self.verification = level;
+        self
+    }
+
+    /// Decompress zstd chunks with windows of up to 2^`log` bytes, instead of zstd's default
+    /// limit of 2^27
+    ///
+    /// Files encoded with an `EncoderOptions::window_log` above 27 need it. zstd allocates
+    /// the window of every chunk it decompresses, so only raise the limit for files from a
+    /// trusted source. A log zstd does not support fails when a chunk is decompressed.
+    #[cfg(feature = "zstd")]
+    pub fn zstd_window_log_max(mut self, log: u32) -> Self {
+        self.large_window_zstd = Some(LargeWindowZstd {
+            window_log_max: log,
+        });
+        self
+    }
+
+    /// The backend that decompresses chunks of `compression_type`
+    #[cfg(feature = "zstd")]
+    pub(crate) fn backend(
+        &self,
+        compression_type: CompressionType,
+    ) -> Result<&dyn Compression, ZchunkError> {
+        if let (CompressionType::Zstd, Some(backend)) = (compression_type, &self.large_window_zstd)
+        {
+            return Ok(backend);
+        }
+        self.compression_registry.backend(compression_type)
+    }
+}
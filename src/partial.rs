@@ -0,0 +1,166 @@
+use std::io::{BufRead, Seek, Write};
+
+use sha2::{Digest, Sha256};
+
+use crate::{
+    errors::{WriteStage, ZchunkError},
+    format::{ChunkId, CountingWriter, Decoder, Header, Index, Lead, Preface, Signatures},
+};
+
+/// How the chunks of a partial archive relate to the original file, see
+/// `Decoder::partial_archive`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PartialManifest {
+    /// The header checksum of the original file
+    pub original_header_checksum: Vec<u8>,
+    /// `(original id, partial id)` of every kept data chunk, in index order
+    pub kept: Vec<(ChunkId, ChunkId)>,
+    /// Data chunks of the original left out of the partial archive, in index order
+    pub omitted: Vec<ChunkId>,
+}
+
+impl PartialManifest {
+    /// The id in the partial archive of the original data chunk `id`, `None` when omitted
+    pub fn partial_id(&self, id: ChunkId) -> Option<ChunkId> {
+        self.kept
+            .binary_search_by_key(&id, |&(original, _)| original)
+            .ok()
+            .map(|i| self.kept[i].1)
+    }
+}
+
+impl<R: BufRead + Seek> Decoder<R> {
+    /// Write a valid zchunk file holding the dict and only the data chunks in `keep`
+    ///
+    /// The kept chunks are stored in index order, duplicates in `keep` are written once. The
+    /// chunks are copied as they are and checked on the way, the header is new: offsets and
+    /// the data checksum are recomputed, while the optional elements of the preface, which
+    /// may refer to chunk ids, and the signatures are dropped. The kept chunks are held in
+    /// memory until the header is written.
+    ///
+    /// The manifest maps the chunk ids of both files, so a client holding the omitted chunks
+    /// can put the original data back together.
+    pub fn partial_archive(
+        &mut self,
+        keep: &[ChunkId],
+        out: impl Write,
+    ) -> Result<PartialManifest, ZchunkError> {
+        let total = self.header.index.data_chunks.len();
+        if let Some(&id) = keep.iter().find(|&&id| id >= total) {
+            return Err(ZchunkError::ChunkNotFound(id));
+        }
+        let mut keep = keep.to_vec();
+        keep.sort_unstable();
+        keep.dedup();
+
+        let dict_chunk = self.header.index.dict_chunk.clone();
+        let dict_data = self.get_chunk_data(None, 0, &dict_chunk)?;
+        let mut data_hasher = Sha256::new();
+        data_hasher.update(&dict_data);
+
+        let mut chunks = Vec::with_capacity(keep.len());
+        let mut payloads = Vec::with_capacity(keep.len());
+        for &id in &keep {
+            let (chunk, offset) = self.header.index.data_chunks[id].clone();
+            let data = self.get_chunk_data(Some(id), offset, &chunk)?;
+            data_hasher.update(&data);
+            chunks.push(chunk);
+            payloads.push(data);
+        }
+
+        let old = &self.header.preface;
+        let preface = Preface {
+            data_checksum: data_hasher.finalize()[..].try_into()?,
+            flags: old.flags.without_optional(),
+            compression_type: old.compression_type.clone(),
+            optional_elements: Vec::new(),
+        };
+        let checksum_type = self.header.checksum_type()?;
+        let index = Index::with_checksum_type(checksum_type, Some(dict_chunk), chunks)?;
+        let signatures = Signatures::new(Vec::new());
+        let header_size = signatures.byte_size() + index.byte_size() + preface.byte_size();
+        let lead = Lead::with_checksum_type(header_size, self.header.lead.checksum_type()?)?;
+        let mut header = Header::new(lead, preface, index, signatures);
+        header.compute_and_set_checksum()?;
+
+        let mut writer = CountingWriter::new(out);
+        header
+            .write_to(&mut writer, false)
+            .map_err(|e| writer.fail(WriteStage::Header, e))?;
+        writer
+            .write_all(&dict_data)
+            .map_err(|e| writer.fail(WriteStage::Dict, e))?;
+        for (&id, payload) in keep.iter().zip(&payloads) {
+            writer
+                .write_all(payload)
+                .map_err(|e| writer.fail(WriteStage::Chunk(id), e))?;
+        }
+
+        let omitted = (0..total)
+            .filter(|id| keep.binary_search(id).is_err())
+            .collect();
+        Ok(PartialManifest {
+            original_header_checksum: self.header.lead.header_checksum.to_vec(),
+            kept: keep
+                .into_iter()
+                .enumerate()
+                .map(|(new, id)| (id, new))
+                .collect(),
+            omitted,
+        })
+    }
+}
+
+#[cfg(all(test, feature = "zstd", feature = "sha512"))]
+mod tests {
+    use std::{fs, io::Cursor};
+
+    use sha2::{Digest, Sha256};
+
+    use crate::{Decoder, VerifyOptions, ZchunkError};
+
+    const FILE: &str = "testdata/c25ffa05cf1fdeb67801847df96c33933b1ee1ea081af52edff4ff371a1c814c-comps-Server.x86_64.xml.zck";
+
+    #[test]
+    fn test_partial_archive() {
+        let mut original = Decoder::new(Cursor::new(fs::read(FILE).unwrap())).unwrap();
+        let mut expected = Vec::new();
+        original.decompress_to(&mut expected).unwrap();
+        let total = original.header().index.data_chunks.len();
+
+        for keep in [vec![], vec![2, 0, 2], (0..total).collect()] {
+            let mut archive = Vec::new();
+            let manifest = original.partial_archive(&keep, &mut archive).unwrap();
+            assert_eq!(
+                manifest.original_header_checksum,
+                original.header().lead.header_checksum.to_vec()
+            );
+            assert_eq!(manifest.kept.len() + manifest.omitted.len(), total);
+
+            let mut partial = Decoder::new(Cursor::new(archive)).unwrap();
+            assert!(partial.verify(&VerifyOptions::new()).unwrap().is_ok());
+            assert_eq!(
+                partial.header().index.data_chunks.len(),
+                manifest.kept.len()
+            );
+
+            // the client merges the archive with the chunks it already holds
+            let mut hasher = Sha256::new();
+            for id in 0..total {
+                let data = match manifest.partial_id(id) {
+                    Some(partial_id) => partial.decompress_chunk(partial_id).unwrap(),
+                    None => {
+                        assert!(manifest.omitted.contains(&id));
+                        original.decompress_chunk(id).unwrap()
+                    }
+                };
+                hasher.update(&data);
+            }
+            assert_eq!(hasher.finalize(), Sha256::digest(&expected));
+        }
+
+        let err = original.partial_archive(&[total], Vec::new()).unwrap_err();
+        assert!(matches!(err, ZchunkError::ChunkNotFound(id) if id == total));
+    }
+}
@@ -0,0 +1,8 @@
+//! Planning which chunks of a file to fetch, and in which requests
+
+pub use crate::{
+    availability::ChunkAvailability,
+    bloom::ChunkBloom,
+    chunk_key::ChunkKey,
+    planner::{CoalescePolicy, CoalescedRequest, RangePlanner},
+};
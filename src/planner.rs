@@ -0,0 +1,174 @@
+use std::ops::Range;
+
+/// Constraints for `RangePlanner::coalesce`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoalescePolicy {
+    /// Merge ranges until at most this many requests remain, as far as the waste budget allows
+    pub max_requests: usize,
+    /// Upper bound on the total number of unneeded bytes fetched by all requests
+    pub max_waste_bytes: u64,
+    /// Gaps up to this size are always merged, as far as the waste budget allows
+    pub gap_tolerance: u64,
+}
+
+impl Default for CoalescePolicy {
+    fn default() -> Self {
+        Self {
+            max_requests: usize::MAX,
+            max_waste_bytes: u64::MAX,
+            gap_tolerance: 0,
+        }
+    }
+}
+
+/// A range request produced by `RangePlanner::coalesce`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoalescedRequest {
+    pub range: Range<u64>,
+    /// Bytes inside `range` that were not asked for
+    pub wasted_bytes: u64,
+}
+
+/// Turns byte ranges, e.g. from `Header::missing_ranges`, into fewer range requests
+pub struct RangePlanner;
+
+impl RangePlanner {
+    /// Merge `ranges` into requests that trade round trips for re-downloaded bytes
+    ///
+    /// Overlapping and adjacent ranges are always merged. Then the gaps between the remaining
+    /// ranges are visited from the smallest to the largest, and a gap is merged while it is
+    /// within `gap_tolerance` or there are more than `max_requests` requests, as long as the
+    /// total waste stays within `max_waste_bytes`. The waste budget wins over `max_requests`,
+    /// so more requests than asked for are returned when the budget runs out. Merging the
+    /// smallest gaps first gives the fewest wasted bytes for any number of merges.
+    ///
+    /// The requests are sorted by offset, and ties between equal gaps are broken by offset, so
+    /// the same ranges always give the same requests regardless of their input order.
+    pub fn coalesce(ranges: &[Range<u64>], policy: &CoalescePolicy) -> Vec<CoalescedRequest> {
+        let mut sorted: Vec<Range<u64>> =
+            ranges.iter().filter(|r| r.start < r.end).cloned().collect();
+        sorted.sort_by_key(|r| r.start);
+
+        let mut merged: Vec<Range<u64>> = Vec::new();
+        for range in sorted {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+
+        // gap i lies between merged[i] and merged[i + 1]
+        let gap = |i: usize| merged[i + 1].start - merged[i].end;
+        let mut gaps: Vec<usize> = (0..merged.len().saturating_sub(1)).collect();
+        gaps.sort_by_key(|&i| (gap(i), i));
+
+        let mut bridged = vec![false; gaps.len()];
+        let mut requests = merged.len();
+        let mut waste = 0u64;
+        for i in gaps {
+            let size = gap(i);
+            let wanted = size <= policy.gap_tolerance || requests > policy.max_requests;
+            if !wanted || waste + size > policy.max_waste_bytes {
+                break;
+            }
+            bridged[i] = true;
+            requests -= 1;
+            waste += size;
+        }
+
+        let mut result: Vec<CoalescedRequest> = Vec::with_capacity(requests);
+        for (i, range) in merged.iter().enumerate() {
+            match result.last_mut() {
+                Some(last) if bridged[i - 1] => {
+                    last.wasted_bytes += range.start - last.range.end;
+                    last.range.end = range.end;
+                }
+                _ => result.push(CoalescedRequest {
+                    range: range.clone(),
+                    wasted_bytes: 0,
+                }),
+            }
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CoalescePolicy, CoalescedRequest, RangePlanner};
+
+    fn request(start: u64, end: u64, wasted_bytes: u64) -> CoalescedRequest {
+        CoalescedRequest {
+            range: start..end,
+            wasted_bytes,
+        }
+    }
+
+    #[test]
+    fn test_coalesce_overlapping() {
+        let requests = RangePlanner::coalesce(
+            &[30..40, 0..10, 5..20, 20..25, 50..50],
+            &CoalescePolicy::default(),
+        );
+        assert_eq!(requests, vec![request(0, 25, 0), request(30, 40, 0)]);
+        assert_eq!(
+            RangePlanner::coalesce(&[], &CoalescePolicy::default()),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn test_coalesce_gap_tolerance() {
+        // gaps: 5, 50, 2
+        let ranges = [0..10, 15..20, 70..80, 82..90];
+        let policy = CoalescePolicy {
+            gap_tolerance: 5,
+            ..Default::default()
+        };
+        assert_eq!(
+            RangePlanner::coalesce(&ranges, &policy),
+            vec![request(0, 20, 5), request(70, 90, 2)]
+        );
+    }
+
+    #[test]
+    fn test_coalesce_max_requests() {
+        // gaps: 5, 50, 2, 20
+        let ranges = [0..10, 15..20, 70..80, 82..90, 110..120];
+        let policy = CoalescePolicy {
+            max_requests: 2,
+            ..Default::default()
+        };
+        // the optimum for two requests leaves the largest gap open
+        assert_eq!(
+            RangePlanner::coalesce(&ranges, &policy),
+            vec![request(0, 20, 5), request(70, 120, 22)]
+        );
+
+        let policy = CoalescePolicy {
+            max_requests: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            RangePlanner::coalesce(&ranges, &policy),
+            vec![request(0, 120, 77)]
+        );
+    }
+
+    #[test]
+    fn test_coalesce_waste_budget() {
+        // gaps: 5, 50, 2, 20
+        let ranges = [0..10, 15..20, 70..80, 82..90, 110..120];
+        let policy = CoalescePolicy {
+            max_requests: 1,
+            max_waste_bytes: 30,
+            gap_tolerance: 0,
+        };
+        // 2 + 5 + 20 fit the budget, the 50 byte gap does not
+        assert_eq!(
+            RangePlanner::coalesce(&ranges, &policy),
+            vec![request(0, 20, 5), request(70, 120, 22)]
+        );
+    }
+}
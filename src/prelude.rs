@@ -0,0 +1,12 @@
+//! The most used types and traits, for glob imports
+//!
+//! ```
+//! use zchunk::prelude::*;
+//! ```
+
+pub use crate::{
+    ChecksumType, Chunk, ChunkId, ChunkSource, ChunkTransform, CompressionType, DecodeOptions,
+    Decoder, Header, ReadVariantInt, WriteVariantInt, ZchunkError,
+};
+#[cfg(feature = "zstd")]
+pub use crate::{Encoder, EncoderOptions};
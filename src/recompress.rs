@@ -0,0 +1,182 @@
+use std::{
+    io::{self, BufRead, Seek, Write},
+    sync::Arc,
+};
+
+use crate::{
+    compress::MAX_PREALLOCATED_TEMP,
+    errors::ZchunkError,
+    format::{Decoder, Encoder},
+    options::EncoderOptions,
+    report::EncodeStats,
+    temp::MemoryTemp,
+};
+
+/// Recompress every data chunk of the file `input` reads with `options`, write the new file
+/// to `out` and return its `Encoder::stats`
+///
+/// Chunk boundaries and uncompressed lengths are kept as they are, every chunk is
+/// decompressed and given to `Encoder::prepare_chunks_from`. The dict is the one set in
+/// `options` or trained with `EncoderOptions::auto_dict`, and otherwise the dict of `input`,
+/// reused like `EncoderOptions::dict_from` does. Unless set in `options`, the checksum types,
+/// the preface optional elements and the chunk streams are taken from `input` too.
+/// Signatures no longer match the new index, so they are dropped, `options` may add signers.
+/// The recompressed chunks are held in memory until the header is written.
+pub fn recompress<R: BufRead + Seek>(
+    input: &mut Decoder<R>,
+    mut options: EncoderOptions,
+    out: impl Write,
+) -> Result<EncodeStats, ZchunkError> {
+    let old = input.header();
+    options.checksum_type.get_or_insert(old.checksum_type()?);
+    options
+        .header_checksum_type
+        .get_or_insert(old.lead.checksum_type()?);
+    if options.optional_elements.is_empty() && options.chunk_annotations.is_none() {
+        options.optional_elements = old.optional_elements().to_vec();
+    }
+    if old.preface.flags.has_stream() && options.chunk_stream.is_none() {
+        let streams = old
+            .index
+            .data_chunks
+            .iter()
+            .map(|(c, _)| c.stream.as_ref().map_or(Ok(0), |s| s.to_u64()))
+            .collect::<Result<Vec<_>, _>>()?;
+        options = options.chunk_streams(Arc::new(move |id, _| streams[id]));
+    }
+    let chunk_count = old.index.data_chunks.len();
+    // the data size is read from the header, so it only bounds the preallocation
+    let capacity = old.data_size()?.min(MAX_PREALLOCATED_TEMP);
+    if options.dict.is_none() && options.auto_dict_max_size.is_none() {
+        let checksum_type = options.checksum_type;
+        options = options.dict_from(input)?;
+        options.checksum_type = checksum_type;
+    }
+
+    let temp = MemoryTemp::with_capacity(capacity as usize);
+    let mut encoder = Encoder::with_options(io::empty(), temp, options)?;
+    encoder.prepare_chunks_from((0..chunk_count).map(|id| input.decompress_chunk(id)))?;
+    encoder.compress(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    #[cfg(feature = "sha512")]
+    use std::{fs::File, io::BufReader};
+
+    #[cfg(feature = "sha512")]
+    use sha2::{Digest, Sha256};
+
+    use super::recompress;
+    #[cfg(feature = "sha512")]
+    use crate::ZchunkError;
+    use crate::{test_utils::HeaderBuilder, Decoder, EncoderOptions};
+
+    #[cfg(feature = "sha512")]
+    const FIXTURE: &str = "testdata/c25ffa05cf1fdeb67801847df96c33933b1ee1ea081af52edff4ff371a1c814c-comps-Server.x86_64.xml.zck";
+
+    #[cfg(feature = "sha512")]
+    fn uncompressed_lengths<R>(decoder: &Decoder<R>) -> Vec<u64> {
+        decoder
+            .header
+            .index
+            .data_chunks
+            .iter()
+            .map(|(c, _)| c.uncompressed_length.to_u64().unwrap())
+            .collect()
+    }
+
+    #[cfg(feature = "sha512")]
+    #[test]
+    fn test_recompress() {
+        let mut input = Decoder::new(BufReader::new(File::open(FIXTURE).unwrap())).unwrap();
+        let mut output = Vec::new();
+        let options = EncoderOptions::new().compression_level(19);
+        let stats = recompress(&mut input, options, &mut output).unwrap();
+        assert_eq!(stats.file_size(), output.len() as u64);
+
+        let original_size = std::fs::metadata(FIXTURE).unwrap().len();
+        assert!((output.len() as u64) < original_size);
+
+        let mut decoder = Decoder::new(Cursor::new(output)).unwrap();
+        assert_eq!(uncompressed_lengths(&decoder), uncompressed_lengths(&input));
+        // the stats describe the file written
+        let header = decoder.header();
+        let dict_bytes = header.index.dict_chunk.length.to_u64().unwrap();
+        assert_eq!(stats.chunks, header.index.data_chunks.len());
+        assert_eq!(
+            stats.input_bytes,
+            uncompressed_lengths(&decoder).iter().sum::<u64>()
+        );
+        assert_eq!(stats.dict_bytes, dict_bytes);
+        assert_eq!(
+            stats.compressed_bytes,
+            header.data_size().unwrap() - dict_bytes
+        );
+        assert_eq!(stats.header_bytes, header.header_size().unwrap());
+        // the dict chunk is reused as it is
+        assert_eq!(
+            decoder.header().index.dict_chunk,
+            input.header().index.dict_chunk
+        );
+        assert_eq!(
+            decoder.header().checksum_type().unwrap(),
+            input.header().checksum_type().unwrap()
+        );
+
+        let mut hasher = Sha256::new();
+        decoder.decompress_to(&mut hasher).unwrap();
+        assert_eq!(
+            hex::encode(hasher.finalize()),
+            "14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68"
+        );
+
+        let mut output = Vec::new();
+        let options = EncoderOptions::new().compression_level(1000);
+        assert!(matches!(
+            recompress(&mut input, options, &mut output),
+            Err(ZchunkError::InvalidOption {
+                name: "compression_level",
+                value: 1000,
+                ..
+            })
+        ));
+        assert!(output.is_empty());
+    }
+
+    #[cfg(feature = "sha512")]
+    #[test]
+    fn test_recompress_new_dict() {
+        let mut input = Decoder::new(BufReader::new(File::open(FIXTURE).unwrap())).unwrap();
+        let mut output = Vec::new();
+        let options = EncoderOptions::new().dict(b"<group>".repeat(64));
+        recompress(&mut input, options, &mut output).unwrap();
+
+        let mut decoder = Decoder::new(Cursor::new(output)).unwrap();
+        assert_eq!(uncompressed_lengths(&decoder), uncompressed_lengths(&input));
+        assert_eq!(
+            decoder.get_uncompressed_dict().unwrap().unwrap(),
+            b"<group>".repeat(64)
+        );
+        let mut hasher = Sha256::new();
+        decoder.decompress_to(&mut hasher).unwrap();
+        assert_eq!(
+            hex::encode(hasher.finalize()),
+            "14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68"
+        );
+    }
+
+    #[test]
+    fn test_recompress_truncated() {
+        // the header declares 64 TiB of data that the file does not have
+        let header = HeaderBuilder::new()
+            .chunk("00000000000000000000000000000000", 1 << 46, 1 << 46)
+            .build()
+            .unwrap();
+        let mut bytes = Vec::new();
+        header.write_to(&mut bytes, false).unwrap();
+        let mut input = Decoder::new(Cursor::new(bytes)).unwrap();
+        assert!(recompress(&mut input, EncoderOptions::new(), &mut Vec::new()).is_err());
+    }
+}
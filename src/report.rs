@@ -0,0 +1,134 @@
+use crate::format::ChunkId;
+
+/// Sample-based estimate of how much a dict saves over dict-less compression
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DictEffectiveness {
+    /// Number of data chunks also compressed without the dict
+    pub sampled_chunks: usize,
+    /// Compressed size of the sampled chunks with the dict
+    pub sampled_with_dict: u64,
+    /// Compressed size of the sampled chunks without the dict
+    pub sampled_without_dict: u64,
+    /// Compressed size of all data chunks with the dict
+    pub data_with_dict: u64,
+    /// Size of the dict chunk itself
+    pub dict_chunk_size: u64,
+}
+
+impl DictEffectiveness {
+    /// Estimated size of the data chunks when compressed without the dict
+    pub fn estimated_data_without_dict(&self) -> u64 {
+        if self.sampled_with_dict == 0 {
+            return self.data_with_dict;
+        }
+        (self.data_with_dict as f64 * self.sampled_without_dict as f64
+            / self.sampled_with_dict as f64) as u64
+    }
+
+    /// Estimated bytes saved by the dict, including the cost of the dict chunk,
+    /// negative when the dict makes the output larger
+    pub fn estimated_saving(&self) -> i64 {
+        self.estimated_data_without_dict() as i64
+            - self.data_with_dict as i64
+            - self.dict_chunk_size as i64
+    }
+
+    /// Bytes wasted by the dict as a fraction of the estimated dict-less size, 0 when the dict helps
+    pub fn regression(&self) -> f64 {
+        let saving = self.estimated_saving();
+        if saving >= 0 {
+            return 0.0;
+        }
+        -saving as f64 / self.estimated_data_without_dict().max(1) as f64
+    }
+}
+
+/// What the encoder did while preparing chunks
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EncodeReport {
+    /// Present when a dict was configured
+    pub dict_effectiveness: Option<DictEffectiveness>,
+    /// Whether the configured dict was dropped for being ineffective
+    pub dict_dropped: bool,
+    /// Whether the dict was trained from the input, see `EncoderOptions::auto_dict`
+    pub dict_trained: bool,
+    /// Whether zstd did not pay off and the chunks were stored uncompressed, see
+    /// `EncoderOptions::store_incompressible`
+    pub stored_uncompressed: bool,
+}
+
+/// Sizes of the file a prepared encoder writes, see `Encoder::stats`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EncodeStats {
+    /// Number of data chunks
+    pub chunks: usize,
+    /// Input bytes, the uncompressed size of the data chunks
+    pub input_bytes: u64,
+    /// Compressed size of the data chunks
+    pub compressed_bytes: u64,
+    /// Size of the dict chunk, 0 without a dict
+    pub dict_bytes: u64,
+    /// Size of the header, lead to signatures
+    pub header_bytes: u64,
+    /// Uncompressed sizes of the data chunks, `None` when there are no data chunks
+    pub chunk_sizes: Option<ChunkSizeSummary>,
+}
+
+impl EncodeStats {
+    /// Size of the whole file
+    pub fn file_size(&self) -> u64 {
+        self.header_bytes + self.dict_bytes + self.compressed_bytes
+    }
+}
+
+/// Smallest, average and largest size of a set of chunks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChunkSizeSummary {
+    pub min: u64,
+    pub average: f64,
+    pub max: u64,
+}
+
+/// How far `Encoder::prepare_chunks` got, see `EncoderOptions::progress`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EncodeProgress {
+    /// Input bytes read so far
+    pub bytes_consumed: u64,
+    /// Data chunks stored so far
+    pub chunks: usize,
+    /// Compressed bytes stored so far, the dict chunk included
+    pub bytes_written: u64,
+    /// `bytes_consumed` relative to the input size hint, at most 1.0 and exactly 1.0 once all
+    /// input is chunked, `None` without a hint
+    pub fraction: Option<f64>,
+}
+
+/// Where `Decoder::sync_to` took the data chunks from
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncStats {
+    pub chunks_from_cache: usize,
+    pub chunks_from_source: usize,
+    /// Chunks found in the cache whose data was corrupt or truncated, and were taken from
+    /// the source instead, in index order
+    pub cache_fallbacks: Vec<ChunkId>,
+}
+
+/// Percentiles of the data chunk compression ratios, see `Chunk::ratio`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RatioPercentiles {
+    pub min: f64,
+    pub p10: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub max: f64,
+}
+
+/// Size statistics of the data chunks, from `Header::chunk_stats`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChunkStats {
+    pub chunks: usize,
+    pub compressed_bytes: u64,
+    pub uncompressed_bytes: u64,
+    /// `None` when there are no data chunks
+    pub ratio_percentiles: Option<RatioPercentiles>,
+}
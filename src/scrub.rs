@@ -0,0 +1,279 @@
+//! Verifying a file in slices that can be paused and resumed, see `Scrubber`
+
+use std::{
+    io::{BufRead, Seek},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    errors::ZchunkError,
+    format::{ChunkId, Decoder},
+    verify::{VerifyOptions, VerifyReport},
+};
+
+/// How much work one call of `Scrubber::scrub_some` may do, at least one chunk is checked
+#[derive(Debug, Clone, Default)]
+pub struct ScrubBudget {
+    max_chunks: Option<usize>,
+    max_time: Option<Duration>,
+}
+
+impl ScrubBudget {
+    /// Check at most `n` data chunks
+    pub fn chunks(n: usize) -> Self {
+        Self {
+            max_chunks: Some(n),
+            ..Default::default()
+        }
+    }
+
+    /// Stop checking chunks once `time` has passed
+    pub fn time(time: Duration) -> Self {
+        Self {
+            max_time: Some(time),
+            ..Default::default()
+        }
+    }
+
+    /// Also stop after `n` data chunks
+    pub fn max_chunks(mut self, n: usize) -> Self {
+        self.max_chunks = Some(n);
+        self
+    }
+
+    /// Also stop once `time` has passed
+    pub fn max_time(mut self, time: Duration) -> Self {
+        self.max_time = Some(time);
+        self
+    }
+}
+
+/// Where a scrub stands, to be persisted between `Scrubber` runs
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ScrubState {
+    /// Digest of the header the scrub started on, see `Scrubber::new`
+    header_digest: [u8; 32],
+    /// The first data chunk not checked yet
+    next_chunk: ChunkId,
+    report: VerifyReport,
+}
+
+impl ScrubState {
+    /// The last data chunk that was checked, `None` before the first one
+    pub fn last_verified_chunk(&self) -> Option<ChunkId> {
+        self.next_chunk.checked_sub(1)
+    }
+
+    /// Failures found so far
+    pub fn report(&self) -> &VerifyReport {
+        &self.report
+    }
+}
+
+/// The outcome of one `Scrubber::scrub_some` call
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScrubProgress {
+    /// Data chunks checked by this call
+    pub chunks_scrubbed: usize,
+    /// Data chunks checked since the scrub started, including earlier runs
+    pub chunks_done: usize,
+    pub chunks_total: usize,
+}
+
+impl ScrubProgress {
+    /// Whether every data chunk was checked
+    pub fn is_finished(&self) -> bool {
+        self.chunks_done == self.chunks_total
+    }
+}
+
+/// Verifies the chunks of a file in index order, a budget at a time
+///
+/// The report at the end equals the one of `Decoder::verify` with default options. Between
+/// calls the state can be saved and the scrub continued later, also in another process.
+pub struct Scrubber<R> {
+    decoder: Decoder<R>,
+    state: ScrubState,
+}
+
+impl<R: BufRead + Seek> Scrubber<R> {
+    /// Start a scrub, or continue the one in `state`
+    ///
+    /// A new scrub checks the header checksum and the dict chunk right away. A resumed scrub
+    /// fails with `ScrubStateMismatch` when the header differs from the one the state was
+    /// made for, as the chunks checked so far may have changed.
+    pub fn new(mut decoder: Decoder<R>, state: Option<ScrubState>) -> Result<Self, ZchunkError> {
+        let header_digest = decoder.header().content_digest()?;
+        let state = match state {
+            Some(state) => {
+                if state.header_digest != header_digest
+                    || state.next_chunk > decoder.header().index.data_chunks.len()
+                {
+                    return Err(ZchunkError::ScrubStateMismatch);
+                }
+                state
+            }
+            None => ScrubState {
+                header_digest,
+                next_chunk: 0,
+                report: decoder.verify_header_and_dict()?,
+            },
+        };
+        Ok(Self { decoder, state })
+    }
+
+    /// Check data chunks until the budget is spent or all are checked
+    ///
+    /// IO errors abort the call and leave the state at the chunk that failed, so the call can
+    /// be repeated.
+    pub fn scrub_some(&mut self, budget: ScrubBudget) -> Result<ScrubProgress, ZchunkError> {
+        let start = Instant::now();
+        let total = self.decoder.header().index.data_chunks.len();
+        let options = VerifyOptions::new();
+
+        let mut scrubbed = 0;
+        while self.state.next_chunk < total {
+            let chunks_spent = budget.max_chunks.is_some_and(|n| scrubbed >= n);
+            let time_spent = budget.max_time.is_some_and(|t| start.elapsed() >= t);
+            if scrubbed > 0 && (chunks_spent || time_spent) {
+                break;
+            }
+
+            // the report is only changed once the chunk was read
+            self.decoder.verify_data_chunk(
+                self.state.next_chunk,
+                &options,
+                &mut self.state.report,
+            )?;
+            self.state.next_chunk += 1;
+            scrubbed += 1;
+        }
+
+        Ok(ScrubProgress {
+            chunks_scrubbed: scrubbed,
+            chunks_done: self.state.next_chunk,
+            chunks_total: total,
+        })
+    }
+
+    /// The state to persist for resuming later
+    pub fn state(&self) -> &ScrubState {
+        &self.state
+    }
+
+    /// Give back the decoder and the state
+    pub fn into_parts(self) -> (Decoder<R>, ScrubState) {
+        (self.decoder, self.state)
+    }
+}
+
+#[cfg(all(test, feature = "sha512"))]
+mod tests {
+    use std::{
+        fs::{self, File},
+        io::{BufReader, Cursor},
+    };
+
+    use super::{ScrubBudget, Scrubber};
+    use crate::{Decoder, VerifyOptions, ZchunkError};
+
+    const FILE: &str = "testdata/c25ffa05cf1fdeb67801847df96c33933b1ee1ea081af52edff4ff371a1c814c-comps-Server.x86_64.xml.zck";
+
+    fn corrupt_file() -> Vec<u8> {
+        let mut bytes = fs::read(FILE).unwrap();
+        let decoder = Decoder::new(Cursor::new(bytes.clone())).unwrap();
+        let start = decoder.header().chunk_range(1).unwrap().start as usize;
+        bytes[start + 20] ^= 0xff;
+        bytes
+    }
+
+    #[test]
+    fn test_scrub_in_slices() {
+        let bytes = corrupt_file();
+        let expected = Decoder::new(Cursor::new(bytes.clone()))
+            .unwrap()
+            .verify(&VerifyOptions::new())
+            .unwrap();
+        assert_eq!(expected.failures.len(), 1);
+
+        let mut scrubber =
+            Scrubber::new(Decoder::new(Cursor::new(bytes.clone())).unwrap(), None).unwrap();
+        assert_eq!(scrubber.state().last_verified_chunk(), None);
+        let progress = scrubber.scrub_some(ScrubBudget::chunks(1)).unwrap();
+        assert_eq!(progress.chunks_scrubbed, 1);
+        assert!(!progress.is_finished());
+
+        // resume in a new scrubber, as after a restart
+        let (_, state) = scrubber.into_parts();
+        assert_eq!(state.last_verified_chunk(), Some(0));
+        let mut scrubber =
+            Scrubber::new(Decoder::new(Cursor::new(bytes)).unwrap(), Some(state)).unwrap();
+        loop {
+            let progress = scrubber.scrub_some(ScrubBudget::chunks(1)).unwrap();
+            if progress.is_finished() {
+                assert_eq!(progress.chunks_done, progress.chunks_total);
+                break;
+            }
+        }
+        assert_eq!(scrubber.state().report(), &expected);
+
+        // a spent budget still checks one chunk, and a finished scrub checks none
+        let mut scrubber = Scrubber::new(
+            Decoder::new(BufReader::new(File::open(FILE).unwrap())).unwrap(),
+            None,
+        )
+        .unwrap();
+        let progress = scrubber
+            .scrub_some(ScrubBudget::time(std::time::Duration::ZERO))
+            .unwrap();
+        assert_eq!(progress.chunks_scrubbed, 1);
+        scrubber.scrub_some(ScrubBudget::default()).unwrap();
+        assert_eq!(
+            scrubber
+                .scrub_some(ScrubBudget::default())
+                .unwrap()
+                .chunks_scrubbed,
+            0
+        );
+        assert!(scrubber.state().report().is_ok());
+    }
+
+    #[test]
+    fn test_scrub_file_changed() {
+        let mut scrubber = Scrubber::new(
+            Decoder::new(Cursor::new(fs::read(FILE).unwrap())).unwrap(),
+            None,
+        )
+        .unwrap();
+        scrubber.scrub_some(ScrubBudget::chunks(1)).unwrap();
+        let (_, state) = scrubber.into_parts();
+
+        let other = "testdata/3c6181c789ef9e8ed23f4072eb2f8f529002abd5166273a9734d7d39f7a810ae-comps-Server.x86_64.xml.zck";
+        let decoder = Decoder::new(Cursor::new(fs::read(other).unwrap())).unwrap();
+        assert!(matches!(
+            Scrubber::new(decoder, Some(state)),
+            Err(ZchunkError::ScrubStateMismatch)
+        ));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_scrub_state_serde() {
+        let bytes = corrupt_file();
+        let mut scrubber =
+            Scrubber::new(Decoder::new(Cursor::new(bytes.clone())).unwrap(), None).unwrap();
+        scrubber.scrub_some(ScrubBudget::chunks(2)).unwrap();
+
+        let json = serde_json::to_string(scrubber.state()).unwrap();
+        let state = serde_json::from_str(&json).unwrap();
+        assert_eq!(&state, scrubber.state());
+        let mut resumed =
+            Scrubber::new(Decoder::new(Cursor::new(bytes)).unwrap(), Some(state)).unwrap();
+        assert!(resumed
+            .scrub_some(ScrubBudget::default())
+            .unwrap()
+            .is_finished());
+        assert_eq!(resumed.state().report().failures.len(), 1);
+    }
+}
@@ -0,0 +1,227 @@
+//! A versioned envelope around the artifacts this crate serializes next to zchunk files
+//!
+//! Every binary encoding of a crate-specific type is framed the same way, so readers can tell
+//! what they hold and whether they understand its version before parsing it:
+//!
+//! ```text
+//! magic    "\0ZSC"
+//! kind     u16 little endian, see `SidecarKind`
+//! version  u16 little endian, counted per kind from 1
+//! length   u64 little endian
+//! payload  `length` bytes, the encoding of that kind and version
+//! ```
+//!
+//! A reader accepts every version up to the newest it knows. A change a reader of the
+//! previous version could misread needs a new version.
+
+use std::io::{Read, Write};
+
+use crate::errors::ZchunkError;
+
+const SIDECAR_MAGIC: &[u8; 4] = b"\0ZSC";
+
+/// Bytes in front of the payload
+pub(crate) const ENVELOPE_HEADER_LEN: usize = 16;
+
+/// What the payload of an envelope holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum SidecarKind {
+    /// A header delta, see `Header::encode_delta`
+    HeaderDelta,
+    /// A bloom filter over chunk checksums, see `ChunkBloom::to_bytes`
+    ChunkBloom,
+    /// The progress of an interrupted encode, see `Encoder::checkpoint`
+    EncodeCheckpoint,
+}
+
+impl SidecarKind {
+    pub fn from_u16(n: u16) -> Result<Self, ZchunkError> {
+        match n {
+            1 => Ok(Self::HeaderDelta),
+            2 => Ok(Self::ChunkBloom),
+            3 => Ok(Self::EncodeCheckpoint),
+            _ => Err(ZchunkError::UnknownSidecarKind(n)),
+        }
+    }
+
+    pub fn to_u16(self) -> u16 {
+        match self {
+            Self::HeaderDelta => 1,
+            Self::ChunkBloom => 2,
+            Self::EncodeCheckpoint => 3,
+        }
+    }
+}
+
+/// Write `payload` framed as `version` of `kind`
+pub fn write_envelope(
+    kind: SidecarKind,
+    version: u16,
+    payload: &[u8],
+    mut w: impl Write,
+) -> Result<(), ZchunkError> {
+    w.write_all(SIDECAR_MAGIC)?;
+    w.write_all(&kind.to_u16().to_le_bytes())?;
+    w.write_all(&version.to_le_bytes())?;
+    w.write_all(&(payload.len() as u64).to_le_bytes())?;
+    w.write_all(payload)?;
+    Ok(())
+}
+
+/// Read an envelope written by `write_envelope`, the version is not checked
+///
+/// Nothing past the payload is read.
+pub fn read_envelope(mut r: impl Read) -> Result<(SidecarKind, u16, Vec<u8>), ZchunkError> {
+    let mut head = [0; ENVELOPE_HEADER_LEN];
+    r.read_exact(&mut head)?;
+    if &head[..4] != SIDECAR_MAGIC {
+        return Err(ZchunkError::InvalidSidecar);
+    }
+    let kind = SidecarKind::from_u16(u16::from_le_bytes(head[4..6].try_into()?))?;
+    let version = u16::from_le_bytes(head[6..8].try_into()?);
+    let length = u64::from_le_bytes(head[8..].try_into()?);
+
+    let mut payload = Vec::new();
+    r.take(length).read_to_end(&mut payload)?;
+    if payload.len() as u64 != length {
+        return Err(ZchunkError::InvalidSidecar);
+    }
+    Ok((kind, version, payload))
+}
+
+/// Frame `payload` in memory, see `write_envelope`
+pub(crate) fn envelope(kind: SidecarKind, version: u16, payload: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(ENVELOPE_HEADER_LEN + payload.len());
+    write_envelope(kind, version, payload, &mut bytes).expect("writing to a Vec does not fail");
+    bytes
+}
+
+/// Read an envelope of `kind` whose version is at most `newest`, the newest version the
+/// caller can parse
+///
+/// The whole of `bytes` must be the envelope.
+pub(crate) fn open_envelope(
+    bytes: &[u8],
+    kind: SidecarKind,
+    newest: u16,
+) -> Result<(u16, Vec<u8>), ZchunkError> {
+    let mut reader = bytes;
+    let (found, version, payload) = read_envelope(&mut reader)?;
+    if found != kind {
+        return Err(ZchunkError::SidecarKindMismatch {
+            expected: kind,
+            found,
+        });
+    }
+    if version == 0 || version > newest {
+        return Err(ZchunkError::UnsupportedSidecarVersion {
+            kind,
+            version,
+            newest,
+        });
+    }
+    if !reader.is_empty() {
+        return Err(ZchunkError::InvalidSidecar);
+    }
+    Ok((version, payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{envelope, open_envelope, read_envelope, write_envelope, SidecarKind};
+    use crate::ZchunkError;
+
+    /// A bloom payload as a reader that knows versions up to `newest` parses it, version 2
+    /// appends a byte the first version does not have
+    fn parse_bloom(bytes: &[u8], newest: u16) -> Result<(u8, Option<u8>), ZchunkError> {
+        let (version, payload) = open_envelope(bytes, SidecarKind::ChunkBloom, newest)?;
+        match version {
+            1 => Ok((payload[0], None)),
+            _ => Ok((payload[0], Some(payload[1]))),
+        }
+    }
+
+    #[test]
+    fn test_envelope_round_trip() {
+        let mut bytes = Vec::new();
+        write_envelope(SidecarKind::HeaderDelta, 3, b"payload", &mut bytes).unwrap();
+        assert_eq!(bytes, envelope(SidecarKind::HeaderDelta, 3, b"payload"));
+        bytes.extend_from_slice(b"trailing");
+
+        let mut reader = bytes.as_slice();
+        let (kind, version, payload) = read_envelope(&mut reader).unwrap();
+        assert_eq!((kind, version), (SidecarKind::HeaderDelta, 3));
+        assert_eq!(payload, b"payload");
+        assert_eq!(reader, b"trailing");
+    }
+
+    #[test]
+    fn test_envelope_versions() {
+        let v1 = envelope(SidecarKind::ChunkBloom, 1, &[7]);
+        let v2 = envelope(SidecarKind::ChunkBloom, 2, &[7, 9]);
+
+        // a reader that only knows version 1
+        assert_eq!(parse_bloom(&v1, 1).unwrap(), (7, None));
+        assert!(matches!(
+            parse_bloom(&v2, 1),
+            Err(ZchunkError::UnsupportedSidecarVersion {
+                kind: SidecarKind::ChunkBloom,
+                version: 2,
+                newest: 1,
+            })
+        ));
+
+        // a reader that knows version 2 still reads version 1
+        assert_eq!(parse_bloom(&v1, 2).unwrap(), (7, None));
+        assert_eq!(parse_bloom(&v2, 2).unwrap(), (7, Some(9)));
+
+        assert!(matches!(
+            parse_bloom(&envelope(SidecarKind::ChunkBloom, 0, &[7]), 2),
+            Err(ZchunkError::UnsupportedSidecarVersion { version: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn test_envelope_errors() {
+        let bytes = envelope(SidecarKind::HeaderDelta, 1, b"payload");
+        assert!(matches!(
+            open_envelope(&bytes, SidecarKind::ChunkBloom, 1),
+            Err(ZchunkError::SidecarKindMismatch {
+                expected: SidecarKind::ChunkBloom,
+                found: SidecarKind::HeaderDelta,
+            })
+        ));
+
+        let mut zck = bytes.clone();
+        zck[..4].copy_from_slice(b"\0ZCK");
+        assert!(matches!(
+            read_envelope(zck.as_slice()),
+            Err(ZchunkError::InvalidSidecar)
+        ));
+
+        let mut unknown = bytes.clone();
+        unknown[4] = 0xff;
+        assert!(matches!(
+            read_envelope(unknown.as_slice()),
+            Err(ZchunkError::UnknownSidecarKind(0xff))
+        ));
+
+        assert!(matches!(
+            read_envelope(&bytes[..bytes.len() - 1]),
+            Err(ZchunkError::InvalidSidecar)
+        ));
+        assert!(matches!(
+            read_envelope(&bytes[..8]),
+            Err(ZchunkError::Io(_))
+        ));
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(matches!(
+            open_envelope(&trailing, SidecarKind::HeaderDelta, 1),
+            Err(ZchunkError::InvalidSidecar)
+        ));
+    }
+}
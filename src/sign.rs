@@ -0,0 +1,246 @@
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+
+use crate::{errors::ZchunkError, format::PartialDecoder};
+
+/// Signs the header while encoding, see `EncoderOptions::signer`
+///
+/// The lead counts the signature bytes, so the type and size of the signature are known
+/// before signing. A signature shorter than `max_size` is padded with zeros, like a
+/// placeholder filled by `sign_in_place`, so the signature format must tell its own length.
+pub trait Signer: Send + Sync {
+    /// The signature type written to the header
+    fn signature_type(&self) -> u64;
+
+    /// The most bytes `sign` returns
+    fn max_size(&self) -> usize;
+
+    /// Sign `signed_region`, see `Header::signature_input`
+    fn sign(&self, signed_region: &[u8]) -> Result<Vec<u8>, ZchunkError>;
+}
+
+/// Write `signature_bytes` into the first unused signature placeholder of the zchunk file,
+/// see `EncoderOptions::reserve_signature`
+///
+/// The placeholder keeps its type and size, the bytes past the signature stay zero, so the
+/// signature format must tell its own length. Only the placeholder and the header checksum
+/// in the lead are rewritten, the header checksum covers the signatures while the lead,
+/// preface and index, and the data after the header, are unchanged. The file must start at
+/// offset 0 and its header checksum is checked before anything is written.
+pub fn sign_in_place(
+    file: &mut (impl Read + Write + Seek),
+    signature_bytes: &[u8],
+) -> Result<(), ZchunkError> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut header = PartialDecoder::peek(BufReader::new(&mut *file))?
+        .into_full()?
+        .header;
+
+    header.check_checksum()?;
+
+    // the signatures end the header
+    let signatures = &header.signatures;
+    let mut offset =
+        header.data_offset()? - signatures.byte_size() as u64 + signatures.count.byte_size() as u64;
+    let mut slot = None;
+    for (i, signature) in signatures.signatures.iter().enumerate() {
+        let payload = offset + (signature.type_.byte_size() + signature.size.byte_size()) as u64;
+        if signature.is_placeholder() {
+            slot = Some((i, payload));
+            break;
+        }
+        offset += signature.byte_size() as u64;
+    }
+    let (i, payload) = slot.ok_or(ZchunkError::SignaturePlaceholderMissing)?;
+
+    let placeholder = &mut header.signatures.signatures[i].signature;
+    if signature_bytes.len() > placeholder.len() {
+        return Err(ZchunkError::SignatureTooLarge {
+            len: signature_bytes.len(),
+            capacity: placeholder.len(),
+        });
+    }
+    placeholder[..signature_bytes.len()].copy_from_slice(signature_bytes);
+    header.compute_and_set_checksum()?;
+
+    file.seek(SeekFrom::Start(payload))?;
+    file.write_all(signature_bytes)?;
+    let lead_checksum = header.lead.byte_size() - header.lead.header_checksum.len();
+    file.seek(SeekFrom::Start(lead_checksum as u64))?;
+    file.write_all(&header.lead.header_checksum)?;
+    file.flush()?;
+
+    Ok(())
+}
+
+#[cfg(all(test, feature = "zstd"))]
+mod tests {
+    use std::{fs::File, io::Cursor, sync::Arc};
+
+    use sha2::{Digest, Sha256};
+
+    use super::{sign_in_place, Signer};
+    use crate::{format::Signatures, Decoder, Encoder, EncoderOptions, VerifyOptions, ZchunkError};
+
+    const INPUT: &str = "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml";
+
+    fn encode(options: EncoderOptions) -> Vec<u8> {
+        let input = File::open(INPUT).unwrap();
+        let mut encoder = Encoder::with_options(input, Cursor::new(Vec::new()), options).unwrap();
+        encoder.prepare_chunks().unwrap();
+        let mut output = Vec::new();
+        encoder.compress_to(&mut output).unwrap();
+        output
+    }
+
+    #[test]
+    fn test_sign_in_place() {
+        let unsigned = encode(EncoderOptions::new().reserve_signature(7, 600));
+        let decoder = Decoder::new(Cursor::new(unsigned.clone())).unwrap();
+        let signatures = &decoder.header().signatures.signatures;
+        assert_eq!(signatures.len(), 1);
+        assert_eq!(signatures[0].signature, vec![0; 600]);
+        let data_offset = decoder.header().data_offset().unwrap() as usize;
+        let index_end = data_offset - decoder.header().signatures.byte_size();
+
+        let mut file = Cursor::new(unsigned.clone());
+        sign_in_place(&mut file, b"a detached signature").unwrap();
+        let signed = file.into_inner();
+        assert_eq!(signed.len(), unsigned.len());
+        assert_eq!(signed[data_offset..], unsigned[data_offset..]);
+        // only the lead checksum and the placeholder changed
+        let lead_size = decoder.header().lead.byte_size();
+        let checksum_start = lead_size - 32;
+        assert_eq!(signed[..checksum_start], unsigned[..checksum_start]);
+        assert_ne!(
+            signed[checksum_start..lead_size],
+            unsigned[checksum_start..lead_size]
+        );
+        assert_eq!(signed[lead_size..index_end], unsigned[lead_size..index_end]);
+
+        let mut decoder = Decoder::new(Cursor::new(signed.clone())).unwrap();
+        let signature = &decoder.header().signatures.signatures[0];
+        assert_eq!(signature.type_.to_u64().unwrap(), 7);
+        assert!(signature.signature.starts_with(b"a detached signature"));
+        assert!(signature.signature[20..].iter().all(|&b| b == 0));
+        assert!(decoder.verify(&VerifyOptions::new()).unwrap().is_ok());
+
+        // the placeholder is used up
+        let err = sign_in_place(&mut Cursor::new(signed), b"again").unwrap_err();
+        assert!(matches!(err, ZchunkError::SignaturePlaceholderMissing));
+    }
+
+    /// Signs with a fixed blob, or with the SHA-256 of the signed region
+    struct DummySigner {
+        blob: Option<Vec<u8>>,
+    }
+
+    impl Signer for DummySigner {
+        fn signature_type(&self) -> u64 {
+            match self.blob {
+                Some(_) => 1,
+                None => 2,
+            }
+        }
+
+        fn max_size(&self) -> usize {
+            32
+        }
+
+        fn sign(&self, signed_region: &[u8]) -> Result<Vec<u8>, ZchunkError> {
+            match &self.blob {
+                Some(blob) => Ok(blob.clone()),
+                None => Ok(Sha256::digest(signed_region).to_vec()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_signer() {
+        let output = encode(
+            EncoderOptions::new()
+                .signer(Arc::new(DummySigner {
+                    blob: Some(b"fixed blob".to_vec()),
+                }))
+                .signer(Arc::new(DummySigner { blob: None }))
+                .reserve_signature(7, 16),
+        );
+        let mut decoder = Decoder::new(Cursor::new(output.clone())).unwrap();
+        assert!(decoder.verify(&VerifyOptions::new()).unwrap().is_ok());
+        let header = decoder.header();
+        let signed_region = header.signature_input().unwrap();
+
+        // the signatures follow the signed region and the header checksum
+        let start = signed_region.len() + 32;
+        let end = header.data_offset().unwrap() as usize;
+        assert_eq!(output[..5], signed_region[..5]);
+        let mut section = &output[start..end];
+        let signatures = Signatures::from_reader(&mut section).unwrap().signatures;
+        assert!(section.is_empty());
+        let parsed: Vec<(u64, Vec<u8>)> = signatures
+            .iter()
+            .map(|s| (s.type_.to_u64().unwrap(), s.signature.clone()))
+            .collect();
+        let mut blob = b"fixed blob".to_vec();
+        blob.resize(32, 0);
+        assert_eq!(
+            parsed,
+            [
+                (1, blob),
+                (2, Sha256::digest(&signed_region).to_vec()),
+                (7, vec![0; 16]),
+            ]
+        );
+
+        // the placeholder is still there for sign_in_place
+        let mut file = Cursor::new(output);
+        sign_in_place(&mut file, b"later").unwrap();
+
+        let err = Encoder::with_options(
+            File::open(INPUT).unwrap(),
+            Cursor::new(Vec::new()),
+            EncoderOptions::new().signer(Arc::new(DummySigner {
+                blob: Some(vec![1; 33]),
+            })),
+        )
+        .unwrap()
+        .prepare_chunks()
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            ZchunkError::SignatureTooLarge {
+                len: 33,
+                capacity: 32
+            }
+        ));
+    }
+
+    #[test]
+    fn test_sign_in_place_errors() {
+        let err =
+            sign_in_place(&mut Cursor::new(encode(EncoderOptions::new())), b"sig").unwrap_err();
+        assert!(matches!(err, ZchunkError::SignaturePlaceholderMissing));
+
+        let unsigned = encode(EncoderOptions::new().reserve_signature(7, 16));
+        let mut file = Cursor::new(unsigned.clone());
+        let err = sign_in_place(&mut file, &[1; 17]).unwrap_err();
+        assert!(matches!(
+            err,
+            ZchunkError::SignatureTooLarge {
+                len: 17,
+                capacity: 16
+            }
+        ));
+        assert_eq!(file.into_inner(), unsigned);
+
+        // a corrupt header is not given a fresh checksum
+        let mut corrupt = unsigned.clone();
+        let preface_start = Decoder::new(Cursor::new(unsigned))
+            .unwrap()
+            .header()
+            .lead
+            .byte_size();
+        corrupt[preface_start] ^= 0xff;
+        let err = sign_in_place(&mut Cursor::new(corrupt), &[1; 16]).unwrap_err();
+        assert!(matches!(err, ZchunkError::HeaderChecksumNotMatch { .. }));
+    }
+}
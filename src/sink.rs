@@ -0,0 +1,230 @@
+use std::io::{self, Write};
+
+use crate::{
+    chunker::Boundaries,
+    errors::ZchunkError,
+    format::{Encoder, PrepareState},
+    options::EncoderOptions,
+    report::EncodeStats,
+    temp::TempStore,
+};
+
+/// An encoder fed by writing the input to it, for producers that cannot hand out a `Read`
+///
+/// Written bytes are chunked like `Encoder::prepare_chunks` chunks a reader, and every chunk
+/// is compressed into the temp as soon as its boundary is found. Between writes less than
+/// one maximum chunk size of input is buffered, and while a write is chunked at most two.
+/// `finish` writes the zchunk file, which is the same as
+/// an `Encoder` with the same options writes for the same input. With
+/// `EncoderOptions::auto_dict` all chunks are held in memory until `finish` trains the dict.
+///
+/// A failed write leaves the sink unusable, later writes and `finish` return `SinkFailed`.
+pub struct EncoderSink<RW> {
+    encoder: Encoder<RW, io::Empty>,
+    boundaries: Boundaries,
+    pending: Vec<u8>,
+    /// `None` while the chunks are held back to train a dict
+    state: Option<PrepareState>,
+    held: Vec<Vec<u8>>,
+    failed: bool,
+}
+
+impl<RW: TempStore> EncoderSink<RW> {
+    /// A sink that stores the compressed chunks in `temp`
+    pub fn new(temp: RW, options: EncoderOptions) -> Result<Self, ZchunkError> {
+        let mut encoder = Encoder::with_options(io::empty(), temp, options)?;
+        let boundaries = Boundaries::new(encoder.chunker_params())?;
+        let state = encoder.start_push()?;
+        Ok(Self {
+            encoder,
+            pending: Vec::new(),
+            boundaries,
+            state,
+            held: Vec::new(),
+            failed: false,
+        })
+    }
+
+    /// Chunk the rest of the input, write the zchunk file to `writer` and return the
+    /// `Encoder::stats`
+    pub fn finish(mut self, writer: impl Write) -> Result<EncodeStats, ZchunkError> {
+        self.finish_prepare()?;
+        self.compress_to(writer)
+    }
+
+    /// Store the chunks written so far and build the header, the first half of `finish`
+    pub(crate) fn finish_prepare(&mut self) -> Result<(), ZchunkError> {
+        if self.failed {
+            return Err(ZchunkError::SinkFailed);
+        }
+        // the input ends, so the chunker cuts what is left
+        let pending = std::mem::take(&mut self.pending);
+        let mut rest = pending.as_slice();
+        while !rest.is_empty() {
+            let cut = self.boundaries.next_cut(rest);
+            self.store(&rest[..cut])?;
+            rest = &rest[cut..];
+        }
+        match self.state.take() {
+            Some(state) => self.encoder.finish_prepare(state),
+            None => self
+                .encoder
+                .prepare_chunks_from(std::mem::take(&mut self.held).into_iter().map(Ok)),
+        }
+    }
+
+    /// Write the zchunk file once `finish_prepare` built the header, the second half of
+    /// `finish`
+    pub(crate) fn compress_to(&mut self, writer: impl Write) -> Result<EncodeStats, ZchunkError> {
+        self.encoder.compress_to(writer)?;
+        self.encoder.stats()
+    }
+
+    /// Buffer `buf` and store every chunk whose boundary it makes final
+    pub(crate) fn push(&mut self, buf: &[u8]) -> Result<(), ZchunkError> {
+        if self.failed {
+            return Err(ZchunkError::SinkFailed);
+        }
+        let result = self.push_inner(buf);
+        self.failed = result.is_err();
+        result
+    }
+
+    fn push_inner(&mut self, mut buf: &[u8]) -> Result<(), ZchunkError> {
+        // a boundary is only final once the maximum chunk size is buffered
+        let max = self.boundaries.max;
+        if !self.pending.is_empty() {
+            // chunks starting in the pending input may end in `buf`
+            let old = self.pending.len();
+            let taken = buf.len().min(max);
+            self.pending.extend_from_slice(&buf[..taken]);
+            let pending = std::mem::take(&mut self.pending);
+            let mut start = 0;
+            while start < old && pending.len() - start >= max {
+                let cut = self.boundaries.next_cut(&pending[start..]);
+                self.store(&pending[start..start + cut])?;
+                start += cut;
+            }
+            self.pending = pending;
+            if start < old {
+                // less than the maximum chunk size is left, so all of `buf` was taken
+                self.pending.drain(..start);
+                return Ok(());
+            }
+            // the rest is in `buf`, cut it from there
+            self.pending.clear();
+            buf = &buf[start - old..];
+        }
+        while buf.len() >= max {
+            let cut = self.boundaries.next_cut(buf);
+            self.store(&buf[..cut])?;
+            buf = &buf[cut..];
+        }
+        self.pending.extend_from_slice(buf);
+        Ok(())
+    }
+
+    /// Store a chunk cut from the input
+    fn store(&mut self, chunk: &[u8]) -> Result<(), ZchunkError> {
+        match &mut self.state {
+            Some(state) => self.encoder.push_chunk(state, chunk),
+            None => {
+                self.held.push(chunk.to_vec());
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<RW: TempStore> Write for EncoderSink<RW> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.push(buf).map_err(|e| match e {
+            ZchunkError::Io(e) => e,
+            e => io::Error::other(e),
+        })?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use sha2::{Digest, Sha256};
+
+    use super::EncoderSink;
+    use crate::{ChunkerParams, Encoder, EncoderOptions, MemoryTemp, ZchunkError};
+
+    const INPUT: &str = "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml";
+
+    /// Split `input` into writes of 0 to `max_write` bytes, the sizes taken from `seed`
+    fn write_randomly(sink: &mut impl Write, input: &[u8], max_write: usize, seed: u64) {
+        let mut rest = input;
+        let mut i = 0u64;
+        while !rest.is_empty() {
+            let digest = Sha256::digest([seed.to_le_bytes(), i.to_le_bytes()].concat());
+            let len =
+                u64::from_le_bytes(digest[..8].try_into().unwrap()) as usize % (max_write + 1);
+            let (head, tail) = rest.split_at(len.min(rest.len()));
+            sink.write_all(head).unwrap();
+            rest = tail;
+            i += 1;
+        }
+    }
+
+    #[test]
+    fn test_sink_matches_encoder() {
+        let input = std::fs::read(INPUT).unwrap();
+        let options = || {
+            [
+                EncoderOptions::new(),
+                EncoderOptions::new().chunker_params(ChunkerParams::new(1024, 8192, 2047)),
+                EncoderOptions::new()
+                    .chunker_params(ChunkerParams::new(1024, 8192, 2047))
+                    .auto_dict(4096),
+            ]
+        };
+        for (seed, (sink_options, encoder_options)) in
+            options().into_iter().zip(options()).enumerate()
+        {
+            let mut encoder =
+                Encoder::with_options(input.as_slice(), Cursor::new(Vec::new()), encoder_options)
+                    .unwrap();
+            let mut expected = Vec::new();
+            let expected_stats = encoder.compress(&mut expected).unwrap();
+
+            for max_write in [1, 100, 20_000, input.len()] {
+                let mut sink = EncoderSink::new(MemoryTemp::new(), sink_options.clone()).unwrap();
+                write_randomly(&mut sink, &input, max_write, seed as u64);
+                // only the input after the last final boundary is kept
+                assert!(sink.pending.len() < sink.boundaries.max);
+                let mut output = Vec::new();
+                let stats = sink.finish(&mut output).unwrap();
+                assert!(output == expected, "{seed} {max_write}");
+                assert_eq!(stats, expected_stats);
+            }
+        }
+
+        // nothing written is an empty file
+        let mut expected = Vec::new();
+        Encoder::compress_small(&[], &mut expected, EncoderOptions::new()).unwrap();
+        let mut output = Vec::new();
+        EncoderSink::new(MemoryTemp::new(), EncoderOptions::new())
+            .unwrap()
+            .finish(&mut output)
+            .unwrap();
+        assert_eq!(output, expected);
+
+        // a maximum chunk size that cannot be buffered is refused before it is allocated
+        let options =
+            EncoderOptions::new().chunker_params(ChunkerParams::new(64, usize::MAX, 1023));
+        assert!(matches!(
+            EncoderSink::new(MemoryTemp::new(), options),
+            Err(ZchunkError::InvalidChunkerParams { .. })
+        ));
+    }
+}
@@ -0,0 +1,172 @@
+use std::{
+    fmt,
+    io::{self, BufRead, Read},
+};
+
+use crate::format::{ZCHUNK_DETACHED_VERSION_1, ZCHUNK_VERSION_1};
+
+/// Length of the magic bytes at the start of a zchunk file
+pub(crate) const MAGIC_LEN: usize = 5;
+
+/// Compression formats that are mistaken for zchunk files, recognized by their magic bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KnownFormat {
+    Zstd,
+    Gzip,
+    Xz,
+    Bzip2,
+}
+
+impl KnownFormat {
+    /// Recognize a format from the leading bytes of a file
+    pub(crate) fn detect(prefix: &[u8]) -> Option<Self> {
+        [
+            (Self::Zstd, &b"\x28\xb5\x2f\xfd"[..]),
+            (Self::Gzip, b"\x1f\x8b"),
+            (Self::Xz, b"\xfd7zXZ"),
+            (Self::Bzip2, b"BZh"),
+        ]
+        .into_iter()
+        .find(|(_, magic)| prefix.starts_with(magic))
+        .map(|(format, _)| format)
+    }
+
+    /// The command that decompresses files of this format
+    pub fn tool(self) -> &'static str {
+        match self {
+            Self::Zstd => "zstd -d",
+            Self::Gzip => "gzip -d",
+            Self::Xz => "xz -d",
+            Self::Bzip2 => "bzip2 -d",
+        }
+    }
+}
+
+impl fmt::Display for KnownFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Zstd => "zstd",
+            Self::Gzip => "gzip",
+            Self::Xz => "xz",
+            Self::Bzip2 => "bzip2",
+        })
+    }
+}
+
+/// Read up to `MAGIC_LEN` bytes, fewer only when the input ends
+pub(crate) fn read_magic(mut reader: impl Read) -> io::Result<([u8; MAGIC_LEN], usize)> {
+    let mut magic = [0; MAGIC_LEN];
+    let mut len = 0;
+    while len < MAGIC_LEN {
+        match reader.read(&mut magic[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok((magic, len))
+}
+
+/// Check whether `reader` is at the start of a zchunk file or detached header, without
+/// consuming any of it
+///
+/// Meant for dispatchers handling several formats, which can then hand `reader` on, pipes
+/// included. Only the bytes `fill_buf` returns are looked at, so a reader that buffers fewer
+/// than the five magic bytes before the input ends is not a zchunk file. A `true` does not
+/// mean the rest of the file is valid.
+pub fn is_zchunk(mut reader: impl BufRead) -> Result<bool, io::Error> {
+    let buf = loop {
+        match reader.fill_buf() {
+            Ok(buf) => break buf,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    };
+    Ok(buf.starts_with(ZCHUNK_VERSION_1) || buf.starts_with(ZCHUNK_DETACHED_VERSION_1))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, Cursor, Read};
+
+    use super::{is_zchunk, KnownFormat};
+    use crate::{Decoder, ZchunkError};
+
+    #[cfg(feature = "sha512")]
+    const ZCK: &str = "testdata/c25ffa05cf1fdeb67801847df96c33933b1ee1ea081af52edff4ff371a1c814c-comps-Server.x86_64.xml.zck";
+
+    /// Leading bytes of real files, as written by each tool
+    const PREFIXES: &[(KnownFormat, &[u8])] = &[
+        (
+            KnownFormat::Zstd,
+            b"\x28\xb5\x2f\xfd\x24\x4d\x69\x01\x00\x3c\x3f\x78\x6d\x6c",
+        ),
+        (
+            KnownFormat::Gzip,
+            b"\x1f\x8b\x08\x00\x00\x00\x00\x00\x00\x03\xb3\xb1\xaf\xc8",
+        ),
+        (
+            KnownFormat::Xz,
+            b"\xfd\x37\x7a\x58\x5a\x00\x00\x04\xe6\xd6\xb4\x46\x02\x00",
+        ),
+        (
+            KnownFormat::Bzip2,
+            b"\x42\x5a\x68\x39\x31\x41\x59\x26\x53\x59\x8c\x2a\x1c\x6b",
+        ),
+    ];
+
+    #[test]
+    fn test_known_formats() {
+        for &(format, prefix) in PREFIXES {
+            let err = Decoder::new(Cursor::new(prefix)).err().unwrap();
+            assert!(
+                matches!(err, ZchunkError::NotAZchunkFile { detected: Some(f) } if f == format),
+                "{err:?}"
+            );
+            assert!(err.to_string().contains(format.tool()), "{err}");
+            assert!(!is_zchunk(Cursor::new(prefix)).unwrap());
+        }
+
+        let err = Decoder::new(Cursor::new(b"\0ZC")).err().unwrap();
+        assert!(matches!(
+            err,
+            ZchunkError::NotAZchunkFile { detected: None }
+        ));
+
+        let err = Decoder::new(Cursor::new(b"<?xml version")).err().unwrap();
+        assert_eq!(err.to_string(), "invalid leader id: 3c3f786d6c (\"<?xml\")");
+    }
+
+    #[cfg(feature = "sha512")]
+    #[test]
+    fn test_is_zchunk() {
+        let mut reader = Cursor::new(std::fs::read(ZCK).unwrap());
+        assert!(is_zchunk(&mut reader).unwrap());
+        assert_eq!(reader.position(), 0);
+        Decoder::new(&mut reader).unwrap();
+
+        let mut reader = Cursor::new(b"\0ZC");
+        assert!(!is_zchunk(&mut reader).unwrap());
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"\0ZC");
+    }
+
+    #[test]
+    fn test_is_zchunk_unseekable() {
+        // a pipe, which can be read only once
+        let file = [&b"\0ZHR1"[..], b"rest of the header"].concat();
+        let mut reader = BufReader::new(file.as_slice().chain(&b""[..]));
+        assert!(is_zchunk(&mut reader).unwrap());
+        let mut all = Vec::new();
+        reader.read_to_end(&mut all).unwrap();
+        assert_eq!(all, file);
+
+        let mut reader = BufReader::new((&b"\x1f\x8b\x08"[..]).chain(&b"\0ZCK1"[..]));
+        assert!(!is_zchunk(&mut reader).unwrap());
+        let mut all = Vec::new();
+        reader.read_to_end(&mut all).unwrap();
+        assert_eq!(all, b"\x1f\x8b\x08\0ZCK1");
+    }
+}
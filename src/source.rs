@@ -0,0 +1,181 @@
+use std::ops::Range;
+
+use crate::{
+    errors::ZchunkError,
+    format::{ChunkId, Header},
+};
+
+/// Default number of retries after the first failed attempt
+const DEFAULT_MAX_RETRIES: u32 = 3;
+
+/// A place to fetch compressed chunk data of a target file from, e.g. HTTP range requests
+pub trait ChunkSource {
+    /// Fetch the bytes of `range`, absolute offsets in the target file
+    fn fetch(&mut self, range: Range<u64>) -> Result<Vec<u8>, ZchunkError>;
+}
+
+impl<S: ChunkSource + ?Sized> ChunkSource for &mut S {
+    fn fetch(&mut self, range: Range<u64>) -> Result<Vec<u8>, ZchunkError> {
+        (**self).fetch(range)
+    }
+}
+
+/// A `ChunkSource` wrapper that verifies every fetched chunk against the target header
+/// and retries failed or corrupted fetches
+///
+/// A checksum mismatch counts as a retryable failure. Once the retries are used up the
+/// last error is surfaced as `ZchunkError::ChunkFetchFailed`.
+pub struct RetryingSource<'h, S> {
+    source: S,
+    header: &'h Header,
+    max_retries: u32,
+    backoff: Option<Box<dyn FnMut(ChunkId, u32) + 'h>>,
+}
+
+impl<'h, S: ChunkSource> RetryingSource<'h, S> {
+    pub fn new(source: S, header: &'h Header) -> Self {
+        Self {
+            source,
+            header,
+            max_retries: DEFAULT_MAX_RETRIES,
+            backoff: None,
+        }
+    }
+
+    /// Set how many times a chunk is retried after the first failed attempt
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Set a hook called with the chunk id and the failed attempt count before each retry,
+    /// e.g. to sleep
+    pub fn backoff(mut self, backoff: impl FnMut(ChunkId, u32) + 'h) -> Self {
+        self.backoff = Some(Box::new(backoff));
+        self
+    }
+
+    /// Unwrap the inner source
+    pub fn into_inner(self) -> S {
+        self.source
+    }
+
+    /// Fetch the compressed data of a data chunk, verified against its checksum
+    pub fn fetch_chunk(&mut self, id: ChunkId) -> Result<Vec<u8>, ZchunkError> {
+        let range = self.header.chunk_range(id)?;
+
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let result = self.source.fetch(range.clone()).and_then(|data| {
+                self.header.verify_chunk_bytes(id, &data)?;
+                Ok(data)
+            });
+
+            match result {
+                Ok(data) => return Ok(data),
+                Err(e) if attempts > self.max_retries => {
+                    return Err(ZchunkError::ChunkFetchFailed {
+                        id,
+                        attempts,
+                        last_error: Box::new(e),
+                    })
+                }
+                Err(_) => {
+                    if let Some(backoff) = self.backoff.as_mut() {
+                        backoff(id, attempts);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "sha512"))]
+mod tests {
+    use std::{
+        collections::HashMap,
+        fs::File,
+        io::{self, BufReader},
+        ops::Range,
+    };
+
+    use super::{ChunkSource, RetryingSource};
+    use crate::{Decoder, ZchunkError};
+
+    const FIXTURE: &str = "testdata/c25ffa05cf1fdeb67801847df96c33933b1ee1ea081af52edff4ff371a1c814c-comps-Server.x86_64.xml.zck";
+
+    /// Serves ranges of a file, failing the first `failures` fetches of each range with
+    /// alternating I/O errors and corrupted data
+    struct FlakySource {
+        data: Vec<u8>,
+        failures: u32,
+        attempts: HashMap<u64, u32>,
+    }
+
+    impl ChunkSource for FlakySource {
+        fn fetch(&mut self, range: Range<u64>) -> Result<Vec<u8>, ZchunkError> {
+            let attempt = self.attempts.entry(range.start).or_default();
+            *attempt += 1;
+            let mut data = self.data[range.start as usize..range.end as usize].to_vec();
+            if *attempt > self.failures {
+                return Ok(data);
+            }
+            if *attempt % 2 == 1 {
+                return Err(io::Error::other("connection reset").into());
+            }
+            data[0] ^= 0xff;
+            Ok(data)
+        }
+    }
+
+    fn flaky_source(failures: u32) -> FlakySource {
+        FlakySource {
+            data: std::fs::read(FIXTURE).unwrap(),
+            failures,
+            attempts: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_retry_until_verified() {
+        let decoder = Decoder::new(BufReader::new(File::open(FIXTURE).unwrap())).unwrap();
+        let header = &decoder.header;
+
+        let mut retries = Vec::new();
+        let mut source = RetryingSource::new(flaky_source(2), header)
+            .max_retries(2)
+            .backoff(|id, attempts| retries.push((id, attempts)));
+
+        for id in 0..header.index.data_chunks.len() {
+            let range = header.chunk_range(id).unwrap();
+            let data = source.fetch_chunk(id).unwrap();
+            assert_eq!(data.len() as u64, range.end - range.start);
+        }
+        drop(source);
+
+        assert_eq!(retries[..2], [(0, 1), (0, 2)]);
+        assert_eq!(retries.len(), header.index.data_chunks.len() * 2);
+    }
+
+    #[test]
+    fn test_retry_exhausted() {
+        let decoder = Decoder::new(BufReader::new(File::open(FIXTURE).unwrap())).unwrap();
+        let mut source = RetryingSource::new(flaky_source(2), &decoder.header).max_retries(1);
+
+        match source.fetch_chunk(0) {
+            Err(ZchunkError::ChunkFetchFailed {
+                id,
+                attempts,
+                last_error,
+            }) => {
+                assert_eq!((id, attempts), (0, 2));
+                assert!(matches!(
+                    *last_error,
+                    ZchunkError::ChunkChecksumNotMatch { .. }
+                ));
+            }
+            other => panic!("unexpected result: {other:?}"),
+        }
+    }
+}
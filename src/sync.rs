@@ -0,0 +1,137 @@
+use std::{
+    fs::{self, File},
+    io::{BufRead, BufReader, BufWriter, Seek},
+    path::Path,
+};
+
+use crate::{
+    errors::ZchunkError, format::Decoder, options::SyncFileOptions, report::SyncStats,
+    temp::TempFile,
+};
+
+/// Update the zchunk file at `cache_path` in place to the file of `source`, reusing the
+/// chunks it already holds
+///
+/// The new file is written to a temp file next to the cache, checked by parsing it again and
+/// comparing its header checksum with the source, and then renamed over the cache, which
+/// replaces it atomically on both Unix and Windows. A crash or error at any point leaves
+/// the old cache intact, and the temp file is removed on errors. A missing, unreadable or
+/// corrupt cache is replaced by a copy of the source.
+pub fn sync_file<R: BufRead + Seek>(
+    source: &mut Decoder<R>,
+    cache_path: &Path,
+    options: SyncFileOptions,
+) -> Result<SyncStats, ZchunkError> {
+    let (stats, temp) = write_verified(source, cache_path, &options)?;
+    temp.persist(cache_path, options.fsync)?;
+    Ok(stats)
+}
+
+/// Write the synced file to a temp next to `cache_path` and check it, the cache is only read
+fn write_verified<R: BufRead + Seek>(
+    source: &mut Decoder<R>,
+    cache_path: &Path,
+    options: &SyncFileOptions,
+) -> Result<(SyncStats, TempFile), ZchunkError> {
+    let (temp, file) = TempFile::create_sibling(cache_path)?;
+
+    // the cache is closed again before the rename, which Windows requires
+    let stats = {
+        let mut cache = File::open(cache_path)
+            .ok()
+            .and_then(|f| Decoder::new(BufReader::new(f)).ok());
+        let mut writer = BufWriter::new(file);
+        let stats = source.sync_with(cache.as_mut(), None, &mut writer)?;
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        if options.fsync {
+            file.sync_all()?;
+        }
+        stats
+    };
+
+    let written = Decoder::new(BufReader::new(File::open(&temp.path)?))?;
+    let expected = source.header().lead.header_checksum;
+    if written.header().lead.header_checksum != expected
+        || written.header().computed_checksum()? != expected
+        || fs::metadata(&temp.path)?.len() != source.header().file_size()?
+    {
+        return Err(ZchunkError::SyncedFileMismatch);
+    }
+
+    Ok((stats, temp))
+}
+
+#[cfg(all(test, feature = "sha512"))]
+mod tests {
+    use std::{fs, io::Cursor, path::Path};
+
+    use tempfile::Builder;
+
+    use super::{sync_file, write_verified};
+    use crate::{Decoder, SyncFileOptions, ZchunkError};
+
+    const OLD: &str = "testdata/3c6181c789ef9e8ed23f4072eb2f8f529002abd5166273a9734d7d39f7a810ae-comps-Server.x86_64.xml.zck";
+    const NEW: &str = "testdata/c25ffa05cf1fdeb67801847df96c33933b1ee1ea081af52edff4ff371a1c814c-comps-Server.x86_64.xml.zck";
+
+    fn source() -> Decoder<Cursor<Vec<u8>>> {
+        Decoder::new(Cursor::new(fs::read(NEW).unwrap())).unwrap()
+    }
+
+    /// Names in `dir` other than the cache
+    fn leftovers(dir: &Path) -> Vec<String> {
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name != "cache.zck")
+            .collect()
+    }
+
+    #[test]
+    fn test_sync_file() {
+        let dir = Builder::new().tempdir().unwrap();
+        let cache_path = dir.path().join("cache.zck");
+        fs::copy(OLD, &cache_path).unwrap();
+
+        let stats = sync_file(&mut source(), &cache_path, SyncFileOptions::new()).unwrap();
+        assert_eq!(fs::read(&cache_path).unwrap(), fs::read(NEW).unwrap());
+        assert_eq!(stats.chunks_from_cache + stats.chunks_from_source, 3);
+        assert!(leftovers(dir.path()).is_empty());
+
+        // a corrupt cache is replaced by a copy of the source
+        fs::write(&cache_path, b"not a zchunk file").unwrap();
+        let stats = sync_file(&mut source(), &cache_path, SyncFileOptions::new()).unwrap();
+        assert_eq!(fs::read(&cache_path).unwrap(), fs::read(NEW).unwrap());
+        assert_eq!(stats.chunks_from_source, 3);
+
+        // and so is a missing one
+        fs::remove_file(&cache_path).unwrap();
+        sync_file(&mut source(), &cache_path, SyncFileOptions::new()).unwrap();
+        assert_eq!(fs::read(&cache_path).unwrap(), fs::read(NEW).unwrap());
+    }
+
+    #[test]
+    fn test_sync_file_failure_keeps_cache() {
+        let dir = Builder::new().tempdir().unwrap();
+        let cache_path = dir.path().join("cache.zck");
+        fs::copy(OLD, &cache_path).unwrap();
+        let old = fs::read(OLD).unwrap();
+
+        // a crash between writing the temp and renaming it
+        let (_, temp) =
+            write_verified(&mut source(), &cache_path, &SyncFileOptions::new()).unwrap();
+        assert_eq!(fs::read(&temp.path).unwrap(), fs::read(NEW).unwrap());
+        assert_eq!(fs::read(&cache_path).unwrap(), old);
+        Decoder::new(Cursor::new(fs::read(&cache_path).unwrap())).unwrap();
+        drop(temp);
+        assert!(leftovers(dir.path()).is_empty());
+
+        // a source failing halfway through the chunks
+        let mut truncated = fs::read(NEW).unwrap();
+        truncated.truncate(truncated.len() - 100);
+        let mut source = Decoder::new(Cursor::new(truncated)).unwrap();
+        let err = sync_file(&mut source, &cache_path, SyncFileOptions::new()).unwrap_err();
+        assert!(matches!(err, ZchunkError::Io(_)));
+        assert_eq!(fs::read(&cache_path).unwrap(), old);
+        assert!(leftovers(dir.path()).is_empty());
+    }
+}
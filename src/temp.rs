@@ -0,0 +1,413 @@
+use std::{
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, Cursor, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+/// Attempts at finding an unused temp file name
+const TEMP_ATTEMPTS: u32 = 16;
+
+/// The directory holding `path`
+pub(crate) fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+/// A temp file that is removed on drop unless it was persisted
+#[derive(Debug)]
+pub(crate) struct TempFile {
+    pub(crate) path: PathBuf,
+    persisted: bool,
+}
+
+impl TempFile {
+    /// Create a new hidden file next to `path`, in the same directory so the rename cannot
+    /// cross file systems
+    pub(crate) fn create_sibling(path: &Path) -> io::Result<(Self, File)> {
+        let name = path
+            .file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+
+        for attempt in 0..TEMP_ATTEMPTS {
+            let mut temp_name = OsString::from(".");
+            temp_name.push(name);
+            temp_name.push(format!(".{}-{attempt}.tmp", std::process::id()));
+            let temp_path = parent_dir(path).join(temp_name);
+
+            match OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&temp_path)
+            {
+                Ok(file) => {
+                    let temp = Self {
+                        path: temp_path,
+                        persisted: false,
+                    };
+                    return Ok((temp, file));
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "no unused temp file name",
+        ))
+    }
+
+    /// Rename the temp over `path`, with `fsync` making the rename durable
+    pub(crate) fn persist(mut self, path: &Path, fsync: bool) -> io::Result<()> {
+        fs::rename(&self.path, path)?;
+        self.persisted = true;
+
+        // the rename lives in the directory, Windows has no handle to sync it through
+        #[cfg(unix)]
+        if fsync {
+            File::open(parent_dir(path))?.sync_all()?;
+        }
+        #[cfg(not(unix))]
+        let _ = fsync;
+
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Where `Encoder` keeps the compressed chunks until the header is written
+///
+/// The encoder writes the chunks in order, then rewinds and reads them back, and may rewind
+/// to overwrite them when it recompresses. Every `Read + Write + Seek` type is a temp store,
+/// `MemoryTemp`, `FileTemp` and `SpillingTemp` pick a backend or switch between two.
+pub trait TempStore {
+    /// Move to `offset` from the start, where the next write or read begins
+    fn rewind_to(&mut self, offset: u64) -> io::Result<()>;
+
+    /// Write all of `buf` and move past it
+    fn write_bytes(&mut self, buf: &[u8]) -> io::Result<()>;
+
+    /// Read exactly `buf.len()` bytes written before and move past them
+    fn read_back(&mut self, buf: &mut [u8]) -> io::Result<()>;
+
+    /// Flush buffered writes, for a store that is the output itself
+    fn flush_bytes(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Drop the bytes past `len`, left over from a longer earlier use
+    ///
+    /// The encoder never reads them, so a store that cannot truncate may keep them, which
+    /// is what a plain `Read + Write + Seek` temp does.
+    fn truncate_to(&mut self, len: u64) -> io::Result<()> {
+        let _ = len;
+        Ok(())
+    }
+}
+
+impl<T: Read + Write + Seek> TempStore for T {
+    fn rewind_to(&mut self, offset: u64) -> io::Result<()> {
+        self.seek(SeekFrom::Start(offset)).map(|_| ())
+    }
+
+    fn write_bytes(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.write_all(buf)
+    }
+
+    fn read_back(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        self.read_exact(buf)
+    }
+
+    fn flush_bytes(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+/// A `Write` over a temp store, for code that writes through adapters
+#[cfg(feature = "zstd")]
+pub(crate) struct TempWriter<'a, T>(pub(crate) &'a mut T);
+
+#[cfg(feature = "zstd")]
+impl<T: TempStore> Write for TempWriter<'_, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write_bytes(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush_bytes()
+    }
+}
+
+/// A temp store in memory, which grows to the size of the compressed data
+#[derive(Debug, Default)]
+pub struct MemoryTemp {
+    buf: Cursor<Vec<u8>>,
+}
+
+impl MemoryTemp {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A store with room for `capacity` bytes before it reallocates
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            buf: Cursor::new(Vec::with_capacity(capacity)),
+        }
+    }
+
+    /// Bytes written so far, overwritten ones included
+    pub fn len(&self) -> u64 {
+        self.buf.get_ref().len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.get_ref().is_empty()
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.buf.into_inner()
+    }
+}
+
+impl TempStore for MemoryTemp {
+    fn rewind_to(&mut self, offset: u64) -> io::Result<()> {
+        self.buf.set_position(offset);
+        Ok(())
+    }
+
+    fn truncate_to(&mut self, len: u64) -> io::Result<()> {
+        let len = usize::try_from(len).unwrap_or(usize::MAX);
+        self.buf.get_mut().truncate(len);
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.buf.write_all(buf)
+    }
+
+    fn read_back(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        self.buf.read_exact(buf)
+    }
+}
+
+/// A temp store in a file, removed on drop when this store created it
+#[derive(Debug)]
+pub struct FileTemp {
+    file: File,
+    temp: Option<TempFile>,
+}
+
+impl FileTemp {
+    /// Use `file`, which must be open for reading and writing, and is left in place
+    pub fn new(file: File) -> Self {
+        Self { file, temp: None }
+    }
+
+    /// Create a hidden file in `dir` that is removed when the store is dropped
+    pub fn create_in(dir: &Path) -> io::Result<Self> {
+        let (temp, file) = TempFile::create_sibling(&dir.join("zchunk-temp"))?;
+        Ok(Self {
+            file,
+            temp: Some(temp),
+        })
+    }
+
+    /// The file created by `create_in`
+    pub fn path(&self) -> Option<&Path> {
+        self.temp.as_ref().map(|t| t.path.as_path())
+    }
+}
+
+impl TempStore for FileTemp {
+    fn rewind_to(&mut self, offset: u64) -> io::Result<()> {
+        self.file.rewind_to(offset)
+    }
+
+    fn truncate_to(&mut self, len: u64) -> io::Result<()> {
+        if self.file.metadata()?.len() > len {
+            self.file.set_len(len)?;
+        }
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.file.write_all(buf)
+    }
+
+    fn read_back(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        self.file.read_exact(buf)
+    }
+}
+
+/// A temp store that starts in memory and moves to a file in a directory once more than a
+/// threshold of bytes is written
+///
+/// Small inputs never touch the disk, while large ones do not hold their compressed data
+/// in memory. The output does not depend on where the bytes are kept.
+#[derive(Debug)]
+pub struct SpillingTemp {
+    memory: MemoryTemp,
+    file: Option<FileTemp>,
+    threshold: u64,
+    dir: PathBuf,
+}
+
+impl SpillingTemp {
+    /// Keep up to `threshold` bytes in memory, and spill to a file in `dir` past it
+    pub fn new(threshold: u64, dir: impl Into<PathBuf>) -> Self {
+        Self {
+            memory: MemoryTemp::new(),
+            file: None,
+            threshold,
+            dir: dir.into(),
+        }
+    }
+
+    /// Whether the bytes have moved to a file
+    pub fn is_spilled(&self) -> bool {
+        self.file.is_some()
+    }
+
+    /// Move the bytes written so far to a file, keeping the position
+    fn spill(&mut self) -> io::Result<&mut FileTemp> {
+        let position = self.memory.buf.position();
+        let mut file = FileTemp::create_in(&self.dir)?;
+        file.write_bytes(self.memory.buf.get_ref())?;
+        file.rewind_to(position)?;
+        self.memory = MemoryTemp::new();
+        Ok(self.file.insert(file))
+    }
+}
+
+impl TempStore for SpillingTemp {
+    fn rewind_to(&mut self, offset: u64) -> io::Result<()> {
+        match &mut self.file {
+            Some(file) => file.rewind_to(offset),
+            None => self.memory.rewind_to(offset),
+        }
+    }
+
+    fn write_bytes(&mut self, buf: &[u8]) -> io::Result<()> {
+        if let Some(file) = &mut self.file {
+            return file.write_bytes(buf);
+        }
+        let end = self.memory.buf.position() + buf.len() as u64;
+        if end.max(self.memory.len()) > self.threshold {
+            return self.spill()?.write_bytes(buf);
+        }
+        self.memory.write_bytes(buf)
+    }
+
+    fn read_back(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        match &mut self.file {
+            Some(file) => file.read_back(buf),
+            None => self.memory.read_back(buf),
+        }
+    }
+
+    fn truncate_to(&mut self, len: u64) -> io::Result<()> {
+        match &mut self.file {
+            Some(file) => file.truncate_to(len),
+            None => self.memory.truncate_to(len),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::tempdir;
+
+    use super::{SpillingTemp, TempStore};
+
+    fn files_in(dir: &std::path::Path) -> usize {
+        fs::read_dir(dir).unwrap().count()
+    }
+
+    #[test]
+    fn test_spilling_temp() {
+        let dir = tempdir().unwrap();
+        let mut temp = SpillingTemp::new(16, dir.path());
+        temp.write_bytes(b"0123456789").unwrap();
+        temp.rewind_to(4).unwrap();
+        temp.write_bytes(b"abcd").unwrap();
+        assert!(!temp.is_spilled());
+        assert_eq!(files_in(dir.path()), 0);
+
+        // the write that crosses the threshold continues where the memory left off
+        temp.write_bytes(b"efghijklm").unwrap();
+        assert!(temp.is_spilled());
+        assert_eq!(files_in(dir.path()), 1);
+        temp.write_bytes(b"n").unwrap();
+
+        let mut buf = [0; 18];
+        temp.rewind_to(0).unwrap();
+        temp.read_back(&mut buf).unwrap();
+        assert_eq!(&buf, b"0123abcdefghijklmn");
+        assert!(temp.read_back(&mut [0]).is_err());
+
+        drop(temp);
+        assert_eq!(files_in(dir.path()), 0);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_spilling_encode() {
+        use super::MemoryTemp;
+        use crate::{Encoder, EncoderOptions};
+
+        const INPUT: &str = "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml";
+
+        fn encode(
+            temp: impl TempStore,
+            options: EncoderOptions,
+            spilled: Option<&std::path::Path>,
+        ) -> Vec<u8> {
+            let input = fs::File::open(INPUT).unwrap();
+            let mut encoder = Encoder::with_options(input, temp, options).unwrap();
+            encoder.prepare_chunks().unwrap();
+            if let Some(dir) = spilled {
+                assert_eq!(files_in(dir), 1);
+            }
+            let mut output = Vec::new();
+            encoder.compress_to(&mut output).unwrap();
+            output
+        }
+
+        let dir = tempdir().unwrap();
+        let options = || {
+            [
+                EncoderOptions::new(),
+                EncoderOptions::new().auto_dict(4096),
+                // recompresses the stored chunks without the dict
+                EncoderOptions::new()
+                    .dict(vec![0xa5; 4096])
+                    .auto_drop_ineffective_dict(0.0),
+            ]
+        };
+        for (memory, spilling) in options().into_iter().zip(options()) {
+            let expected = encode(MemoryTemp::new(), memory, None);
+            let output = encode(
+                SpillingTemp::new(10_000, dir.path()),
+                spilling,
+                Some(dir.path()),
+            );
+            assert!(output == expected);
+            assert_eq!(files_in(dir.path()), 0);
+        }
+    }
+}
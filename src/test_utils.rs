@@ -0,0 +1,401 @@
+//! Helpers for fabricating headers and files in tests, enabled by the `test-utils` feature
+
+use proptest::{collection::vec, prelude::*};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    checksum::{compute_checksum, Checksum, ChecksumType, DEFAULT_CHECKSUM_TYPE},
+    chunker::ChunkerParams,
+    errors::ZchunkError,
+    format::{Chunk, Header, IndexBuilder, Lead, Preface, PrefaceFlags, Signatures},
+};
+
+#[derive(Debug, Clone)]
+struct ChunkSpec {
+    checksum: Checksum,
+    length: u64,
+    uncompressed_length: u64,
+}
+
+/// Decode a checksum of up to 64 bytes from hex, panic on invalid input
+fn decode_checksum_hex(checksum_hex: &str) -> Checksum {
+    assert!(
+        checksum_hex.len().is_multiple_of(2),
+        "checksum must be whole bytes"
+    );
+
+    let bytes: Vec<u8> = (0..checksum_hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&checksum_hex[i..i + 2], 16).expect("checksum must be hex digits")
+        })
+        .collect();
+    Checksum::from_bytes(&bytes).expect("checksum must be at most 128 hex digits")
+}
+
+/// `checksum` zero padded or truncated to `len` bytes
+fn fit_checksum(checksum: &Checksum, len: usize) -> Checksum {
+    let mut fitted = Checksum::zeroed(len);
+    let n = len.min(checksum.len());
+    fitted[..n].copy_from_slice(&checksum[..n]);
+    fitted
+}
+
+/// A builder that fabricates valid `Header`s from simple chunk descriptions
+///
+/// All sizes and the header checksum are computed by the builder. Declared checksums are
+/// zero padded or truncated to the width of the checksum type, so the same descriptions
+/// build headers of every type.
+#[derive(Debug, Clone)]
+pub struct HeaderBuilder {
+    checksum_type: ChecksumType,
+    flags: u64,
+    dict: Option<ChunkSpec>,
+    chunks: Vec<ChunkSpec>,
+    auto_checksums: bool,
+}
+
+impl Default for HeaderBuilder {
+    fn default() -> Self {
+        Self {
+            checksum_type: DEFAULT_CHECKSUM_TYPE,
+            flags: 0,
+            dict: None,
+            chunks: Vec::new(),
+            auto_checksums: false,
+        }
+    }
+}
+
+impl HeaderBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a data chunk, panic if `checksum_hex` is not hex of at most 64 bytes
+    pub fn chunk(mut self, checksum_hex: &str, length: u64, uncompressed_length: u64) -> Self {
+        self.chunks.push(ChunkSpec {
+            checksum: decode_checksum_hex(checksum_hex),
+            length,
+            uncompressed_length,
+        });
+        self
+    }
+
+    /// Set the dict chunk, panic if `checksum_hex` is not hex of at most 64 bytes
+    pub fn dict(mut self, checksum_hex: &str, length: u64, uncompressed_length: u64) -> Self {
+        self.dict = Some(ChunkSpec {
+            checksum: decode_checksum_hex(checksum_hex),
+            length,
+            uncompressed_length,
+        });
+        self
+    }
+
+    /// Set the index checksum type
+    pub fn checksum_type(mut self, checksum_type: ChecksumType) -> Self {
+        self.checksum_type = checksum_type;
+        self
+    }
+
+    /// Set the preface flags, chunks get stream 0 when the stream flag is set
+    pub fn flags(mut self, flags: u64) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Compute checksums and lengths from the payloads passed to `to_file_bytes`
+    /// instead of the declared values
+    pub fn auto_checksums(mut self) -> Self {
+        self.auto_checksums = true;
+        self
+    }
+
+    /// Build the header, with a zeroed data checksum
+    pub fn build(&self) -> Result<Header, ZchunkError> {
+        self.build_with(&self.dict, &self.chunks, [0; 32])
+    }
+
+    /// Produce a complete file from the header and the chunk payloads
+    ///
+    /// `chunk_payloads` holds the dict payload first when a dict is declared,
+    /// followed by one payload per data chunk in index order.
+    pub fn to_file_bytes(&self, chunk_payloads: &[Vec<u8>]) -> Result<Vec<u8>, ZchunkError> {
+        let mut dict = self.dict.clone();
+        let mut chunks = self.chunks.clone();
+
+        let expected_count = chunks.len() + dict.iter().count();
+        if chunk_payloads.len() != expected_count {
+            return Err(ZchunkError::SizeNotMatch {
+                expected: expected_count as u64,
+                found: chunk_payloads.len() as u64,
+            });
+        }
+
+        for (spec, payload) in dict.iter_mut().chain(chunks.iter_mut()).zip(chunk_payloads) {
+            if self.auto_checksums {
+                spec.checksum = compute_checksum(self.checksum_type, payload)?;
+                spec.length = payload.len() as u64;
+            } else if spec.length != payload.len() as u64 {
+                return Err(ZchunkError::SizeNotMatch {
+                    expected: spec.length,
+                    found: payload.len() as u64,
+                });
+            }
+        }
+
+        let mut hasher = Sha256::new();
+        chunk_payloads.iter().for_each(|p| hasher.update(p));
+        let data_checksum = hasher.finalize();
+
+        let header = self.build_with(&dict, &chunks, data_checksum[..].try_into()?)?;
+
+        let mut bytes = Vec::new();
+        header.write_to(&mut bytes, false)?;
+        chunk_payloads
+            .iter()
+            .for_each(|p| bytes.extend_from_slice(p));
+
+        Ok(bytes)
+    }
+
+    fn build_with(
+        &self,
+        dict: &Option<ChunkSpec>,
+        chunks: &[ChunkSpec],
+        data_checksum: [u8; 32],
+    ) -> Result<Header, ZchunkError> {
+        let flags = PrefaceFlags::from_u64(self.flags);
+        let checksum_size = self.checksum_type.digest_size();
+        let to_chunk = |spec: &ChunkSpec| {
+            let checksum = fit_checksum(&spec.checksum, checksum_size);
+            let mut chunk = Chunk::new(checksum, spec.length, spec.uncompressed_length);
+            if flags.has_stream() {
+                chunk.stream = Some(0u64.into());
+            }
+            chunk
+        };
+
+        let empty_dict = ChunkSpec {
+            checksum: Checksum::zeroed(checksum_size),
+            length: 0,
+            uncompressed_length: 0,
+        };
+        let index = IndexBuilder::new()
+            .checksum_type(self.checksum_type)
+            .dict_chunk(to_chunk(dict.as_ref().unwrap_or(&empty_dict)))
+            .chunks(chunks.iter().map(to_chunk))
+            .build()?;
+
+        let mut preface = Preface::new(data_checksum);
+        preface.flags = flags;
+
+        let signatures = Signatures::new(Vec::new());
+        let header_size = signatures.byte_size() + index.byte_size() + preface.byte_size();
+        let lead = Lead::new(header_size)?;
+
+        let mut header = Header::new(lead, preface, index, signatures);
+        header.compute_and_set_checksum()?;
+
+        Ok(header)
+    }
+}
+
+/// Inputs of at most `max_len` bytes, mixing random bytes with repeated patterns and long
+/// runs of a single byte, so that both content-defined and maximum-size boundaries occur
+pub fn arb_input(max_len: usize) -> impl Strategy<Value = Vec<u8>> {
+    let segment = prop_oneof![
+        vec(any::<u8>(), 0..4096),
+        (vec(any::<u8>(), 1..64), 1usize..2048).prop_map(|(pattern, n)| pattern.repeat(n)),
+        (any::<u8>(), 0usize..65536).prop_map(|(b, n)| vec![b; n]),
+    ];
+    vec(segment, 0..48).prop_map(move |segments| {
+        let mut input = segments.concat();
+        input.truncate(max_len);
+        input
+    })
+}
+
+/// Chunker parameters with small chunk sizes, so that short inputs still span many chunks
+pub fn arb_chunker_params() -> impl Strategy<Value = ChunkerParams> {
+    (64usize..4096, 2usize..16, 6u32..14, 0u8..3).prop_map(|(min, factor, bits, level)| {
+        ChunkerParams::new(min, min * factor, 2u32.pow(bits) - 1).normalization_level(level)
+    })
+}
+
+/// Header builders with arbitrary chunk entries, including empty chunks, duplicate checksums,
+/// a dict chunk and the stream flag
+pub fn arb_header_builder() -> impl Strategy<Value = HeaderBuilder> {
+    let spec = |lengths: BoxedStrategy<(u64, u64)>| {
+        (any::<[u8; 32]>(), lengths).prop_map(|(checksum, (length, uncompressed_length))| {
+            ChunkSpec {
+                checksum: Checksum::from_bytes(&checksum).unwrap(),
+                length,
+                uncompressed_length,
+            }
+        })
+    };
+    // a dict chunk is empty or has both lengths, see `ZchunkError::InvalidDictChunk`
+    let dict_lengths = prop_oneof![Just((0, 0)), (1u64..100_000, 1u64..1_000_000)].boxed();
+    let chunk_lengths = (0u64..100_000, 0u64..1_000_000).boxed();
+    (
+        prop_oneof![Just(DEFAULT_CHECKSUM_TYPE), Just(ChecksumType::Sha256)],
+        0u64..2,
+        proptest::option::of(spec(dict_lengths)),
+        vec(spec(chunk_lengths), 0..64),
+    )
+        .prop_map(|(checksum_type, flags, dict, chunks)| HeaderBuilder {
+            checksum_type,
+            flags,
+            dict,
+            chunks,
+            auto_checksums: false,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use proptest::prelude::*;
+
+    #[cfg(feature = "zstd")]
+    use super::{arb_chunker_params, arb_input};
+    use super::{arb_header_builder, HeaderBuilder};
+    use crate::{ChecksumType, Decoder};
+    #[cfg(feature = "zstd")]
+    use crate::{ChunkAvailability, ChunkerParams, Encoder, EncoderOptions};
+
+    #[cfg(feature = "zstd")]
+    fn encode(input: &[u8], params: ChunkerParams) -> Vec<u8> {
+        let options = EncoderOptions::new().chunker_params(params);
+        let mut encoder = Encoder::with_options(input, Cursor::new(Vec::new()), options).unwrap();
+        encoder.prepare_chunks().unwrap();
+        let mut output = Vec::new();
+        encoder.compress_to(&mut output).unwrap();
+        output
+    }
+
+    fn header_bytes(decoder: &Decoder<Cursor<Vec<u8>>>) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        decoder.header().write_to(&mut bytes, false).unwrap();
+        bytes
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_empty_input_round_trip() {
+        let file = encode(&[], ChunkerParams::default());
+        let mut decoder = Decoder::new(Cursor::new(file)).unwrap();
+        assert!(decoder.header().index.data_chunks.is_empty());
+
+        let mut output = Vec::new();
+        decoder.decompress_to(&mut output).unwrap();
+        assert!(output.is_empty());
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(24))]
+
+        #[cfg(feature = "zstd")]
+        #[test]
+        fn prop_round_trip(input in arb_input(4 << 20), params in arb_chunker_params()) {
+            let file = encode(&input, params.clone());
+            let mut decoder = Decoder::new(Cursor::new(file)).unwrap();
+
+            let mut output = Vec::new();
+            decoder.decompress_to(&mut output).unwrap();
+            prop_assert_eq!(&output, &input);
+
+            // every chunk but the last one is within the chunker limits
+            let lengths: Vec<u64> = decoder
+                .header()
+                .index
+                .data_chunks
+                .iter()
+                .map(|(c, _)| c.uncompressed_length.to_u64().unwrap())
+                .collect();
+            prop_assert_eq!(lengths.iter().sum::<u64>(), input.len() as u64);
+            if let Some((_, init)) = lengths.split_last() {
+                for length in init {
+                    prop_assert!(params.min as u64 <= *length && *length <= params.max as u64);
+                }
+            }
+        }
+
+        #[cfg(feature = "zstd")]
+        #[test]
+        fn prop_sync_with_itself(input in arb_input(1 << 20)) {
+            let file = encode(&input, ChunkerParams::default());
+            let cache = Decoder::new(Cursor::new(file.clone())).unwrap();
+
+            // the source has no chunk data at all, so every chunk must come from the cache
+            let mut source_bytes = header_bytes(&cache);
+            source_bytes.resize(file.len(), 0);
+            let count = cache.header().index.data_chunks.len();
+            let mut source =
+                Decoder::with_availability(Cursor::new(source_bytes), ChunkAvailability::none(count))
+                    .unwrap();
+
+            let mut output = Vec::new();
+            source.sync_to(cache, &mut output).unwrap();
+            prop_assert_eq!(output, file);
+        }
+
+        #[test]
+        fn prop_header_round_trip(builder in arb_header_builder()) {
+            let header = builder.build().unwrap();
+            let mut bytes = Vec::new();
+            header.write_to(&mut bytes, false).unwrap();
+
+            let decoder = Decoder::new(Cursor::new(bytes.clone())).unwrap();
+            prop_assert_eq!(header_bytes(&decoder), bytes);
+        }
+    }
+
+    #[test]
+    fn test_header_builder() {
+        let header = HeaderBuilder::new()
+            .checksum_type(ChecksumType::Sha256)
+            .chunk("000102030405060708090a0b0c0d0e0f", 10, 20)
+            .chunk("ffffffffffffffffffffffffffffffff", 30, 40)
+            .flags(0x01)
+            .build()
+            .unwrap();
+
+        assert_eq!(header.index.data_chunks.len(), 2);
+        assert_eq!(header.index.data_chunks[1].1, 10);
+        // declared checksums are padded to the width of SHA-256
+        let checksum = header.index.data_chunks[1].0.checksum;
+        assert_eq!(checksum[..], [[0xff; 16], [0; 16]].concat());
+        assert!(header.index.data_chunks[0].0.stream.is_some());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_header_builder_file_bytes() {
+        let payloads: Vec<Vec<u8>> = [&b"hello "[..], b"zchunk", b""]
+            .iter()
+            .map(|p| zstd::encode_all(*p, 3).unwrap())
+            .collect();
+
+        let bytes = HeaderBuilder::new()
+            .chunk("00000000000000000000000000000000", 0, 6)
+            .chunk("00000000000000000000000000000000", 0, 6)
+            .chunk("00000000000000000000000000000000", 0, 0)
+            .auto_checksums()
+            .to_file_bytes(&payloads)
+            .unwrap();
+
+        let mut decoder = Decoder::new(Cursor::new(bytes)).unwrap();
+        let mut output = Vec::new();
+        decoder.decompress_to(&mut output).unwrap();
+        assert_eq!(output, b"hello zchunk");
+
+        // declared lengths must match the payloads
+        assert!(HeaderBuilder::new()
+            .chunk("00000000000000000000000000000000", 1, 6)
+            .to_file_bytes(&payloads[..1])
+            .is_err());
+    }
+}
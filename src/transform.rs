@@ -0,0 +1,99 @@
+use crate::format::ChunkId;
+
+/// A transform applied to compressed data chunks, such as encryption
+///
+/// `encode` runs after compression when encoding and `decode` runs before decompression when
+/// decoding. Chunk checksums and lengths are computed over the transformed bytes, so sync and
+/// verify work unmodified. To keep chunks reusable across files, equal input should produce
+/// equal output regardless of `chunk_index`. The dict chunk is not transformed.
+pub trait ChunkTransform: Send + Sync {
+    fn encode(&self, chunk_index: ChunkId, plaintext_compressed: &[u8]) -> Vec<u8>;
+
+    fn decode(&self, chunk_index: ChunkId, transformed: &[u8]) -> Vec<u8>;
+}
+
+/// A transform that leaves chunks unchanged, which is the default
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IdentityTransform;
+
+impl ChunkTransform for IdentityTransform {
+    fn encode(&self, _chunk_index: ChunkId, plaintext_compressed: &[u8]) -> Vec<u8> {
+        plaintext_compressed.to_vec()
+    }
+
+    fn decode(&self, _chunk_index: ChunkId, transformed: &[u8]) -> Vec<u8> {
+        transformed.to_vec()
+    }
+}
+
+#[cfg(all(test, feature = "zstd"))]
+mod tests {
+    use std::{fs::File, io::Cursor, sync::Arc};
+
+    use sha2::{Digest, Sha256};
+
+    use super::ChunkTransform;
+    use crate::{format::ChunkId, DecodeOptions, Decoder, Encoder, EncoderOptions, VerifyOptions};
+
+    struct XorTransform(u8);
+
+    impl ChunkTransform for XorTransform {
+        fn encode(&self, _chunk_index: ChunkId, plaintext_compressed: &[u8]) -> Vec<u8> {
+            plaintext_compressed.iter().map(|b| b ^ self.0).collect()
+        }
+
+        fn decode(&self, _chunk_index: ChunkId, transformed: &[u8]) -> Vec<u8> {
+            transformed.iter().map(|b| b ^ self.0).collect()
+        }
+    }
+
+    fn encode_with_xor(path: &str) -> Vec<u8> {
+        let options = EncoderOptions::new().transform(Arc::new(XorTransform(0x5a)));
+        let mut encoder =
+            Encoder::with_options(File::open(path).unwrap(), Cursor::new(Vec::new()), options)
+                .unwrap();
+        encoder.prepare_chunks().unwrap();
+        let mut output = Vec::new();
+        encoder.compress_to(&mut output).unwrap();
+        output
+    }
+
+    fn decoder_with_xor(bytes: Vec<u8>) -> Decoder<Cursor<Vec<u8>>> {
+        let options = DecodeOptions::new().transform(Arc::new(XorTransform(0x5a)));
+        Decoder::with_options(Cursor::new(bytes), options).unwrap()
+    }
+
+    #[test]
+    fn test_transform_round_trip() {
+        let path = "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml";
+        let bytes = encode_with_xor(path);
+
+        // without the transform the chunks are not valid zstd frames
+        let mut decoder = Decoder::new(Cursor::new(bytes.clone())).unwrap();
+        assert!(decoder.decompress_to(&mut Vec::new()).is_err());
+
+        let mut decoder = decoder_with_xor(bytes);
+        let mut hasher = Sha256::new();
+        decoder.decompress_to(&mut hasher).unwrap();
+        assert_eq!(
+            hex::encode(hasher.finalize()),
+            "14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68"
+        );
+
+        let options = VerifyOptions::new().frame_headers_only(true);
+        assert!(decoder.verify(&options).unwrap().is_ok());
+        assert!(decoder.verify(&VerifyOptions::new()).unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_transform_sync() {
+        let source = encode_with_xor("testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml");
+        let cache = encode_with_xor("testdata/4a1a7a9d98dd9764f67d4a608828fa8afca99889afe8b178228f5d37959c1ebf-comps-Server.x86_64.xml");
+
+        let mut source_decoder = decoder_with_xor(source.clone());
+        let cache_decoder = decoder_with_xor(cache);
+        let mut output = Vec::new();
+        source_decoder.sync_to(cache_decoder, &mut output).unwrap();
+        assert_eq!(output, source);
+    }
+}
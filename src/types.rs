@@ -1,4 +1,9 @@
-use std::io::{self, Error, ErrorKind, Write};
+use std::{
+    fmt,
+    io::{self, Error, Write},
+};
+
+use crate::hex::HexPrefix;
 
 /// Extends `Read` with methods for reading variant int. (For `std::io`.)
 pub trait ReadVariantInt: io::Read {
@@ -48,9 +53,22 @@ impl<W: io::Write + ?Sized> WriteVariantInt for W {}
 ///
 /// First bit of each byte is used to mark the end, 1: end, 0: not end
 /// Last seven bit of each byte store the data
-#[derive(Debug, Clone, PartialEq, Hash)]
+#[derive(Clone, PartialEq, Hash)]
 pub struct VariantInt(Vec<u8>);
 
+/// Formats the value, or the raw bytes when they do not fit in `u64`
+impl fmt::Debug for VariantInt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.to_u64() {
+            Ok(n) => write!(f, "{n}"),
+            Err(_) => f
+                .debug_tuple("VariantInt")
+                .field(&HexPrefix(&self.0))
+                .finish(),
+        }
+    }
+}
+
 impl From<u64> for VariantInt {
     fn from(value: u64) -> Self {
         let mut num = value;
@@ -78,15 +96,16 @@ impl VariantInt {
     /// Convert the variant int to `u64`
     pub fn to_u64(&self) -> Result<u64, std::io::Error> {
         if self.0.len() > 10 {
-            return Err(Error::new(
-                ErrorKind::Other,
-                "VariantInt has greater than 10 bytes",
-            ));
+            return Err(Error::other("VariantInt has greater than 10 bytes"));
         }
 
         let mut num = 0u64;
         for (i, &byte) in self.0.iter().enumerate() {
             let last_seven_bits = byte & 0x7f;
+            // only the lowest bit of the 10th byte fits in `u64`
+            if i == 9 && last_seven_bits > 1 {
+                return Err(Error::other("VariantInt overflows u64"));
+            }
             num |= (last_seven_bits as u64) << (7 * i);
             if byte & 0x80 != 0 {
                 return Ok(num);
@@ -103,7 +122,7 @@ impl VariantInt {
 
 #[cfg(test)]
 mod tests {
-    use crate::VariantInt;
+    use crate::{ReadVariantInt, VariantInt, WriteVariantInt};
 
     fn test_variant_int_inner(n: u64, expect_bytes_size: usize, expect_bytes: &[u8]) {
         let mut buf = Vec::new();
@@ -123,4 +142,28 @@ mod tests {
         let vint = VariantInt::from_bytes(vec![0; 1]);
         assert_eq!(vint.to_u64().unwrap(), 0);
     }
+
+    #[test]
+    fn test_variant_int_max() {
+        let mut max = vec![0x7f; 9];
+        max.push(0x81);
+        test_variant_int_inner(u64::MAX, 10, &max);
+
+        for n in [u64::MAX, u64::MAX - 1, 1 << 63, (1 << 63) - 1] {
+            let mut buf = Vec::new();
+            buf.write_variant_int(VariantInt::from(n)).unwrap();
+            let vint = buf.as_slice().read_variant_int().unwrap();
+            assert_eq!(vint.to_u64().unwrap(), n);
+        }
+
+        // a 10th byte carrying more than the top bit does not fit
+        let mut overflow = vec![0x7f; 9];
+        overflow.push(0x82);
+        assert!(VariantInt::from_bytes(overflow).to_u64().is_err());
+
+        // neither does an 11th byte
+        let mut too_long = vec![0; 10];
+        too_long.push(0x80);
+        assert!(VariantInt::from_bytes(too_long).to_u64().is_err());
+    }
 }
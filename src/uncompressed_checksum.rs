@@ -0,0 +1,184 @@
+#[cfg(feature = "zstd")]
+use std::io::{BufRead, Seek, Write};
+
+#[cfg(feature = "zstd")]
+use sha2::{Digest, Sha256};
+
+use crate::format::Decoder;
+#[cfg(feature = "zstd")]
+use crate::{
+    checksum::{Checksum, MultiHasher},
+    errors::ZchunkError,
+    format::OptionalElement,
+};
+
+/// Optional element id of the SHA-256 of the uncompressed data
+///
+/// Specific to this crate like the chunk annotations, other readers skip the element.
+pub(crate) const UNCOMPRESSED_CHECKSUM_ELEMENT_ID: u64 = 0x7a63_6b02;
+
+/// Build the element from the digest of the whole uncompressed input
+#[cfg(feature = "zstd")]
+pub(crate) fn uncompressed_checksum_element(digest: [u8; 32]) -> OptionalElement {
+    OptionalElement {
+        id: UNCOMPRESSED_CHECKSUM_ELEMENT_ID,
+        data: digest.to_vec(),
+    }
+}
+
+impl<R> Decoder<R> {
+    /// The SHA-256 of the uncompressed data, recorded with
+    /// `EncoderOptions::uncompressed_checksum`
+    ///
+    /// `None` when the file has no such element, or its payload is not 32 bytes.
+    pub fn expected_uncompressed_checksum(&self) -> Option<[u8; 32]> {
+        self.header
+            .preface
+            .optional_element(UNCOMPRESSED_CHECKSUM_ELEMENT_ID)?
+            .try_into()
+            .ok()
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl<R: BufRead + Seek> Decoder<R> {
+    /// `decompress_to`, then check the output against `expected_uncompressed_checksum`
+    ///
+    /// A mismatch fails with `UncompressedChecksumNotMatch` once everything is written, so
+    /// the caller must discard the output. A file without the checksum is decompressed like
+    /// `decompress_to` does.
+    pub fn decompress_to_verified(&mut self, writer: impl Write) -> Result<(), ZchunkError> {
+        let Some(expected) = self.expected_uncompressed_checksum() else {
+            return self.decompress_to(writer);
+        };
+        let mut hasher = Sha256::new();
+        self.decompress_to(MultiHasher::new(writer, [&mut hasher]))?;
+        let found: [u8; 32] = hasher.finalize().into();
+        if found != expected {
+            return Err(ZchunkError::UncompressedChecksumNotMatch {
+                expected: Box::new(Checksum::from_bytes(&expected)?),
+                found: Box::new(Checksum::from_bytes(&found)?),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "zstd"))]
+mod tests {
+    use std::io::Cursor;
+
+    use sha2::{Digest, Sha256};
+
+    use super::{uncompressed_checksum_element, UNCOMPRESSED_CHECKSUM_ELEMENT_ID};
+    use crate::{
+        ChunkerParams, CompressionType, DecodeOptions, Decoder, Encoder, EncoderOptions,
+        MemoryTemp, OptionalElement, VerificationLevel, ZchunkError,
+    };
+
+    const INPUT: &str = "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml";
+
+    fn encode(input: &[u8], options: EncoderOptions) -> Vec<u8> {
+        let options = options.chunker_params(ChunkerParams::new(1024, 8192, 2047));
+        let mut file = Vec::new();
+        Encoder::compress_small(input, &mut file, options).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_uncompressed_checksum_round_trip() {
+        let input = std::fs::read(INPUT).unwrap();
+        let digest: [u8; 32] = Sha256::digest(&input).into();
+        for options in [
+            EncoderOptions::new().uncompressed_checksum(true),
+            EncoderOptions::new()
+                .uncompressed_checksum(true)
+                .auto_dict(4096),
+            // an element of the same id given by the caller is replaced
+            EncoderOptions::new()
+                .optional_element(OptionalElement::new(
+                    UNCOMPRESSED_CHECKSUM_ELEMENT_ID,
+                    vec![0; 32],
+                ))
+                .uncompressed_checksum(true),
+        ] {
+            let file = encode(&input, options);
+            let mut decoder = Decoder::new(Cursor::new(file)).unwrap();
+            assert_eq!(decoder.expected_uncompressed_checksum(), Some(digest));
+            assert_eq!(decoder.header().optional_elements().len(), 1);
+            let mut output = Vec::new();
+            decoder.decompress_to_verified(&mut output).unwrap();
+            assert!(output == input);
+        }
+
+        // appending records the checksum of the old and the new data
+        let (old, new) = input.split_at(input.len() / 2);
+        let mut existing = Decoder::new(Cursor::new(encode(old, EncoderOptions::new()))).unwrap();
+        let options = EncoderOptions::new()
+            .chunker_params(ChunkerParams::new(1024, 8192, 2047))
+            .uncompressed_checksum(true);
+        let mut encoder = Encoder::with_options(new, MemoryTemp::new(), options).unwrap();
+        let mut file = Vec::new();
+        encoder.append(&mut existing, &mut file).unwrap();
+        let decoder = Decoder::new(Cursor::new(file)).unwrap();
+        assert_eq!(decoder.expected_uncompressed_checksum(), Some(digest));
+    }
+
+    #[test]
+    fn test_uncompressed_checksum_absent() {
+        let input = std::fs::read(INPUT).unwrap();
+        let mut decoder = Decoder::new(Cursor::new(encode(&input, EncoderOptions::new()))).unwrap();
+        assert_eq!(decoder.expected_uncompressed_checksum(), None);
+        assert!(decoder.header().optional_elements().is_empty());
+        let mut output = Vec::new();
+        decoder.decompress_to_verified(&mut output).unwrap();
+        assert!(output == input);
+
+        // a payload of another length is not a checksum
+        let file = encode(
+            &input,
+            EncoderOptions::new().optional_element(OptionalElement::new(
+                UNCOMPRESSED_CHECKSUM_ELEMENT_ID,
+                vec![0; 31],
+            )),
+        );
+        let decoder = Decoder::new(Cursor::new(file)).unwrap();
+        assert_eq!(decoder.expected_uncompressed_checksum(), None);
+    }
+
+    #[test]
+    fn test_uncompressed_checksum_mismatch() {
+        let input = b"<group>".repeat(2000);
+        let options = EncoderOptions::new()
+            .compression_type(CompressionType::None)
+            .uncompressed_checksum(true);
+        let mut file = encode(&input, options);
+
+        // corrupt a payload byte, which only the chunk checksums and the uncompressed
+        // checksum can notice
+        let decoder = Decoder::new(Cursor::new(file.as_slice())).unwrap();
+        let data_offset = decoder.header().data_offset().unwrap() as usize;
+        file[data_offset + 100] ^= 0xff;
+        let decode_options = DecodeOptions::new().verification(VerificationLevel::HeaderOnly);
+        let mut decoder = Decoder::with_options(Cursor::new(file), decode_options).unwrap();
+        let mut output = Vec::new();
+        let err = decoder.decompress_to_verified(&mut output).unwrap_err();
+        assert!(matches!(
+            err,
+            ZchunkError::UncompressedChecksumNotMatch { expected, found }
+                if expected.as_bytes() == Sha256::digest(&input).as_slice()
+                    && found.as_bytes() == Sha256::digest(&output).as_slice()
+        ));
+
+        // the element of other data is a mismatch too
+        let other = encode(
+            &input,
+            EncoderOptions::new().optional_element(uncompressed_checksum_element([7; 32])),
+        );
+        let mut decoder = Decoder::new(Cursor::new(other)).unwrap();
+        assert!(matches!(
+            decoder.decompress_to_verified(std::io::sink()),
+            Err(ZchunkError::UncompressedChecksumNotMatch { .. })
+        ));
+    }
+}
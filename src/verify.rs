@@ -0,0 +1,434 @@
+//! Checking chunks of a file against its index, see `Decoder::verify`
+
+use std::io::{BufRead, Seek};
+
+use crate::{
+    checksum::Checksum,
+    errors::ZchunkError,
+    format::{ChunkId, CompressionType, Decoder},
+};
+
+/// Maximum size of a zstd frame header, see `ZSTD_FRAMEHEADERSIZE_MAX`
+const ZSTD_FRAME_HEADER_SIZE_MAX: usize = 18;
+
+/// Options that control how `Decoder::verify` checks chunks
+#[derive(Debug, Clone, Default)]
+pub struct VerifyOptions {
+    frame_headers_only: bool,
+    sample: Option<(f64, u64)>,
+}
+
+impl VerifyOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only read the zstd frame header of each chunk and compare the embedded
+    /// content size with the index `uncompressed_length`, skipping checksums
+    ///
+    /// Chunks whose frame does not record a content size are not checked.
+    pub fn frame_headers_only(mut self, enable: bool) -> Self {
+        self.frame_headers_only = enable;
+        self
+    }
+
+    /// Only check a pseudo-random `fraction` of the data chunks
+    ///
+    /// A chunk is selected when `splitmix64(seed ^ id) / 2^64 < fraction`, so the selection
+    /// depends on the seed and the chunk id only: re-runs with the same seed check the same
+    /// chunks, also after chunks are appended to the file. The header checksum and the dict
+    /// chunk are checked regardless.
+    pub fn sample(mut self, fraction: f64, seed: u64) -> Self {
+        self.sample = Some((fraction, seed));
+        self
+    }
+
+    /// Whether the data chunk is checked under these options
+    fn selects(&self, id: ChunkId) -> bool {
+        match self.sample {
+            Some((fraction, seed)) => {
+                (splitmix64(seed ^ id as u64) as f64 / 2f64.powi(64)) < fraction
+            }
+            None => true,
+        }
+    }
+}
+
+/// The splitmix64 finalizer, a fast well-mixing 64 bit hash
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// Why a chunk failed verification
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FailureReason {
+    /// The checksum of the chunk data does not match the index
+    ChecksumMismatch { expected: Checksum, found: Checksum },
+    /// The zstd frame content size does not match the index `uncompressed_length`
+    ContentSizeMismatch { expected: u64, found: u64 },
+    /// The chunk data does not start with a readable zstd frame header
+    InvalidFrameHeader,
+}
+
+/// A chunk that failed verification
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VerifyFailure {
+    pub id: ChunkId,
+    pub reason: FailureReason,
+}
+
+/// The result of `Decoder::verify`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VerifyReport {
+    /// Number of data chunks that were actually checked
+    pub chunks_checked: usize,
+    /// Number of chunk bytes read from the file
+    pub bytes_checked: u64,
+    /// Ordered by chunk id
+    pub failures: Vec<VerifyFailure>,
+    /// Chunks skipped because they are absent from a partial file, ordered by chunk id
+    pub unavailable: Vec<ChunkId>,
+    /// Number of data chunks left out by `VerifyOptions::sample`
+    pub chunks_skipped: usize,
+    /// Whether the header checksum in the lead does not match the header
+    pub header_checksum_mismatch: bool,
+    /// Why the dict chunk failed verification, if it did
+    pub dict_failure: Option<FailureReason>,
+}
+
+impl VerifyReport {
+    /// Whether nothing failed verification, absent chunks of a partial file are not failures
+    pub fn is_ok(&self) -> bool {
+        self.failures.is_empty() && !self.header_checksum_mismatch && self.dict_failure.is_none()
+    }
+
+    /// Fraction of the available data chunks that were checked
+    pub fn coverage(&self) -> f64 {
+        let total = self.chunks_checked + self.chunks_skipped;
+        if total == 0 {
+            return 1.0;
+        }
+        self.chunks_checked as f64 / total as f64
+    }
+}
+
+/// Compare the content size of the zstd frame at the start of `data` with the expected size
+///
+/// Return `None` when the sizes agree or the frame does not record a content size.
+#[cfg(feature = "zstd")]
+fn check_frame_content_size(data: &[u8], expected: u64) -> Option<FailureReason> {
+    match zstd::zstd_safe::get_frame_content_size(data) {
+        Ok(Some(found)) if found != expected => {
+            Some(FailureReason::ContentSizeMismatch { expected, found })
+        }
+        Ok(_) => None,
+        Err(_) => Some(FailureReason::InvalidFrameHeader),
+    }
+}
+
+/// Frame headers are not checked without the zstd feature, only chunk checksums
+#[cfg(not(feature = "zstd"))]
+fn check_frame_content_size(_data: &[u8], _expected: u64) -> Option<FailureReason> {
+    None
+}
+
+impl<R: BufRead + Seek> Decoder<R> {
+    /// Check data chunks against the index, reporting every chunk that fails
+    ///
+    /// IO errors abort the verification, while corrupt chunks are collected in the report.
+    /// The header checksum and the dict chunk are always checked.
+    pub fn verify(&mut self, options: &VerifyOptions) -> Result<VerifyReport, ZchunkError> {
+        let mut report = self.verify_header_and_dict()?;
+
+        let is_zstd = self.header.compression_type()? == CompressionType::Zstd;
+        if options.frame_headers_only && !is_zstd {
+            return Ok(report);
+        }
+
+        for id in 0..self.header.index.data_chunks.len() {
+            self.verify_data_chunk(id, options, &mut report)?;
+        }
+
+        Ok(report)
+    }
+
+    /// Start a report with the header checksum and the dict chunk checked
+    pub(crate) fn verify_header_and_dict(&mut self) -> Result<VerifyReport, ZchunkError> {
+        let mut report = VerifyReport {
+            header_checksum_mismatch: self.header.computed_checksum()?
+                != self.header.lead.header_checksum,
+            ..Default::default()
+        };
+
+        let is_zstd = self.header.compression_type()? == CompressionType::Zstd;
+        let dict_chunk = self.header.index.dict_chunk.clone();
+        if self.header.index.has_dict() && self.dict_available() {
+            report.dict_failure = match self.get_chunk_data(None, 0, &dict_chunk) {
+                Ok(data) if is_zstd => {
+                    check_frame_content_size(&data, dict_chunk.uncompressed_length.to_u64()?)
+                }
+                Ok(_) => None,
+                Err(ZchunkError::ChunkChecksumNotMatch {
+                    expected, found, ..
+                }) => Some(FailureReason::ChecksumMismatch {
+                    expected: *expected,
+                    found: *found,
+                }),
+                Err(e) => return Err(e),
+            };
+        }
+
+        Ok(report)
+    }
+
+    /// Check one data chunk and record the outcome in `report`
+    pub(crate) fn verify_data_chunk(
+        &mut self,
+        id: ChunkId,
+        options: &VerifyOptions,
+        report: &mut VerifyReport,
+    ) -> Result<(), ZchunkError> {
+        if !self.is_chunk_available(id) {
+            report.unavailable.push(id);
+            return Ok(());
+        }
+        if !options.selects(id) {
+            report.chunks_skipped += 1;
+            return Ok(());
+        }
+        let (chunk, offset) = self.header.index.data_chunks[id].clone();
+        let uncompressed_length = chunk.uncompressed_length.to_u64()?;
+        let is_zstd = self.header.compression_type()? == CompressionType::Zstd;
+
+        let transform = self.options.transform.clone();
+
+        let (data, mut failure) = if options.frame_headers_only {
+            // the frame header of transformed chunks is only readable after decoding the
+            // whole chunk, which is read like any other without its checksum checked
+            let prefix = match transform {
+                Some(_) => self.read_chunk_data(Some(id), offset, &chunk, false)?,
+                None => self.read_chunk_prefix(offset, &chunk, ZSTD_FRAME_HEADER_SIZE_MAX)?,
+            };
+            (prefix, None)
+        } else {
+            match self.get_chunk_data(Some(id), offset, &chunk) {
+                Ok(data) => (data, None),
+                Err(ZchunkError::ChunkChecksumNotMatch {
+                    expected, found, ..
+                }) => (
+                    Vec::new(),
+                    Some(FailureReason::ChecksumMismatch {
+                        expected: *expected,
+                        found: *found,
+                    }),
+                ),
+                Err(e) => return Err(e),
+            }
+        };
+
+        report.chunks_checked += 1;
+        report.bytes_checked += data.len() as u64;
+
+        if failure.is_none() && is_zstd && !data.is_empty() {
+            failure = match &transform {
+                Some(t) => check_frame_content_size(&t.decode(id, &data), uncompressed_length),
+                None => check_frame_content_size(&data, uncompressed_length),
+            };
+        }
+        if let Some(reason) = failure {
+            report.failures.push(VerifyFailure { id, reason });
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(any(feature = "zstd", feature = "sha512"))]
+    use std::fs::File;
+    #[cfg(feature = "sha512")]
+    use std::io::BufReader;
+    #[cfg(feature = "zstd")]
+    use std::io::Cursor;
+
+    #[cfg(any(feature = "zstd", feature = "sha512"))]
+    use super::VerifyOptions;
+    #[cfg(feature = "zstd")]
+    use super::{FailureReason, VerifyFailure};
+    #[cfg(any(feature = "zstd", feature = "sha512"))]
+    use crate::Decoder;
+    #[cfg(feature = "zstd")]
+    use crate::{ChunkerParams, Encoder, EncoderOptions};
+
+    #[cfg(feature = "zstd")]
+    fn encode_fixture() -> Vec<u8> {
+        encode_fixture_with(EncoderOptions::new())
+    }
+
+    #[cfg(feature = "zstd")]
+    fn encode_fixture_with(options: EncoderOptions) -> Vec<u8> {
+        let input = File::open(
+            "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml",
+        )
+        .unwrap();
+
+        let mut encoder = Encoder::with_options(input, Cursor::new(Vec::new()), options).unwrap();
+        encoder.prepare_chunks().unwrap();
+        let mut output = Vec::new();
+        encoder.compress_to(&mut output).unwrap();
+        output
+    }
+
+    #[cfg(feature = "sha512")]
+    #[test]
+    fn test_verify() {
+        let file = File::open("testdata/c25ffa05cf1fdeb67801847df96c33933b1ee1ea081af52edff4ff371a1c814c-comps-Server.x86_64.xml.zck").unwrap();
+        let mut decoder = Decoder::new(BufReader::new(file)).unwrap();
+
+        let report = decoder.verify(&VerifyOptions::new()).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(
+            report.chunks_checked,
+            decoder.header.index.data_chunks.len()
+        );
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_verify_frame_headers_only() {
+        let file = encode_fixture();
+        let mut decoder = Decoder::new(Cursor::new(file.as_slice())).unwrap();
+        let options = VerifyOptions::new().frame_headers_only(true);
+
+        let report = decoder.verify(&options).unwrap();
+        assert!(report.is_ok());
+        assert!(decoder.header.index.data_chunks.len() > 1);
+        assert_eq!(
+            report.chunks_checked,
+            decoder.header.index.data_chunks.len()
+        );
+
+        // doctor the uncompressed length of the second chunk in the index of the file, a
+        // length of the same varint width keeps the rest of the file in place
+        let mut chunk = decoder.header.index.data_chunks[1].0.clone();
+        let mut entry = Vec::new();
+        chunk.write_to(&mut entry).unwrap();
+        let found = chunk.uncompressed_length.to_u64().unwrap();
+        let expected = found ^ 1;
+        chunk.uncompressed_length = expected.into();
+        let mut doctored = Vec::new();
+        chunk.write_to(&mut doctored).unwrap();
+        assert_eq!(doctored.len(), entry.len());
+        let at = file
+            .windows(entry.len())
+            .position(|window| window == entry)
+            .unwrap();
+        let mut file = file;
+        file[at..at + entry.len()].copy_from_slice(&doctored);
+
+        let mut decoder = Decoder::new(Cursor::new(file)).unwrap();
+        let report = decoder.verify(&options).unwrap();
+        assert!(report.header_checksum_mismatch);
+        assert_eq!(
+            report.failures,
+            vec![VerifyFailure {
+                id: 1,
+                reason: FailureReason::ContentSizeMismatch { expected, found },
+            }]
+        );
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_verify_frame_headers_only_transformed_huge_chunk() {
+        use std::sync::Arc;
+
+        use crate::{test_utils::HeaderBuilder, DecodeOptions, IdentityTransform};
+
+        // a chunk declared far longer than the file, which must not be allocated up front
+        let header = HeaderBuilder::new()
+            .chunk("00", 1 << 40, 1)
+            .build()
+            .unwrap();
+        let mut file = Vec::new();
+        header.write_to(&mut file, false).unwrap();
+        file.extend_from_slice(&[0; 8]);
+
+        let options = DecodeOptions::new().transform(Arc::new(IdentityTransform));
+        let mut decoder = Decoder::with_options(Cursor::new(file), options).unwrap();
+        assert!(decoder
+            .verify(&VerifyOptions::new().frame_headers_only(true))
+            .is_err());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_verify_sample() {
+        let params = ChunkerParams::new(1024, 8192, 2047);
+        let file = encode_fixture_with(EncoderOptions::new().chunker_params(params));
+        let options = VerifyOptions::new().sample(0.25, 7);
+
+        let decoder = Decoder::new(Cursor::new(file.clone())).unwrap();
+        let count = decoder.header.index.data_chunks.len();
+        assert!(count > 8);
+        let sampled: Vec<usize> = (0..count).filter(|id| options.selects(*id)).collect();
+        let unsampled = (0..count).find(|id| !options.selects(*id)).unwrap();
+        assert!(!sampled.is_empty() && sampled.len() < count);
+
+        let corrupt = |id: usize| {
+            let range = decoder.header.chunk_range(id).unwrap();
+            let mut file = file.clone();
+            file[(range.start + range.end) as usize / 2] ^= 0xff;
+            Decoder::new(Cursor::new(file)).unwrap()
+        };
+
+        // corruption inside the sample is caught
+        let report = corrupt(sampled[0]).verify(&options).unwrap();
+        assert_eq!(report.chunks_checked, sampled.len());
+        assert_eq!(report.chunks_skipped, count - sampled.len());
+        assert_eq!(report.coverage(), sampled.len() as f64 / count as f64);
+        assert_eq!(report.failures.len(), 1);
+        assert_eq!(report.failures[0].id, sampled[0]);
+
+        // corruption outside the sample is not, by design
+        let mut decoder = corrupt(unsampled);
+        assert!(decoder.verify(&options).unwrap().is_ok());
+        assert!(!decoder.verify(&VerifyOptions::new()).unwrap().is_ok());
+
+        // another seed selects other chunks
+        let other = VerifyOptions::new().sample(0.25, 8);
+        assert_ne!(
+            (0..count)
+                .filter(|id| other.selects(*id))
+                .collect::<Vec<_>>(),
+            sampled
+        );
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_verify_header_checksum() {
+        let mut decoder = Decoder::new(Cursor::new(encode_fixture())).unwrap();
+        assert!(
+            !decoder
+                .verify(&VerifyOptions::new())
+                .unwrap()
+                .header_checksum_mismatch
+        );
+
+        decoder.header.lead.header_checksum[0] ^= 0xff;
+        let report = decoder
+            .verify(&VerifyOptions::new().sample(0.0, 0))
+            .unwrap();
+        assert!(report.header_checksum_mismatch);
+        assert_eq!(report.chunks_checked, 0);
+        assert!(!report.is_ok());
+    }
+}
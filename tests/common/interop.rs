@@ -0,0 +1,276 @@
+//! Running the upstream zchunk tools against this crate
+//!
+//! The tools are found in the directory named by `ZCK_BIN_DIR`. Tests that need them are
+//! ignored by default and return early when the variable is unset, so
+//! `ZCK_BIN_DIR=/usr/bin cargo test -- --ignored` runs them. A set variable without the tools
+//! in it fails the test instead of skipping it.
+
+use std::{
+    collections::BTreeMap,
+    env,
+    fs::File,
+    io::{BufReader, Cursor},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use sha2::{Digest, Sha256};
+use zchunk::{ChecksumType, Decoder, PartialDecoder};
+
+/// The environment variable naming the directory with the upstream binaries
+pub const BIN_DIR_VAR: &str = "ZCK_BIN_DIR";
+
+const TOOLS: [&str; 3] = ["zck", "unzck", "zck_read_header"];
+
+/// The upstream command line tools
+pub struct ZckTools {
+    dir: PathBuf,
+}
+
+impl ZckTools {
+    /// The tools in `ZCK_BIN_DIR`, `None` when the variable is unset
+    ///
+    /// Panics when a tool is missing from the directory.
+    pub fn from_env() -> Option<Self> {
+        let dir = PathBuf::from(env::var_os(BIN_DIR_VAR)?);
+        for tool in TOOLS {
+            let path = dir.join(tool);
+            assert!(
+                path.is_file(),
+                "{BIN_DIR_VAR} is set, but {path:?} is missing"
+            );
+        }
+        Some(Self { dir })
+    }
+
+    /// Run `tool` with `args` and return its stdout, panicking with its stderr on failure
+    pub fn run(&self, tool: &str, args: &[&Path]) -> Vec<u8> {
+        let output = Command::new(self.dir.join(tool))
+            .args(args)
+            .output()
+            .unwrap_or_else(|e| panic!("{tool} failed to start: {e}"));
+        assert!(
+            output.status.success(),
+            "{tool} {args:?} exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        output.stdout
+    }
+
+    /// Compress `input` with `zck` into `output`
+    pub fn zck(&self, input: &Path, output: &Path) {
+        self.run("zck", &[Path::new("-o"), output, input]);
+    }
+
+    /// Decompress `path` with `unzck`
+    pub fn unzck(&self, path: &Path) -> Vec<u8> {
+        self.run("unzck", &[Path::new("-c"), path])
+    }
+
+    /// The header of `path` as printed by `zck_read_header`, with the chunk table
+    pub fn read_header(&self, path: &Path) -> ReadHeader {
+        let output = self.run("zck_read_header", &[Path::new("-c"), path]);
+        ReadHeader::parse(&String::from_utf8(output).unwrap())
+    }
+}
+
+/// The tools, or `None` after noting on stderr that the test is skipped
+pub fn tools_or_skip(test: &str) -> Option<ZckTools> {
+    let tools = ZckTools::from_env();
+    if tools.is_none() {
+        eprintln!("{test} skipped: {BIN_DIR_VAR} is not set");
+    }
+    tools
+}
+
+/// A row of the chunk table, the dict chunk is number 0
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkRow {
+    pub number: usize,
+    pub checksum: String,
+    /// Absolute offset in the file
+    pub start: u64,
+    pub length: u64,
+    pub uncompressed_length: u64,
+}
+
+/// The output of `zck_read_header -c`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReadHeader {
+    /// `Name: value` lines
+    pub fields: BTreeMap<String, String>,
+    pub chunks: Vec<ChunkRow>,
+}
+
+impl ReadHeader {
+    /// Parse the output, lines that are neither a field nor a chunk row are skipped
+    pub fn parse(output: &str) -> Self {
+        let mut parsed = Self::default();
+        for line in output.lines() {
+            if let Some(row) = parse_chunk_row(line) {
+                parsed.chunks.push(row);
+            } else if let Some((name, value)) = line.split_once(": ") {
+                parsed
+                    .fields
+                    .insert(name.trim().to_string(), value.trim().to_string());
+            }
+        }
+        parsed
+    }
+
+    /// The value of a field, panicking when it is missing
+    pub fn field(&self, name: &str) -> &str {
+        self.fields
+            .get(name)
+            .unwrap_or_else(|| panic!("no {name:?} in {:?}", self.fields))
+    }
+
+    fn number(&self, name: &str) -> u64 {
+        let value = self.field(name);
+        value
+            .parse()
+            .unwrap_or_else(|e| panic!("{name}: {value:?}: {e}"))
+    }
+
+    pub fn header_size(&self) -> u64 {
+        self.number("Header size")
+    }
+
+    /// The number of chunks, the dict chunk included
+    pub fn chunk_count(&self) -> u64 {
+        self.number("Chunk count")
+    }
+
+    pub fn header_checksum(&self) -> &str {
+        self.field("Header checksum")
+    }
+
+    pub fn data_checksum(&self) -> &str {
+        self.field("Data checksum")
+    }
+
+    pub fn chunk_checksum_type(&self) -> ChecksumType {
+        checksum_type_from_name(self.field("Index checksum type"))
+    }
+}
+
+/// A chunk table row: number, checksum, start, compressed and uncompressed size, and
+/// possibly a verification mark
+fn parse_chunk_row(line: &str) -> Option<ChunkRow> {
+    let mut fields = line.split_whitespace();
+    let number = fields.next()?.parse().ok()?;
+    let checksum = fields.next()?;
+    if !checksum.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some(ChunkRow {
+        number,
+        checksum: checksum.to_string(),
+        start: fields.next()?.parse().ok()?,
+        length: fields.next()?.parse().ok()?,
+        uncompressed_length: fields.next()?.parse().ok()?,
+    })
+}
+
+/// The checksum type upstream prints as `name`
+pub fn checksum_type_from_name(name: &str) -> ChecksumType {
+    match name {
+        "SHA-1" => ChecksumType::Sha1,
+        "SHA-256" => ChecksumType::Sha256,
+        "SHA-512" => ChecksumType::Sha512,
+        "SHA-512/128" => ChecksumType::Sha512_128,
+        _ => panic!("unknown checksum type name {name:?}"),
+    }
+}
+
+/// SHA-256 of `bytes` as hex, the form payload digests are compared in
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
+
+/// Assert that two payloads are the same, showing digests and lengths instead of the bytes
+pub fn assert_same_payload(ours: &[u8], theirs: &[u8], context: &str) {
+    assert!(
+        ours == theirs,
+        "{context}: this crate has {} bytes with SHA-256 {}, upstream {} bytes with {}",
+        ours.len(),
+        sha256_hex(ours),
+        theirs.len(),
+        sha256_hex(theirs)
+    );
+}
+
+/// Decompress `path` with this crate
+pub fn decompress(path: &Path) -> Vec<u8> {
+    let mut decoder = Decoder::new(BufReader::new(File::open(path).unwrap())).unwrap();
+    let mut payload = Vec::new();
+    decoder.decompress_to(&mut payload).unwrap();
+    payload
+}
+
+/// Assert that `zck_read_header` and this crate read the same header from `path`
+pub fn assert_header_matches(read: &ReadHeader, path: &Path) {
+    let context = path.display();
+    let bytes = std::fs::read(path).unwrap();
+    let partial = PartialDecoder::peek(Cursor::new(bytes.as_slice())).unwrap();
+    assert_eq!(
+        read.header_size(),
+        partial.header_size().unwrap(),
+        "{context}"
+    );
+    assert_eq!(
+        read.header_checksum(),
+        hex::encode(partial.header_checksum()),
+        "{context}"
+    );
+    assert_eq!(
+        read.data_checksum(),
+        hex::encode(partial.data_checksum()),
+        "{context}"
+    );
+
+    let decoder = partial.into_full().unwrap();
+    let header = decoder.header();
+    let keys = header.export_chunk_keys().unwrap();
+    assert_eq!(read.chunk_count(), keys.len() as u64 + 1, "{context}");
+    assert!(
+        keys.iter()
+            .all(|k| k.checksum_type() == read.chunk_checksum_type()),
+        "{context}"
+    );
+
+    assert_eq!(
+        read.chunks.len(),
+        keys.len() + 1,
+        "{context}: {:?}",
+        read.chunks
+    );
+    for id in 0..keys.len() {
+        let row = &read.chunks[id + 1];
+        let range = header.chunk_range(id).unwrap();
+        assert_eq!(row.number, id + 1, "{context}");
+        assert_eq!(row.start, range.start, "{context}: chunk {id}");
+        assert_eq!(row.length, range.end - range.start, "{context}: chunk {id}");
+    }
+
+    // the manifest is the only public view of the dict chunk and the uncompressed lengths
+    let mut manifest = Vec::new();
+    decoder.write_manifest(&mut manifest).unwrap();
+    let manifest = String::from_utf8(manifest).unwrap();
+    for line in manifest.lines() {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let row = match fields[0] {
+            "dict" => &read.chunks[0],
+            "header" => continue,
+            id => &read.chunks[id.parse::<usize>().unwrap() + 1],
+        };
+        assert_eq!(row.length.to_string(), fields[2], "{context}: {line}");
+        assert_eq!(row.checksum, fields[4], "{context}: {line}");
+        assert_eq!(
+            row.uncompressed_length.to_string(),
+            fields[3],
+            "{context}: {line}"
+        );
+    }
+}
@@ -0,0 +1,4 @@
+//! Helpers shared by the integration tests, each test target uses a part of them
+#![allow(dead_code)]
+
+pub mod interop;
@@ -0,0 +1,32 @@
+#!/bin/sh
+# Generate the interop fixtures with the upstream zchunk tools
+# (https://github.com/zchunk/zchunk), run once from the crate root and check the output in.
+#
+# Every fixture compresses the same input, so the tests know the payload digest. After
+# generating, record the data chunk count in tests/interop/main.rs and drop the `#[ignore]`
+# of the fixture's test.
+set -eu
+
+INPUT=testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml
+OUT=tests/interop/fixtures
+DICT=$(mktemp)
+trap 'rm -f "$DICT"' EXIT
+
+mkdir -p "$OUT"
+zck --version
+
+# a dict trained on the second comps file, which shares most of its content
+head -c 65536 testdata/4a1a7a9d98dd9764f67d4a608828fa8afca99889afe8b178228f5d37959c1ebf-comps-Server.x86_64.xml >"$DICT"
+zck -D "$DICT" -o "$OUT/dict.zck" "$INPUT"
+
+zck --chunk-hash-type sha256 -o "$OUT/sha256.zck" "$INPUT"
+zck --chunk-hash-type sha512 -o "$OUT/sha512.zck" "$INPUT"
+zck --chunk-hash-type sha1 -o "$OUT/sha1.zck" "$INPUT"
+
+# Stream flags, optional elements and signatures are part of the format, but no upstream
+# command line tool writes them. Their fixtures (stream.zck, optional-elements.zck and
+# signatures.zck) need another writer and stay ignored until one exists.
+
+for f in "$OUT"/*.zck; do
+    zck_read_header -c "$f" | head -n 20
+done
@@ -0,0 +1,252 @@
+//! Compatibility with files written by the upstream C zchunk tools
+//!
+//! Every fixture compresses the same input, `testdata/14a39837...-comps-Server.x86_64.xml`,
+//! with one format feature enabled. `generate.sh` documents how each was produced. Fixtures
+//! that are not checked in yet, or exercise features this crate does not support, have
+//! ignored tests, so `cargo test -- --ignored` lists what is left.
+#![cfg(all(feature = "zstd", feature = "sha512"))]
+
+use std::{
+    fs::File,
+    io::{BufReader, Cursor},
+    path::Path,
+};
+
+use sha2::{Digest, Sha256};
+use zchunk::{ChecksumType, CompressionType, DecodeOptions, Decoder, Encoder, PartialDecoder};
+
+const INPUT: &str =
+    "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml";
+const INPUT_SHA256: &str = "14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68";
+
+/// A fixture and the header fields upstream wrote into it
+struct Fixture {
+    path: &'static str,
+    compression_type: CompressionType,
+    checksum_type: ChecksumType,
+    /// `None` until the fixture is generated and the count recorded
+    data_chunks: Option<usize>,
+    has_dict: bool,
+    payload_sha256: &'static str,
+}
+
+impl Fixture {
+    /// A fixture from `generate.sh`, which compresses `INPUT`
+    const fn generated(path: &'static str, checksum_type: ChecksumType, has_dict: bool) -> Self {
+        Self {
+            path,
+            compression_type: CompressionType::Zstd,
+            checksum_type,
+            data_chunks: None,
+            has_dict,
+            payload_sha256: INPUT_SHA256,
+        }
+    }
+}
+
+/// Files from a Fedora repository, written by upstream with its default settings
+const UPSTREAM: &[Fixture] = &[
+    Fixture {
+        path: "testdata/c25ffa05cf1fdeb67801847df96c33933b1ee1ea081af52edff4ff371a1c814c-comps-Server.x86_64.xml.zck",
+        compression_type: CompressionType::Zstd,
+        checksum_type: ChecksumType::Sha512_128,
+        data_chunks: Some(3),
+        has_dict: false,
+        payload_sha256: INPUT_SHA256,
+    },
+    Fixture {
+        path: "testdata/3c6181c789ef9e8ed23f4072eb2f8f529002abd5166273a9734d7d39f7a810ae-comps-Server.x86_64.xml.zck",
+        compression_type: CompressionType::Zstd,
+        checksum_type: ChecksumType::Sha512_128,
+        data_chunks: Some(3),
+        has_dict: false,
+        payload_sha256: "4a1a7a9d98dd9764f67d4a608828fa8afca99889afe8b178228f5d37959c1ebf",
+    },
+];
+
+fn check_fixture(fixture: &Fixture) {
+    let path = Path::new(fixture.path);
+    assert!(
+        path.exists(),
+        "{} is missing, see generate.sh",
+        fixture.path
+    );
+    let mut decoder = Decoder::new(BufReader::new(File::open(path).unwrap())).unwrap();
+
+    let header = decoder.header();
+    assert_eq!(
+        header.compression_type().unwrap(),
+        fixture.compression_type,
+        "{}",
+        fixture.path
+    );
+    let keys = header.export_chunk_keys().unwrap();
+    assert!(keys
+        .iter()
+        .all(|k| k.checksum_type() == fixture.checksum_type));
+    if let Some(count) = fixture.data_chunks {
+        assert_eq!(keys.len(), count, "{}", fixture.path);
+    }
+
+    // the manifest is the only public view of the dict chunk
+    let mut manifest = Vec::new();
+    decoder.write_manifest(&mut manifest).unwrap();
+    let manifest = String::from_utf8(manifest).unwrap();
+    let dict_line = manifest.lines().find(|l| l.starts_with("dict\t")).unwrap();
+    assert_eq!(
+        !dict_line.starts_with("dict\t0\t0\t"),
+        fixture.has_dict,
+        "{}",
+        fixture.path
+    );
+
+    let mut hasher = Sha256::new();
+    decoder.decompress_to(&mut hasher).unwrap();
+    assert_eq!(
+        hex::encode(hasher.finalize()),
+        fixture.payload_sha256,
+        "{}",
+        fixture.path
+    );
+}
+
+/// Upstream hashes the header with the checksum field left out, not zeroed, which is also
+/// what this crate writes and checks
+#[test]
+fn test_header_checksum_domain() {
+    for fixture in UPSTREAM {
+        let partial =
+            PartialDecoder::peek(BufReader::new(File::open(fixture.path).unwrap())).unwrap();
+        let stored = partial.header_checksum().to_vec();
+        let decoder = partial
+            .into_full_with_options(DecodeOptions::new().keep_header_bytes(true))
+            .unwrap();
+        let bytes = decoder.header_bytes().unwrap();
+        let at = bytes.windows(32).position(|w| *w == stored[..]).unwrap();
+
+        let mut omitted = Sha256::new();
+        omitted.update(&bytes[..at]);
+        omitted.update(&bytes[at + 32..]);
+        assert_eq!(omitted.finalize()[..], stored[..], "{}", fixture.path);
+
+        let mut zeroed = bytes.to_vec();
+        zeroed[at..at + 32].fill(0);
+        assert_ne!(Sha256::digest(&zeroed)[..], stored[..], "{}", fixture.path);
+    }
+}
+
+#[test]
+fn test_upstream_defaults() {
+    UPSTREAM.iter().for_each(check_fixture);
+}
+
+#[test]
+fn test_reencode_upstream_payload() {
+    for fixture in UPSTREAM {
+        let mut decoder = Decoder::new(BufReader::new(File::open(fixture.path).unwrap())).unwrap();
+        let mut payload = Vec::new();
+        decoder.decompress_to(&mut payload).unwrap();
+
+        let mut encoder = Encoder::new(payload.as_slice(), Cursor::new(Vec::new())).unwrap();
+        encoder.prepare_chunks().unwrap();
+        let mut output = Vec::new();
+        encoder.compress_to(&mut output).unwrap();
+
+        let mut hasher = Sha256::new();
+        Decoder::new(Cursor::new(output))
+            .unwrap()
+            .decompress_to(&mut hasher)
+            .unwrap();
+        assert_eq!(hex::encode(hasher.finalize()), fixture.payload_sha256);
+    }
+}
+
+#[test]
+#[ignore = "the encoder uses other compression settings than upstream"]
+fn test_reencode_byte_identical() {
+    let upstream = std::fs::read(UPSTREAM[0].path).unwrap();
+    let mut encoder = Encoder::new(File::open(INPUT).unwrap(), Cursor::new(Vec::new())).unwrap();
+    encoder.prepare_chunks().unwrap();
+    let mut output = Vec::new();
+    encoder.compress_to(&mut output).unwrap();
+    assert!(output == upstream);
+}
+
+#[test]
+#[ignore = "fixture not generated yet, see generate.sh"]
+fn test_dict() {
+    let fixture = Fixture::generated(
+        "tests/interop/fixtures/dict.zck",
+        ChecksumType::Sha512_128,
+        true,
+    );
+    check_fixture(&fixture);
+
+    // upstream records the uncompressed length of the dict file in the dict entry
+    let decoder = Decoder::new(BufReader::new(File::open(fixture.path).unwrap())).unwrap();
+    let mut manifest = Vec::new();
+    decoder.write_manifest(&mut manifest).unwrap();
+    let manifest = String::from_utf8(manifest).unwrap();
+    let dict_line = manifest.lines().find(|l| l.starts_with("dict\t")).unwrap();
+    assert_eq!(dict_line.split('\t').nth(3), Some("65536"), "{dict_line}");
+}
+
+#[test]
+#[ignore = "fixture not generated yet, see generate.sh"]
+fn test_sha256_chunks() {
+    check_fixture(&Fixture::generated(
+        "tests/interop/fixtures/sha256.zck",
+        ChecksumType::Sha256,
+        false,
+    ));
+}
+
+#[test]
+#[ignore = "fixture not generated yet, see generate.sh"]
+fn test_sha512_chunks() {
+    check_fixture(&Fixture::generated(
+        "tests/interop/fixtures/sha512.zck",
+        ChecksumType::Sha512,
+        false,
+    ));
+}
+
+#[test]
+#[ignore = "SHA-1 chunk checksums are not verified by this crate"]
+fn test_sha1_chunks() {
+    check_fixture(&Fixture::generated(
+        "tests/interop/fixtures/sha1.zck",
+        ChecksumType::Sha1,
+        false,
+    ));
+}
+
+#[test]
+#[ignore = "no upstream tool writes stream flags, see generate.sh"]
+fn test_stream_flag() {
+    check_fixture(&Fixture::generated(
+        "tests/interop/fixtures/stream.zck",
+        ChecksumType::Sha512_128,
+        false,
+    ));
+}
+
+#[test]
+#[ignore = "no upstream tool writes optional elements, see generate.sh"]
+fn test_optional_elements() {
+    check_fixture(&Fixture::generated(
+        "tests/interop/fixtures/optional-elements.zck",
+        ChecksumType::Sha512_128,
+        false,
+    ));
+}
+
+#[test]
+#[ignore = "no upstream tool writes signatures, see generate.sh"]
+fn test_signatures() {
+    check_fixture(&Fixture::generated(
+        "tests/interop/fixtures/signatures.zck",
+        ChecksumType::Sha512_128,
+        false,
+    ));
+}
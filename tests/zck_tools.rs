@@ -0,0 +1,127 @@
+//! Round trips through the upstream zchunk command line tools
+//!
+//! The tests that run the tools are ignored, see `common::interop` for how to enable them.
+#![cfg(all(feature = "zstd", feature = "sha512"))]
+
+mod common;
+
+use std::{fs, io::Cursor, path::Path};
+
+use common::interop::{
+    assert_header_matches, assert_same_payload, decompress, tools_or_skip, ChunkRow, ReadHeader,
+};
+use zchunk::{ChecksumType, Encoder};
+
+/// Uncompressed inputs in testdata
+const INPUTS: &[&str] = &[
+    "testdata/14a39837e647b53517485cb00acc4d3cd989d13d68033213b1bb143330349f68-comps-Server.x86_64.xml",
+    "testdata/4a1a7a9d98dd9764f67d4a608828fa8afca99889afe8b178228f5d37959c1ebf-comps-Server.x86_64.xml",
+];
+
+/// Files written by upstream, the checked in ones and those `tests/interop/generate.sh`
+/// wrote so far
+fn upstream_files() -> Vec<String> {
+    let mut files = vec![
+        "testdata/c25ffa05cf1fdeb67801847df96c33933b1ee1ea081af52edff4ff371a1c814c-comps-Server.x86_64.xml.zck".to_string(),
+        "testdata/3c6181c789ef9e8ed23f4072eb2f8f529002abd5166273a9734d7d39f7a810ae-comps-Server.x86_64.xml.zck".to_string(),
+    ];
+    if let Ok(entries) = fs::read_dir("tests/interop/fixtures") {
+        for entry in entries {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|e| e == "zck") {
+                files.push(path.to_str().unwrap().to_string());
+            }
+        }
+    }
+    files
+}
+
+fn encode(input: &[u8]) -> Vec<u8> {
+    let mut encoder = Encoder::new(input, Cursor::new(Vec::new())).unwrap();
+    encoder.prepare_chunks().unwrap();
+    let mut output = Vec::new();
+    encoder.compress_to(&mut output).unwrap();
+    output
+}
+
+#[test]
+fn test_parse_read_header() {
+    let output = "\
+Overall checksum type: SHA-256
+Header size: 1041
+Header checksum: aada6500791e41e0306c756fdc4c5cb7750c6ad21af847b1ea5e5622da1a5975
+Data checksum: 9f055555d05bc8b8fa75074ea175100efc0156800662d0cadedd75707d0f9a7d
+Index checksum type: SHA-512/128
+Chunk count: 2
+       Chunk Checksum                                  Start    Comp size         Size
+           0 00000000000000000000000000000000          1041            0            0
+           1 5c3dbc692dd5c7a9f3f66992ad430823          1041        18071        67252 +
+";
+    let read = ReadHeader::parse(output);
+    assert_eq!(read.header_size(), 1041);
+    assert_eq!(read.chunk_count(), 2);
+    assert_eq!(read.chunk_checksum_type(), ChecksumType::Sha512_128);
+    assert_eq!(read.field("Overall checksum type"), "SHA-256");
+    assert_eq!(
+        read.chunks[1],
+        ChunkRow {
+            number: 1,
+            checksum: "5c3dbc692dd5c7a9f3f66992ad430823".to_string(),
+            start: 1041,
+            length: 18071,
+            uncompressed_length: 67252,
+        }
+    );
+    assert_eq!(read.chunks.len(), 2);
+}
+
+/// (a) files of this crate decompress with `unzck`
+#[test]
+#[ignore = "needs the upstream zchunk tools, set ZCK_BIN_DIR"]
+fn test_unzck_reads_ours() {
+    let Some(tools) = tools_or_skip("test_unzck_reads_ours") else {
+        return;
+    };
+    let dir = tempfile::tempdir().unwrap();
+    for input in INPUTS {
+        let payload = fs::read(input).unwrap();
+        let path = dir.path().join("ours.zck");
+        fs::write(&path, encode(&payload)).unwrap();
+        assert_same_payload(&payload, &tools.unzck(&path), input);
+        assert_header_matches(&tools.read_header(&path), &path);
+    }
+}
+
+/// (b) files of upstream decompress with this crate, to what `unzck` makes of them
+#[test]
+#[ignore = "needs the upstream zchunk tools, set ZCK_BIN_DIR"]
+fn test_we_read_upstream() {
+    let Some(tools) = tools_or_skip("test_we_read_upstream") else {
+        return;
+    };
+    for file in upstream_files() {
+        let path = Path::new(&file);
+        assert_same_payload(&decompress(path), &tools.unzck(path), &file);
+    }
+
+    // and what `zck` writes now, not only the checked in files
+    let dir = tempfile::tempdir().unwrap();
+    for input in INPUTS {
+        let path = dir.path().join("theirs.zck");
+        tools.zck(Path::new(input), &path);
+        assert_same_payload(&decompress(&path), &fs::read(input).unwrap(), input);
+    }
+}
+
+/// (c) `zck_read_header` and this crate agree on the header fields
+#[test]
+#[ignore = "needs the upstream zchunk tools, set ZCK_BIN_DIR"]
+fn test_read_header_fields() {
+    let Some(tools) = tools_or_skip("test_read_header_fields") else {
+        return;
+    };
+    for file in upstream_files() {
+        let path = Path::new(&file);
+        assert_header_matches(&tools.read_header(path), path);
+    }
+}